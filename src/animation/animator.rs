@@ -0,0 +1,268 @@
+use legion::prelude::*;
+use nalgebra::Matrix4;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    assets::{Assets, Handle},
+    engine::Time,
+    graphics::skinned_model::SkinnedModel,
+};
+
+use super::{clip::AnimationClip, skeleton::Skeleton};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Playback {
+    clip: usize,
+    time: f32,
+    looping: bool,
+}
+
+impl Playback {
+    fn advance(&mut self, delta_time: f32, clips: &[AnimationClip]) {
+        let duration = clips[self.clip].duration;
+        self.time += delta_time;
+        if self.looping && duration > 0.0 {
+            self.time = self.time.rem_euclid(duration);
+        } else {
+            self.time = self.time.min(duration).max(0.0);
+        }
+    }
+}
+
+// Plays the clips of the SkinnedModel of the same entity, clips are referred to by
+// their index in the model, see SkinnedModel::clip_index for looking them up by name
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Animator {
+    // playback rate of every clip, negative values play backwards
+    pub speed: f32,
+    current: Option<Playback>,
+    // faded out while the current clip fades in
+    previous: Option<Playback>,
+    fade_duration: f32,
+    fade_time: f32,
+    // skinning matrices of the last update, uploaded by the renderer
+    #[serde(skip)]
+    joint_matrices: Vec<Matrix4<f32>>,
+}
+
+impl Default for Animator {
+    fn default() -> Self {
+        Animator {
+            speed: 1.0,
+            current: None,
+            previous: None,
+            fade_duration: 0.0,
+            fade_time: 0.0,
+            joint_matrices: Vec::new(),
+        }
+    }
+}
+
+impl Animator {
+    pub fn new() -> Self {
+        Animator::default()
+    }
+
+    pub fn play(&mut self, clip: usize, looping: bool) {
+        self.current = Some(Playback {
+            clip,
+            time: 0.0,
+            looping,
+        });
+        self.previous = None;
+    }
+
+    // Blends from the pose of the current clip to the new one over duration seconds
+    pub fn cross_fade(&mut self, clip: usize, looping: bool, duration: f32) {
+        self.previous = self.current.take();
+        self.current = Some(Playback {
+            clip,
+            time: 0.0,
+            looping,
+        });
+        self.fade_duration = duration;
+        self.fade_time = 0.0;
+    }
+
+    // The skeleton returns to its bind pose
+    pub fn stop(&mut self) {
+        self.current = None;
+        self.previous = None;
+    }
+
+    pub fn current_clip(&self) -> Option<usize> {
+        self.current.map(|playback| playback.clip)
+    }
+
+    // Non looping clips are finished once they reach their last keyframe
+    pub fn is_finished(&self, clips: &[AnimationClip]) -> bool {
+        self.current.map_or(true, |playback| {
+            !playback.looping && playback.time >= clips[playback.clip].duration
+        })
+    }
+
+    pub fn joint_matrices(&self) -> &[Matrix4<f32>] {
+        &self.joint_matrices
+    }
+
+    pub fn update(&mut self, delta_time: f32, skeleton: &Skeleton, clips: &[AnimationClip]) {
+        let delta_time = delta_time * self.speed;
+        let mut pose = skeleton.bind_pose();
+        if let Some(current) = self.current.as_mut() {
+            current.advance(delta_time, clips);
+            clips[current.clip].sample(current.time, &mut pose);
+        }
+        if let Some(previous) = self.previous.as_mut() {
+            self.fade_time += delta_time.abs();
+            if self.fade_time >= self.fade_duration {
+                self.previous = None;
+            } else {
+                previous.advance(delta_time, clips);
+                let mut previous_pose = skeleton.bind_pose();
+                clips[previous.clip].sample(previous.time, &mut previous_pose);
+                let weight = self.fade_time / self.fade_duration;
+                for (pose, previous_pose) in pose.iter_mut().zip(&previous_pose) {
+                    *pose = previous_pose.interpolate(pose, weight);
+                }
+            }
+        }
+        skeleton.joint_matrices(&pose, &mut self.joint_matrices);
+    }
+}
+
+// Advances every Animator and computes its joint matrices
+pub fn animation_system() -> Box<dyn Schedulable> {
+    SystemBuilder::new("animation-system")
+        .read_resource::<Time>()
+        .read_resource::<Assets<SkinnedModel>>()
+        .with_query(<(Write<Animator>, Tagged<Handle<SkinnedModel>>)>::query())
+        .build(|_, world, (time, model_storage), query| {
+            for (mut animator, handle) in query.iter_mut(world) {
+                // the model is loaded during the first rendered frame
+                if let Some(model) = model_storage.get(handle) {
+                    animator.update(time.delta_time, &model.skeleton, &model.clips);
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::animation::{
+        clip::{Channel, JointTrack},
+        skeleton::{Joint, JointPose},
+        Interpolation, Track,
+    };
+    use nalgebra::Vector3;
+
+    // A single joint with an identity bind pose, so its skinning
+    // matrix is the sampled pose of the joint
+    fn skeleton() -> Skeleton {
+        Skeleton::new(
+            vec![Joint {
+                name: "root".to_string(),
+                parent: None,
+                inverse_bind_matrix: Matrix4::identity(),
+                bind_pose: JointPose::default(),
+            }],
+            Matrix4::identity(),
+        )
+    }
+
+    // Clips that move the joint along x over a second
+    fn clip(from: f32, to: f32) -> AnimationClip {
+        let track = Track::new(Interpolation::Linear)
+            .with_keyframe(0.0, Vector3::new(from, 0.0, 0.0))
+            .with_keyframe(1.0, Vector3::new(to, 0.0, 0.0));
+        AnimationClip::new(
+            format!("{} to {}", from, to),
+            vec![Channel {
+                joint: 0,
+                track: JointTrack::Translation(track),
+            }],
+        )
+    }
+
+    fn clips() -> Vec<AnimationClip> {
+        vec![clip(0.0, 0.0), clip(10.0, 10.0), clip(0.0, 10.0)]
+    }
+
+    fn x(animator: &Animator) -> f32 {
+        animator.joint_matrices()[0][(0, 3)]
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn without_a_clip_the_skeleton_is_in_its_bind_pose() {
+        let mut animator = Animator::new();
+        animator.update(0.5, &skeleton(), &clips());
+        assert_eq!(animator.joint_matrices(), &[Matrix4::identity()][..]);
+        assert!(animator.is_finished(&clips()));
+    }
+
+    #[test]
+    fn play_switches_clips_right_away() {
+        let (skeleton, clips) = (skeleton(), clips());
+        let mut animator = Animator::new();
+        animator.play(0, true);
+        animator.update(0.25, &skeleton, &clips);
+        animator.cross_fade(2, true, 1.0);
+        animator.play(1, true);
+        animator.update(0.25, &skeleton, &clips);
+        assert_close(x(&animator), 10.0);
+        assert_eq!(animator.current_clip(), Some(1));
+    }
+
+    #[test]
+    fn cross_fades_blend_by_the_elapsed_fraction() {
+        let (skeleton, clips) = (skeleton(), clips());
+        let mut animator = Animator::new();
+        animator.play(0, true);
+        animator.update(0.1, &skeleton, &clips);
+        animator.cross_fade(1, true, 1.0);
+        for &(delta_time, expected) in &[(0.25, 2.5), (0.25, 5.0), (0.25, 7.5), (0.25, 10.0)] {
+            animator.update(delta_time, &skeleton, &clips);
+            assert_close(x(&animator), expected);
+        }
+        // the fade is over
+        animator.update(0.25, &skeleton, &clips);
+        assert_close(x(&animator), 10.0);
+    }
+
+    #[test]
+    fn speed_scales_the_playback_and_the_fade() {
+        let (skeleton, clips) = (skeleton(), clips());
+        let mut animator = Animator::new();
+        animator.speed = 2.0;
+        animator.play(2, false);
+        animator.update(0.25, &skeleton, &clips);
+        assert_close(x(&animator), 5.0);
+        assert!(!animator.is_finished(&clips));
+        animator.update(0.25, &skeleton, &clips);
+        assert_close(x(&animator), 10.0);
+        assert!(animator.is_finished(&clips));
+
+        animator.play(0, true);
+        animator.cross_fade(1, true, 1.0);
+        animator.update(0.25, &skeleton, &clips);
+        assert_close(x(&animator), 5.0);
+    }
+
+    #[test]
+    fn negative_speeds_play_backwards() {
+        let (skeleton, clips) = (skeleton(), clips());
+        let mut animator = Animator::new();
+        animator.speed = -1.0;
+        animator.play(2, true);
+        animator.update(0.25, &skeleton, &clips);
+        assert_close(x(&animator), 7.5);
+
+        animator.play(2, false);
+        animator.update(0.25, &skeleton, &clips);
+        assert_close(x(&animator), 0.0);
+    }
+}
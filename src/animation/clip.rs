@@ -0,0 +1,117 @@
+use nalgebra::{UnitQuaternion, Vector3};
+
+use super::{skeleton::JointPose, track::Track};
+
+#[derive(Debug, Clone)]
+pub enum JointTrack {
+    Translation(Track<Vector3<f32>>),
+    Rotation(Track<UnitQuaternion<f32>>),
+    Scale(Track<Vector3<f32>>),
+}
+
+// Keyframes of a single property of a joint
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub joint: usize,
+    pub track: JointTrack,
+}
+
+impl Channel {
+    pub fn duration(&self) -> f32 {
+        match &self.track {
+            JointTrack::Translation(track) => track.duration(),
+            JointTrack::Rotation(track) => track.duration(),
+            JointTrack::Scale(track) => track.duration(),
+        }
+    }
+
+    pub fn sample(&self, time: f32, pose: &mut JointPose) {
+        match &self.track {
+            JointTrack::Translation(track) => {
+                if let Some(translation) = track.sample(time) {
+                    pose.translation = translation;
+                }
+            }
+            JointTrack::Rotation(track) => {
+                if let Some(rotation) = track.sample(time) {
+                    pose.rotation = rotation;
+                }
+            }
+            JointTrack::Scale(track) => {
+                if let Some(scale) = track.sample(time) {
+                    pose.scale = scale;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnimationClip {
+    pub name: String,
+    // time of the last keyframe of any channel
+    pub duration: f32,
+    pub channels: Vec<Channel>,
+}
+
+impl AnimationClip {
+    pub fn new(name: String, channels: Vec<Channel>) -> Self {
+        let duration = channels.iter().map(Channel::duration).fold(0.0, f32::max);
+        AnimationClip {
+            name,
+            duration,
+            channels,
+        }
+    }
+
+    // Joints without channels keep the values already in the pose
+    pub fn sample(&self, time: f32, pose: &mut [JointPose]) {
+        for channel in &self.channels {
+            channel.sample(time, &mut pose[channel.joint]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::animation::Interpolation;
+
+    fn translation(joint: usize, keyframes: &[(f32, f32)]) -> Channel {
+        let track = keyframes
+            .iter()
+            .fold(Track::new(Interpolation::Linear), |track, &(time, x)| {
+                track.with_keyframe(time, Vector3::new(x, 0.0, 0.0))
+            });
+        Channel {
+            joint,
+            track: JointTrack::Translation(track),
+        }
+    }
+
+    #[test]
+    fn the_duration_is_the_longest_channel() {
+        let clip = AnimationClip::new(
+            "walk".to_string(),
+            vec![
+                translation(0, &[(0.0, 0.0), (1.5, 1.0)]),
+                translation(1, &[(0.0, 0.0), (0.5, 1.0)]),
+            ],
+        );
+        assert!((clip.duration - 1.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn joints_without_channels_keep_their_pose() {
+        let clip = AnimationClip::new(
+            "walk".to_string(),
+            vec![translation(1, &[(0.0, 0.0), (1.0, 4.0)])],
+        );
+        let mut pose = vec![JointPose::default(); 2];
+        pose[0].scale = Vector3::new(2.0, 2.0, 2.0);
+        clip.sample(0.25, &mut pose);
+        assert_eq!(pose[0].scale, Vector3::new(2.0, 2.0, 2.0));
+        assert!((pose[1].translation - Vector3::new(1.0, 0.0, 0.0)).norm() < 1e-5);
+        assert_eq!(pose[1].scale, Vector3::new(1.0, 1.0, 1.0));
+    }
+}
@@ -0,0 +1,14 @@
+pub mod animator;
+pub mod clip;
+pub mod skeleton;
+pub mod track;
+pub mod tween;
+
+pub use animator::{animation_system, Animator};
+pub use clip::AnimationClip;
+pub use skeleton::{JointPose, Skeleton};
+pub use track::{Interpolation, Track};
+pub use tween::{
+    keyframe_animation_system, AnimationEvents, AnimationFinished, KeyframeAnimation, PlaybackMode,
+    PropertyTrack,
+};
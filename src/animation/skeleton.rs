@@ -0,0 +1,200 @@
+use nalgebra::{Matrix4, UnitQuaternion, Vector3};
+
+// Transform of a joint relative to its parent
+#[derive(Debug, Clone, Copy)]
+pub struct JointPose {
+    pub translation: Vector3<f32>,
+    pub rotation: UnitQuaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl Default for JointPose {
+    fn default() -> Self {
+        JointPose {
+            translation: Vector3::zeros(),
+            rotation: UnitQuaternion::identity(),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+impl JointPose {
+    pub fn to_matrix(&self) -> Matrix4<f32> {
+        Matrix4::new_translation(&self.translation)
+            * self.rotation.to_homogeneous()
+            * Matrix4::new_nonuniform_scaling(&self.scale)
+    }
+
+    // Translation and scale are blended linearly and the rotation along the shortest arc
+    pub fn interpolate(&self, other: &JointPose, t: f32) -> JointPose {
+        JointPose {
+            translation: self.translation.lerp(&other.translation, t),
+            // fails when the rotations are (almost) equal or opposite
+            rotation: self
+                .rotation
+                .try_slerp(&other.rotation, t, 1.0e-6)
+                .unwrap_or(if t < 0.5 {
+                    self.rotation
+                } else {
+                    other.rotation
+                }),
+            scale: self.scale.lerp(&other.scale, t),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Joint {
+    pub name: String,
+    pub parent: Option<usize>,
+    // from model space into the space of the joint in the bind pose
+    pub inverse_bind_matrix: Matrix4<f32>,
+    // used for joints without animated channels
+    pub bind_pose: JointPose,
+}
+
+// Joints are kept in the order of the skin so the joint indices of the vertices stay valid
+#[derive(Debug, Clone)]
+pub struct Skeleton {
+    pub joints: Vec<Joint>,
+    // transform of the nodes above the root joints
+    pub root_transform: Matrix4<f32>,
+    // parents come before their children
+    evaluation_order: Vec<usize>,
+}
+
+impl Skeleton {
+    pub fn new(joints: Vec<Joint>, root_transform: Matrix4<f32>) -> Self {
+        let depth = |mut joint: usize| {
+            let mut depth = 0;
+            while let Some(parent) = joints[joint].parent {
+                joint = parent;
+                depth += 1;
+            }
+            depth
+        };
+        let mut evaluation_order = (0..joints.len()).collect::<Vec<_>>();
+        evaluation_order.sort_by_key(|&joint| depth(joint));
+        Skeleton {
+            joints,
+            root_transform,
+            evaluation_order,
+        }
+    }
+
+    pub fn joint_index(&self, name: &str) -> Option<usize> {
+        self.joints.iter().position(|joint| joint.name == name)
+    }
+
+    pub fn bind_pose(&self) -> Vec<JointPose> {
+        self.joints.iter().map(|joint| joint.bind_pose).collect()
+    }
+
+    // Model space skinning matrices of the given local joint poses
+    pub fn joint_matrices(&self, pose: &[JointPose], matrices: &mut Vec<Matrix4<f32>>) {
+        let mut global = vec![Matrix4::identity(); self.joints.len()];
+        for &joint in &self.evaluation_order {
+            let parent = self.joints[joint]
+                .parent
+                .map_or(self.root_transform, |parent| global[parent]);
+            global[joint] = parent * pose[joint].to_matrix();
+        }
+        matrices.clear();
+        matrices.extend(
+            global
+                .iter()
+                .zip(&self.joints)
+                .map(|(global, joint)| global * joint.inverse_bind_matrix),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joint(name: &str, parent: Option<usize>, bind_pose: JointPose) -> Joint {
+        Joint {
+            name: name.to_string(),
+            parent,
+            inverse_bind_matrix: Matrix4::identity(),
+            bind_pose,
+        }
+    }
+
+    fn translation(x: f32, y: f32, z: f32) -> JointPose {
+        JointPose {
+            translation: Vector3::new(x, y, z),
+            ..Default::default()
+        }
+    }
+
+    // The inverse bind matrices undo the bind pose, like the ones of a loaded skin
+    fn skeleton(mut joints: Vec<Joint>) -> Skeleton {
+        let bind_pose = joints
+            .iter()
+            .map(|joint| joint.bind_pose)
+            .collect::<Vec<_>>();
+        let mut globals = Vec::new();
+        Skeleton::new(joints.clone(), Matrix4::identity()).joint_matrices(&bind_pose, &mut globals);
+        for (joint, global) in joints.iter_mut().zip(&globals) {
+            joint.inverse_bind_matrix = global.try_inverse().unwrap();
+        }
+        Skeleton::new(joints, Matrix4::identity())
+    }
+
+    fn assert_matrix_eq(a: &Matrix4<f32>, b: &Matrix4<f32>) {
+        assert!((a - b).amax() < 1e-5, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn the_bind_pose_skins_to_identity() {
+        let rotated = JointPose {
+            rotation: UnitQuaternion::from_euler_angles(0.3, -0.2, 1.0),
+            scale: Vector3::new(1.0, 2.0, 1.0),
+            ..translation(0.0, 1.0, 0.0)
+        };
+        // the child comes first to check the parents are evaluated before it
+        let skeleton = skeleton(vec![
+            joint("hand", Some(1), translation(0.0, 0.5, 0.0)),
+            joint("arm", Some(2), rotated),
+            joint("root", None, translation(1.0, 2.0, 3.0)),
+        ]);
+        let mut matrices = Vec::new();
+        skeleton.joint_matrices(&skeleton.bind_pose(), &mut matrices);
+        assert_eq!(matrices.len(), 3);
+        for matrix in &matrices {
+            assert_matrix_eq(matrix, &Matrix4::identity());
+        }
+    }
+
+    #[test]
+    fn joints_move_with_their_parents() {
+        let skeleton = skeleton(vec![
+            joint("root", None, JointPose::default()),
+            joint("child", Some(0), translation(0.0, 1.0, 0.0)),
+        ]);
+        let mut pose = skeleton.bind_pose();
+        pose[0] = translation(2.0, 0.0, 0.0);
+        let mut matrices = Vec::new();
+        skeleton.joint_matrices(&pose, &mut matrices);
+        let moved = Matrix4::new_translation(&Vector3::new(2.0, 0.0, 0.0));
+        assert_matrix_eq(&matrices[0], &moved);
+        assert_matrix_eq(&matrices[1], &moved);
+        assert_eq!(skeleton.joint_index("child"), Some(1));
+    }
+
+    #[test]
+    fn poses_interpolate_along_the_shortest_arc() {
+        let from = JointPose::default();
+        let to = JointPose {
+            rotation: UnitQuaternion::from_euler_angles(0.0, 0.0, 1.0),
+            scale: Vector3::new(3.0, 1.0, 1.0),
+            ..translation(4.0, 0.0, 0.0)
+        };
+        let half = from.interpolate(&to, 0.5);
+        assert!((half.translation - Vector3::new(2.0, 0.0, 0.0)).norm() < 1e-5);
+        assert!((half.scale - Vector3::new(2.0, 1.0, 1.0)).norm() < 1e-5);
+        assert!((half.rotation.angle() - 0.5).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,265 @@
+use nalgebra::{Quaternion, UnitQuaternion, Vector3};
+use serde::{Deserialize, Serialize};
+use std::ops::{Add, Mul, Sub};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Interpolation {
+    Step,
+    Linear,
+    // Catmull-Rom spline through the neighbouring keyframes
+    Cubic,
+}
+
+// Values that can be stored in a track
+pub trait Keyframe: Copy {
+    fn lerp(&self, other: &Self, t: f32) -> Self;
+    // t goes from p1 to p2, p0 and p3 are the keyframes around them
+    fn catmull_rom(p0: &Self, p1: &Self, p2: &Self, p3: &Self, t: f32) -> Self;
+}
+
+fn catmull_rom<T>(p0: T, p1: T, p2: T, p3: T, t: f32) -> T
+where
+    T: Copy + Add<Output = T> + Sub<Output = T> + Mul<f32, Output = T>,
+{
+    let t2 = t * t;
+    let t3 = t2 * t;
+    (p1 * 2.0
+        + (p2 - p0) * t
+        + (p0 * 2.0 - p1 * 5.0 + p2 * 4.0 - p3) * t2
+        + (p1 * 3.0 - p0 - p2 * 3.0 + p3) * t3)
+        * 0.5
+}
+
+impl Keyframe for f32 {
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+
+    fn catmull_rom(p0: &Self, p1: &Self, p2: &Self, p3: &Self, t: f32) -> Self {
+        catmull_rom(*p0, *p1, *p2, *p3, t)
+    }
+}
+
+impl Keyframe for Vector3<f32> {
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        Vector3::lerp(self, other, t)
+    }
+
+    fn catmull_rom(p0: &Self, p1: &Self, p2: &Self, p3: &Self, t: f32) -> Self {
+        catmull_rom(*p0, *p1, *p2, *p3, t)
+    }
+}
+
+impl Keyframe for UnitQuaternion<f32> {
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        // fails when the rotations are (almost) equal or opposite
+        self.try_slerp(other, t, 1.0e-6)
+            .unwrap_or(if t < 0.5 { *self } else { *other })
+    }
+
+    // The spline goes through the components, which are flipped
+    // to the side of p1 so it takes the shortest arcs
+    fn catmull_rom(p0: &Self, p1: &Self, p2: &Self, p3: &Self, t: f32) -> Self {
+        let reference = p1.coords;
+        let aligned = |quaternion: &Self| {
+            if quaternion.coords.dot(&reference) < 0.0 {
+                -quaternion.coords
+            } else {
+                quaternion.coords
+            }
+        };
+        let coords = catmull_rom(aligned(p0), reference, aligned(p2), aligned(p3), t);
+        UnitQuaternion::from_quaternion(Quaternion::from(coords))
+    }
+}
+
+// The keyframes around time and how far between them it is,
+// times before the first or after the last keyframe are clamped
+pub(crate) fn keyframes(times: &[f32], time: f32) -> (usize, usize, f32) {
+    let last = times.len() - 1;
+    if time <= times[0] {
+        return (0, 0, 0.0);
+    }
+    if time >= times[last] {
+        return (last, last, 0.0);
+    }
+    let next = match times.binary_search_by(|probe| probe.partial_cmp(&time).unwrap()) {
+        Ok(exact) => return (exact, exact, 0.0),
+        Err(next) => next,
+    };
+    let previous = next - 1;
+    let t = (time - times[previous]) / (times[next] - times[previous]);
+    (previous, next, t)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Track<T> {
+    pub interpolation: Interpolation,
+    // ascending keyframe times in seconds
+    times: Vec<f32>,
+    values: Vec<T>,
+}
+
+impl<T: Keyframe> Track<T> {
+    pub fn new(interpolation: Interpolation) -> Self {
+        Track {
+            interpolation,
+            times: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    // The times must already be sorted
+    pub fn from_keyframes(interpolation: Interpolation, times: Vec<f32>, values: Vec<T>) -> Self {
+        assert!(
+            times.len() == values.len(),
+            "Every keyframe time needs a value"
+        );
+        Track {
+            interpolation,
+            times,
+            values,
+        }
+    }
+
+    // Keyframes can be added in any order
+    pub fn with_keyframe(mut self, time: f32, value: T) -> Self {
+        let index = self
+            .times
+            .iter()
+            .position(|&keyframe_time| keyframe_time > time)
+            .unwrap_or_else(|| self.times.len());
+        self.times.insert(index, time);
+        self.values.insert(index, value);
+        self
+    }
+
+    // Time of the last keyframe
+    pub fn duration(&self) -> f32 {
+        self.times.last().copied().unwrap_or(0.0)
+    }
+
+    pub fn sample(&self, time: f32) -> Option<T> {
+        if self.times.is_empty() {
+            return None;
+        }
+        let (previous, next, t) = keyframes(&self.times, time);
+        let values = &self.values;
+        Some(match self.interpolation {
+            Interpolation::Step => values[previous],
+            Interpolation::Linear => values[previous].lerp(&values[next], t),
+            Interpolation::Cubic => T::catmull_rom(
+                &values[previous.saturating_sub(1)],
+                &values[previous],
+                &values[next],
+                &values[(next + 1).min(values.len() - 1)],
+                t,
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(interpolation: Interpolation) -> Track<f32> {
+        Track::from_keyframes(
+            interpolation,
+            vec![0.0, 1.0, 2.0, 3.0, 4.0],
+            vec![0.0, 1.0, 2.0, 3.0, 5.0],
+        )
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn empty_tracks_have_no_value() {
+        assert_eq!(Track::<f32>::new(Interpolation::Linear).sample(1.0), None);
+    }
+
+    #[test]
+    fn keyframes_are_sorted_by_time() {
+        let track = Track::new(Interpolation::Step)
+            .with_keyframe(2.0, 2.0)
+            .with_keyframe(0.0, 0.0)
+            .with_keyframe(1.0, 1.0);
+        assert_close(track.duration(), 2.0);
+        assert_eq!(track.sample(0.5), Some(0.0));
+        assert_eq!(track.sample(1.5), Some(1.0));
+    }
+
+    #[test]
+    fn times_outside_the_track_are_clamped() {
+        for &interpolation in &[
+            Interpolation::Step,
+            Interpolation::Linear,
+            Interpolation::Cubic,
+        ] {
+            let track = track(interpolation);
+            assert_eq!(track.sample(-1.0), Some(0.0));
+            assert_eq!(track.sample(5.0), Some(5.0));
+        }
+    }
+
+    #[test]
+    fn step_holds_the_previous_keyframe() {
+        let track = track(Interpolation::Step);
+        assert_eq!(track.sample(0.0), Some(0.0));
+        assert_eq!(track.sample(0.99), Some(0.0));
+        assert_eq!(track.sample(1.0), Some(1.0));
+        assert_eq!(track.sample(2.5), Some(2.0));
+    }
+
+    #[test]
+    fn linear_interpolates_between_keyframes() {
+        let track = track(Interpolation::Linear);
+        assert_close(track.sample(0.25).unwrap(), 0.25);
+        assert_close(track.sample(1.0).unwrap(), 1.0);
+        assert_close(track.sample(3.5).unwrap(), 4.0);
+    }
+
+    #[test]
+    fn cubic_goes_through_the_keyframes() {
+        let track = track(Interpolation::Cubic);
+        for &(time, value) in &[(0.0, 0.0), (1.0, 1.0), (2.0, 2.0), (4.0, 5.0)] {
+            assert_close(track.sample(time).unwrap(), value);
+        }
+        // the keyframes around 1..2 lie on a line, so the spline does too
+        assert_close(track.sample(1.5).unwrap(), 1.5);
+        // but it bends towards the jump at the end
+        assert_close(track.sample(3.5).unwrap(), 4.0625);
+    }
+
+    #[test]
+    fn cubic_rotations_take_the_shortest_arc() {
+        let axis = Vector3::y_axis();
+        let rotation = |degrees: f32| UnitQuaternion::from_axis_angle(&axis, degrees.to_radians());
+        // the same rotations as 0, 90 and 180 degrees with the middle quaternion flipped
+        let flipped = UnitQuaternion::new_unchecked(-rotation(90.0).into_inner());
+        let track = Track::from_keyframes(
+            Interpolation::Cubic,
+            vec![0.0, 1.0, 2.0],
+            vec![rotation(0.0), flipped, rotation(180.0)],
+        );
+        // the clamped spline isn't symmetric, so it's only near 45 degrees
+        let sampled = track.sample(0.5).unwrap();
+        assert!(
+            sampled.angle_to(&rotation(45.0)) < 10.0f32.to_radians(),
+            "{:?}",
+            sampled.euler_angles()
+        );
+    }
+
+    #[test]
+    fn linear_rotations_slerp() {
+        let axis = Vector3::z_axis();
+        let rotation = |degrees: f32| UnitQuaternion::from_axis_angle(&axis, degrees.to_radians());
+        let track = Track::new(Interpolation::Linear)
+            .with_keyframe(0.0, rotation(0.0))
+            .with_keyframe(1.0, rotation(90.0));
+        assert!(track.sample(0.5).unwrap().angle_to(&rotation(45.0)) < 1e-3);
+    }
+}
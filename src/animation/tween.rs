@@ -0,0 +1,440 @@
+use legion::prelude::*;
+use nalgebra::{UnitQuaternion, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    camera::Camera,
+    components::{MainCamera, Transform},
+    engine::Time,
+    graphics::{render_target::OffscreenCamera, DirectionalLight, PointLight, SpotLight},
+};
+
+use super::track::Track;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PlaybackMode {
+    // stops at the last keyframe and sends an AnimationFinished event
+    Once,
+    Loop,
+    // plays forwards and backwards in turn
+    PingPong,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PropertyTrack {
+    Translation(Track<Vector3<f32>>),
+    Rotation(Track<UnitQuaternion<f32>>),
+    Scale(Track<Vector3<f32>>),
+    // both the pbr and phong diffuse colors of point, spot and directional lights
+    LightColor(Track<Vector3<f32>>),
+    LightIntensity(Track<f32>),
+    // the camera of an OffscreenCamera on the same entity, otherwise the main camera
+    CameraPosition(Track<Vector3<f32>>),
+    // degrees like Camera::set_yaw and Camera::set_pitch
+    CameraYaw(Track<f32>),
+    CameraPitch(Track<f32>),
+    // vertical field of view
+    CameraFov(Track<f32>),
+}
+
+impl PropertyTrack {
+    fn duration(&self) -> f32 {
+        match self {
+            PropertyTrack::Translation(track)
+            | PropertyTrack::Scale(track)
+            | PropertyTrack::LightColor(track)
+            | PropertyTrack::CameraPosition(track) => track.duration(),
+            PropertyTrack::Rotation(track) => track.duration(),
+            PropertyTrack::LightIntensity(track)
+            | PropertyTrack::CameraYaw(track)
+            | PropertyTrack::CameraPitch(track)
+            | PropertyTrack::CameraFov(track) => track.duration(),
+        }
+    }
+}
+
+// Tweens the transform, light and camera of its entity, tracks
+// for components the entity doesn't have are ignored
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyframeAnimation {
+    // sent along with the AnimationFinished event
+    pub name: String,
+    pub mode: PlaybackMode,
+    pub speed: f32,
+    pub playing: bool,
+    tracks: Vec<PropertyTrack>,
+    time: f32,
+    duration: f32,
+}
+
+impl KeyframeAnimation {
+    pub fn new(name: impl Into<String>, mode: PlaybackMode) -> Self {
+        KeyframeAnimation {
+            name: name.into(),
+            mode,
+            speed: 1.0,
+            playing: true,
+            tracks: Vec::new(),
+            time: 0.0,
+            duration: 0.0,
+        }
+    }
+
+    pub fn with_track(mut self, track: PropertyTrack) -> Self {
+        self.duration = self.duration.max(track.duration());
+        self.tracks.push(track);
+        self
+    }
+
+    pub fn restart(&mut self) {
+        self.time = 0.0;
+        self.playing = true;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.mode == PlaybackMode::Once && self.time >= self.duration
+    }
+
+    // Returns true when the animation finished during this update
+    fn advance(&mut self, delta_time: f32) -> bool {
+        if !self.playing || self.is_finished() {
+            return false;
+        }
+        self.time += delta_time * self.speed;
+        match self.mode {
+            PlaybackMode::Once => {
+                self.time = self.time.min(self.duration).max(0.0);
+                self.is_finished()
+            }
+            PlaybackMode::Loop if self.duration > 0.0 => {
+                self.time = self.time.rem_euclid(self.duration);
+                false
+            }
+            PlaybackMode::PingPong if self.duration > 0.0 => {
+                self.time = self.time.rem_euclid(self.duration * 2.0);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    // Ping pong animations play the second half of their cycle backwards
+    fn track_time(&self) -> f32 {
+        if self.mode == PlaybackMode::PingPong && self.time > self.duration {
+            self.duration * 2.0 - self.time
+        } else {
+            self.time
+        }
+    }
+
+    fn apply_transform(&self, transform: &mut Transform) {
+        let time = self.track_time();
+        for track in &self.tracks {
+            match track {
+                PropertyTrack::Translation(track) => {
+                    if let Some(translation) = track.sample(time) {
+                        transform.isometry.translation.vector = translation;
+                    }
+                }
+                PropertyTrack::Rotation(track) => {
+                    if let Some(rotation) = track.sample(time) {
+                        transform.isometry.rotation = rotation;
+                    }
+                }
+                PropertyTrack::Scale(track) => {
+                    if let Some(scale) = track.sample(time) {
+                        transform.scale = scale;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // The sampled light color and intensity
+    fn light(&self) -> (Option<Vector3<f32>>, Option<f32>) {
+        let time = self.track_time();
+        let mut light = (None, None);
+        for track in &self.tracks {
+            match track {
+                PropertyTrack::LightColor(track) => light.0 = track.sample(time),
+                PropertyTrack::LightIntensity(track) => light.1 = track.sample(time),
+                _ => {}
+            }
+        }
+        light
+    }
+
+    fn apply_camera(&self, camera: &mut Camera) {
+        let time = self.track_time();
+        for track in &self.tracks {
+            match track {
+                PropertyTrack::CameraPosition(track) => {
+                    if let Some(position) = track.sample(time) {
+                        camera.set_position(position);
+                    }
+                }
+                PropertyTrack::CameraYaw(track) => {
+                    if let Some(yaw) = track.sample(time) {
+                        camera.set_yaw(yaw);
+                    }
+                }
+                PropertyTrack::CameraPitch(track) => {
+                    if let Some(pitch) = track.sample(time) {
+                        camera.set_pitch(pitch);
+                    }
+                }
+                PropertyTrack::CameraFov(track) => {
+                    if let Some(fov) = track.sample(time) {
+                        camera.set_fov(fov);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnimationFinished {
+    pub entity: Entity,
+    pub name: String,
+}
+
+// Events of the last update, they are cleared when the animations are advanced again
+#[derive(Default)]
+pub struct AnimationEvents {
+    finished: Vec<AnimationFinished>,
+}
+
+impl AnimationEvents {
+    pub fn new() -> Self {
+        AnimationEvents::default()
+    }
+
+    pub fn finished(&self) -> &[AnimationFinished] {
+        &self.finished
+    }
+}
+
+// Advances every KeyframeAnimation and writes the sampled values into the components
+pub fn keyframe_animation_system() -> Box<dyn Schedulable> {
+    SystemBuilder::new("keyframe-animation-system")
+        .read_resource::<Time>()
+        .write_resource::<Camera>()
+        .write_resource::<AnimationEvents>()
+        .with_query(<Write<KeyframeAnimation>>::query())
+        .with_query(<(Read<KeyframeAnimation>, Write<Transform>)>::query())
+        .with_query(<(Read<KeyframeAnimation>, Write<PointLight>)>::query())
+        .with_query(<(Read<KeyframeAnimation>, Write<SpotLight>)>::query())
+        .with_query(<(Read<KeyframeAnimation>, Write<DirectionalLight>)>::query())
+        .with_query(<(Read<KeyframeAnimation>, Write<OffscreenCamera>)>::query())
+        .with_query(
+            <Read<KeyframeAnimation>>::query()
+                .filter(component::<MainCamera>() & !component::<OffscreenCamera>()),
+        )
+        .build(
+            |_,
+             world,
+             (time, camera, events),
+             (
+                animations,
+                transforms,
+                point_lights,
+                spot_lights,
+                directional_lights,
+                offscreen_cameras,
+                main_camera,
+            )| {
+                events.finished.clear();
+                for (entity, mut animation) in animations.iter_entities_mut(world) {
+                    if animation.advance(time.delta_time) {
+                        events.finished.push(AnimationFinished {
+                            entity,
+                            name: animation.name.clone(),
+                        });
+                    }
+                }
+                for (animation, mut transform) in transforms.iter_mut(world) {
+                    animation.apply_transform(&mut transform);
+                }
+                for (animation, mut light) in point_lights.iter_mut(world) {
+                    let (color, intensity) = animation.light();
+                    if let Some(color) = color {
+                        light.color = color;
+                        light.diffuse = color;
+                    }
+                    light.intensity = intensity.unwrap_or(light.intensity);
+                }
+                for (animation, mut light) in spot_lights.iter_mut(world) {
+                    let (color, intensity) = animation.light();
+                    if let Some(color) = color {
+                        light.color = color;
+                        light.diffuse = color;
+                    }
+                    light.intensity = intensity.unwrap_or(light.intensity);
+                }
+                for (animation, mut light) in directional_lights.iter_mut(world) {
+                    let (color, intensity) = animation.light();
+                    light.color = color.unwrap_or(light.color);
+                    light.intensity = intensity.unwrap_or(light.intensity);
+                }
+                for (animation, mut offscreen_camera) in offscreen_cameras.iter_mut(world) {
+                    animation.apply_camera(&mut offscreen_camera.camera);
+                }
+                for animation in main_camera.iter(world) {
+                    animation.apply_camera(camera);
+                }
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::animation::Interpolation;
+    use nalgebra::Point3;
+
+    fn animation(mode: PlaybackMode) -> KeyframeAnimation {
+        KeyframeAnimation::new("test", mode).with_track(PropertyTrack::LightIntensity(
+            Track::new(Interpolation::Linear)
+                .with_keyframe(0.0, 0.0)
+                .with_keyframe(2.0, 1.0),
+        ))
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+    }
+
+    fn resources() -> Resources {
+        let mut resources = Resources::default();
+        resources.insert(Time {
+            current_time: 0.0,
+            delta_time: 1.5,
+        });
+        resources.insert(Camera::new(
+            Point3::new(0.0, 0.0, 0.0),
+            Vector3::new(0.0, 0.0, -1.0),
+            800,
+            600,
+        ));
+        resources.insert(AnimationEvents::new());
+        resources
+    }
+
+    #[test]
+    fn once_stops_at_the_end() {
+        let mut animation = animation(PlaybackMode::Once);
+        assert!(!animation.advance(1.5));
+        assert_close(animation.track_time(), 1.5);
+        assert!(animation.advance(1.5));
+        assert_close(animation.track_time(), 2.0);
+        assert!(animation.is_finished());
+        assert!(!animation.advance(1.5));
+        assert_close(animation.track_time(), 2.0);
+    }
+
+    #[test]
+    fn loop_wraps_to_the_start() {
+        let mut animation = animation(PlaybackMode::Loop);
+        assert!(!animation.advance(2.5));
+        assert_close(animation.track_time(), 0.5);
+        assert!(!animation.advance(2.0));
+        assert_close(animation.track_time(), 0.5);
+        assert!(!animation.is_finished());
+    }
+
+    #[test]
+    fn ping_pong_plays_backwards_after_the_end() {
+        let mut animation = animation(PlaybackMode::PingPong);
+        assert!(!animation.advance(1.5));
+        assert_close(animation.track_time(), 1.5);
+        assert!(!animation.advance(1.0));
+        assert_close(animation.track_time(), 1.5);
+        assert!(!animation.advance(1.0));
+        assert_close(animation.track_time(), 0.5);
+        assert!(!animation.advance(1.0));
+        assert_close(animation.track_time(), 0.5);
+        assert!(!animation.is_finished());
+    }
+
+    #[test]
+    fn paused_animations_dont_advance() {
+        let mut animation = animation(PlaybackMode::Once);
+        animation.playing = false;
+        assert!(!animation.advance(3.0));
+        assert_close(animation.track_time(), 0.0);
+    }
+
+    #[test]
+    fn restarting_plays_from_the_start() {
+        let mut animation = animation(PlaybackMode::Once);
+        assert!(animation.advance(3.0));
+        animation.restart();
+        assert!(!animation.is_finished());
+        assert_close(animation.track_time(), 0.0);
+        assert!(animation.advance(3.0));
+    }
+
+    #[test]
+    fn finished_is_sent_once() {
+        let mut world = Universe::new().create_world();
+        let mut resources = resources();
+        let mut schedule = Schedule::builder()
+            .add_system(keyframe_animation_system())
+            .build();
+        let entity = world.insert((), vec![(animation(PlaybackMode::Once),)])[0];
+
+        schedule.execute(&mut world, &mut resources);
+        assert!(resources
+            .get::<AnimationEvents>()
+            .unwrap()
+            .finished()
+            .is_empty());
+
+        schedule.execute(&mut world, &mut resources);
+        {
+            let events = resources.get::<AnimationEvents>().unwrap();
+            assert_eq!(events.finished().len(), 1);
+            assert_eq!(events.finished()[0].entity, entity);
+            assert_eq!(events.finished()[0].name, "test");
+        }
+
+        schedule.execute(&mut world, &mut resources);
+        assert!(resources
+            .get::<AnimationEvents>()
+            .unwrap()
+            .finished()
+            .is_empty());
+    }
+
+    #[test]
+    fn only_the_main_camera_entity_moves_the_camera() {
+        let mut world = Universe::new().create_world();
+        let mut resources = resources();
+        let mut schedule = Schedule::builder()
+            .add_system(keyframe_animation_system())
+            .build();
+        let moving = |x: f32| {
+            KeyframeAnimation::new("camera", PlaybackMode::Once).with_track(
+                PropertyTrack::CameraPosition(
+                    Track::new(Interpolation::Step).with_keyframe(0.0, Vector3::new(x, 0.0, 0.0)),
+                ),
+            )
+        };
+        world.insert((), vec![(moving(1.0),)]);
+        schedule.execute(&mut world, &mut resources);
+        assert_eq!(
+            resources.get::<Camera>().unwrap().get_vec_position(),
+            Vector3::new(0.0, 0.0, 0.0)
+        );
+
+        world.insert((), vec![(moving(2.0), MainCamera)]);
+        schedule.execute(&mut world, &mut resources);
+        assert_eq!(
+            resources.get::<Camera>().unwrap().get_vec_position(),
+            Vector3::new(2.0, 0.0, 0.0)
+        );
+    }
+}
@@ -54,6 +54,8 @@ pub trait AssetLoader: Sized {
 
 pub struct Assets<T: AssetLoader> {
     storage: HashMap<Handle<T>, T>,
+    // the file every handle was loaded from
+    paths: HashMap<Handle<T>, PathBuf>,
     gpu_load_queue: VecDeque<(Handle<T>, PathBuf)>,
 }
 
@@ -61,6 +63,7 @@ impl<T: AssetLoader> Assets<T> {
     pub fn new() -> Assets<T> {
         Assets {
             storage: HashMap::default(),
+            paths: HashMap::default(),
             gpu_load_queue: VecDeque::default(),
         }
     }
@@ -69,6 +72,15 @@ impl<T: AssetLoader> Assets<T> {
         self.storage.get(handle)
     }
 
+    pub fn get_mut(&mut self, handle: &Handle<T>) -> Option<&mut T> {
+        self.storage.get_mut(handle)
+    }
+
+    pub fn path(&self, handle: &Handle<T>) -> Option<&Path> {
+        self.paths.get(handle).map(PathBuf::as_path)
+    }
+
+    // Loading a file again returns the first handle so all instances are drawn together
     pub fn load(&mut self, path: impl AsRef<Path>) -> Result<Handle<T>> {
         let pathbuf = PathBuf::from(path.as_ref());
 
@@ -76,11 +88,15 @@ impl<T: AssetLoader> Assets<T> {
             pathbuf.extension().unwrap() == T::extension(),
             "Unexpected file extension"
         );
+        if let Some((handle, _)) = self.paths.iter().find(|(_, loaded)| **loaded == pathbuf) {
+            return Ok(handle.clone());
+        }
         let handle = Handle {
             // Safe because of atomics
             id: unsafe { CURRENT_ID.fetch_add(1, Ordering::AcqRel) },
             _marker: PhantomData::default(),
         };
+        self.paths.insert(handle.clone(), pathbuf.clone());
         self.gpu_load_queue.push_back((handle.clone(), pathbuf));
         Ok(handle)
     }
@@ -67,6 +67,11 @@ impl Camera {
         to_vec(&self.position)
     }
 
+    #[inline]
+    pub fn get_direction(&self) -> Vector3<f32> {
+        self.direction
+    }
+
     #[inline]
     pub fn move_in_direction(&mut self, amount: f32) {
         self.position += self.direction * amount;
@@ -91,6 +96,37 @@ impl Camera {
         );
     }
 
+    #[inline]
+    pub fn set_position(&mut self, position: Vector3<f32>) {
+        self.position = Point3::from(position);
+        self.view_matrix = Matrix4::look_at_rh(
+            &self.position,
+            &(self.position + self.direction),
+            &Vector3::new(0.0, 1.0, 0.0),
+        );
+    }
+
+    // Vertical field of view, passed straight to the perspective projection
+    #[inline]
+    pub fn set_fov(&mut self, fov: f32) {
+        self.projection_matrix.set_fovy(fov);
+    }
+
+    #[inline]
+    pub fn get_fov(&self) -> f32 {
+        self.projection_matrix.fovy()
+    }
+
+    #[inline]
+    pub fn set_aspect_ratio(&mut self, aspect_ratio: f32) {
+        self.projection_matrix.set_aspect(aspect_ratio);
+    }
+
+    #[inline]
+    pub fn get_aspect_ratio(&self) -> f32 {
+        self.projection_matrix.aspect()
+    }
+
     #[inline]
     pub fn get_view_matrix(&self) -> &Matrix4<f32> {
         &self.view_matrix
@@ -101,6 +137,39 @@ impl Camera {
         &self.projection_matrix.as_matrix()
     }
 
+    #[inline]
+    pub fn get_near_plane(&self) -> f32 {
+        self.projection_matrix.znear()
+    }
+
+    #[inline]
+    pub fn get_far_plane(&self) -> f32 {
+        self.projection_matrix.zfar()
+    }
+
+    // World space corners of the part of the view frustum between near and far
+    pub fn frustum_corners(&self, near: f32, far: f32) -> [Point3<f32>; 8] {
+        let projection = Perspective3::new(
+            self.projection_matrix.aspect(),
+            self.projection_matrix.fovy(),
+            near,
+            far,
+        );
+        let inverse = (projection.as_matrix() * self.view_matrix)
+            .try_inverse()
+            .expect("View projection matrix isn't invertible");
+        let mut corners = [Point3::origin(); 8];
+        for (i, corner) in corners.iter_mut().enumerate() {
+            let ndc = Point3::new(
+                if i & 1 == 0 { -1.0 } else { 1.0 },
+                if i & 2 == 0 { -1.0 } else { 1.0 },
+                if i & 4 == 0 { -1.0 } else { 1.0 },
+            );
+            *corner = inverse.transform_point(&ndc);
+        }
+        corners
+    }
+
     #[inline]
     pub fn set_pitch(&mut self, pitch: f32) {
         if pitch < -89.0 {
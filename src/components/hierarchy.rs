@@ -0,0 +1,205 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, bail, Result};
+use legion::prelude::*;
+use nalgebra::{Isometry3, Matrix4, Translation3, UnitQuaternion, Vector3};
+
+use crate::camera::Camera;
+
+use super::Transform;
+
+// The Transform of an entity with a parent is relative to the global transform of the parent
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Parent(pub Entity);
+
+// Kept in sync with the Parent components by the transform hierarchy system
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Children(pub Vec<Entity>);
+
+// World space model matrix, written every frame from the Transform of the
+// entity and of its ancestors. Rendering only draws entities that have one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GlobalTransform {
+    pub matrix: Matrix4<f32>,
+}
+
+impl GlobalTransform {
+    pub fn get_model_matrix(&self) -> Matrix4<f32> {
+        self.matrix
+    }
+
+    pub fn translation(&self) -> Vector3<f32> {
+        Vector3::new(
+            self.matrix[(0, 3)],
+            self.matrix[(1, 3)],
+            self.matrix[(2, 3)],
+        )
+    }
+
+    pub fn rotation(&self) -> UnitQuaternion<f32> {
+        Transform::from_matrix(&self.matrix).isometry.rotation
+    }
+}
+
+// The Transform of an entity with this is set to the pose of the main camera
+// every frame, children of it move with the camera. Like the camera it looks down -z
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MainCamera;
+
+fn camera_isometry(camera: &Camera) -> Isometry3<f32> {
+    Isometry3::from_parts(
+        Translation3::from(camera.get_vec_position()),
+        UnitQuaternion::face_towards(&-camera.get_direction(), &Vector3::y()),
+    )
+}
+
+// World matrix from the Transforms up the parent chain, unlike the GlobalTransform
+// it is up to date right after a Transform or Parent has been changed
+pub(crate) fn world_matrix(world: &World, entity: Entity) -> Result<Matrix4<f32>> {
+    let mut matrix = Matrix4::identity();
+    let mut visited = HashSet::new();
+    let mut current = Some(entity);
+    while let Some(entity) = current {
+        if !visited.insert(entity) {
+            bail!("The parents of {:?} form a cycle", entity);
+        }
+        let transform = world
+            .get_component::<Transform>(entity)
+            .ok_or_else(|| anyhow!("{:?} has no Transform", entity))?;
+        matrix = transform.get_model_matrix() * matrix;
+        current = world
+            .get_component::<Parent>(entity)
+            .map(|parent| parent.0)
+            .filter(|&parent| world.get_component::<Transform>(parent).is_some());
+    }
+    Ok(matrix)
+}
+
+// Adds or refreshes the GlobalTransform of an entity from the Transforms up the
+// parent chain, so a spawned or reparented entity is in the right place before
+// the hierarchy system has run. Returns the world matrix
+pub fn update_global_transform(world: &mut World, entity: Entity) -> Result<Matrix4<f32>> {
+    let matrix = world_matrix(world, entity)?;
+    if let Some(mut global_transform) = world.get_component_mut::<GlobalTransform>(entity) {
+        global_transform.matrix = matrix;
+        return Ok(matrix);
+    }
+    world
+        .add_component(entity, GlobalTransform { matrix })
+        .map_err(|_| anyhow!("Can't add a global transform to {:?}", entity))?;
+    Ok(matrix)
+}
+
+// Attaches the child to the parent, or detaches it with None, and changes
+// its Transform so it keeps the same pose in the world
+pub fn set_parent(world: &mut World, child: Entity, parent: Option<Entity>) -> Result<()> {
+    let child_matrix = world_matrix(world, child)?;
+    let local_matrix = match parent {
+        Some(parent) => {
+            let mut visited = HashSet::new();
+            let mut ancestor = Some(parent);
+            while let Some(entity) = ancestor.filter(|&entity| visited.insert(entity)) {
+                if entity == child {
+                    bail!("{:?} can't be parented to its own descendant", child);
+                }
+                ancestor = world.get_component::<Parent>(entity).map(|parent| parent.0);
+            }
+            let parent_matrix = world_matrix(world, parent)?;
+            parent_matrix
+                .try_inverse()
+                .ok_or_else(|| anyhow!("The transform of {:?} isn't invertible", parent))?
+                * child_matrix
+        }
+        None => child_matrix,
+    };
+    if let Some(mut transform) = world.get_component_mut::<Transform>(child) {
+        *transform = Transform::try_from_matrix(&local_matrix).ok_or_else(|| {
+            anyhow!(
+                "{:?} can't keep its pose without shear under {:?}",
+                child,
+                parent
+            )
+        })?;
+    }
+    match parent {
+        Some(parent) => world
+            .add_component(child, Parent(parent))
+            .map_err(|_| anyhow!("Can't add a parent to {:?}", child))?,
+        None if world.get_component::<Parent>(child).is_some() => world
+            .remove_component::<Parent>(child)
+            .map_err(|_| anyhow!("Can't remove the parent of {:?}", child))?,
+        None => {}
+    }
+    update_global_transform(world, child).map(|_| ())
+}
+
+// Computes the GlobalTransforms from the roots down so parents are always done
+// before their children, it should run after every system that moves things.
+// Entities inserted without a GlobalTransform or Children get one through the
+// command buffer, spawning through scenes and prefabs adds it right away
+pub fn transform_hierarchy_system() -> Box<dyn Schedulable> {
+    SystemBuilder::new("transform-hierarchy-system")
+        .read_resource::<Camera>()
+        .with_query(<Write<Transform>>::query().filter(component::<MainCamera>()))
+        .with_query(<Read<Transform>>::query())
+        .with_query(<Read<Parent>>::query())
+        .with_query(<Write<GlobalTransform>>::query())
+        .with_query(<Write<Children>>::query())
+        .build(
+            |commands,
+             world,
+             camera,
+             (camera_anchors, transforms, parents, global_transforms, children)| {
+                for mut transform in camera_anchors.iter_mut(world) {
+                    transform.isometry = camera_isometry(camera);
+                }
+                let local_matrices = transforms
+                    .iter_entities(world)
+                    .map(|(entity, transform)| (entity, transform.get_model_matrix()))
+                    .collect::<HashMap<_, _>>();
+                let mut parent_map = HashMap::new();
+                let mut child_map: HashMap<Entity, Vec<Entity>> = HashMap::new();
+                for (entity, parent) in parents.iter_entities(world) {
+                    parent_map.insert(entity, parent.0);
+                    child_map.entry(parent.0).or_default().push(entity);
+                }
+
+                // parents without a Transform don't move their children
+                let mut stack = local_matrices
+                    .iter()
+                    .filter(|(entity, _)| {
+                        parent_map
+                            .get(entity)
+                            .map_or(true, |parent| !local_matrices.contains_key(parent))
+                    })
+                    .map(|(&entity, &matrix)| (entity, matrix))
+                    .collect::<Vec<_>>();
+                let mut world_matrices = HashMap::with_capacity(local_matrices.len());
+                while let Some((entity, matrix)) = stack.pop() {
+                    world_matrices.insert(entity, matrix);
+                    for child in child_map.get(&entity).into_iter().flatten() {
+                        if let Some(local) = local_matrices.get(child) {
+                            stack.push((*child, matrix * local));
+                        }
+                    }
+                }
+
+                for (entity, mut global_transform) in global_transforms.iter_entities_mut(world) {
+                    if let Some(matrix) = world_matrices.remove(&entity) {
+                        global_transform.matrix = matrix;
+                    }
+                }
+                for (entity, matrix) in world_matrices {
+                    commands.add_component(entity, GlobalTransform { matrix });
+                }
+                for (entity, mut entity_children) in children.iter_entities_mut(world) {
+                    entity_children.0 = child_map.remove(&entity).unwrap_or_default();
+                }
+                for (entity, entity_children) in child_map {
+                    if local_matrices.contains_key(&entity) {
+                        commands.add_component(entity, Children(entity_children));
+                    }
+                }
+            },
+        )
+}
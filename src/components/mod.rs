@@ -1,9 +1,20 @@
-use nalgebra::{Isometry3, Matrix4, Vector3};
+use nalgebra::{
+    Isometry3, Matrix3, Matrix4, Point3, Rotation3, Translation3, Unit, UnitQuaternion, Vector3,
+};
 use nphysics3d::object::{DefaultBodyHandle, DefaultColliderHandle};
+use serde::{Deserialize, Serialize};
+
+pub mod hierarchy;
+
+pub use hierarchy::{
+    set_parent, transform_hierarchy_system, update_global_transform, Children, GlobalTransform,
+    MainCamera, Parent,
+};
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct LightTag;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct PhysicsBody {
     pub body_handle: DefaultBodyHandle,
     pub collider_handle: DefaultColliderHandle,
@@ -13,22 +24,75 @@ pub struct PhysicsBody {
 pub struct Cube;
 
 #[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Transform {
     pub isometry: Isometry3<f32>,
     pub scale: Vector3<f32>,
 }
-// TODO: implement buildar macro or by hand (new builder struct)
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform::builder().build()
+    }
+}
+
+// Like the camera, transforms look down -z with +y up
 impl Transform {
+    pub fn builder() -> TransformBuilder {
+        TransformBuilder {
+            translation: Vector3::zeros(),
+            rotation: UnitQuaternion::identity(),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+
     pub fn from_position(position: Vector3<f32>) -> Self {
-        let isometry = Isometry3::translation(position.x, position.y, position.z);
-        let scale = Vector3::new(1.0, 1.0, 1.0);
-        Transform { isometry, scale }
+        Transform::builder().set_translation(position).build()
     }
 
     pub fn new(isometry: Isometry3<f32>, scale: Vector3<f32>) -> Self {
         Transform { isometry, scale }
     }
 
+    // Splits an affine matrix into translation, rotation and scale, shear is dropped
+    pub fn from_matrix(matrix: &Matrix4<f32>) -> Self {
+        let translation = Vector3::new(matrix[(0, 3)], matrix[(1, 3)], matrix[(2, 3)]);
+        let linear = Matrix3::from_fn(|row, column| matrix[(row, column)]);
+        let mut scale = Vector3::from_fn(|i, _| linear.column(i).norm());
+        let mut axes = Matrix3::from_fn(|row, column| {
+            if scale[column] > f32::EPSILON {
+                linear[(row, column)] / scale[column]
+            } else if row == column {
+                1.0
+            } else {
+                0.0
+            }
+        });
+        // mirrored matrices keep a proper rotation by flipping the first axis
+        if axes.determinant() < 0.0 {
+            scale.x = -scale.x;
+            axes.column_mut(0).neg_mut();
+        }
+        let rotation =
+            UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix_unchecked(axes));
+        Transform {
+            isometry: Isometry3::from_parts(Translation3::from(translation), rotation),
+            scale,
+        }
+    }
+
+    // Like from_matrix but None when the matrix has shear, which a Transform can't hold.
+    // A non uniform scale followed by a rotation that doesn't line up with its axes makes it
+    pub fn try_from_matrix(matrix: &Matrix4<f32>) -> Option<Self> {
+        let transform = Transform::from_matrix(matrix);
+        let error = (transform.get_model_matrix() - matrix).amax();
+        if error <= MATRIX_EPSILON * matrix.amax().max(1.0) {
+            Some(transform)
+        } else {
+            None
+        }
+    }
+
     pub fn get_model_matrix(&self) -> Matrix4<f32> {
         self.isometry.to_homogeneous() * Matrix4::new_nonuniform_scaling(&self.scale)
     }
@@ -36,7 +100,249 @@ impl Transform {
     pub fn translation(&self) -> Vector3<f32> {
         self.isometry.translation.vector
     }
+
+    pub fn rotation(&self) -> UnitQuaternion<f32> {
+        self.isometry.rotation
+    }
+
+    pub fn forward(&self) -> Vector3<f32> {
+        self.isometry.rotation * -Vector3::z()
+    }
+
+    pub fn right(&self) -> Vector3<f32> {
+        self.isometry.rotation * Vector3::x()
+    }
+
+    pub fn up(&self) -> Vector3<f32> {
+        self.isometry.rotation * Vector3::y()
+    }
+
+    // Turns the transform so forward points at the target, up must not be parallel to it
+    pub fn look_at(&mut self, target: Vector3<f32>, up: Vector3<f32>) {
+        if let Some(rotation) = look_rotation(target - self.translation(), up) {
+            self.isometry.rotation = rotation;
+        }
+    }
+
+    pub fn transform_point(&self, point: Vector3<f32>) -> Vector3<f32> {
+        self.isometry
+            .transform_point(&Point3::from(point.component_mul(&self.scale)))
+            .coords
+    }
+
+    pub fn transform_vector(&self, vector: Vector3<f32>) -> Vector3<f32> {
+        self.isometry
+            .transform_vector(&vector.component_mul(&self.scale))
+    }
+
+    // None when a scale is zero, or when the transform is both rotated and non uniformly
+    // scaled since the inverse of that is sheared
+    pub fn inverse(&self) -> Option<Transform> {
+        self.get_model_matrix()
+            .try_inverse()
+            .and_then(|matrix| Transform::try_from_matrix(&matrix))
+    }
+
+    // The transform of the child in the space this transform is in, the product of their
+    // model matrices. None when a non uniform scale here meets a rotated child, the product
+    // is sheared then. Chains of transforms are better multiplied as matrices and only
+    // split at the end, see GlobalTransform
+    pub fn compose(&self, child: &Transform) -> Option<Transform> {
+        Transform::try_from_matrix(&(self.get_model_matrix() * child.get_model_matrix()))
+    }
+}
+
+// Largest difference, relative to the largest entry, for a decomposed matrix to count as exact
+const MATRIX_EPSILON: f32 = 1e-4;
+
+// Rotation turning -z towards the direction, none when there is no direction
+fn look_rotation(direction: Vector3<f32>, up: Vector3<f32>) -> Option<UnitQuaternion<f32>> {
+    if direction.norm_squared() > f32::EPSILON {
+        Some(UnitQuaternion::face_towards(&-direction, &up))
+    } else {
+        None
+    }
+}
+
+// Angles are in radians, a later rotation replaces an earlier one
+pub struct TransformBuilder {
+    translation: Vector3<f32>,
+    rotation: UnitQuaternion<f32>,
+    scale: Vector3<f32>,
+}
+
+impl TransformBuilder {
+    pub fn set_translation(mut self, translation: Vector3<f32>) -> Self {
+        self.translation = translation;
+        self
+    }
+
+    pub fn set_rotation(mut self, rotation: UnitQuaternion<f32>) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    pub fn set_euler_rotation(mut self, roll: f32, pitch: f32, yaw: f32) -> Self {
+        self.rotation = UnitQuaternion::from_euler_angles(roll, pitch, yaw);
+        self
+    }
+
+    pub fn set_axis_angle_rotation(mut self, axis: Vector3<f32>, angle: f32) -> Self {
+        self.rotation = UnitQuaternion::from_axis_angle(&Unit::new_normalize(axis), angle);
+        self
+    }
+
+    // Rotates towards the target from the translation set so far
+    pub fn look_at(mut self, target: Vector3<f32>, up: Vector3<f32>) -> Self {
+        if let Some(rotation) = look_rotation(target - self.translation, up) {
+            self.rotation = rotation;
+        }
+        self
+    }
+
+    pub fn set_scale(mut self, scale: f32) -> Self {
+        self.scale = Vector3::new(scale, scale, scale);
+        self
+    }
+
+    pub fn set_non_uniform_scale(mut self, scale: Vector3<f32>) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn build(self) -> Transform {
+        Transform {
+            isometry: Isometry3::from_parts(Translation3::from(self.translation), self.rotation),
+            scale: self.scale,
+        }
+    }
 }
 
 #[derive(Clone, PartialEq)]
 pub struct Selected;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_matrix_eq(a: &Matrix4<f32>, b: &Matrix4<f32>) {
+        assert!((a - b).amax() < 1e-4, "{} != {}", a, b);
+    }
+
+    fn assert_vector_eq(a: &Vector3<f32>, b: &Vector3<f32>) {
+        assert!((a - b).amax() < 1e-4, "{} != {}", a, b);
+    }
+
+    fn parent() -> Transform {
+        Transform::builder()
+            .set_translation(Vector3::new(1.0, 2.0, 3.0))
+            .set_axis_angle_rotation(Vector3::y(), std::f32::consts::FRAC_PI_2)
+            .set_scale(2.0)
+            .build()
+    }
+
+    fn child() -> Transform {
+        Transform::builder()
+            .set_translation(Vector3::new(-1.0, 0.5, 4.0))
+            .set_euler_rotation(0.3, -0.2, 1.1)
+            .set_non_uniform_scale(Vector3::new(1.0, 3.0, 0.5))
+            .build()
+    }
+
+    #[test]
+    fn compose_matches_the_model_matrices() {
+        let composed = parent().compose(&child()).expect("uniform parents compose");
+        assert_matrix_eq(
+            &composed.get_model_matrix(),
+            &(parent().get_model_matrix() * child().get_model_matrix()),
+        );
+    }
+
+    #[test]
+    fn compose_moves_points_into_the_parent_space() {
+        let point = Vector3::new(0.5, -2.0, 1.0);
+        assert_vector_eq(
+            &parent().compose(&child()).unwrap().transform_point(point),
+            &parent().transform_point(child().transform_point(point)),
+        );
+    }
+
+    #[test]
+    fn compose_with_a_non_uniform_parent_matches_the_model_matrices_or_is_none() {
+        let parent = Transform::builder()
+            .set_translation(Vector3::new(1.0, 2.0, 3.0))
+            .set_non_uniform_scale(Vector3::new(1.0, 4.0, 1.0))
+            .build();
+        // the product with a rotated child is sheared
+        assert!(parent.compose(&child()).is_none());
+
+        let unrotated = Transform::builder()
+            .set_translation(Vector3::new(-1.0, 0.5, 4.0))
+            .set_non_uniform_scale(Vector3::new(1.0, 3.0, 0.5))
+            .build();
+        let composed = parent
+            .compose(&unrotated)
+            .expect("axis aligned scales compose");
+        assert_matrix_eq(
+            &composed.get_model_matrix(),
+            &(parent.get_model_matrix() * unrotated.get_model_matrix()),
+        );
+    }
+
+    #[test]
+    fn inverse_undoes_the_transform() {
+        let inverse = parent().inverse().expect("parent is invertible");
+        assert_matrix_eq(
+            &parent().compose(&inverse).unwrap().get_model_matrix(),
+            &Matrix4::identity(),
+        );
+        let point = Vector3::new(3.0, -1.0, 2.0);
+        assert_vector_eq(
+            &inverse.transform_point(parent().transform_point(point)),
+            &point,
+        );
+    }
+
+    #[test]
+    fn inverse_of_a_rotated_non_uniform_scale_is_none() {
+        let transform = Transform::builder()
+            .set_euler_rotation(0.3, -0.2, 1.1)
+            .set_non_uniform_scale(Vector3::new(1.0, 3.0, 0.5))
+            .build();
+        assert!(transform.inverse().is_none());
+    }
+
+    #[test]
+    fn inverse_of_a_zero_scale_is_none() {
+        let transform = Transform::builder()
+            .set_translation(Vector3::new(1.0, 0.0, 0.0))
+            .set_non_uniform_scale(Vector3::new(1.0, 0.0, 1.0))
+            .build();
+        assert!(transform.inverse().is_none());
+    }
+
+    #[test]
+    fn look_at_points_forward_at_the_target() {
+        let mut transform = Transform::from_position(Vector3::new(1.0, 1.0, 1.0));
+        let target = Vector3::new(4.0, 1.0, -3.0);
+        transform.look_at(target, Vector3::y());
+        assert_vector_eq(
+            &transform.forward(),
+            &(target - transform.translation()).normalize(),
+        );
+        assert!(transform.up().dot(&Vector3::y()) > 0.0);
+
+        let built = Transform::builder()
+            .set_translation(Vector3::new(1.0, 1.0, 1.0))
+            .look_at(target, Vector3::y())
+            .build();
+        assert_vector_eq(&built.forward(), &transform.forward());
+    }
+
+    #[test]
+    fn look_at_its_own_position_keeps_the_rotation() {
+        let mut transform = parent();
+        transform.look_at(transform.translation(), Vector3::y());
+        assert_eq!(transform.rotation(), parent().rotation());
+    }
+}
@@ -1,9 +1,26 @@
-use crate::graphics::WgpuRenderer;
+use crate::animation::AnimationEvents;
+use crate::graphics::{
+    environment_map::ImageBasedLighting,
+    pass::post_process_pass::{Bloom, ColorGrading, Fxaa, PostProcessStack, Vignette},
+    pass::ssao_pass::Ssao,
+    pass::tone_mapping_pass::ToneMapping,
+    render_target::RenderTargets,
+    screenshot::ScreenshotQueue,
+    shadow_atlas::ShadowAtlas,
+    shadow_texture::ShadowFiltering,
+    skinned_model::SkinnedModel,
+    skybox_texture::Skybox,
+    wgpu_renderer::{RenderPath, RendererSettings},
+    WgpuRenderer,
+};
+use crate::prefab::Prefabs;
 use crate::states::State;
 use crate::{assets::Assets, camera::Camera, graphics::model::Model};
+use anyhow::Result;
 use glfw::{Action, Glfw, Key, MouseButton, Window, WindowEvent};
 use legion::prelude::*;
 use nalgebra::{Point3, Vector3};
+use serde::{Deserialize, Serialize};
 use std::io::Write;
 use std::sync::mpsc::Receiver;
 
@@ -14,6 +31,7 @@ pub enum InputEvent {
     MouseButton { button: MouseButton, action: Action },
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Time {
     pub current_time: f32,
     pub delta_time: f32,
@@ -34,14 +52,53 @@ pub struct Engine<R> {
 pub const WINDOW_HEIGHT: u32 = 1200;
 pub const WINDOW_WIDTH: u32 = 1600;
 
+pub struct EngineBuilder {
+    name: String,
+    renderer_settings: RendererSettings,
+}
+
+impl EngineBuilder {
+    pub fn set_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    // Multisample anti-aliasing for the scene, 1 disables it. The count is
+    // checked by the renderer so build fails when the backend can't do it
+    pub fn set_msaa_samples(mut self, sample_count: u32) -> Self {
+        self.renderer_settings.sample_count = sample_count;
+        self
+    }
+
+    // The deferred path doesn't support msaa, the renderer falls back to a single sample
+    pub fn set_render_path(mut self, render_path: RenderPath) -> Self {
+        self.renderer_settings.render_path = render_path;
+        self
+    }
+
+    pub fn build(self, start_state: Box<dyn State>) -> Result<Engine<WgpuRenderer>> {
+        Engine::new(self.name, start_state, self.renderer_settings)
+    }
+}
+
 impl Engine<WgpuRenderer> {
+    pub fn builder() -> EngineBuilder {
+        EngineBuilder {
+            name: String::from("Smol engine"),
+            renderer_settings: RendererSettings::default(),
+        }
+    }
+
     //noinspection ALL
-    // TODO: make builder instead
-    pub fn new(name: impl AsRef<str>, start_state: Box<dyn State>) -> Self {
+    fn new(
+        name: impl AsRef<str>,
+        start_state: Box<dyn State>,
+        renderer_settings: RendererSettings,
+    ) -> Result<Self> {
         let mut glfw = glfw::init(glfw::FAIL_ON_ERRORS).unwrap();
 
+        // multisampling is handled by the renderer
         glfw.window_hint(glfw::WindowHint::ClientApi(glfw::ClientApiHint::NoApi));
-        //    glfw.window_hint(glfw::WindowHint::Samples(Some(4))); <- better to do manually
 
         let (mut window, events) = glfw
             .create_window(
@@ -69,8 +126,24 @@ impl Engine<WgpuRenderer> {
         });
 
         let model_assets = Assets::<Model>::new();
-        let renderer = futures::executor::block_on(WgpuRenderer::new(&window));
+        let renderer = futures::executor::block_on(WgpuRenderer::new(&window, renderer_settings))?;
         resources.insert(model_assets);
+        resources.insert(Assets::<SkinnedModel>::new());
+        resources.insert(AnimationEvents::new());
+        resources.insert(RenderTargets::new());
+        resources.insert(ScreenshotQueue::new());
+        resources.insert(ToneMapping::default());
+        resources.insert(PostProcessStack::default());
+        resources.insert(Bloom::default());
+        resources.insert(Fxaa::default());
+        resources.insert(Vignette::default());
+        resources.insert(ColorGrading::default());
+        resources.insert(ImageBasedLighting::default());
+        resources.insert(ShadowFiltering::default());
+        resources.insert(ShadowAtlas::default());
+        resources.insert(Ssao::default());
+        resources.insert(Skybox::default());
+        resources.insert(Prefabs::new());
         let camera = Camera::new(
             Point3::new(0., 0., 3.),
             Vector3::new(0.0, 0.0, -1.0),
@@ -78,7 +151,7 @@ impl Engine<WgpuRenderer> {
             WINDOW_HEIGHT,
         );
         resources.insert(camera);
-        Engine {
+        Ok(Engine {
             renderer,
             current_state: start_state,
             world,
@@ -86,13 +159,14 @@ impl Engine<WgpuRenderer> {
             glfw,
             window,
             events,
-        }
+        })
     }
     // Run the main game loop
     pub fn run(&mut self) {
-        let mut last_frame = 0.0;
         self.current_state
             .start(&mut self.world, &mut self.resources);
+        // Time is advanced by the deltas, so they have to start from the current clock
+        let mut last_frame = self.glfw.get_time() as f32;
         let stdout = std::io::stdout();
         let mut handle = stdout.lock();
         while !self.window.should_close() {
@@ -105,7 +179,8 @@ impl Engine<WgpuRenderer> {
             {
                 let mut time = self.resources.get_mut::<Time>().unwrap();
                 time.delta_time = delta_time;
-                time.current_time = current_frame;
+                // advanced rather than read from the clock so restored saves keep their time
+                time.current_time += delta_time;
             }
             self.process_events();
             self.current_state
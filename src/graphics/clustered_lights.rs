@@ -0,0 +1,619 @@
+use nalgebra::{Matrix4, Point3, Vector3};
+use once_cell::sync::OnceCell;
+use smol_renderer::{TextureData, TextureShaderLayout};
+use std::sync::atomic::{AtomicBool, Ordering};
+use wgpu::{
+    BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, Binding, BindingResource,
+    BindingType, Buffer, BufferDescriptor, BufferUsage, CommandEncoder, Device, Extent3d,
+    ShaderStage, TextureDimension, TextureFormat, TextureUsage,
+};
+
+use crate::camera::Camera;
+
+use super::{
+    directional_light::DirectionalLightRaw,
+    point_light::PointLightRaw,
+    shadow_texture::{ShadowFilter, ShadowFiltering},
+    spot_light::SpotLightRaw,
+};
+
+pub const CLUSTER_TILES_X: u32 = 16;
+pub const CLUSTER_TILES_Y: u32 = 9;
+pub const CLUSTER_SLICES: u32 = 24;
+pub const CLUSTER_COUNT: u32 = CLUSTER_TILES_X * CLUSTER_TILES_Y * CLUSTER_SLICES;
+// Lights further into a full cluster are dropped from it, which is warned about once
+pub const MAX_LIGHTS_PER_CLUSTER: u32 = 64;
+pub const MAX_POINT_LIGHTS: u32 = 1024;
+pub const MAX_SPOT_LIGHTS: u32 = 8;
+
+pub(crate) fn as_bytes<T>(data: &[T]) -> &[u8] {
+    unsafe {
+        std::slice::from_raw_parts(
+            data.as_ptr() as *const u8,
+            data.len() * std::mem::size_of::<T>(),
+        )
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct LightUniforms {
+    directional_light: DirectionalLightRaw,
+    spot_lights_used: i32,
+    shadow_filter: i32,
+    shadow_kernel_radius: i32,
+    _pad: i32,
+    spot_lights: [SpotLightRaw; MAX_SPOT_LIGHTS as usize],
+}
+
+impl LightUniforms {
+    pub fn new(
+        directional_light: DirectionalLightRaw,
+        spot_lights: &[SpotLightRaw],
+        shadow_filtering: &ShadowFiltering,
+    ) -> Self {
+        assert!(
+            spot_lights.len() <= MAX_SPOT_LIGHTS as usize,
+            "Too many spot lights"
+        );
+        let mut spot_data = [SpotLightRaw::default(); MAX_SPOT_LIGHTS as usize];
+        spot_data[..spot_lights.len()].copy_from_slice(spot_lights);
+        LightUniforms {
+            directional_light,
+            spot_lights_used: spot_lights.len() as i32,
+            shadow_filter: match shadow_filtering.filter {
+                ShadowFilter::Hard => 0,
+                ShadowFilter::Pcf => 1,
+                ShadowFilter::Pcss => 2,
+            },
+            shadow_kernel_radius: shadow_filtering.kernel_radius as i32,
+            _pad: 0,
+            spot_lights: spot_data,
+        }
+    }
+}
+
+// Camera the clusters were built for, the fragment shader uses it to find its cluster
+#[repr(C)]
+#[derive(Debug, Clone)]
+struct ClusterUniforms {
+    view: Matrix4<f32>,
+    projection: Matrix4<f32>,
+    near: f32,
+    far: f32,
+    lights_used: i32,
+    _pad: f32,
+}
+
+// World space sphere outside of which a point light has no effect
+#[derive(Debug, Clone, Copy)]
+pub struct LightBounds {
+    pub position: Vector3<f32>,
+    pub range: f32,
+}
+
+// Light uniforms, point lights, the cluster grid of (offset, count)
+// pairs and the cluster light index list in a single bind group
+pub struct ClusterTextures;
+
+impl TextureShaderLayout for ClusterTextures {
+    fn get_layout(device: &Device) -> &'static BindGroupLayout {
+        static LAYOUT: OnceCell<BindGroupLayout> = OnceCell::new();
+        LAYOUT.get_or_init(|| {
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                bindings: &[
+                    BindGroupLayoutEntry::new(
+                        0,
+                        ShaderStage::FRAGMENT,
+                        BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                        },
+                    ),
+                    BindGroupLayoutEntry::new(
+                        1,
+                        ShaderStage::FRAGMENT,
+                        BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                        },
+                    ),
+                    // also read by the deferred light volumes
+                    BindGroupLayoutEntry::new(
+                        2,
+                        ShaderStage::VERTEX | ShaderStage::FRAGMENT,
+                        BindingType::StorageBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                            readonly: true,
+                        },
+                    ),
+                    BindGroupLayoutEntry::new(
+                        3,
+                        ShaderStage::FRAGMENT,
+                        BindingType::StorageBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                            readonly: true,
+                        },
+                    ),
+                    BindGroupLayoutEntry::new(
+                        4,
+                        ShaderStage::FRAGMENT,
+                        BindingType::StorageBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                            readonly: true,
+                        },
+                    ),
+                ],
+                label: Some("Cluster textures layout"),
+            })
+        })
+    }
+}
+
+pub struct ClusteredLights {
+    pub textures: TextureData<ClusterTextures>,
+    light_uniform_buffer: Buffer,
+    cluster_uniform_buffer: Buffer,
+    point_light_buffer: Buffer,
+    grid_buffer: Buffer,
+    light_index_buffer: Buffer,
+}
+
+pub(crate) fn create_buffer(
+    device: &Device,
+    label: &str,
+    size: usize,
+    usage: BufferUsage,
+) -> Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: Some(label),
+        size: size as u64,
+        usage: usage | BufferUsage::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+// Copies through a staging buffer recorded in the encoder so each camera
+// sees its own clusters in the passes recorded after the update
+pub(crate) fn upload(device: &Device, encoder: &mut CommandEncoder, target: &Buffer, bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+    let staging = device.create_buffer_with_data(bytes, BufferUsage::COPY_SRC);
+    encoder.copy_buffer_to_buffer(&staging, 0, target, 0, bytes.len() as u64);
+}
+
+// The limits are usually hit every frame once they are, so each only gets a single warning
+pub(crate) fn warn_once(warned: &AtomicBool, message: impl FnOnce() -> String) {
+    if !warned.swap(true, Ordering::Relaxed) {
+        eprintln!("{}", message());
+    }
+}
+
+fn depth_slice(depth: f32, near: f32, far: f32) -> u32 {
+    // logarithmic slices keep clusters roughly cube shaped along the view direction
+    let slice = (depth.max(near) / near).ln() / (far / near).ln() * CLUSTER_SLICES as f32;
+    (slice.max(0.0) as u32).min(CLUSTER_SLICES - 1)
+}
+
+fn tile(ndc: f32, tiles: u32) -> u32 {
+    let tile = (ndc * 0.5 + 0.5) * tiles as f32;
+    (tile.max(0.0) as u32).min(tiles - 1)
+}
+
+// The (offset, count) pair of every cluster and the light indices they point into
+fn bin_lights(camera: &Camera, lights: &[LightBounds]) -> (Vec<[u32; 2]>, Vec<u32>) {
+    let view = *camera.get_view_matrix();
+    let projection = *camera.get_projection_matrix();
+    let near = camera.get_near_plane();
+    let far = camera.get_far_plane();
+
+    let mut cluster_lights = vec![Vec::new(); CLUSTER_COUNT as usize];
+    let mut dropped = false;
+    for (light_index, light) in lights.iter().enumerate() {
+        let center = view.transform_point(&Point3::from(light.position));
+        // the camera looks down negative z
+        let min_depth = -center.z - light.range;
+        let max_depth = -center.z + light.range;
+        if max_depth < near || min_depth > far {
+            continue;
+        }
+        let (mut min_x, mut min_y) = (0, 0);
+        let (mut max_x, mut max_y) = (CLUSTER_TILES_X - 1, CLUSTER_TILES_Y - 1);
+        // spheres reaching behind the near plane can cover any tile
+        if min_depth > near {
+            let mut min_ndc = Vector3::repeat(f32::MAX);
+            let mut max_ndc = Vector3::repeat(f32::MIN);
+            for corner in 0..8 {
+                let offset = Vector3::new(
+                    if corner & 1 == 0 { -1.0 } else { 1.0 },
+                    if corner & 2 == 0 { -1.0 } else { 1.0 },
+                    if corner & 4 == 0 { -1.0 } else { 1.0 },
+                ) * light.range;
+                let ndc = projection.transform_point(&(center + offset));
+                min_ndc = min_ndc.inf(&ndc.coords);
+                max_ndc = max_ndc.sup(&ndc.coords);
+            }
+            if min_ndc.x > 1.0 || max_ndc.x < -1.0 || min_ndc.y > 1.0 || max_ndc.y < -1.0 {
+                continue;
+            }
+            min_x = tile(min_ndc.x, CLUSTER_TILES_X);
+            max_x = tile(max_ndc.x, CLUSTER_TILES_X);
+            min_y = tile(min_ndc.y, CLUSTER_TILES_Y);
+            max_y = tile(max_ndc.y, CLUSTER_TILES_Y);
+        }
+        let min_slice = depth_slice(min_depth, near, far);
+        let max_slice = depth_slice(max_depth, near, far);
+        for z in min_slice..=max_slice {
+            for y in min_y..=max_y {
+                for x in min_x..=max_x {
+                    let cluster =
+                        (x + y * CLUSTER_TILES_X + z * CLUSTER_TILES_X * CLUSTER_TILES_Y) as usize;
+                    if cluster_lights[cluster].len() < MAX_LIGHTS_PER_CLUSTER as usize {
+                        cluster_lights[cluster].push(light_index as u32);
+                    } else {
+                        dropped = true;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut grid = Vec::with_capacity(CLUSTER_COUNT as usize);
+    let mut light_indices = Vec::new();
+    for indices in cluster_lights.iter() {
+        grid.push([light_indices.len() as u32, indices.len() as u32]);
+        light_indices.extend_from_slice(indices);
+    }
+    if dropped {
+        static WARNED: AtomicBool = AtomicBool::new(false);
+        warn_once(&WARNED, || {
+            format!(
+                "More than {} lights reach a cluster, the rest are dropped from it",
+                MAX_LIGHTS_PER_CLUSTER
+            )
+        });
+    }
+    (grid, light_indices)
+}
+
+impl ClusteredLights {
+    pub fn new(device: &Device) -> Self {
+        let light_uniform_buffer = create_buffer(
+            device,
+            "Light uniforms",
+            std::mem::size_of::<LightUniforms>(),
+            BufferUsage::UNIFORM,
+        );
+        let cluster_uniform_buffer = create_buffer(
+            device,
+            "Cluster uniforms",
+            std::mem::size_of::<ClusterUniforms>(),
+            BufferUsage::UNIFORM,
+        );
+        let point_light_buffer = create_buffer(
+            device,
+            "Point lights",
+            std::mem::size_of::<PointLightRaw>() * MAX_POINT_LIGHTS as usize,
+            BufferUsage::STORAGE,
+        );
+        let light_index_buffer = create_buffer(
+            device,
+            "Cluster light indices",
+            std::mem::size_of::<u32>() * (CLUSTER_COUNT * MAX_LIGHTS_PER_CLUSTER) as usize,
+            BufferUsage::STORAGE,
+        );
+        let grid_buffer = create_buffer(
+            device,
+            "Cluster grid",
+            std::mem::size_of::<[u32; 2]>() * CLUSTER_COUNT as usize,
+            BufferUsage::STORAGE,
+        );
+        // The texture data wrapper needs a texture even though everything
+        // in this bind group is a buffer, it is never bound
+        let placeholder = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Cluster placeholder"),
+            size: Extent3d {
+                width: 1,
+                height: 1,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R8Unorm,
+            usage: TextureUsage::SAMPLED,
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Cluster placeholder sampler"),
+            ..Default::default()
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: ClusterTextures::get_layout(device),
+            bindings: &[
+                Binding {
+                    binding: 0,
+                    resource: BindingResource::Buffer(light_uniform_buffer.slice(..)),
+                },
+                Binding {
+                    binding: 1,
+                    resource: BindingResource::Buffer(cluster_uniform_buffer.slice(..)),
+                },
+                Binding {
+                    binding: 2,
+                    resource: BindingResource::Buffer(point_light_buffer.slice(..)),
+                },
+                Binding {
+                    binding: 3,
+                    resource: BindingResource::Buffer(grid_buffer.slice(..)),
+                },
+                Binding {
+                    binding: 4,
+                    resource: BindingResource::Buffer(light_index_buffer.slice(..)),
+                },
+            ],
+            label: Some("Cluster textures bindgroup"),
+        });
+        ClusteredLights {
+            textures: TextureData::new(bind_group, placeholder, Vec::new(), sampler),
+            light_uniform_buffer,
+            cluster_uniform_buffer,
+            point_light_buffer,
+            grid_buffer,
+            light_index_buffer,
+        }
+    }
+
+    pub fn update_lights(
+        &self,
+        device: &Device,
+        encoder: &mut CommandEncoder,
+        uniforms: &LightUniforms,
+        point_lights: &[PointLightRaw],
+    ) {
+        assert!(
+            point_lights.len() <= MAX_POINT_LIGHTS as usize,
+            "Too many point lights"
+        );
+        upload(
+            device,
+            encoder,
+            &self.light_uniform_buffer,
+            as_bytes(std::slice::from_ref(uniforms)),
+        );
+        upload(
+            device,
+            encoder,
+            &self.point_light_buffer,
+            as_bytes(point_lights),
+        );
+    }
+
+    // Bins the lights into the view space clusters of the camera, lights must
+    // be in the same order as the point lights passed to update_lights
+    pub fn update_clusters(
+        &self,
+        device: &Device,
+        encoder: &mut CommandEncoder,
+        camera: &Camera,
+        lights: &[LightBounds],
+    ) {
+        let (grid, light_indices) = bin_lights(camera, lights);
+
+        let uniforms = ClusterUniforms {
+            view: *camera.get_view_matrix(),
+            projection: *camera.get_projection_matrix(),
+            near: camera.get_near_plane(),
+            far: camera.get_far_plane(),
+            lights_used: lights.len() as i32,
+            _pad: 0.0,
+        };
+        upload(
+            device,
+            encoder,
+            &self.cluster_uniform_buffer,
+            as_bytes(std::slice::from_ref(&uniforms)),
+        );
+        upload(
+            device,
+            encoder,
+            &self.light_index_buffer,
+            as_bytes(&light_indices),
+        );
+        upload(device, encoder, &self.grid_buffer, as_bytes(&grid));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera() -> Camera {
+        Camera::new(Point3::origin(), Vector3::new(0.0, 0.0, -1.0), 1600, 900)
+    }
+
+    fn light(x: f32, y: f32, z: f32, range: f32) -> LightBounds {
+        LightBounds {
+            position: Vector3::new(x, y, z),
+            range,
+        }
+    }
+
+    fn cluster(x: u32, y: u32, z: u32) -> usize {
+        (x + y * CLUSTER_TILES_X + z * CLUSTER_TILES_X * CLUSTER_TILES_Y) as usize
+    }
+
+    // View space bounding box of a cluster, the camera is at the origin looking down -z
+    fn cluster_bounds(camera: &Camera, x: u32, y: u32, z: u32) -> (Vector3<f32>, Vector3<f32>) {
+        let projection = camera.get_projection_matrix();
+        let (near, far) = (camera.get_near_plane(), camera.get_far_plane());
+        let depth = |slice: u32| near * (far / near).powf(slice as f32 / CLUSTER_SLICES as f32);
+        let ndc = |tile: u32, tiles: u32| tile as f32 / tiles as f32 * 2.0 - 1.0;
+        let mut min = Vector3::repeat(f32::MAX);
+        let mut max = Vector3::repeat(f32::MIN);
+        for &depth in &[depth(z), depth(z + 1)] {
+            for &ndc_x in &[ndc(x, CLUSTER_TILES_X), ndc(x + 1, CLUSTER_TILES_X)] {
+                for &ndc_y in &[ndc(y, CLUSTER_TILES_Y), ndc(y + 1, CLUSTER_TILES_Y)] {
+                    let corner = Vector3::new(
+                        ndc_x * depth / projection[(0, 0)],
+                        ndc_y * depth / projection[(1, 1)],
+                        -depth,
+                    );
+                    min = min.inf(&corner);
+                    max = max.sup(&corner);
+                }
+            }
+        }
+        (min, max)
+    }
+
+    fn lights_of(grid: &[[u32; 2]], light_indices: &[u32], cluster: usize) -> Vec<u32> {
+        let [offset, count] = grid[cluster];
+        light_indices[offset as usize..(offset + count) as usize].to_vec()
+    }
+
+    fn lights() -> Vec<LightBounds> {
+        vec![
+            light(0.0, 0.0, -10.0, 2.0),
+            light(3.0, -1.5, -4.0, 1.0),
+            light(-20.0, 8.0, -60.0, 6.0),
+        ]
+    }
+
+    // The cluster a fragment at the view space point looks its lights up in
+    fn cluster_at(camera: &Camera, point: &Point3<f32>) -> Option<usize> {
+        let ndc = camera.get_projection_matrix().transform_point(point);
+        let depth = -point.z;
+        if ndc.x.abs() > 1.0 || ndc.y.abs() > 1.0 || depth < camera.get_near_plane() {
+            return None;
+        }
+        let (near, far) = (camera.get_near_plane(), camera.get_far_plane());
+        Some(cluster(
+            tile(ndc.x, CLUSTER_TILES_X),
+            tile(ndc.y, CLUSTER_TILES_Y),
+            depth_slice(depth, near, far),
+        ))
+    }
+
+    #[test]
+    fn lights_are_in_every_cluster_their_range_reaches() {
+        let camera = camera();
+        let mut lights = lights();
+        // reaches behind the near plane
+        lights.push(light(0.5, 0.0, -0.5, 1.0));
+        let (grid, light_indices) = bin_lights(&camera, &lights);
+        let steps = 8;
+        for (index, light) in lights.iter().enumerate() {
+            for i in 0..=steps {
+                for j in 0..=steps {
+                    for k in 0..=steps {
+                        let offset = Vector3::new(i, j, k).map(|step| step as f32) / steps as f32
+                            * 2.0
+                            - Vector3::repeat(1.0);
+                        if offset.norm() > 0.99 {
+                            continue;
+                        }
+                        let point = Point3::from(light.position + offset * light.range);
+                        if let Some(cluster) = cluster_at(&camera, &point) {
+                            assert!(
+                                lights_of(&grid, &light_indices, cluster).contains(&(index as u32)),
+                                "light {} is missing from the cluster at {:?}",
+                                index,
+                                point
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lights_are_only_in_clusters_near_their_range() {
+        let camera = camera();
+        let lights = lights();
+        let (grid, light_indices) = bin_lights(&camera, &lights);
+        for z in 0..CLUSTER_SLICES {
+            for y in 0..CLUSTER_TILES_Y {
+                for x in 0..CLUSTER_TILES_X {
+                    let (min, max) = cluster_bounds(&camera, x, y, z);
+                    for &index in &lights_of(&grid, &light_indices, cluster(x, y, z)) {
+                        let light = &lights[index as usize];
+                        let closest = light.position.sup(&min).inf(&max);
+                        // the screen space bounds of the light are a bit larger than its range
+                        assert!(
+                            (closest - light.position).norm() < light.range * 2.5,
+                            "light {} is too far from cluster {:?}",
+                            index,
+                            (x, y, z)
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lights_stay_in_the_slices_of_their_depth() {
+        let camera = camera();
+        let (near, far) = (camera.get_near_plane(), camera.get_far_plane());
+        let (grid, light_indices) = bin_lights(&camera, &[light(0.0, 0.0, -10.0, 2.0)]);
+        for z in 0..CLUSTER_SLICES {
+            let binned = !lights_of(&grid, &light_indices, cluster(8, 4, z)).is_empty();
+            let reached = (depth_slice(8.0, near, far)..=depth_slice(12.0, near, far)).contains(&z);
+            assert_eq!(binned, reached, "slice {}", z);
+        }
+    }
+
+    #[test]
+    fn lights_outside_the_frustum_are_not_binned() {
+        let lights = [
+            light(0.0, 0.0, 5.0, 1.0),
+            light(0.0, 0.0, -200.0, 10.0),
+            light(500.0, 0.0, -10.0, 1.0),
+        ];
+        let (grid, light_indices) = bin_lights(&camera(), &lights);
+        assert!(light_indices.is_empty());
+        assert!(grid.iter().all(|&[_, count]| count == 0));
+    }
+
+    #[test]
+    fn the_grid_points_at_consecutive_index_ranges() {
+        let lights = [
+            light(0.0, 0.0, -10.0, 2.0),
+            light(3.0, -1.5, -4.0, 1.0),
+            light(0.0, 0.0, -10.0, 0.5),
+        ];
+        let camera = camera();
+        let (grid, light_indices) = bin_lights(&camera, &lights);
+        assert_eq!(grid.len(), CLUSTER_COUNT as usize);
+        let mut offset = 0;
+        for &[cluster_offset, count] in &grid {
+            assert_eq!(cluster_offset, offset);
+            let indices = &light_indices[offset as usize..(offset + count) as usize];
+            assert!(indices.windows(2).all(|pair| pair[0] < pair[1]));
+            offset += count;
+        }
+        assert_eq!(offset as usize, light_indices.len());
+        let center = cluster_at(&camera, &Point3::new(0.0, 0.0, -10.0)).unwrap();
+        assert_eq!(lights_of(&grid, &light_indices, center), vec![0, 2]);
+    }
+
+    #[test]
+    fn full_clusters_keep_the_first_lights() {
+        let lights = vec![light(0.0, 0.0, -10.0, 0.5); MAX_LIGHTS_PER_CLUSTER as usize + 6];
+        let camera = camera();
+        let (grid, light_indices) = bin_lights(&camera, &lights);
+        let center = cluster_at(&camera, &Point3::new(0.0, 0.0, -10.0)).unwrap();
+        assert_eq!(
+            lights_of(&grid, &light_indices, center),
+            (0..MAX_LIGHTS_PER_CLUSTER).collect::<Vec<_>>()
+        );
+        assert!(grid
+            .iter()
+            .all(|&[_, count]| count <= MAX_LIGHTS_PER_CLUSTER));
+    }
+}
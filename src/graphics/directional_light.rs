@@ -0,0 +1,225 @@
+use nalgebra::{geometry::Orthographic3, Matrix4, Point3, Vector3};
+use serde::{Deserialize, Serialize};
+use smol_renderer::GpuData;
+
+use crate::camera::Camera;
+
+use super::shadow_texture::{shadow_map_crop, shadow_map_scale, CASCADE_SHADOW_SIZE};
+
+pub const SHADOW_CASCADES: usize = 4;
+// Blend between logarithmic (1.0) and uniform (0.0) cascade splits
+const CASCADE_SPLIT_LAMBDA: f32 = 0.75;
+// How far behind each cascade shadow casters are still included
+const CASTER_DISTANCE: f32 = 50.0;
+
+// Light infinitely far away, like the sun. Only the first one in the world is used
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DirectionalLight {
+    pub direction: Vector3<f32>,
+    pub color: Vector3<f32>,
+    // illuminance in lux
+    pub intensity: f32,
+    // distance from the camera covered by the shadow cascades
+    pub shadow_distance: f32,
+    pub cast_shadows: bool,
+    // size of each cascade in texels
+    pub shadow_resolution: u32,
+    // world space offset against shadow acne, scaled up on steep surfaces
+    pub shadow_bias: f32,
+    // apparent size of the light in radians, controls the penumbra of pcss shadows
+    pub angular_diameter: f32,
+}
+
+impl Default for DirectionalLight {
+    fn default() -> Self {
+        DirectionalLight {
+            direction: Vector3::new(-0.3, -1.0, -0.4).normalize(),
+            color: Vector3::new(1.0, 1.0, 1.0),
+            intensity: 3.0,
+            shadow_distance: 50.0,
+            cast_shadows: true,
+            shadow_resolution: 2048,
+            shadow_bias: 0.05,
+            angular_diameter: 1.0_f32.to_radians(),
+        }
+    }
+}
+
+impl DirectionalLight {
+    // Splits the camera frustum and fits an orthographic projection around each split
+    pub fn cascade_view_projections(&self, camera: &Camera) -> [Matrix4<f32>; SHADOW_CASCADES] {
+        let splits = self.cascade_splits(camera);
+        let direction = self.direction.normalize();
+        let up = if direction.y.abs() > 0.99 {
+            Vector3::z()
+        } else {
+            Vector3::y()
+        };
+        let crop = shadow_map_crop(self.shadow_scale());
+        let mut view_projections = [Matrix4::identity(); SHADOW_CASCADES];
+        for (i, view_projection) in view_projections.iter_mut().enumerate() {
+            let corners = camera.frustum_corners(splits[i], splits[i + 1]);
+            let center = corners
+                .iter()
+                .fold(Vector3::zeros(), |sum, corner| sum + corner.coords)
+                / corners.len() as f32;
+            // a bounding sphere keeps the projection size constant while the camera rotates
+            let radius = corners
+                .iter()
+                .map(|corner| (corner.coords - center).norm())
+                .fold(0.0, f32::max)
+                .ceil();
+            let center = Point3::from(center);
+            let eye = center - direction * (radius + CASTER_DISTANCE);
+            let view = Matrix4::look_at_rh(&eye, &center, &up);
+            let mut projection = Orthographic3::new(
+                -radius,
+                radius,
+                -radius,
+                radius,
+                0.0,
+                2.0 * radius + CASTER_DISTANCE,
+            )
+            .to_homogeneous();
+            // snap to whole texels to avoid shimmering edges when the camera moves
+            let texels = self.shadow_resolution.min(CASCADE_SHADOW_SIZE.width) as f32 / 2.0;
+            let origin = (projection * view).transform_point(&Point3::origin());
+            projection[(0, 3)] += ((origin.x * texels).round() - origin.x * texels) / texels;
+            projection[(1, 3)] += ((origin.y * texels).round() - origin.y * texels) / texels;
+            *view_projection = crop * projection * view;
+        }
+        view_projections
+    }
+
+    // View depths where the cascades start and end, from the near plane
+    // to the shadow distance or the far plane when it is closer
+    fn cascade_splits(&self, camera: &Camera) -> [f32; SHADOW_CASCADES + 1] {
+        let near = camera.get_near_plane();
+        let far = camera.get_far_plane().min(self.shadow_distance);
+        let mut splits = [0.0; SHADOW_CASCADES + 1];
+        splits[0] = near;
+        for (i, split) in splits.iter_mut().enumerate().skip(1) {
+            let fraction = i as f32 / SHADOW_CASCADES as f32;
+            let logarithmic = near * (far / near).powf(fraction);
+            let uniform = near + (far - near) * fraction;
+            *split = CASCADE_SPLIT_LAMBDA * logarithmic + (1.0 - CASCADE_SPLIT_LAMBDA) * uniform;
+        }
+        splits
+    }
+
+    pub fn shadow_scale(&self) -> f32 {
+        shadow_map_scale(self.shadow_resolution, CASCADE_SHADOW_SIZE.width)
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, GpuData)]
+pub struct DirectionalLightRaw {
+    direction: Vector3<f32>,
+    intensity: f32,
+    color: Vector3<f32>,
+    enabled: i32,
+    shadow_bias: f32,
+    shadow_scale: f32,
+    // penumbra width per unit of distance between occluder and receiver
+    penumbra_slope: f32,
+    cast_shadows: i32,
+    cascade_matrices: [Matrix4<f32>; SHADOW_CASCADES],
+}
+
+impl DirectionalLightRaw {
+    pub fn new(light: &DirectionalLight, camera: &Camera) -> Self {
+        DirectionalLightRaw {
+            direction: light.direction.normalize(),
+            intensity: light.intensity,
+            color: light.color,
+            enabled: 1,
+            shadow_bias: light.shadow_bias,
+            shadow_scale: light.shadow_scale(),
+            penumbra_slope: 2.0 * (light.angular_diameter / 2.0).tan(),
+            cast_shadows: light.cast_shadows as i32,
+            cascade_matrices: light.cascade_view_projections(camera),
+        }
+    }
+
+    // Used when there is no directional light in the world
+    pub fn disabled() -> Self {
+        DirectionalLightRaw {
+            direction: Vector3::new(0.0, -1.0, 0.0),
+            intensity: 0.0,
+            color: Vector3::zeros(),
+            enabled: 0,
+            shadow_bias: 0.0,
+            shadow_scale: 1.0,
+            penumbra_slope: 0.0,
+            cast_shadows: 0,
+            cascade_matrices: [Matrix4::identity(); SHADOW_CASCADES],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera(position: Vector3<f32>) -> Camera {
+        let mut camera = Camera::new(Point3::origin(), Vector3::new(0.3, -0.2, -1.0), 1600, 900);
+        camera.set_position(position);
+        camera
+    }
+
+    #[test]
+    fn splits_increase_from_the_near_plane_to_the_shadow_distance() {
+        let camera = camera(Vector3::zeros());
+        for &shadow_distance in &[20.0, 50.0, 1000.0] {
+            let light = DirectionalLight {
+                shadow_distance,
+                ..Default::default()
+            };
+            let splits = light.cascade_splits(&camera);
+            let far = camera.get_far_plane().min(shadow_distance);
+            assert!((splits[0] - camera.get_near_plane()).abs() < 1e-6);
+            assert!((splits[SHADOW_CASCADES] - far).abs() < 1e-3 * far);
+            assert!(
+                splits.windows(2).all(|pair| pair[0] < pair[1]),
+                "{:?}",
+                splits
+            );
+        }
+    }
+
+    // Position of a world point in the cascade layer in texels
+    fn texel(view_projection: &Matrix4<f32>, point: &Point3<f32>) -> Vector3<f32> {
+        let ndc = view_projection.transform_point(point);
+        (ndc.coords + Vector3::repeat(1.0)) / 2.0 * CASCADE_SHADOW_SIZE.width as f32
+    }
+
+    #[test]
+    fn cascades_move_in_whole_texels() {
+        let point = Point3::new(3.3, 0.7, -5.1);
+        for &shadow_resolution in &[CASCADE_SHADOW_SIZE.width, 1024] {
+            let light = DirectionalLight {
+                shadow_resolution,
+                ..Default::default()
+            };
+            let start = light.cascade_view_projections(&camera(Vector3::zeros()));
+            for &offset in &[
+                Vector3::new(0.013, 0.0, 0.0),
+                Vector3::new(0.4, -0.07, 0.21),
+                Vector3::new(-2.37, 1.1, 5.6),
+            ] {
+                let moved = light.cascade_view_projections(&camera(offset));
+                for (start, moved) in start.iter().zip(&moved) {
+                    let step = texel(moved, &point) - texel(start, &point);
+                    assert!(
+                        (step.x - step.x.round()).abs() < 0.01
+                            && (step.y - step.y.round()).abs() < 0.01,
+                        "{:?} isn't a whole number of texels",
+                        step
+                    );
+                }
+            }
+        }
+    }
+}
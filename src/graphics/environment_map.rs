@@ -0,0 +1,421 @@
+use anyhow::Result;
+use once_cell::sync::OnceCell;
+use smol_renderer::{
+    FragmentShader, GpuData, RenderNode, TextureData, TextureShaderLayout, UniformBindGroup,
+    VertexShader,
+};
+use wgpu::{
+    AddressMode, BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, Binding,
+    BindingResource, BindingType, BufferUsage, CommandEncoderDescriptor, Device, Extent3d,
+    FilterMode, LoadOp, Operations, Queue, RenderPassColorAttachmentDescriptor,
+    RenderPassDescriptor, ShaderStage, TextureComponentType, TextureDimension, TextureUsage,
+    TextureView, TextureViewDescriptor, TextureViewDimension,
+};
+
+use super::hdr_texture::HDR_FORMAT;
+use super::skybox_texture::SkyboxTexture;
+
+const CUBE_FACES: u32 = 6;
+const IRRADIANCE_SIZE: u32 = 32;
+const PREFILTERED_SIZE: u32 = 128;
+const PREFILTERED_MIP_LEVELS: u32 = 5;
+const BRDF_LUT_SIZE: u32 = 512;
+
+// Resource scaling the environment lighting, replaces the old constant ambient term
+pub struct ImageBasedLighting {
+    pub intensity: f32,
+}
+
+impl Default for ImageBasedLighting {
+    fn default() -> Self {
+        ImageBasedLighting { intensity: 1.0 }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, GpuData)]
+struct CubeFaceUniforms {
+    face: i32,
+    roughness: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone)]
+struct EnvironmentUniforms {
+    intensity: f32,
+    max_reflection_lod: f32,
+    _pad: [f32; 2],
+}
+
+impl EnvironmentUniforms {
+    fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const EnvironmentUniforms as *const u8,
+                std::mem::size_of::<EnvironmentUniforms>(),
+            )
+        }
+    }
+}
+
+// Irradiance map, prefiltered specular map, brdf lut and
+// the lighting uniforms in a single bind group
+pub struct EnvironmentTextures;
+
+impl TextureShaderLayout for EnvironmentTextures {
+    fn get_layout(device: &Device) -> &'static BindGroupLayout {
+        static LAYOUT: OnceCell<BindGroupLayout> = OnceCell::new();
+        LAYOUT.get_or_init(|| {
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                bindings: &[
+                    BindGroupLayoutEntry::new(
+                        0,
+                        ShaderStage::FRAGMENT,
+                        BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: TextureViewDimension::Cube,
+                            component_type: TextureComponentType::Float,
+                        },
+                    ),
+                    BindGroupLayoutEntry::new(
+                        1,
+                        ShaderStage::FRAGMENT,
+                        BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: TextureViewDimension::Cube,
+                            component_type: TextureComponentType::Float,
+                        },
+                    ),
+                    BindGroupLayoutEntry::new(
+                        2,
+                        ShaderStage::FRAGMENT,
+                        BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: TextureViewDimension::D2,
+                            component_type: TextureComponentType::Float,
+                        },
+                    ),
+                    BindGroupLayoutEntry::new(
+                        3,
+                        ShaderStage::FRAGMENT,
+                        BindingType::Sampler { comparison: false },
+                    ),
+                    BindGroupLayoutEntry::new(
+                        4,
+                        ShaderStage::FRAGMENT,
+                        BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                        },
+                    ),
+                ],
+                label: Some("Environment Textures layout"),
+            })
+        })
+    }
+}
+
+fn create_target_texture(
+    device: &Device,
+    label: &str,
+    size: u32,
+    layers: u32,
+    mip_level_count: u32,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: Extent3d {
+            width: size,
+            height: size,
+            depth: layers,
+        },
+        mip_level_count,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format: HDR_FORMAT,
+        usage: TextureUsage::OUTPUT_ATTACHMENT | TextureUsage::SAMPLED,
+    })
+}
+
+fn face_view(texture: &wgpu::Texture, face: u32, mip_level: u32) -> TextureView {
+    texture.create_view(&TextureViewDescriptor {
+        format: HDR_FORMAT,
+        dimension: TextureViewDimension::D2,
+        aspect: wgpu::TextureAspect::default(),
+        base_mip_level: mip_level,
+        level_count: 1,
+        base_array_layer: face,
+        array_layer_count: 1,
+        label: Some("Environment face view"),
+    })
+}
+
+fn cube_view(texture: &wgpu::Texture, mip_level_count: u32) -> TextureView {
+    texture.create_view(&TextureViewDescriptor {
+        format: HDR_FORMAT,
+        dimension: TextureViewDimension::Cube,
+        aspect: wgpu::TextureAspect::default(),
+        base_mip_level: 0,
+        level_count: mip_level_count,
+        base_array_layer: 0,
+        array_layer_count: CUBE_FACES,
+        label: Some("Environment cube view"),
+    })
+}
+
+fn convolution_node(device: &Device, fragment_shader: &str, name: &str) -> Result<RenderNode> {
+    Ok(RenderNode::builder()
+        .set_vertex_shader(VertexShader::new(
+            device,
+            "src/shader_files/vs_fullscreen.shader",
+        )?)
+        .set_fragment_shader(FragmentShader::new(device, fragment_shader)?)
+        .add_texture::<SkyboxTexture>()
+        .add_default_color_state_desc(HDR_FORMAT)
+        .set_default_rasterization_state()
+        .add_local_uniform_bind_group(
+            UniformBindGroup::with_name(name)
+                .add_binding::<CubeFaceUniforms>(ShaderStage::FRAGMENT)?
+                .build(device),
+        )
+        .build(device)?)
+}
+
+// Lighting precomputed from the skybox, used for the ambient
+// term in the phong and pbr passes
+pub struct EnvironmentMap {
+    pub textures: TextureData<EnvironmentTextures>,
+    _irradiance: wgpu::Texture,
+    _brdf_lut: wgpu::Texture,
+    uniform_buffer: wgpu::Buffer,
+    irradiance_node: RenderNode,
+    prefilter_node: RenderNode,
+    // render targets for every face of the irradiance map and every face and mip level
+    // of the prefiltered map, kept so the maps can be recomputed for a new skybox
+    irradiance_faces: Vec<TextureView>,
+    prefiltered_faces: Vec<TextureView>,
+}
+
+impl EnvironmentMap {
+    pub fn precompute(
+        device: &Device,
+        queue: &Queue,
+        skybox: &TextureData<SkyboxTexture>,
+    ) -> Result<Self> {
+        let irradiance_node = convolution_node(
+            device,
+            "src/shader_files/fs_irradiance.shader",
+            "Irradiance uniform",
+        )?;
+        let prefilter_node = convolution_node(
+            device,
+            "src/shader_files/fs_prefilter.shader",
+            "Prefilter uniform",
+        )?;
+        let brdf_node = RenderNode::builder()
+            .set_vertex_shader(VertexShader::new(
+                device,
+                "src/shader_files/vs_fullscreen.shader",
+            )?)
+            .set_fragment_shader(FragmentShader::new(
+                device,
+                "src/shader_files/fs_brdf_lut.shader",
+            )?)
+            .add_default_color_state_desc(HDR_FORMAT)
+            .set_default_rasterization_state()
+            .build(device)?;
+
+        let irradiance =
+            create_target_texture(device, "Irradiance map", IRRADIANCE_SIZE, CUBE_FACES, 1);
+        let prefiltered = create_target_texture(
+            device,
+            "Prefiltered environment map",
+            PREFILTERED_SIZE,
+            CUBE_FACES,
+            PREFILTERED_MIP_LEVELS,
+        );
+        let brdf_lut = create_target_texture(device, "Brdf lut", BRDF_LUT_SIZE, 1, 1);
+        let irradiance_faces = (0..CUBE_FACES)
+            .map(|face| face_view(&irradiance, face, 0))
+            .collect();
+        let mut prefiltered_faces = Vec::new();
+        for mip_level in 0..PREFILTERED_MIP_LEVELS {
+            for face in 0..CUBE_FACES {
+                prefiltered_faces.push(face_view(&prefiltered, face, mip_level));
+            }
+        }
+
+        // the brdf lut doesn't depend on the skybox
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("Brdf lut encoder"),
+        });
+        {
+            let view = face_view(&brdf_lut, 0, 0);
+            let mut runner = brdf_node.runner(
+                &mut encoder,
+                RenderPassDescriptor {
+                    color_attachments: &[RenderPassColorAttachmentDescriptor {
+                        attachment: &view,
+                        resolve_target: None,
+                        ops: Operations {
+                            load: LoadOp::Clear(wgpu::Color::BLACK),
+                            store: true,
+                        },
+                    }],
+                    depth_stencil_attachment: None,
+                },
+            );
+            runner.draw(0..3, 0..1);
+        }
+        queue.submit(vec![encoder.finish()]);
+
+        let irradiance_view = cube_view(&irradiance, 1);
+        let prefiltered_view = cube_view(&prefiltered, PREFILTERED_MIP_LEVELS);
+        let brdf_view = brdf_lut.create_default_view();
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Environment sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            address_mode_w: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Linear,
+            lod_min_clamp: 0.0,
+            lod_max_clamp: 100.0,
+            compare: None,
+            ..Default::default()
+        });
+        let uniforms = EnvironmentUniforms {
+            intensity: ImageBasedLighting::default().intensity,
+            max_reflection_lod: (PREFILTERED_MIP_LEVELS - 1) as f32,
+            _pad: [0.0; 2],
+        };
+        let uniform_buffer = device.create_buffer_with_data(
+            uniforms.as_bytes(),
+            BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+        );
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: EnvironmentTextures::get_layout(device),
+            bindings: &[
+                Binding {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&irradiance_view),
+                },
+                Binding {
+                    binding: 1,
+                    resource: BindingResource::TextureView(&prefiltered_view),
+                },
+                Binding {
+                    binding: 2,
+                    resource: BindingResource::TextureView(&brdf_view),
+                },
+                Binding {
+                    binding: 3,
+                    resource: BindingResource::Sampler(&sampler),
+                },
+                Binding {
+                    binding: 4,
+                    resource: BindingResource::Buffer(uniform_buffer.slice(..)),
+                },
+            ],
+            label: Some("Environment bindgroup"),
+        });
+
+        let environment_map = EnvironmentMap {
+            textures: TextureData::new(
+                bind_group,
+                prefiltered,
+                vec![irradiance_view, prefiltered_view, brdf_view],
+                sampler,
+            ),
+            _irradiance: irradiance,
+            _brdf_lut: brdf_lut,
+            uniform_buffer,
+            irradiance_node,
+            prefilter_node,
+            irradiance_faces,
+            prefiltered_faces,
+        };
+        environment_map.convolve(device, queue, skybox);
+        Ok(environment_map)
+    }
+
+    // Renders the irradiance and prefiltered maps from the skybox, the passes
+    // sampling them see the new lighting without rebuilding their bind groups
+    pub fn convolve(&self, device: &Device, queue: &Queue, skybox: &TextureData<SkyboxTexture>) {
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("Environment precompute encoder"),
+        });
+        for (face, view) in self.irradiance_faces.iter().enumerate() {
+            self.irradiance_node
+                .update(
+                    device,
+                    &mut encoder,
+                    0,
+                    &CubeFaceUniforms {
+                        face: face as i32,
+                        roughness: 0.0,
+                    },
+                )
+                .unwrap();
+            let mut runner = self.irradiance_node.runner(
+                &mut encoder,
+                RenderPassDescriptor {
+                    color_attachments: &[RenderPassColorAttachmentDescriptor {
+                        attachment: view,
+                        resolve_target: None,
+                        ops: Operations {
+                            load: LoadOp::Clear(wgpu::Color::BLACK),
+                            store: true,
+                        },
+                    }],
+                    depth_stencil_attachment: None,
+                },
+            );
+            runner.set_texture_data(0, skybox);
+            runner.draw(0..3, 0..1);
+        }
+        for (i, view) in self.prefiltered_faces.iter().enumerate() {
+            let face = i as u32 % CUBE_FACES;
+            let mip_level = i as u32 / CUBE_FACES;
+            let roughness = mip_level as f32 / (PREFILTERED_MIP_LEVELS - 1) as f32;
+            self.prefilter_node
+                .update(
+                    device,
+                    &mut encoder,
+                    0,
+                    &CubeFaceUniforms {
+                        face: face as i32,
+                        roughness,
+                    },
+                )
+                .unwrap();
+            let mut runner = self.prefilter_node.runner(
+                &mut encoder,
+                RenderPassDescriptor {
+                    color_attachments: &[RenderPassColorAttachmentDescriptor {
+                        attachment: view,
+                        resolve_target: None,
+                        ops: Operations {
+                            load: LoadOp::Clear(wgpu::Color::BLACK),
+                            store: true,
+                        },
+                    }],
+                    depth_stencil_attachment: None,
+                },
+            );
+            runner.set_texture_data(0, skybox);
+            runner.draw(0..3, 0..1);
+        }
+        queue.submit(vec![encoder.finish()]);
+    }
+
+    pub fn update_lighting(&self, queue: &Queue, lighting: &ImageBasedLighting) {
+        let uniforms = EnvironmentUniforms {
+            intensity: lighting.intensity,
+            max_reflection_lod: (PREFILTERED_MIP_LEVELS - 1) as f32,
+            _pad: [0.0; 2],
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, uniforms.as_bytes());
+    }
+}
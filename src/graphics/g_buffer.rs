@@ -0,0 +1,189 @@
+use once_cell::sync::OnceCell;
+use smol_renderer::{TextureData, TextureShaderLayout};
+use wgpu::{
+    AddressMode, BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, Binding,
+    BindingResource, BindingType, Device, Extent3d, FilterMode, ShaderStage, TextureComponentType,
+    TextureDimension, TextureFormat, TextureUsage, TextureView, TextureViewDimension,
+};
+
+use super::wgpu_renderer::DEPTH_FORMAT;
+
+// rgb: diffuse or albedo color, a: shading model (0 phong, 1 pbr)
+pub const ALBEDO_FORMAT: TextureFormat = TextureFormat::Rgba8Unorm;
+// xyz: world space normal, w: 1 for unlit surfaces
+pub const NORMAL_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+// phong: rgb specular color, pbr: r metallic, g roughness, b ambient occlusion
+pub const MATERIAL_FORMAT: TextureFormat = TextureFormat::Rgba8Unorm;
+// rgb: emitted radiance
+pub const EMISSIVE_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+// Surface attributes written by the deferred geometry pass and read back
+// per pixel by the lighting pass, positions are reconstructed from depth
+pub struct GBufferTextures;
+
+fn sampled_texture_entry(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry::new(
+        binding,
+        ShaderStage::FRAGMENT,
+        BindingType::SampledTexture {
+            multisampled: false,
+            dimension: TextureViewDimension::D2,
+            component_type: TextureComponentType::Float,
+        },
+    )
+}
+
+impl TextureShaderLayout for GBufferTextures {
+    fn get_layout(device: &Device) -> &'static BindGroupLayout {
+        static LAYOUT: OnceCell<BindGroupLayout> = OnceCell::new();
+        LAYOUT.get_or_init(|| {
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                bindings: &[
+                    sampled_texture_entry(0),
+                    sampled_texture_entry(1),
+                    sampled_texture_entry(2),
+                    sampled_texture_entry(3),
+                    sampled_texture_entry(4),
+                    BindGroupLayoutEntry::new(
+                        5,
+                        ShaderStage::FRAGMENT,
+                        BindingType::Sampler { comparison: false },
+                    ),
+                ],
+                label: Some("G-buffer layout"),
+            })
+        })
+    }
+}
+
+fn create_target(
+    device: &Device,
+    label: &str,
+    width: u32,
+    height: u32,
+    format: TextureFormat,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: Extent3d {
+            width,
+            height,
+            depth: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format,
+        usage: TextureUsage::OUTPUT_ATTACHMENT | TextureUsage::SAMPLED,
+    })
+}
+
+pub struct GBuffer {
+    // the albedo texture is owned by the texture data
+    pub textures: TextureData<GBufferTextures>,
+    normal_texture: wgpu::Texture,
+    _material_texture: wgpu::Texture,
+    _emissive_texture: wgpu::Texture,
+    depth_texture: wgpu::Texture,
+    albedo_view: TextureView,
+    normal_view: TextureView,
+    material_view: TextureView,
+    emissive_view: TextureView,
+    depth_view: TextureView,
+}
+
+impl GBuffer {
+    pub fn new(device: &Device, width: u32, height: u32) -> Self {
+        let albedo_texture = create_target(device, "G-buffer albedo", width, height, ALBEDO_FORMAT);
+        let normal_texture = create_target(device, "G-buffer normal", width, height, NORMAL_FORMAT);
+        let material_texture =
+            create_target(device, "G-buffer material", width, height, MATERIAL_FORMAT);
+        let emissive_texture =
+            create_target(device, "G-buffer emissive", width, height, EMISSIVE_FORMAT);
+        let depth_texture = create_target(device, "G-buffer depth", width, height, DEPTH_FORMAT);
+        let views = vec![
+            albedo_texture.create_default_view(),
+            normal_texture.create_default_view(),
+            material_texture.create_default_view(),
+            emissive_texture.create_default_view(),
+            depth_texture.create_default_view(),
+        ];
+        // the lighting pass fetches texels directly, the sampler is never filtered
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("G-buffer sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            address_mode_w: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Nearest,
+            min_filter: FilterMode::Nearest,
+            mipmap_filter: FilterMode::Nearest,
+            ..Default::default()
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: GBufferTextures::get_layout(device),
+            bindings: &[
+                Binding {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&views[0]),
+                },
+                Binding {
+                    binding: 1,
+                    resource: BindingResource::TextureView(&views[1]),
+                },
+                Binding {
+                    binding: 2,
+                    resource: BindingResource::TextureView(&views[2]),
+                },
+                Binding {
+                    binding: 3,
+                    resource: BindingResource::TextureView(&views[3]),
+                },
+                Binding {
+                    binding: 4,
+                    resource: BindingResource::TextureView(&views[4]),
+                },
+                Binding {
+                    binding: 5,
+                    resource: BindingResource::Sampler(&sampler),
+                },
+            ],
+            label: Some("G-buffer bindgroup"),
+        });
+        GBuffer {
+            albedo_view: albedo_texture.create_default_view(),
+            normal_view: normal_texture.create_default_view(),
+            material_view: material_texture.create_default_view(),
+            emissive_view: emissive_texture.create_default_view(),
+            depth_view: depth_texture.create_default_view(),
+            textures: TextureData::new(bind_group, albedo_texture, views, sampler),
+            normal_texture,
+            _material_texture: material_texture,
+            _emissive_texture: emissive_texture,
+            depth_texture,
+        }
+    }
+
+    // Color attachments of the geometry pass in location order
+    #[inline]
+    pub fn color_views(&self) -> [&TextureView; 4] {
+        [
+            &self.albedo_view,
+            &self.normal_view,
+            &self.material_view,
+            &self.emissive_view,
+        ]
+    }
+
+    #[inline]
+    pub fn depth_view(&self) -> &TextureView {
+        &self.depth_view
+    }
+
+    // New sampled views of the normals and depth for the ssao pass
+    pub fn normal_depth_views(&self) -> (TextureView, TextureView) {
+        (
+            self.normal_texture.create_default_view(),
+            self.depth_texture.create_default_view(),
+        )
+    }
+}
@@ -0,0 +1,281 @@
+use once_cell::sync::OnceCell;
+use smol_renderer::{TextureData, TextureShaderLayout};
+use wgpu::{
+    AddressMode, BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, Binding,
+    BindingResource, BindingType, Device, Extent3d, FilterMode, ShaderStage, TextureComponentType,
+    TextureDimension, TextureFormat, TextureUsage, TextureView, TextureViewDescriptor,
+    TextureViewDimension,
+};
+
+use super::{
+    g_buffer::GBuffer,
+    ssao_texture::{DepthNormalTargets, AMBIENT_OCCLUSION_FORMAT},
+    wgpu_renderer::{create_depth_texture, RenderPath},
+};
+
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+// Floating point color texture the scene is rendered into before
+// being resolved to the swap chain by the tone mapping pass
+pub struct HdrTexture;
+
+impl TextureShaderLayout for HdrTexture {
+    fn get_layout(device: &Device) -> &'static BindGroupLayout {
+        static LAYOUT: OnceCell<BindGroupLayout> = OnceCell::new();
+        LAYOUT.get_or_init(|| {
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                bindings: &[
+                    BindGroupLayoutEntry::new(
+                        0,
+                        ShaderStage::FRAGMENT,
+                        BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: TextureViewDimension::D2,
+                            component_type: TextureComponentType::Float,
+                        },
+                    ),
+                    BindGroupLayoutEntry::new(
+                        1,
+                        ShaderStage::FRAGMENT,
+                        BindingType::Sampler { comparison: false },
+                    ),
+                ],
+                label: Some("Hdr Texture layout"),
+            })
+        })
+    }
+}
+
+impl HdrTexture {
+    pub fn allocate(device: &Device, width: u32, height: u32) -> TextureData<HdrTexture> {
+        Self::allocate_with_format(device, width, height, HDR_FORMAT)
+    }
+
+    // The same layout is used by the ldr post processing buffers
+    pub fn allocate_with_format(
+        device: &Device,
+        width: u32,
+        height: u32,
+        format: TextureFormat,
+    ) -> TextureData<HdrTexture> {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Hdr texture"),
+            size: Extent3d {
+                width,
+                height,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format,
+            usage: TextureUsage::OUTPUT_ATTACHMENT | TextureUsage::SAMPLED,
+        });
+        let view = texture.create_default_view();
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Hdr sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            address_mode_w: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Nearest,
+            lod_min_clamp: 0.0,
+            lod_max_clamp: 100.0,
+            compare: None,
+            ..Default::default()
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: Self::get_layout(device),
+            bindings: &[
+                Binding {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&view),
+                },
+                Binding {
+                    binding: 1,
+                    resource: BindingResource::Sampler(&sampler),
+                },
+            ],
+            label: Some("Hdr texture bindgroup"),
+        });
+        TextureData::new(bind_group, texture, vec![view], sampler)
+    }
+}
+
+// A texture that is both rendered to and sampled from in a later pass
+pub struct SampledTarget {
+    texture: TextureData<HdrTexture>,
+    view: TextureView,
+}
+
+impl SampledTarget {
+    pub fn new(device: &Device, width: u32, height: u32, format: TextureFormat) -> Self {
+        let texture = HdrTexture::allocate_with_format(device, width, height, format);
+        let view = texture.create_new_view(&TextureViewDescriptor {
+            format,
+            dimension: TextureViewDimension::D2,
+            aspect: wgpu::TextureAspect::default(),
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            array_layer_count: 1,
+            label: Some("Sampled target view"),
+        });
+        SampledTarget { texture, view }
+    }
+
+    #[inline]
+    pub fn texture(&self) -> &TextureData<HdrTexture> {
+        &self.texture
+    }
+
+    #[inline]
+    pub fn view(&self) -> &TextureView {
+        &self.view
+    }
+}
+
+// The buffers the scene and post processing passes render into,
+// each output (window, render targets) needs its own set
+pub struct SceneTargets {
+    pub width: u32,
+    pub height: u32,
+    // the scene passes render into this when msaa is enabled and resolve into hdr
+    msaa_texture: Option<wgpu::Texture>,
+    msaa_view: Option<TextureView>,
+    hdr: SampledTarget,
+    _depth_texture: wgpu::Texture,
+    depth_view: TextureView,
+    // half resolution ping pong buffers for the bloom blur
+    bloom: [SampledTarget; 2],
+    // ping pong buffers for the post processing effects after tone mapping
+    ldr: [SampledTarget; 2],
+    // only allocated for the deferred render path
+    g_buffer: Option<GBuffer>,
+    // input of the ssao pass, shares the g-buffer textures when deferred
+    depth_normals: DepthNormalTargets,
+    // raw and blurred ambient occlusion
+    ambient_occlusion: [SampledTarget; 2],
+}
+
+impl SceneTargets {
+    pub fn new(
+        device: &Device,
+        width: u32,
+        height: u32,
+        output_format: TextureFormat,
+        sample_count: u32,
+        render_path: RenderPath,
+    ) -> Self {
+        let depth_texture = create_depth_texture(device, width, height, sample_count);
+        let depth_view = depth_texture.create_default_view();
+        let msaa_texture = if sample_count > 1 {
+            Some(device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("Multisampled hdr texture"),
+                size: Extent3d {
+                    width,
+                    height,
+                    depth: 1,
+                },
+                mip_level_count: 1,
+                sample_count,
+                dimension: TextureDimension::D2,
+                format: HDR_FORMAT,
+                usage: TextureUsage::OUTPUT_ATTACHMENT,
+            }))
+        } else {
+            None
+        };
+        let msaa_view = msaa_texture
+            .as_ref()
+            .map(|texture| texture.create_default_view());
+        let (bloom_width, bloom_height) = ((width / 2).max(1), (height / 2).max(1));
+        let g_buffer = match render_path {
+            RenderPath::Forward => None,
+            RenderPath::Deferred => Some(GBuffer::new(device, width, height)),
+        };
+        let depth_normals = match &g_buffer {
+            Some(g_buffer) => DepthNormalTargets::from_g_buffer(device, g_buffer),
+            None => DepthNormalTargets::new(device, width, height),
+        };
+        SceneTargets {
+            width,
+            height,
+            msaa_texture,
+            msaa_view,
+            hdr: SampledTarget::new(device, width, height, HDR_FORMAT),
+            _depth_texture: depth_texture,
+            depth_view,
+            bloom: [
+                SampledTarget::new(device, bloom_width, bloom_height, HDR_FORMAT),
+                SampledTarget::new(device, bloom_width, bloom_height, HDR_FORMAT),
+            ],
+            ldr: [
+                SampledTarget::new(device, width, height, output_format),
+                SampledTarget::new(device, width, height, output_format),
+            ],
+            g_buffer,
+            depth_normals,
+            ambient_occlusion: [
+                SampledTarget::new(device, width, height, AMBIENT_OCCLUSION_FORMAT),
+                SampledTarget::new(device, width, height, AMBIENT_OCCLUSION_FORMAT),
+            ],
+        }
+    }
+
+    #[inline]
+    pub fn hdr(&self) -> &SampledTarget {
+        &self.hdr
+    }
+
+    // Color attachment for the scene passes
+    #[inline]
+    pub fn color_attachment(&self) -> &TextureView {
+        self.msaa_view.as_ref().unwrap_or(&self.hdr.view)
+    }
+
+    // Resolve target for the scene passes, only used with msaa
+    #[inline]
+    pub fn resolve_target(&self) -> Option<&TextureView> {
+        self.msaa_view.as_ref().map(|_| &self.hdr.view)
+    }
+
+    #[inline]
+    pub fn depth_view(&self) -> &TextureView {
+        &self.depth_view
+    }
+
+    // Depth of the opaque geometry, the g-buffer has its own on the deferred path
+    #[inline]
+    pub fn scene_depth_view(&self) -> &TextureView {
+        self.g_buffer
+            .as_ref()
+            .map_or(&self.depth_view, |g_buffer| g_buffer.depth_view())
+    }
+
+    #[inline]
+    pub fn g_buffer(&self) -> Option<&GBuffer> {
+        self.g_buffer.as_ref()
+    }
+
+    #[inline]
+    pub fn depth_normals(&self) -> &DepthNormalTargets {
+        &self.depth_normals
+    }
+
+    #[inline]
+    pub fn ambient_occlusion(&self, index: usize) -> &SampledTarget {
+        &self.ambient_occlusion[index]
+    }
+
+    #[inline]
+    pub fn bloom(&self, index: usize) -> &SampledTarget {
+        &self.bloom[index]
+    }
+
+    #[inline]
+    pub fn ldr(&self, index: usize) -> &SampledTarget {
+        &self.ldr[index]
+    }
+}
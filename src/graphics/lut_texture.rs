@@ -0,0 +1,160 @@
+use image::GenericImage;
+use once_cell::sync::OnceCell;
+use smol_renderer::{LoadableTexture, RenderError, TextureData, TextureShaderLayout};
+use std::path::Path;
+use wgpu::{
+    AddressMode, BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, Binding,
+    BindingResource, BindingType, Device, Extent3d, FilterMode, Origin3d, Queue, ShaderStage,
+    TextureComponentType, TextureCopyView, TextureDataLayout, TextureDimension, TextureFormat,
+    TextureUsage, TextureViewDimension,
+};
+
+pub const IDENTITY_LUT_SIZE: u32 = 16;
+
+// 3D color lookup table used for color grading
+pub struct LutTexture;
+
+impl TextureShaderLayout for LutTexture {
+    fn get_layout(device: &Device) -> &'static BindGroupLayout {
+        static LAYOUT: OnceCell<BindGroupLayout> = OnceCell::new();
+        LAYOUT.get_or_init(|| {
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                bindings: &[
+                    BindGroupLayoutEntry::new(
+                        0,
+                        ShaderStage::FRAGMENT,
+                        BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: TextureViewDimension::D3,
+                            component_type: TextureComponentType::Float,
+                        },
+                    ),
+                    BindGroupLayoutEntry::new(
+                        1,
+                        ShaderStage::FRAGMENT,
+                        BindingType::Sampler { comparison: false },
+                    ),
+                ],
+                label: Some("Lut Texture layout"),
+            })
+        })
+    }
+}
+
+impl LoadableTexture for LutTexture {
+    // Loads a lut stored as a horizontal strip of blue slices,
+    // i.e an image of size (size * size) x size
+    fn load_texture(
+        device: &Device,
+        queue: &Queue,
+        path: impl AsRef<Path>,
+    ) -> Result<TextureData<Self>, RenderError> {
+        let image = image::open(path.as_ref())?;
+        let (width, height) = image.dimensions();
+        if width != height * height {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Lut {:?} must be a strip of {} slices, found width {}",
+                    path.as_ref(),
+                    height,
+                    width
+                ),
+            )
+            .into());
+        }
+        let size = height;
+        let strip = image.to_rgba();
+        let mut data = Vec::with_capacity((size * size * size * 4) as usize);
+        for blue in 0..size {
+            for green in 0..size {
+                for red in 0..size {
+                    data.extend_from_slice(&strip.get_pixel(blue * size + red, green).data);
+                }
+            }
+        }
+        Ok(Self::create(device, queue, size, &data))
+    }
+}
+
+impl LutTexture {
+    // A lut that maps every color to itself
+    pub fn identity(device: &Device, queue: &Queue) -> TextureData<LutTexture> {
+        let size = IDENTITY_LUT_SIZE;
+        let max = (size - 1) as f32;
+        let mut data = Vec::with_capacity((size * size * size * 4) as usize);
+        for blue in 0..size {
+            for green in 0..size {
+                for red in 0..size {
+                    data.extend_from_slice(&[
+                        (red as f32 / max * 255.0) as u8,
+                        (green as f32 / max * 255.0) as u8,
+                        (blue as f32 / max * 255.0) as u8,
+                        255,
+                    ]);
+                }
+            }
+        }
+        Self::create(device, queue, size, &data)
+    }
+
+    fn create(device: &Device, queue: &Queue, size: u32, data: &[u8]) -> TextureData<LutTexture> {
+        let extent = Extent3d {
+            width: size,
+            height: size,
+            depth: size,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Lut texture"),
+            size: extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D3,
+            format: TextureFormat::Rgba8Unorm,
+            usage: TextureUsage::SAMPLED | TextureUsage::COPY_DST,
+        });
+        queue.write_texture(
+            TextureCopyView {
+                texture: &texture,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+            },
+            data,
+            TextureDataLayout {
+                offset: 0,
+                bytes_per_row: 4 * size,
+                rows_per_image: size,
+            },
+            extent,
+        );
+        let view = texture.create_default_view();
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Lut sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            address_mode_w: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Nearest,
+            lod_min_clamp: 0.0,
+            lod_max_clamp: 100.0,
+            compare: None,
+            ..Default::default()
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: Self::get_layout(device),
+            bindings: &[
+                Binding {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&view),
+                },
+                Binding {
+                    binding: 1,
+                    resource: BindingResource::Sampler(&sampler),
+                },
+            ],
+            label: Some("Lut texture bindgroup"),
+        });
+        TextureData::new(bind_group, texture, vec![view], sampler)
+    }
+}
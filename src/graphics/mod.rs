@@ -1,17 +1,36 @@
 use legion::prelude::{Resources, World};
 
+pub mod clustered_lights;
+pub mod directional_light;
+pub mod environment_map;
+pub mod g_buffer;
+pub mod hdr_texture;
+pub mod lut_texture;
 pub mod model;
+pub mod morph_targets;
 pub mod pass;
+pub mod pbr_material;
+pub mod phong_material;
 pub mod point_light;
+pub mod render_target;
+pub mod screenshot;
+pub mod shader_include;
+pub mod shadow_atlas;
 pub mod shadow_texture;
+pub mod skinned_model;
+pub mod skinning;
 pub mod skybox_texture;
+pub mod spot_light;
+pub mod ssao_texture;
 pub mod wgpu_renderer;
 
 pub use pass::Pass;
 
 pub use wgpu_renderer::WgpuRenderer;
 
+pub use directional_light::DirectionalLight;
 pub use point_light::PointLight;
+pub use spot_light::SpotLight;
 //pub mod basic_renderer;
 //pub use basic_renderer::BasicRenderer;
 use glfw::{Glfw, Window};
@@ -5,6 +5,7 @@ use smol_renderer::{
     SimpleTexture, TextureData, VertexBuffer,
 };
 use std::path::Path;
+use std::sync::Arc;
 use std::{ops::Range, path::PathBuf};
 use wgpu::{
     Buffer, BufferAddress, BufferUsage, Device, Queue, VertexAttributeDescriptor, VertexFormat,
@@ -12,6 +13,8 @@ use wgpu::{
 
 use crate::assets::AssetLoader;
 
+use super::{pbr_material::PbrMaterial, phong_material::PhongMaterial};
+
 const INDEX_BUFFER_SIZE: u64 = 16_000;
 
 #[repr(C)]
@@ -96,10 +99,65 @@ impl VertexBuffer for InstanceData {
         ]
     }
 }
+// How the alpha of a material is used when it's rendered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlendMode {
+    Opaque,
+    // Fragments with an alpha below the cutoff are discarded
+    AlphaTest(f32),
+    // Sorted back to front and blended over the opaque geometry
+    AlphaBlend,
+}
+
+impl BlendMode {
+    // Read from the alpha_mode (opaque, mask or blend) and alpha_cutoff mtl parameters,
+    // materials without them are blended if their dissolve is below one
+    pub fn from_obj_material(material: &tobj::Material) -> Self {
+        let params = &material.unknown_param;
+        match params.get("alpha_mode").map(|mode| mode.trim()) {
+            Some("opaque") => BlendMode::Opaque,
+            Some("mask") => BlendMode::AlphaTest(
+                params
+                    .get("alpha_cutoff")
+                    .and_then(|cutoff| cutoff.trim().parse::<f32>().ok())
+                    .unwrap_or(0.5),
+            ),
+            Some("blend") => BlendMode::AlphaBlend,
+            _ if material.dissolve < 1.0 => BlendMode::AlphaBlend,
+            _ => BlendMode::Opaque,
+        }
+    }
+
+    // The cutoff used by the shaders, 0 never discards anything
+    pub fn alpha_cutoff(self) -> f32 {
+        match self {
+            BlendMode::AlphaTest(cutoff) => cutoff,
+            _ => 0.0,
+        }
+    }
+
+    #[inline]
+    pub fn is_blended(self) -> bool {
+        self == BlendMode::AlphaBlend
+    }
+}
+
+// Illumination model 0 of the mtl format, a constant color without any lighting
+pub fn is_unlit(material: &tobj::Material) -> bool {
+    material.illumination_model == Some(0)
+}
+
 // TODO: This should be its own texture type
+// Textures are shared so render targets can be used as material inputs
 pub struct Material {
-    pub diffuse_texture: TextureData<SimpleTexture>,
-    pub specular_texture: TextureData<SimpleTexture>,
+    pub diffuse_texture: Arc<TextureData<SimpleTexture>>,
+    pub specular_texture: Arc<TextureData<SimpleTexture>>,
+    // emission, dissolve, alpha cutoff and unlit flag of the phong shading
+    pub phong: PhongMaterial,
+    // Meshes with a pbr material are drawn by the pbr pass instead of the phong model pass
+    pub pbr: Option<PbrMaterial>,
+    // Blended meshes are skipped by the opaque passes and drawn by the TransparentPass
+    pub blend_mode: BlendMode,
 }
 
 pub struct Mesh {
@@ -128,6 +186,24 @@ impl Model {
         let mut materials = Vec::with_capacity(obj_materials.len());
 
         for material in obj_materials {
+            let pbr = if PbrMaterial::is_pbr(&material) {
+                Some(PbrMaterial::from_obj_material(
+                    device,
+                    queue,
+                    &material,
+                    current_folder,
+                )?)
+            } else {
+                None
+            };
+            let blend_mode = BlendMode::from_obj_material(&material);
+            let phong = PhongMaterial::from_obj_material(
+                device,
+                queue,
+                &material,
+                current_folder,
+                blend_mode,
+            )?;
             let diffuse_path = material.diffuse_texture;
             let mut specular_path = material.specular_texture;
             //let ambient_path = material.ambient_texture; TODO: Should this be handled?
@@ -140,8 +216,11 @@ impl Model {
                 SimpleTexture::load_texture(&device, queue, current_folder.join(specular_path))?;
 
             materials.push(Material {
-                diffuse_texture,
-                specular_texture,
+                diffuse_texture: Arc::new(diffuse_texture),
+                specular_texture: Arc::new(specular_texture),
+                phong,
+                pbr,
+                blend_mode,
             });
         }
 
@@ -182,7 +261,6 @@ impl Model {
             });
         }
         let instance_buffer_len = INDEX_BUFFER_SIZE as usize / std::mem::size_of::<InstanceData>();
-        println!("INSTANCE BUFFER LEN: {}", instance_buffer_len);
         let buffer_data = vec![InstanceData::default(); instance_buffer_len];
         let instance_buffer = VertexBuffer::allocate_mutable_buffer(device, &buffer_data);
         Ok(Model {
@@ -202,9 +280,19 @@ pub trait DrawModel<'b> {
         instances: Range<u32>,
     );
 
+    fn draw_pbr_mesh_instanced(
+        &mut self,
+        mesh: &'b Mesh,
+        material: &'b PbrMaterial,
+        instance_buffer: &'b MutableVertexData<InstanceData>,
+        instances: Range<u32>,
+    );
+
     fn draw_untextured(&mut self, model: &'b Model, instances: Range<u32>);
 
     fn draw_model_instanced(&mut self, model: &'b Model, instances: Range<u32>);
+
+    fn draw_pbr_model_instanced(&mut self, model: &'b Model, instances: Range<u32>);
 }
 
 impl<'a, 'b> DrawModel<'b> for RenderNodeRunner<'a, 'b> {
@@ -220,12 +308,35 @@ impl<'a, 'b> DrawModel<'b> for RenderNodeRunner<'a, 'b> {
         self.set_index_buffer(mesh.index_buffer.slice(..));
         self.set_texture_data(0, &material.diffuse_texture);
         self.set_texture_data(1, &material.specular_texture);
+        self.set_texture_data(2, &material.phong.textures);
         self.draw_indexed(0..mesh.num_indexes, 0, instances);
     }
 
+    fn draw_pbr_mesh_instanced(
+        &mut self,
+        mesh: &'b Mesh,
+        material: &'b PbrMaterial,
+        instance_buffer: &'b MutableVertexData<InstanceData>,
+        instances: Range<u32>,
+    ) {
+        self.set_vertex_buffer_data(0, &mesh.vertex_buffer);
+        self.set_vertex_buffer_data(1, instance_buffer);
+        self.set_index_buffer(mesh.index_buffer.slice(..));
+        self.set_texture_data(0, &material.textures);
+        self.draw_indexed(0..mesh.num_indexes, 0, instances);
+    }
+
+    // Depth only drawing for the shadow and ssao prepasses. Blended meshes are skipped,
+    // the diffuse texture and phong factors at 0 and 1 are only read for the alpha test
     fn draw_untextured(&mut self, model: &'b Model, instances: Range<u32>) {
         let instance_buffer = &model.instance_buffer;
         for mesh in &model.meshes {
+            let material = &model.materials[mesh.material];
+            if material.blend_mode.is_blended() {
+                continue;
+            }
+            self.set_texture_data(0, &material.diffuse_texture);
+            self.set_texture_data(1, &material.phong.textures);
             self.set_vertex_buffer_data(0, &mesh.vertex_buffer);
             self.set_vertex_buffer_data(1, instance_buffer);
             self.set_index_buffer(mesh.index_buffer.slice(..));
@@ -233,11 +344,28 @@ impl<'a, 'b> DrawModel<'b> for RenderNodeRunner<'a, 'b> {
         }
     }
 
+    // Only draws the meshes with opaque or alpha tested phong materials
     fn draw_model_instanced(&mut self, model: &'b Model, instances: Range<u32>) {
         let instance_buffer = &model.instance_buffer;
         for mesh in &model.meshes {
             let material = &model.materials[mesh.material];
-            self.draw_mesh_instanced(mesh, material, instance_buffer, instances.clone());
+            if material.pbr.is_none() && !material.blend_mode.is_blended() {
+                self.draw_mesh_instanced(mesh, material, instance_buffer, instances.clone());
+            }
+        }
+    }
+
+    // Only draws the meshes with opaque or alpha tested pbr materials
+    fn draw_pbr_model_instanced(&mut self, model: &'b Model, instances: Range<u32>) {
+        let instance_buffer = &model.instance_buffer;
+        for mesh in &model.meshes {
+            let material = &model.materials[mesh.material];
+            if material.blend_mode.is_blended() {
+                continue;
+            }
+            if let Some(pbr) = &material.pbr {
+                self.draw_pbr_mesh_instanced(mesh, pbr, instance_buffer, instances.clone());
+            }
         }
     }
 }
@@ -0,0 +1,147 @@
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use smol_renderer::{TextureData, TextureShaderLayout};
+use wgpu::{
+    BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, Binding, BindingResource,
+    BindingType, Buffer, BufferUsage, Device, Extent3d, ShaderStage, TextureDimension,
+    TextureFormat, TextureUsage,
+};
+
+use super::clustered_lights::as_bytes;
+
+// Per instance weights of the morph targets of a SkinnedModel, instances without
+// one use the default weights of the model. Weights past the targets of the model are ignored
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MorphWeights {
+    pub weights: Vec<f32>,
+}
+
+// Offsets of a single vertex in a single target, padded to the storage buffer alignment
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MorphDelta {
+    pub position: [f32; 4],
+    pub normal: [f32; 4],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone)]
+struct MorphUniforms {
+    vertex_count: u32,
+    target_count: u32,
+    // index of the first weight of the mesh in the weights of its model
+    weight_offset: u32,
+    _pad: u32,
+}
+
+// Target count and deltas of a mesh in a single bind group
+pub struct MorphTextures;
+
+impl TextureShaderLayout for MorphTextures {
+    fn get_layout(device: &Device) -> &'static BindGroupLayout {
+        static LAYOUT: OnceCell<BindGroupLayout> = OnceCell::new();
+        LAYOUT.get_or_init(|| {
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                bindings: &[
+                    BindGroupLayoutEntry::new(
+                        0,
+                        ShaderStage::VERTEX,
+                        BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                        },
+                    ),
+                    BindGroupLayoutEntry::new(
+                        1,
+                        ShaderStage::VERTEX,
+                        BindingType::StorageBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                            readonly: true,
+                        },
+                    ),
+                ],
+                label: Some("Morph textures layout"),
+            })
+        })
+    }
+}
+
+pub struct MorphTargets {
+    pub textures: TextureData<MorphTextures>,
+    pub target_count: u32,
+    _uniform_buffer: Buffer,
+    _delta_buffer: Buffer,
+}
+
+impl MorphTargets {
+    // Deltas are ordered by target and then by vertex
+    pub fn new(
+        device: &Device,
+        vertex_count: u32,
+        weight_offset: u32,
+        deltas: &[MorphDelta],
+    ) -> Self {
+        let target_count = if vertex_count == 0 {
+            0
+        } else {
+            deltas.len() as u32 / vertex_count
+        };
+        let uniforms = MorphUniforms {
+            vertex_count,
+            target_count,
+            weight_offset,
+            _pad: 0,
+        };
+        let uniform_buffer = device.create_buffer_with_data(
+            as_bytes(std::slice::from_ref(&uniforms)),
+            BufferUsage::UNIFORM,
+        );
+        // bound buffers can't be empty
+        let deltas = if deltas.is_empty() {
+            &[MorphDelta::default()][..]
+        } else {
+            deltas
+        };
+        let delta_buffer = device.create_buffer_with_data(as_bytes(deltas), BufferUsage::STORAGE);
+        // The texture data wrapper needs a texture even though everything
+        // in this bind group is a buffer, it is never bound
+        let placeholder = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Morph placeholder"),
+            size: Extent3d {
+                width: 1,
+                height: 1,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R8Unorm,
+            usage: TextureUsage::SAMPLED,
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Morph placeholder sampler"),
+            ..Default::default()
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: MorphTextures::get_layout(device),
+            bindings: &[
+                Binding {
+                    binding: 0,
+                    resource: BindingResource::Buffer(uniform_buffer.slice(..)),
+                },
+                Binding {
+                    binding: 1,
+                    resource: BindingResource::Buffer(delta_buffer.slice(..)),
+                },
+            ],
+            label: Some("Morph textures bindgroup"),
+        });
+        MorphTargets {
+            textures: TextureData::new(bind_group, placeholder, Vec::new(), sampler),
+            target_count,
+            _uniform_buffer: uniform_buffer,
+            _delta_buffer: delta_buffer,
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use std::{rc::Rc, sync::Arc};
+
+use anyhow::Result;
+use nalgebra::{Matrix4, Vector3};
+use smol_renderer::{
+    FragmentShader, GpuData, RenderNode, TextureData, UniformBindGroup, VertexShader,
+};
+use wgpu::{CommandEncoder, Device, RenderPassDescriptor, ShaderStage, TextureFormat};
+
+use crate::{
+    camera::Camera,
+    graphics::{
+        clustered_lights::{ClusterTextures, ClusteredLights},
+        environment_map::{EnvironmentMap, EnvironmentTextures},
+        g_buffer::GBufferTextures,
+        hdr_texture::HdrTexture,
+        shader_include::expand_includes,
+        shadow_texture::{ShadowMaps, ShadowTexture},
+    },
+};
+
+#[repr(C)]
+#[derive(GpuData)]
+pub struct DeferredUniforms {
+    view_projection: Matrix4<f32>,
+    // reconstructs world positions from the g-buffer depth
+    inverse_view_projection: Matrix4<f32>,
+    view_pos: Vector3<f32>,
+}
+
+impl From<&Camera> for DeferredUniforms {
+    fn from(camera: &Camera) -> Self {
+        let view_projection = camera.get_projection_matrix() * camera.get_view_matrix();
+        DeferredUniforms {
+            view_projection,
+            inverse_view_projection: view_projection
+                .try_inverse()
+                .unwrap_or_else(Matrix4::identity),
+            view_pos: camera.get_vec_position(),
+        }
+    }
+}
+
+// Lights the g-buffer written by the GeometryPass. Ambient, directional and spot
+// lights are applied in a single fullscreen pass while every point light is
+// additively blended over the pixels covered by a cube around its range
+pub struct DeferredLightingPass {
+    shadow_maps: Rc<ShadowMaps>,
+    environment_map: Rc<EnvironmentMap>,
+    clustered_lights: Rc<ClusteredLights>,
+    uniforms: Arc<UniformBindGroup>,
+    lighting_node: RenderNode,
+    light_volume_node: RenderNode,
+}
+
+impl DeferredLightingPass {
+    pub fn new(
+        device: &Device,
+        shadow_maps: Rc<ShadowMaps>,
+        environment_map: Rc<EnvironmentMap>,
+        clustered_lights: Rc<ClusteredLights>,
+        color_format: TextureFormat,
+    ) -> Result<Self> {
+        let uniforms = Arc::new(
+            UniformBindGroup::with_name("Deferred lighting uniforms")
+                .add_binding::<DeferredUniforms>(ShaderStage::VERTEX | ShaderStage::FRAGMENT)?
+                .build(device),
+        );
+        let lighting_node = RenderNode::builder()
+            .set_vertex_shader(VertexShader::new(
+                device,
+                "src/shader_files/vs_fullscreen.shader",
+            )?)
+            .set_fragment_shader(FragmentShader::new(
+                device,
+                &expand_includes("src/shader_files/fs_deferred_lighting.shader")?,
+            )?)
+            // albedo, normal, material, emissive and depth
+            .add_texture::<GBufferTextures>()
+            // point, directional and spot light shadow maps
+            .add_texture::<ShadowTexture>()
+            // irradiance, prefiltered specular and brdf lut
+            .add_texture::<EnvironmentTextures>()
+            // light uniforms, point lights and their clusters
+            .add_texture::<ClusterTextures>()
+            // screen space ambient occlusion
+            .add_texture::<HdrTexture>()
+            .add_default_color_state_desc(color_format)
+            .set_default_rasterization_state()
+            .add_shared_uniform_bind_group(uniforms.clone())
+            .build(device)?;
+        let light_volume_node = RenderNode::builder()
+            .set_vertex_shader(VertexShader::new(
+                device,
+                "src/shader_files/vs_light_volume.shader",
+            )?)
+            .set_fragment_shader(FragmentShader::new(
+                device,
+                &expand_includes("src/shader_files/fs_light_volume.shader")?,
+            )?)
+            .add_texture::<GBufferTextures>()
+            .add_texture::<ShadowTexture>()
+            .add_texture::<EnvironmentTextures>()
+            .add_texture::<ClusterTextures>()
+            // every light volume adds its contribution
+            .add_color_state_desc(wgpu::ColorStateDescriptor {
+                format: color_format,
+                color_blend: wgpu::BlendDescriptor {
+                    src_factor: wgpu::BlendFactor::One,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                write_mask: wgpu::ColorWrite::ALL,
+            })
+            .set_rasterization_state(wgpu::RasterizationStateDescriptor {
+                front_face: wgpu::FrontFace::Ccw,
+                // only the back faces are drawn so the camera can be inside the volume
+                cull_mode: wgpu::CullMode::Front,
+                depth_bias: 0,
+                depth_bias_slope_scale: 0.0,
+                depth_bias_clamp: 0.0,
+            })
+            .add_shared_uniform_bind_group(uniforms.clone())
+            .build(device)?;
+
+        Ok(Self {
+            shadow_maps,
+            environment_map,
+            clustered_lights,
+            uniforms,
+            lighting_node,
+            light_volume_node,
+        })
+    }
+
+    // Recorded in the encoder like the camera uniforms so each camera gets its own
+    pub fn update_uniforms(&self, device: &Device, camera: &Camera, encoder: &mut CommandEncoder) {
+        self.uniforms
+            .update_buffer_data(device, encoder, &DeferredUniforms::from(camera))
+            .unwrap();
+    }
+
+    // The background is left untouched, so the skybox should already be in the target
+    pub fn render_lighting<'encoder>(
+        &'encoder self,
+        g_buffer: &'encoder TextureData<GBufferTextures>,
+        ambient_occlusion: &'encoder TextureData<HdrTexture>,
+        encoder: &mut CommandEncoder,
+        render_pass_descriptor: RenderPassDescriptor,
+    ) {
+        let mut runner = self.lighting_node.runner(encoder, render_pass_descriptor);
+        runner.set_texture_data(0, g_buffer);
+        runner.set_texture_data(1, &self.shadow_maps.textures);
+        runner.set_texture_data(2, &self.environment_map.textures);
+        runner.set_texture_data(3, &self.clustered_lights.textures);
+        runner.set_texture_data(4, ambient_occlusion);
+        // the fullscreen triangle is hardcoded into the vertex shader
+        runner.draw(0..3, 0..1);
+    }
+
+    pub fn render_light_volumes<'encoder>(
+        &'encoder self,
+        g_buffer: &'encoder TextureData<GBufferTextures>,
+        point_light_count: u32,
+        encoder: &mut CommandEncoder,
+        render_pass_descriptor: RenderPassDescriptor,
+    ) {
+        if point_light_count == 0 {
+            return;
+        }
+        let mut runner = self
+            .light_volume_node
+            .runner(encoder, render_pass_descriptor);
+        runner.set_texture_data(0, g_buffer);
+        runner.set_texture_data(1, &self.shadow_maps.textures);
+        runner.set_texture_data(2, &self.environment_map.textures);
+        runner.set_texture_data(3, &self.clustered_lights.textures);
+        // the cube is hardcoded into the vertex shader, one instance per light
+        runner.draw(0..36, 0..point_light_count);
+    }
+}
@@ -0,0 +1,278 @@
+use std::{collections::HashMap, rc::Rc, sync::Arc};
+
+use anyhow::Result;
+use legion::prelude::*;
+use smol_renderer::{FragmentShader, RenderNode, SimpleTexture, UniformBindGroup, VertexShader};
+use wgpu::{
+    CommandEncoder, Device, LoadOp, Operations, RenderPassColorAttachmentDescriptor,
+    RenderPassDepthStencilAttachmentDescriptor, RenderPassDescriptor, TextureView,
+};
+
+use crate::{
+    animation::Animator,
+    assets::{Assets, Handle},
+    components::GlobalTransform,
+    graphics::{
+        g_buffer::{GBuffer, ALBEDO_FORMAT, EMISSIVE_FORMAT, MATERIAL_FORMAT, NORMAL_FORMAT},
+        model::{DrawModel, InstanceData, MeshVertex, Model},
+        morph_targets::MorphTextures,
+        pbr_material::PbrTextures,
+        phong_material::PhongTextures,
+        skinned_model::{DrawSkinnedModel, SkinnedInstanceData, SkinnedModel, SkinnedVertex},
+        skinning::{JointPalette, SkinningTextures},
+    },
+};
+
+fn color_attachment(
+    attachment: &TextureView,
+    load: LoadOp<wgpu::Color>,
+) -> RenderPassColorAttachmentDescriptor {
+    RenderPassColorAttachmentDescriptor {
+        attachment,
+        resolve_target: None,
+        ops: Operations { load, store: true },
+    }
+}
+
+// Writes the surface attributes of the phong, pbr and skinned meshes into the g-buffer for
+// the deferred lighting pass, the instance buffers are updated by the ModelPass and SkinnedModelPass
+pub struct GeometryPass {
+    phong_node: RenderNode,
+    pbr_node: RenderNode,
+    skinned_node: RenderNode,
+    joint_palette: Rc<JointPalette>,
+}
+
+impl GeometryPass {
+    pub fn new(
+        device: &Device,
+        global_uniforms: Vec<Arc<UniformBindGroup>>,
+        joint_palette: Rc<JointPalette>,
+    ) -> Result<Self> {
+        let phong_node = RenderNode::builder()
+            .add_vertex_buffer::<MeshVertex>()
+            .add_vertex_buffer::<InstanceData>()
+            .set_vertex_shader(VertexShader::new(
+                device,
+                "src/shader_files/vs_gbuffer.shader",
+            )?)
+            .set_fragment_shader(FragmentShader::new(
+                device,
+                "src/shader_files/fs_gbuffer.shader",
+            )?)
+            // diffuse
+            .add_texture::<SimpleTexture>()
+            // specular
+            .add_texture::<SimpleTexture>()
+            // emissive map and factors
+            .add_texture::<PhongTextures>()
+            .add_default_color_state_desc(ALBEDO_FORMAT)
+            .add_default_color_state_desc(NORMAL_FORMAT)
+            .add_default_color_state_desc(MATERIAL_FORMAT)
+            .add_default_color_state_desc(EMISSIVE_FORMAT)
+            .set_default_depth_stencil_state()
+            .set_default_rasterization_state()
+            // camera
+            .add_shared_uniform_bind_group(global_uniforms[0].clone())
+            .build(&device)?;
+        let pbr_node = RenderNode::builder()
+            .add_vertex_buffer::<MeshVertex>()
+            .add_vertex_buffer::<InstanceData>()
+            .set_vertex_shader(VertexShader::new(
+                device,
+                "src/shader_files/vs_gbuffer_pbr.shader",
+            )?)
+            .set_fragment_shader(FragmentShader::new(
+                device,
+                "src/shader_files/fs_gbuffer_pbr.shader",
+            )?)
+            // material maps and factors
+            .add_texture::<PbrTextures>()
+            .add_default_color_state_desc(ALBEDO_FORMAT)
+            .add_default_color_state_desc(NORMAL_FORMAT)
+            .add_default_color_state_desc(MATERIAL_FORMAT)
+            .add_default_color_state_desc(EMISSIVE_FORMAT)
+            .set_default_depth_stencil_state()
+            .set_default_rasterization_state()
+            // camera
+            .add_shared_uniform_bind_group(global_uniforms[0].clone())
+            .build(&device)?;
+        let skinned_node = RenderNode::builder()
+            .add_vertex_buffer::<SkinnedVertex>()
+            .add_vertex_buffer::<SkinnedInstanceData>()
+            .set_vertex_shader(VertexShader::new(
+                device,
+                "src/shader_files/vs_skinned_gbuffer.shader",
+            )?)
+            .set_fragment_shader(FragmentShader::new(
+                device,
+                "src/shader_files/fs_gbuffer_pbr.shader",
+            )?)
+            // material maps and factors
+            .add_texture::<PbrTextures>()
+            // joint matrices and morph weights of every instance
+            .add_texture::<SkinningTextures>()
+            // morph target deltas of the mesh
+            .add_texture::<MorphTextures>()
+            .add_default_color_state_desc(ALBEDO_FORMAT)
+            .add_default_color_state_desc(NORMAL_FORMAT)
+            .add_default_color_state_desc(MATERIAL_FORMAT)
+            .add_default_color_state_desc(EMISSIVE_FORMAT)
+            .set_default_depth_stencil_state()
+            .set_default_rasterization_state()
+            // camera
+            .add_shared_uniform_bind_group(global_uniforms[0].clone())
+            .build(&device)?;
+
+        Ok(Self {
+            phong_node,
+            pbr_node,
+            skinned_node,
+            joint_palette,
+        })
+    }
+
+    fn render_skinned(
+        &self,
+        resources: &Resources,
+        world: &World,
+        encoder: &mut CommandEncoder,
+        g_buffer: &GBuffer,
+    ) {
+        let asset_storage = resources
+            .get::<Assets<SkinnedModel>>()
+            .expect("Asset not registerd");
+        let [albedo, normal, material, emissive] = g_buffer.color_views();
+        let mut runner = self.skinned_node.runner(
+            encoder,
+            RenderPassDescriptor {
+                color_attachments: &[
+                    color_attachment(albedo, LoadOp::Load),
+                    color_attachment(normal, LoadOp::Load),
+                    color_attachment(material, LoadOp::Load),
+                    color_attachment(emissive, LoadOp::Load),
+                ],
+                depth_stencil_attachment: Some(RenderPassDepthStencilAttachmentDescriptor {
+                    attachment: g_buffer.depth_view(),
+                    depth_ops: Some(Operations {
+                        load: LoadOp::Load,
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            },
+        );
+        runner.set_texture_data(1, &self.joint_palette.textures);
+        let mut offset_map = HashMap::new();
+        let query = <(
+            Read<GlobalTransform>,
+            Read<Animator>,
+            Tagged<Handle<SkinnedModel>>,
+        )>::query();
+        for chunk in query.par_iter_chunks(world) {
+            // This is guaranteed to be the same for each chunk
+            let model = chunk.tag::<Handle<SkinnedModel>>().unwrap();
+            let offset = *offset_map.get(model).unwrap_or(&0);
+            let transforms = chunk.components::<GlobalTransform>().unwrap();
+            offset_map.insert(model.clone(), offset + transforms.len());
+            if let Some(model) = asset_storage.get(model) {
+                runner.draw_skinned_model_instanced(
+                    model,
+                    offset as u32..(offset + transforms.len()) as u32,
+                    2,
+                );
+            }
+        }
+    }
+
+    fn render_node<'encoder>(
+        render_node: &'encoder RenderNode,
+        resources: &'encoder Resources,
+        world: &World,
+        encoder: &mut CommandEncoder,
+        g_buffer: &GBuffer,
+        clear: bool,
+        pbr: bool,
+    ) {
+        let asset_storage = resources
+            .get::<Assets<Model>>()
+            .expect("Asset not registerd");
+        let color_load = if clear {
+            LoadOp::Clear(wgpu::Color::TRANSPARENT)
+        } else {
+            LoadOp::Load
+        };
+        let depth_load = if clear {
+            LoadOp::Clear(1.0)
+        } else {
+            LoadOp::Load
+        };
+        let [albedo, normal, material, emissive] = g_buffer.color_views();
+        let mut runner = render_node.runner(
+            encoder,
+            RenderPassDescriptor {
+                color_attachments: &[
+                    color_attachment(albedo, color_load),
+                    color_attachment(normal, color_load),
+                    color_attachment(material, color_load),
+                    color_attachment(emissive, color_load),
+                ],
+                depth_stencil_attachment: Some(RenderPassDepthStencilAttachmentDescriptor {
+                    attachment: g_buffer.depth_view(),
+                    depth_ops: Some(Operations {
+                        load: depth_load,
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            },
+        );
+        let mut offset_map = HashMap::new();
+        let query = <(Read<GlobalTransform>, Tagged<Handle<Model>>)>::query();
+        for chunk in query.par_iter_chunks(world) {
+            // This is guaranteed to be the same for each chunk
+            let model = chunk.tag::<Handle<Model>>().unwrap();
+            let offset = *offset_map.get(model).unwrap_or(&0);
+            let transforms = chunk.components::<GlobalTransform>().unwrap();
+            offset_map.insert(model.clone(), offset + transforms.len());
+            let model = match asset_storage.get(model) {
+                Some(model) => model,
+                None => continue,
+            };
+            let instances = offset as u32..(offset + transforms.len()) as u32;
+            if pbr {
+                runner.draw_pbr_model_instanced(model, instances);
+            } else {
+                runner.draw_model_instanced(model, instances);
+            }
+        }
+    }
+
+    pub fn render(
+        &self,
+        resources: &Resources,
+        world: &World,
+        encoder: &mut CommandEncoder,
+        g_buffer: &GBuffer,
+    ) {
+        Self::render_node(
+            &self.phong_node,
+            resources,
+            world,
+            encoder,
+            g_buffer,
+            true,
+            false,
+        );
+        Self::render_node(
+            &self.pbr_node,
+            resources,
+            world,
+            encoder,
+            g_buffer,
+            false,
+            true,
+        );
+        self.render_skinned(resources, world, encoder, g_buffer);
+    }
+}
@@ -2,10 +2,18 @@ use legion::prelude::*;
 use wgpu::Device;
 use wgpu::{CommandEncoder, RenderPassDescriptor};
 
-pub mod light_object_pass;
+pub mod deferred_lighting_pass;
+pub mod geometry_pass;
 pub mod model_pass;
+pub mod pbr_model_pass;
+pub mod post_process_pass;
+pub mod projected_shadow_pass;
 pub mod shadow_pass;
+pub mod skinned_model_pass;
 pub mod skybox_pass;
+pub mod ssao_pass;
+pub mod tone_mapping_pass;
+pub mod transparent_pass;
 
 pub trait Pass {
     fn update_uniform_data(
@@ -2,49 +2,42 @@ use std::{collections::HashMap, rc::Rc, sync::Arc};
 
 use anyhow::Result;
 use legion::prelude::*;
-use nalgebra::Vector3;
 use smol_renderer::{
-    FragmentShader, GpuData, RenderNode, SimpleTexture, TextureData, UniformBindGroup, VertexShader,
+    FragmentShader, RenderNode, SimpleTexture, TextureData, UniformBindGroup, VertexShader,
 };
-use wgpu::{CommandEncoder, Device, RenderPassDescriptor, ShaderStage, TextureFormat};
+use wgpu::{CommandEncoder, Device, RenderPassDescriptor, TextureFormat};
 
 use crate::{
-    assets::Assets,
-    assets::Handle,
-    graphics::{model::Model, Pass},
-};
-use crate::{
-    components::Transform,
-    graphics::PointLight,
-    graphics::{model::MeshVertex, shadow_texture::ShadowTexture},
+    assets::{Assets, Handle},
+    components::GlobalTransform,
     graphics::{
-        model::{DrawModel, InstanceData},
-        point_light::PointLightRaw,
+        clustered_lights::{ClusterTextures, ClusteredLights},
+        environment_map::{EnvironmentMap, EnvironmentTextures},
+        hdr_texture::HdrTexture,
+        model::{DrawModel, InstanceData, MeshVertex, Model},
+        phong_material::PhongTextures,
+        shader_include::expand_includes,
+        shadow_texture::{ShadowMaps, ShadowTexture},
     },
 };
 
 pub struct ModelPass {
     //todo: maybe solve in another way instead of Rc (weak ptr)?
-    shadow_texture: Rc<TextureData<ShadowTexture>>,
+    shadow_maps: Rc<ShadowMaps>,
+    environment_map: Rc<EnvironmentMap>,
+    clustered_lights: Rc<ClusteredLights>,
     render_node: RenderNode,
 }
 
-pub const MAX_POINT_LIGHTS: u32 = 16;
-
-#[repr(C)]
-#[derive(Debug, GpuData, Clone)]
-pub struct PointLightsUniforms {
-    lights_used: i32,
-    _pad: [i32; 3],
-    lights: [PointLightRaw; MAX_POINT_LIGHTS as usize],
-}
-
 impl ModelPass {
     pub fn new(
         device: &Device,
         global_uniforms: Vec<Arc<UniformBindGroup>>,
-        shadow_texture: Rc<TextureData<ShadowTexture>>,
+        shadow_maps: Rc<ShadowMaps>,
+        environment_map: Rc<EnvironmentMap>,
+        clustered_lights: Rc<ClusteredLights>,
         color_format: TextureFormat,
+        sample_count: u32,
     ) -> Result<Self> {
         let render_node = RenderNode::builder()
             .add_vertex_buffer::<MeshVertex>()
@@ -55,91 +48,75 @@ impl ModelPass {
             )?)
             .set_fragment_shader(FragmentShader::new(
                 device,
-                "src/shader_files/fs_model.shader",
+                &expand_includes("src/shader_files/fs_model.shader")?,
             )?)
             // diffuse
             .add_texture::<SimpleTexture>()
             // specular
             .add_texture::<SimpleTexture>()
-            // shadow texture
+            // emissive map and factors
+            .add_texture::<PhongTextures>()
+            // point, directional and spot light shadow maps
             .add_texture::<ShadowTexture>()
+            // irradiance, prefiltered specular and brdf lut
+            .add_texture::<EnvironmentTextures>()
+            // light uniforms, point lights and their clusters
+            .add_texture::<ClusterTextures>()
+            // screen space ambient occlusion
+            .add_texture::<HdrTexture>()
             .add_default_color_state_desc(color_format)
             .set_default_depth_stencil_state()
             .set_default_rasterization_state()
-            .add_local_uniform_bind_group(
-                UniformBindGroup::with_name("Point light uniform")
-                    .add_binding::<PointLightsUniforms>(ShaderStage::FRAGMENT)?
-                    .build(device),
-            )
+            .set_sample_count(sample_count)
+            // camera
             .add_shared_uniform_bind_group(global_uniforms[0].clone())
             .build(&device)?;
 
         Ok(Self {
             render_node,
-            shadow_texture,
+            shadow_maps,
+            environment_map,
+            clustered_lights,
         })
     }
 
-    pub fn update_lights(&self, device: &Device, world: &World, encoder: &mut CommandEncoder) {
-        let query = <(Read<PointLight>, Read<Transform>)>::query();
-        // TODO: only runs once unecessary loop
-        for chunk in query.par_iter_chunks(world) {
-            let lights = chunk.components::<PointLight>().unwrap();
-            let positions = chunk.components::<Transform>().unwrap();
-            let mut uniform_data =
-                [PointLightRaw::from((&PointLight::default(), Vector3::new(0.0, 0.0, 0.0)));
-                    MAX_POINT_LIGHTS as usize];
-            let mut lights_used = 0;
-            lights
-                .iter()
-                .zip(positions.iter())
-                .enumerate()
-                .for_each(|(i, (light, pos))| {
-                    uniform_data[i] = PointLightRaw::from((light, pos.translation()));
-                    lights_used += 1;
-                });
-            let data = &PointLightsUniforms {
-                lights_used,
-                _pad: [0; 3],
-                lights: uniform_data,
-            };
-            self.render_node.update(device, encoder, 0, data).unwrap();
-        }
-    }
-}
-
-impl Pass for ModelPass {
-    fn update_uniform_data(
+    pub fn update_uniform_data(
         &self,
         world: &World,
         resources: &Resources,
         device: &Device,
         encoder: &mut CommandEncoder,
     ) {
-        self.update_lights(device, world, encoder);
         let asset_storage = resources
             .get::<Assets<Model>>()
             .expect("Asset not registerd");
-        let mut offsets = HashMap::new();
-        let query = <(Read<Transform>, Tagged<Handle<Model>>)>::query();
+        // a handle can be spread over several chunks so the instances are gathered
+        // in chunk order first and every instance buffer is written once
+        let mut instances: HashMap<Handle<Model>, Vec<InstanceData>> = HashMap::new();
+        let query = <(Read<GlobalTransform>, Tagged<Handle<Model>>)>::query();
         for chunk in query.par_iter_chunks(world) {
             let model = chunk.tag::<Handle<Model>>().unwrap();
-            let transforms = chunk.components::<Transform>().unwrap();
-            let model_matrices = transforms
-                .iter()
-                .map(|trans| InstanceData::new(trans.get_model_matrix()))
-                .collect::<Vec<InstanceData>>();
-            let offset = *offsets.get(model).unwrap_or(&0);
-            let instance_buffer = &asset_storage.get(model).unwrap().instance_buffer;
-            instance_buffer.update(device, encoder, &model_matrices);
-            offsets.insert(model.clone(), offset + model_matrices.len() as u64);
+            let transforms = chunk.components::<GlobalTransform>().unwrap();
+            instances.entry(model.clone()).or_default().extend(
+                transforms
+                    .iter()
+                    .map(|trans| InstanceData::new(trans.get_model_matrix())),
+            );
+        }
+        for (model, instance_data) in instances {
+            if let Some(model) = asset_storage.get(&model) {
+                model
+                    .instance_buffer
+                    .update(device, encoder, &instance_data);
+            }
         }
     }
 
-    fn render<'encoder>(
+    pub fn render<'encoder>(
         &'encoder self,
         resources: &'encoder Resources,
         world: &World,
+        ambient_occlusion: &'encoder TextureData<HdrTexture>,
         encoder: &mut CommandEncoder,
         render_pass_descriptor: RenderPassDescriptor,
     ) {
@@ -147,18 +124,22 @@ impl Pass for ModelPass {
             .get::<Assets<Model>>()
             .expect("Asset not registerd");
         let mut runner = self.render_node.runner(encoder, render_pass_descriptor);
-        runner.set_texture_data(2, &self.shadow_texture);
+        runner.set_texture_data(3, &self.shadow_maps.textures);
+        runner.set_texture_data(4, &self.environment_map.textures);
+        runner.set_texture_data(5, &self.clustered_lights.textures);
+        runner.set_texture_data(6, ambient_occlusion);
         let mut offset_map = HashMap::new();
-        let query =
-            <(Read<Transform>, Tagged<Handle<Model>>)>::query().filter(!component::<PointLight>());
+        let query = <(Read<GlobalTransform>, Tagged<Handle<Model>>)>::query();
         for chunk in query.par_iter_chunks(world) {
             // This is guaranteed to be the same for each chunk
             let model = chunk.tag::<Handle<Model>>().unwrap();
             let offset = *offset_map.get(model).unwrap_or(&0);
-            let transforms = chunk.components::<Transform>().unwrap();
+            let transforms = chunk.components::<GlobalTransform>().unwrap();
             offset_map.insert(model.clone(), offset + transforms.len());
-            let model = asset_storage.get(model).unwrap();
-            runner.draw_model_instanced(model, offset as u32..(offset + transforms.len()) as u32);
+            if let Some(model) = asset_storage.get(model) {
+                runner
+                    .draw_model_instanced(model, offset as u32..(offset + transforms.len()) as u32);
+            }
         }
     }
 }
@@ -0,0 +1,110 @@
+use std::{collections::HashMap, rc::Rc, sync::Arc};
+
+use anyhow::Result;
+use legion::prelude::*;
+use smol_renderer::{FragmentShader, RenderNode, TextureData, UniformBindGroup, VertexShader};
+use wgpu::{CommandEncoder, Device, RenderPassDescriptor, TextureFormat};
+
+use crate::{
+    assets::{Assets, Handle},
+    components::GlobalTransform,
+    graphics::{
+        clustered_lights::{ClusterTextures, ClusteredLights},
+        environment_map::{EnvironmentMap, EnvironmentTextures},
+        hdr_texture::HdrTexture,
+        model::{DrawModel, InstanceData, MeshVertex, Model},
+        pbr_material::PbrTextures,
+        shader_include::expand_includes,
+        shadow_texture::{ShadowMaps, ShadowTexture},
+    },
+};
+
+// Cook-Torrance shading for meshes with pbr materials,
+// runs alongside the phong ModelPass which draws the remaining meshes
+pub struct PbrModelPass {
+    shadow_maps: Rc<ShadowMaps>,
+    environment_map: Rc<EnvironmentMap>,
+    clustered_lights: Rc<ClusteredLights>,
+    render_node: RenderNode,
+}
+
+impl PbrModelPass {
+    pub fn new(
+        device: &Device,
+        global_uniforms: Vec<Arc<UniformBindGroup>>,
+        shadow_maps: Rc<ShadowMaps>,
+        environment_map: Rc<EnvironmentMap>,
+        clustered_lights: Rc<ClusteredLights>,
+        color_format: TextureFormat,
+        sample_count: u32,
+    ) -> Result<Self> {
+        let render_node = RenderNode::builder()
+            .add_vertex_buffer::<MeshVertex>()
+            .add_vertex_buffer::<InstanceData>()
+            .set_vertex_shader(VertexShader::new(device, "src/shader_files/vs_pbr.shader")?)
+            .set_fragment_shader(FragmentShader::new(
+                device,
+                &expand_includes("src/shader_files/fs_pbr.shader")?,
+            )?)
+            // material maps and factors
+            .add_texture::<PbrTextures>()
+            // point, directional and spot light shadow maps
+            .add_texture::<ShadowTexture>()
+            // irradiance, prefiltered specular and brdf lut
+            .add_texture::<EnvironmentTextures>()
+            // light uniforms, point lights and their clusters
+            .add_texture::<ClusterTextures>()
+            // screen space ambient occlusion
+            .add_texture::<HdrTexture>()
+            .add_default_color_state_desc(color_format)
+            .set_default_depth_stencil_state()
+            .set_default_rasterization_state()
+            .set_sample_count(sample_count)
+            // camera
+            .add_shared_uniform_bind_group(global_uniforms[0].clone())
+            .build(&device)?;
+
+        Ok(Self {
+            render_node,
+            shadow_maps,
+            environment_map,
+            clustered_lights,
+        })
+    }
+}
+
+impl PbrModelPass {
+    // The instance buffers are shared with and updated by the ModelPass
+    pub fn render<'encoder>(
+        &'encoder self,
+        resources: &'encoder Resources,
+        world: &World,
+        ambient_occlusion: &'encoder TextureData<HdrTexture>,
+        encoder: &mut CommandEncoder,
+        render_pass_descriptor: RenderPassDescriptor,
+    ) {
+        let asset_storage = resources
+            .get::<Assets<Model>>()
+            .expect("Asset not registerd");
+        let mut runner = self.render_node.runner(encoder, render_pass_descriptor);
+        runner.set_texture_data(1, &self.shadow_maps.textures);
+        runner.set_texture_data(2, &self.environment_map.textures);
+        runner.set_texture_data(3, &self.clustered_lights.textures);
+        runner.set_texture_data(4, ambient_occlusion);
+        let mut offset_map = HashMap::new();
+        let query = <(Read<GlobalTransform>, Tagged<Handle<Model>>)>::query();
+        for chunk in query.par_iter_chunks(world) {
+            // This is guaranteed to be the same for each chunk
+            let model = chunk.tag::<Handle<Model>>().unwrap();
+            let offset = *offset_map.get(model).unwrap_or(&0);
+            let transforms = chunk.components::<GlobalTransform>().unwrap();
+            offset_map.insert(model.clone(), offset + transforms.len());
+            if let Some(model) = asset_storage.get(model) {
+                runner.draw_pbr_model_instanced(
+                    model,
+                    offset as u32..(offset + transforms.len()) as u32,
+                );
+            }
+        }
+    }
+}
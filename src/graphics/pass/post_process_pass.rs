@@ -0,0 +1,487 @@
+use anyhow::Result;
+use legion::prelude::*;
+use smol_renderer::{
+    FragmentShader, GpuData, LoadableTexture, RenderNode, TextureData, UniformBindGroup,
+    VertexShader,
+};
+use std::path::PathBuf;
+use wgpu::{
+    CommandEncoder, Device, LoadOp, Operations, Queue, RenderPassColorAttachmentDescriptor,
+    RenderPassDescriptor, ShaderStage, TextureFormat, TextureView,
+};
+
+use crate::graphics::{
+    hdr_texture::{HdrTexture, SampledTarget, SceneTargets, HDR_FORMAT},
+    lut_texture::LutTexture,
+};
+
+// Effects applied after tone mapping, bloom is applied before
+// since it needs the hdr values
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PostEffect {
+    ColorGrading,
+    Vignette,
+    Fxaa,
+}
+
+// The order the post effects are applied in, effects missing from
+// the list are never applied even if they are enabled
+pub struct PostProcessStack {
+    pub order: Vec<PostEffect>,
+}
+
+impl Default for PostProcessStack {
+    fn default() -> Self {
+        PostProcessStack {
+            order: vec![
+                PostEffect::ColorGrading,
+                PostEffect::Vignette,
+                PostEffect::Fxaa,
+            ],
+        }
+    }
+}
+
+pub struct Bloom {
+    pub enabled: bool,
+    // brightness above which pixels start to bloom
+    pub threshold: f32,
+    // softens the cutoff around the threshold
+    pub knee: f32,
+    pub intensity: f32,
+    pub blur_passes: u32,
+}
+
+impl Default for Bloom {
+    fn default() -> Self {
+        Bloom {
+            enabled: true,
+            threshold: 1.0,
+            knee: 0.5,
+            intensity: 0.6,
+            blur_passes: 4,
+        }
+    }
+}
+
+pub struct Fxaa {
+    pub enabled: bool,
+    pub span_max: f32,
+    pub reduce_min: f32,
+    pub reduce_mul: f32,
+}
+
+impl Default for Fxaa {
+    fn default() -> Self {
+        Fxaa {
+            enabled: true,
+            span_max: 8.0,
+            reduce_min: 1.0 / 128.0,
+            reduce_mul: 1.0 / 8.0,
+        }
+    }
+}
+
+pub struct Vignette {
+    pub enabled: bool,
+    pub intensity: f32,
+    // distance from the center (0.5 is the screen edge) where the darkening starts
+    pub radius: f32,
+    pub smoothness: f32,
+}
+
+impl Default for Vignette {
+    fn default() -> Self {
+        Vignette {
+            enabled: false,
+            intensity: 0.8,
+            radius: 0.75,
+            smoothness: 0.45,
+        }
+    }
+}
+
+pub struct ColorGrading {
+    pub enabled: bool,
+    // lut stored as a horizontal strip of slices, the identity lut is used if None
+    pub lut: Option<PathBuf>,
+    // blend factor between the ungraded and graded color
+    pub contribution: f32,
+}
+
+impl Default for ColorGrading {
+    fn default() -> Self {
+        ColorGrading {
+            enabled: false,
+            lut: None,
+            contribution: 1.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, GpuData)]
+struct BrightPassUniforms {
+    threshold: f32,
+    knee: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, GpuData)]
+struct BlurUniforms {
+    direction: [f32; 2],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, GpuData)]
+struct FxaaUniforms {
+    span_max: f32,
+    reduce_min: f32,
+    reduce_mul: f32,
+    _pad: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, GpuData)]
+struct VignetteUniforms {
+    intensity: f32,
+    radius: f32,
+    smoothness: f32,
+    _pad: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, GpuData)]
+struct ColorGradingUniforms {
+    contribution: f32,
+}
+
+fn color_attachment(view: &TextureView) -> RenderPassColorAttachmentDescriptor {
+    RenderPassColorAttachmentDescriptor {
+        attachment: view,
+        resolve_target: None,
+        ops: Operations {
+            load: LoadOp::Clear(wgpu::Color::BLACK),
+            store: true,
+        },
+    }
+}
+
+fn fullscreen_node(
+    device: &Device,
+    fragment_shader: &str,
+    color_format: TextureFormat,
+    uniforms: UniformBindGroup,
+    with_lut: bool,
+) -> Result<RenderNode> {
+    let mut builder = RenderNode::builder()
+        .set_vertex_shader(VertexShader::new(
+            device,
+            "src/shader_files/vs_fullscreen.shader",
+        )?)
+        .set_fragment_shader(FragmentShader::new(device, fragment_shader)?)
+        .add_texture::<HdrTexture>();
+    if with_lut {
+        builder = builder.add_texture::<LutTexture>();
+    }
+    Ok(builder
+        .add_default_color_state_desc(color_format)
+        .set_default_rasterization_state()
+        .add_local_uniform_bind_group(uniforms)
+        .build(device)?)
+}
+
+pub struct PostProcessPass {
+    bright_node: RenderNode,
+    blur_horizontal_node: RenderNode,
+    blur_vertical_node: RenderNode,
+    color_grading_node: RenderNode,
+    vignette_node: RenderNode,
+    fxaa_node: RenderNode,
+    lut_texture: TextureData<LutTexture>,
+    loaded_lut: Option<PathBuf>,
+}
+
+impl PostProcessPass {
+    pub fn new(device: &Device, queue: &Queue, output_format: TextureFormat) -> Result<Self> {
+        let bright_node = fullscreen_node(
+            device,
+            "src/shader_files/fs_bright_pass.shader",
+            HDR_FORMAT,
+            UniformBindGroup::with_name("Bright pass uniform")
+                .add_binding::<BrightPassUniforms>(ShaderStage::FRAGMENT)?
+                .build(device),
+            false,
+        )?;
+        let blur_horizontal_node = fullscreen_node(
+            device,
+            "src/shader_files/fs_blur.shader",
+            HDR_FORMAT,
+            UniformBindGroup::with_name("Horizontal blur uniform")
+                .add_binding::<BlurUniforms>(ShaderStage::FRAGMENT)?
+                .build(device),
+            false,
+        )?;
+        let blur_vertical_node = fullscreen_node(
+            device,
+            "src/shader_files/fs_blur.shader",
+            HDR_FORMAT,
+            UniformBindGroup::with_name("Vertical blur uniform")
+                .add_binding::<BlurUniforms>(ShaderStage::FRAGMENT)?
+                .build(device),
+            false,
+        )?;
+        let color_grading_node = fullscreen_node(
+            device,
+            "src/shader_files/fs_color_grading.shader",
+            output_format,
+            UniformBindGroup::with_name("Color grading uniform")
+                .add_binding::<ColorGradingUniforms>(ShaderStage::FRAGMENT)?
+                .build(device),
+            true,
+        )?;
+        let vignette_node = fullscreen_node(
+            device,
+            "src/shader_files/fs_vignette.shader",
+            output_format,
+            UniformBindGroup::with_name("Vignette uniform")
+                .add_binding::<VignetteUniforms>(ShaderStage::FRAGMENT)?
+                .build(device),
+            false,
+        )?;
+        let fxaa_node = fullscreen_node(
+            device,
+            "src/shader_files/fs_fxaa.shader",
+            output_format,
+            UniformBindGroup::with_name("Fxaa uniform")
+                .add_binding::<FxaaUniforms>(ShaderStage::FRAGMENT)?
+                .build(device),
+            false,
+        )?;
+        Ok(PostProcessPass {
+            bright_node,
+            blur_horizontal_node,
+            blur_vertical_node,
+            color_grading_node,
+            vignette_node,
+            fxaa_node,
+            lut_texture: LutTexture::identity(device, queue),
+            loaded_lut: None,
+        })
+    }
+
+    pub fn update_uniforms(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        resources: &Resources,
+        encoder: &mut CommandEncoder,
+    ) {
+        let bloom = resources.get::<Bloom>().expect("Bloom not registered");
+        self.bright_node
+            .update(
+                device,
+                encoder,
+                0,
+                &BrightPassUniforms {
+                    threshold: bloom.threshold,
+                    knee: bloom.knee,
+                },
+            )
+            .unwrap();
+        self.blur_horizontal_node
+            .update(
+                device,
+                encoder,
+                0,
+                &BlurUniforms {
+                    direction: [1.0, 0.0],
+                },
+            )
+            .unwrap();
+        self.blur_vertical_node
+            .update(
+                device,
+                encoder,
+                0,
+                &BlurUniforms {
+                    direction: [0.0, 1.0],
+                },
+            )
+            .unwrap();
+
+        let fxaa = resources.get::<Fxaa>().expect("Fxaa not registered");
+        self.fxaa_node
+            .update(
+                device,
+                encoder,
+                0,
+                &FxaaUniforms {
+                    span_max: fxaa.span_max,
+                    reduce_min: fxaa.reduce_min,
+                    reduce_mul: fxaa.reduce_mul,
+                    _pad: 0.0,
+                },
+            )
+            .unwrap();
+
+        let vignette = resources
+            .get::<Vignette>()
+            .expect("Vignette not registered");
+        self.vignette_node
+            .update(
+                device,
+                encoder,
+                0,
+                &VignetteUniforms {
+                    intensity: vignette.intensity,
+                    radius: vignette.radius,
+                    smoothness: vignette.smoothness,
+                    _pad: 0.0,
+                },
+            )
+            .unwrap();
+
+        let color_grading = resources
+            .get::<ColorGrading>()
+            .expect("Color grading not registered");
+        self.color_grading_node
+            .update(
+                device,
+                encoder,
+                0,
+                &ColorGradingUniforms {
+                    contribution: color_grading.contribution,
+                },
+            )
+            .unwrap();
+        if color_grading.lut != self.loaded_lut {
+            self.lut_texture = match &color_grading.lut {
+                Some(path) => LutTexture::load_texture(device, queue, path).unwrap_or_else(|err| {
+                    eprintln!("Failed to load lut {:?}: {}", path, err);
+                    LutTexture::identity(device, queue)
+                }),
+                None => LutTexture::identity(device, queue),
+            };
+            self.loaded_lut = color_grading.lut.clone();
+        }
+    }
+
+    // The enabled effects that run after tone mapping in stack order
+    pub fn enabled_effects(&self, resources: &Resources) -> Vec<PostEffect> {
+        let stack = resources
+            .get::<PostProcessStack>()
+            .expect("Post process stack not registered");
+        stack
+            .order
+            .iter()
+            .copied()
+            .filter(|effect| match effect {
+                PostEffect::ColorGrading => resources.get::<ColorGrading>().unwrap().enabled,
+                PostEffect::Vignette => resources.get::<Vignette>().unwrap().enabled,
+                PostEffect::Fxaa => resources.get::<Fxaa>().unwrap().enabled,
+            })
+            .collect()
+    }
+
+    fn run_fullscreen<'encoder>(
+        node: &'encoder RenderNode,
+        input: &'encoder TextureData<HdrTexture>,
+        lut: Option<&'encoder TextureData<LutTexture>>,
+        encoder: &mut CommandEncoder,
+        render_pass_descriptor: RenderPassDescriptor,
+    ) {
+        let mut runner = node.runner(encoder, render_pass_descriptor);
+        runner.set_texture_data(0, input);
+        if let Some(lut) = lut {
+            runner.set_texture_data(1, lut);
+        }
+        // the fullscreen triangle is hardcoded into the vertex shader
+        runner.draw(0..3, 0..1);
+    }
+
+    // Extracts and blurs the bright parts of the hdr buffer, the returned
+    // target is black if bloom is disabled
+    pub fn render_bloom<'a>(
+        &self,
+        resources: &Resources,
+        encoder: &mut CommandEncoder,
+        targets: &'a SceneTargets,
+    ) -> &'a SampledTarget {
+        let bloom = resources.get::<Bloom>().expect("Bloom not registered");
+        if !bloom.enabled {
+            encoder.begin_render_pass(&RenderPassDescriptor {
+                color_attachments: &[color_attachment(targets.bloom(0).view())],
+                depth_stencil_attachment: None,
+            });
+            return targets.bloom(0);
+        }
+        Self::run_fullscreen(
+            &self.bright_node,
+            targets.hdr().texture(),
+            None,
+            encoder,
+            RenderPassDescriptor {
+                color_attachments: &[color_attachment(targets.bloom(0).view())],
+                depth_stencil_attachment: None,
+            },
+        );
+        for _ in 0..bloom.blur_passes {
+            Self::run_fullscreen(
+                &self.blur_horizontal_node,
+                targets.bloom(0).texture(),
+                None,
+                encoder,
+                RenderPassDescriptor {
+                    color_attachments: &[color_attachment(targets.bloom(1).view())],
+                    depth_stencil_attachment: None,
+                },
+            );
+            Self::run_fullscreen(
+                &self.blur_vertical_node,
+                targets.bloom(1).texture(),
+                None,
+                encoder,
+                RenderPassDescriptor {
+                    color_attachments: &[color_attachment(targets.bloom(0).view())],
+                    depth_stencil_attachment: None,
+                },
+            );
+        }
+        targets.bloom(0)
+    }
+
+    // Applies the effects in order, ping ponging between the ldr buffers.
+    // Expects the tone mapped image in the first ldr buffer
+    pub fn render_effects(
+        &self,
+        effects: &[PostEffect],
+        encoder: &mut CommandEncoder,
+        targets: &SceneTargets,
+        output_view: &TextureView,
+    ) {
+        for (i, effect) in effects.iter().enumerate() {
+            let input = targets.ldr(i % 2);
+            let output = if i + 1 == effects.len() {
+                output_view
+            } else {
+                targets.ldr((i + 1) % 2).view()
+            };
+            let (node, lut) = match effect {
+                PostEffect::ColorGrading => (&self.color_grading_node, Some(&self.lut_texture)),
+                PostEffect::Vignette => (&self.vignette_node, None),
+                PostEffect::Fxaa => (&self.fxaa_node, None),
+            };
+            Self::run_fullscreen(
+                node,
+                input.texture(),
+                lut,
+                encoder,
+                RenderPassDescriptor {
+                    color_attachments: &[color_attachment(output)],
+                    depth_stencil_attachment: None,
+                },
+            );
+        }
+    }
+}
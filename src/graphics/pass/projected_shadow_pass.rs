@@ -0,0 +1,236 @@
+use super::shadow_pass::shadow_caster_ranges;
+use crate::{
+    animation::Animator,
+    assets::{Assets, Handle},
+    components::GlobalTransform,
+    graphics::{
+        model::{DrawModel, InstanceData, MeshVertex, Model},
+        morph_targets::MorphTextures,
+        phong_material::PhongTextures,
+        shadow_texture::{ShadowMaps, CASCADE_SHADOW_SIZE, SHADOW_FORMAT, SPOT_SHADOW_SIZE},
+        skinned_model::{DrawSkinnedModel, SkinnedInstanceData, SkinnedModel, SkinnedVertex},
+        skinning::{JointPalette, SkinningTextures},
+    },
+};
+use anyhow::Result;
+use legion::prelude::*;
+use nalgebra::Matrix4;
+use smol_renderer::{
+    FragmentShader, GpuData, RenderNode, SimpleTexture, UniformBindGroup, VertexShader,
+};
+use std::{collections::HashMap, rc::Rc};
+use wgpu::{
+    Device, LoadOp, Operations, RenderPassDepthStencilAttachmentDescriptor, RenderPassDescriptor,
+    ShaderStage, TextureView,
+};
+
+#[repr(C)]
+#[derive(Clone, GpuData)]
+pub struct ProjectionUniforms {
+    pub view_projection: Matrix4<f32>,
+}
+
+fn depth_stencil_state() -> wgpu::DepthStencilStateDescriptor {
+    wgpu::DepthStencilStateDescriptor {
+        format: SHADOW_FORMAT,
+        depth_write_enabled: true,
+        depth_compare: wgpu::CompareFunction::LessEqual,
+        stencil_front: wgpu::StencilStateFaceDescriptor::IGNORE,
+        stencil_back: wgpu::StencilStateFaceDescriptor::IGNORE,
+        stencil_read_mask: 0,
+        stencil_write_mask: 0,
+    }
+}
+
+fn rasterization_state() -> wgpu::RasterizationStateDescriptor {
+    wgpu::RasterizationStateDescriptor {
+        front_face: wgpu::FrontFace::Ccw,
+        cull_mode: wgpu::CullMode::Front,
+        // each light has its own bias which is applied when sampling
+        depth_bias: 0,
+        depth_bias_slope_scale: 0.0,
+        depth_bias_clamp: 0.0,
+    }
+}
+
+// Renders the depth of the scene for lights with a single projection,
+// i.e each directional light cascade and each spot light
+pub struct ProjectedShadowPass {
+    render_node: RenderNode,
+    skinned_node: RenderNode,
+    joint_palette: Rc<JointPalette>,
+    cascade_views: Vec<TextureView>,
+    spot_views: Vec<TextureView>,
+}
+
+impl ProjectedShadowPass {
+    pub fn new(
+        device: &Device,
+        shadow_maps: &ShadowMaps,
+        joint_palette: Rc<JointPalette>,
+    ) -> Result<Self> {
+        let render_node = RenderNode::builder()
+            .add_vertex_buffer::<MeshVertex>()
+            .add_vertex_buffer::<InstanceData>()
+            .set_vertex_shader(VertexShader::new(
+                device,
+                "src/shader_files/vs_projected_shadow.shader",
+            )?)
+            .set_fragment_shader(FragmentShader::new(
+                device,
+                "src/shader_files/fs_projected_shadow.shader",
+            )?)
+            // diffuse and phong factors for the alpha test
+            .add_texture::<SimpleTexture>()
+            .add_texture::<PhongTextures>()
+            .set_depth_stencil_state(depth_stencil_state())
+            .set_rasterization_state(rasterization_state())
+            .add_local_uniform_bind_group(
+                UniformBindGroup::with_name("Projection uniforms")
+                    .add_binding::<ProjectionUniforms>(ShaderStage::VERTEX)?
+                    .build(device),
+            )
+            .build(&device)?;
+        let skinned_node = RenderNode::builder()
+            .add_vertex_buffer::<SkinnedVertex>()
+            .add_vertex_buffer::<SkinnedInstanceData>()
+            .set_vertex_shader(VertexShader::new(
+                device,
+                "src/shader_files/vs_skinned_projected_shadow.shader",
+            )?)
+            .set_fragment_shader(FragmentShader::new(
+                device,
+                "src/shader_files/fs_skinned_projected_shadow.shader",
+            )?)
+            // joint matrices and morph weights of every instance
+            .add_texture::<SkinningTextures>()
+            // morph target deltas of the mesh
+            .add_texture::<MorphTextures>()
+            .set_depth_stencil_state(depth_stencil_state())
+            .set_rasterization_state(rasterization_state())
+            .add_local_uniform_bind_group(
+                UniformBindGroup::with_name("Skinned projection uniforms")
+                    .add_binding::<ProjectionUniforms>(ShaderStage::VERTEX)?
+                    .build(device),
+            )
+            .build(&device)?;
+
+        let cascade_views = (0..CASCADE_SHADOW_SIZE.depth)
+            .map(|cascade| shadow_maps.cascade_view(cascade))
+            .collect();
+        let spot_views = (0..SPOT_SHADOW_SIZE.depth)
+            .map(|light_index| shadow_maps.spot_view(light_index))
+            .collect();
+
+        Ok(Self {
+            render_node,
+            skinned_node,
+            joint_palette,
+            cascade_views,
+            spot_views,
+        })
+    }
+
+    pub fn cascade_view(&self, cascade: usize) -> &TextureView {
+        &self.cascade_views[cascade]
+    }
+
+    pub fn spot_view(&self, light_index: usize) -> &TextureView {
+        &self.spot_views[light_index]
+    }
+
+    pub fn update_uniforms(
+        &self,
+        device: &Device,
+        view_projection: Matrix4<f32>,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        let uniforms = ProjectionUniforms { view_projection };
+        self.render_node
+            .update(device, encoder, 0, &uniforms)
+            .unwrap();
+        self.skinned_node
+            .update(device, encoder, 0, &uniforms)
+            .unwrap();
+    }
+
+    // Draws the animated models on top of the depth written by render
+    pub fn render_skinned(
+        &self,
+        resources: &Resources,
+        world: &World,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &TextureView,
+    ) {
+        let asset_storage = resources
+            .get::<Assets<SkinnedModel>>()
+            .expect("asset not registered");
+        let mut runner = self.skinned_node.runner(
+            encoder,
+            RenderPassDescriptor {
+                color_attachments: &[],
+                depth_stencil_attachment: Some(RenderPassDepthStencilAttachmentDescriptor {
+                    attachment: target,
+                    depth_ops: Some(Operations {
+                        load: LoadOp::Load,
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            },
+        );
+        runner.set_texture_data(0, &self.joint_palette.textures);
+        let mut offset_map = HashMap::new();
+        let query = <(
+            Read<GlobalTransform>,
+            Read<Animator>,
+            Tagged<Handle<SkinnedModel>>,
+        )>::query();
+        for chunk in query.par_iter_chunks(world) {
+            // This is guaranteed to be the same for each chunk
+            let model = chunk.tag::<Handle<SkinnedModel>>().unwrap();
+            let offset = *offset_map.get(model).unwrap_or(&0);
+            let transforms = chunk.components::<GlobalTransform>().unwrap();
+            offset_map.insert(model.clone(), offset + transforms.len());
+            if let Some(model) = asset_storage.get(model) {
+                runner.draw_skinned_untextured(
+                    model,
+                    offset as u32..(offset + transforms.len()) as u32,
+                    1,
+                );
+            }
+        }
+    }
+
+    // Clears the target and draws the static models into it
+    pub fn render(
+        &self,
+        resources: &Resources,
+        world: &World,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &TextureView,
+    ) {
+        let asset_storage = resources
+            .get::<Assets<Model>>()
+            .expect("asset not registered");
+        let mut runner = self.render_node.runner(
+            encoder,
+            RenderPassDescriptor {
+                color_attachments: &[],
+                depth_stencil_attachment: Some(RenderPassDepthStencilAttachmentDescriptor {
+                    attachment: target,
+                    depth_ops: Some(Operations {
+                        load: LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            },
+        );
+        for (model, instances) in shadow_caster_ranges(world) {
+            if let Some(model) = asset_storage.get(&model) {
+                runner.draw_untextured(model, instances);
+            }
+        }
+    }
+}
@@ -1,49 +1,100 @@
 use super::Pass;
 use crate::{
+    animation::Animator,
     assets::Assets,
     assets::Handle,
-    components::Transform,
+    components::GlobalTransform,
     graphics::model::Model,
     graphics::{
         model::{DrawModel, InstanceData, MeshVertex},
-        point_light::PointLightRaw,
-        shadow_texture::{ShadowTexture, SHADOW_FORMAT},
+        morph_targets::MorphTextures,
+        phong_material::PhongTextures,
+        shadow_texture::{ShadowMaps, MAX_POINT_LIGHT_SHADOWS, SHADOW_CUBE_FACES, SHADOW_FORMAT},
+        skinned_model::{DrawSkinnedModel, SkinnedInstanceData, SkinnedModel, SkinnedVertex},
+        skinning::{JointPalette, SkinningTextures},
         PointLight,
     },
 };
 use anyhow::Result;
 use legion::prelude::World;
 use legion::prelude::*;
+use nalgebra::{Matrix4, Vector3};
 use smol_renderer::{
-    FragmentShader, GpuData, RenderNode, TextureData, UniformBindGroup, VertexShader,
+    FragmentShader, GpuData, RenderNode, SimpleTexture, UniformBindGroup, VertexShader,
+};
+use std::{collections::HashMap, ops::Range, rc::Rc};
+use wgpu::{
+    Device, LoadOp, Operations, RenderPassDepthStencilAttachmentDescriptor, RenderPassDescriptor,
+    ShaderStage, TextureView,
 };
-use std::{collections::HashMap, rc::Rc};
-use wgpu::{Device, ShaderStage};
 
 #[repr(C)]
-#[derive(Default, Clone, GpuData)]
-pub struct LightSpaceMatrix {
-    pub light_space_matrix: [[f32; 4]; 4],
+#[derive(Clone, GpuData)]
+pub struct ShadowFaceUniforms {
+    pub view_projection: Matrix4<f32>,
+    pub light_position: Vector3<f32>,
+    pub far_plane: f32,
 }
 
-impl From<&PointLightRaw> for LightSpaceMatrix {
-    fn from(light: &PointLightRaw) -> Self {
-        LightSpaceMatrix {
-            light_space_matrix: light.light_space_matrix,
+// Instance ranges of the static models that cast shadows. The offsets are counted over
+// every instance like the model pass fills the instance buffers, the meshes of the lights
+// themselves are only left out of the draws since they would block their own light
+pub(crate) fn shadow_caster_ranges(world: &World) -> Vec<(Handle<Model>, Range<u32>)> {
+    let mut offset_map: HashMap<Handle<Model>, u32> = HashMap::new();
+    let mut ranges: Vec<(Handle<Model>, Range<u32>)> = Vec::new();
+    let query = <(Read<GlobalTransform>, Tagged<Handle<Model>>)>::query();
+    for (entity, (_, model)) in query.iter_entities(world) {
+        let offset = offset_map.entry(model.clone()).or_insert(0);
+        let instance = *offset;
+        *offset += 1;
+        if world.get_component::<PointLight>(entity).is_some() {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some((last, range)) if last == model && range.end == instance => range.end += 1,
+            _ => ranges.push((model.clone(), instance..instance + 1)),
         }
     }
+    ranges
+}
+
+fn depth_stencil_state() -> wgpu::DepthStencilStateDescriptor {
+    wgpu::DepthStencilStateDescriptor {
+        format: SHADOW_FORMAT,
+        depth_write_enabled: true,
+        depth_compare: wgpu::CompareFunction::LessEqual,
+        stencil_front: wgpu::StencilStateFaceDescriptor::IGNORE,
+        stencil_back: wgpu::StencilStateFaceDescriptor::IGNORE,
+        stencil_read_mask: 0,
+        stencil_write_mask: 0,
+    }
+}
+
+fn rasterization_state() -> wgpu::RasterizationStateDescriptor {
+    wgpu::RasterizationStateDescriptor {
+        // the cube face projections flip y which also flips the winding order
+        front_face: wgpu::FrontFace::Cw,
+        cull_mode: wgpu::CullMode::Front,
+        // the fragment shader writes the depth so the bias is applied when sampling
+        depth_bias: 0,
+        depth_bias_slope_scale: 0.0,
+        depth_bias_clamp: 0.0,
+    }
 }
 
-// TODO:
-// Don't make the point light contian the target_view, that should be a separate component
-// Update the resize method
 pub struct ShadowPass {
     render_node: RenderNode,
-    shadow_texture: Rc<TextureData<ShadowTexture>>,
+    skinned_node: RenderNode,
+    joint_palette: Rc<JointPalette>,
+    face_views: Vec<TextureView>,
 }
 
 impl ShadowPass {
-    pub fn new(device: &Device, shadow_texture: Rc<TextureData<ShadowTexture>>) -> Result<Self> {
+    pub fn new(
+        device: &Device,
+        shadow_maps: &ShadowMaps,
+        joint_palette: Rc<JointPalette>,
+    ) -> Result<Self> {
         let render_node = RenderNode::builder()
             .add_vertex_buffer::<MeshVertex>()
             .add_vertex_buffer::<InstanceData>()
@@ -55,70 +106,122 @@ impl ShadowPass {
                 device,
                 "src/shader_files/fs_shadow.shader",
             )?)
-            .set_depth_stencil_state(wgpu::DepthStencilStateDescriptor {
-                format: SHADOW_FORMAT,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::LessEqual,
-                stencil_front: wgpu::StencilStateFaceDescriptor::IGNORE,
-                stencil_back: wgpu::StencilStateFaceDescriptor::IGNORE,
-                stencil_read_mask: 0,
-                stencil_write_mask: 0,
-            })
-            .set_rasterization_state(wgpu::RasterizationStateDescriptor {
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: wgpu::CullMode::Front,
-                depth_bias: 0, // Biliniear filtering
-                depth_bias_slope_scale: 2.0,
-                depth_bias_clamp: 0.0,
-            })
+            // diffuse and phong factors for the alpha test
+            .add_texture::<SimpleTexture>()
+            .add_texture::<PhongTextures>()
+            .set_depth_stencil_state(depth_stencil_state())
+            .set_rasterization_state(rasterization_state())
             .add_local_uniform_bind_group(
-                UniformBindGroup::with_name("Light space matrix")
-                    .add_binding::<LightSpaceMatrix>(ShaderStage::VERTEX)?
+                UniformBindGroup::with_name("Shadow face uniforms")
+                    .add_binding::<ShadowFaceUniforms>(ShaderStage::VERTEX | ShaderStage::FRAGMENT)?
                     .build(device),
             )
             .build(&device)?;
+        let skinned_node = RenderNode::builder()
+            .add_vertex_buffer::<SkinnedVertex>()
+            .add_vertex_buffer::<SkinnedInstanceData>()
+            .set_vertex_shader(VertexShader::new(
+                device,
+                "src/shader_files/vs_skinned_shadow.shader",
+            )?)
+            .set_fragment_shader(FragmentShader::new(
+                device,
+                "src/shader_files/fs_skinned_shadow.shader",
+            )?)
+            // joint matrices and morph weights of every instance
+            .add_texture::<SkinningTextures>()
+            // morph target deltas of the mesh
+            .add_texture::<MorphTextures>()
+            .set_depth_stencil_state(depth_stencil_state())
+            .set_rasterization_state(rasterization_state())
+            .add_local_uniform_bind_group(
+                UniformBindGroup::with_name("Skinned shadow face uniforms")
+                    .add_binding::<ShadowFaceUniforms>(ShaderStage::VERTEX | ShaderStage::FRAGMENT)?
+                    .build(device),
+            )
+            .build(&device)?;
+
+        let face_views = (0..MAX_POINT_LIGHT_SHADOWS)
+            .flat_map(|slot| {
+                (0..SHADOW_CUBE_FACES).map(move |face| shadow_maps.point_face_view(slot, face))
+            })
+            .collect();
 
         Ok(Self {
             render_node,
-            shadow_texture,
+            skinned_node,
+            joint_palette,
+            face_views,
         })
     }
 
-    // This is very ugly it's probably better do decouple these
-    // either use events to give new lights a view immedietly
-    // or separate them completely in different components
-    pub fn update_lights_with_texture_view(&self, world: &mut World) {
-        let light_query = <Write<PointLight>>::query();
-        for (i, mut light) in light_query.iter_mut(world).enumerate() {
-            if light.target_view.is_some() {
-                continue;
-            }
-            light.target_view = Some(self.shadow_texture.create_new_view(
-                &wgpu::TextureViewDescriptor {
-                    format: SHADOW_FORMAT,
-                    dimension: wgpu::TextureViewDimension::D2,
-                    aspect: wgpu::TextureAspect::DepthOnly,
-                    base_mip_level: 0,
-                    level_count: 1,
-                    base_array_layer: i as u32,
-                    array_layer_count: 1,
-                    label: Some("Light target view"),
-                },
-            ));
-        }
+    // Render targets for the cube faces of a slot in the shadow atlas
+    pub fn face_views(&self, slot: u32) -> &[TextureView] {
+        let first_layer = (slot * SHADOW_CUBE_FACES) as usize;
+        &self.face_views[first_layer..first_layer + SHADOW_CUBE_FACES as usize]
     }
 
     pub fn update_uniforms(
         &self,
         device: &Device,
-        light: &PointLightRaw,
+        face_uniforms: &ShadowFaceUniforms,
         encoder: &mut wgpu::CommandEncoder,
     ) {
-        let light_space_matrix: LightSpaceMatrix = light.into();
         self.render_node
-            .update(device, encoder, 0, &light_space_matrix)
+            .update(device, encoder, 0, face_uniforms)
+            .unwrap();
+        self.skinned_node
+            .update(device, encoder, 0, face_uniforms)
             .unwrap();
     }
+
+    // Draws the animated models on top of the depth written by render
+    pub fn render_skinned(
+        &self,
+        resources: &Resources,
+        world: &World,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &TextureView,
+    ) {
+        let asset_storage = resources
+            .get::<Assets<SkinnedModel>>()
+            .expect("asset not registered");
+        let mut runner = self.skinned_node.runner(
+            encoder,
+            RenderPassDescriptor {
+                color_attachments: &[],
+                depth_stencil_attachment: Some(RenderPassDepthStencilAttachmentDescriptor {
+                    attachment: target,
+                    depth_ops: Some(Operations {
+                        load: LoadOp::Load,
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            },
+        );
+        runner.set_texture_data(0, &self.joint_palette.textures);
+        let mut offset_map = HashMap::new();
+        let query = <(
+            Read<GlobalTransform>,
+            Read<Animator>,
+            Tagged<Handle<SkinnedModel>>,
+        )>::query();
+        for chunk in query.par_iter_chunks(world) {
+            // This is guaranteed to be the same for each chunk
+            let model = chunk.tag::<Handle<SkinnedModel>>().unwrap();
+            let offset = *offset_map.get(model).unwrap_or(&0);
+            let transforms = chunk.components::<GlobalTransform>().unwrap();
+            offset_map.insert(model.clone(), offset + transforms.len());
+            if let Some(model) = asset_storage.get(model) {
+                runner.draw_skinned_untextured(
+                    model,
+                    offset as u32..(offset + transforms.len()) as u32,
+                    1,
+                );
+            }
+        }
+    }
 }
 
 impl Pass for ShadowPass {
@@ -143,17 +246,10 @@ impl Pass for ShadowPass {
             .get::<Assets<Model>>()
             .expect("asset not registered");
         let mut runner = self.render_node.runner(encoder, render_pass_descriptor);
-        let mut offset_map = HashMap::new();
-        let query =
-            <(Read<Transform>, Tagged<Handle<Model>>)>::query().filter(!component::<PointLight>());
-        for chunk in query.iter_chunks(world) {
-            // This is guaranteed to be the same for each chunk
-            let model = chunk.tag::<Handle<Model>>().unwrap();
-            let offset = *offset_map.get(model).unwrap_or(&0);
-            let transforms = chunk.components::<Transform>().unwrap();
-            offset_map.insert(model.clone(), offset + transforms.len());
-            let model = asset_storage.get(model).unwrap();
-            runner.draw_untextured(model, offset as u32..(offset + transforms.len()) as u32);
+        for (model, instances) in shadow_caster_ranges(world) {
+            if let Some(model) = asset_storage.get(&model) {
+                runner.draw_untextured(model, instances);
+            }
         }
     }
 }
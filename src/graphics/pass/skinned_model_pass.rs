@@ -0,0 +1,186 @@
+use std::{collections::HashMap, rc::Rc, sync::Arc};
+
+use anyhow::Result;
+use legion::prelude::*;
+use smol_renderer::{FragmentShader, RenderNode, TextureData, UniformBindGroup, VertexShader};
+use wgpu::{CommandEncoder, Device, RenderPassDescriptor, TextureFormat};
+
+use crate::{
+    animation::Animator,
+    assets::{Assets, Handle},
+    components::GlobalTransform,
+    graphics::{
+        clustered_lights::{ClusterTextures, ClusteredLights},
+        environment_map::{EnvironmentMap, EnvironmentTextures},
+        hdr_texture::HdrTexture,
+        morph_targets::{MorphTextures, MorphWeights},
+        pbr_material::PbrTextures,
+        shader_include::expand_includes,
+        shadow_texture::{ShadowMaps, ShadowTexture},
+        skinned_model::{DrawSkinnedModel, SkinnedInstanceData, SkinnedModel, SkinnedVertex},
+        skinning::{JointPalette, SkinningTextures},
+    },
+};
+
+// Draws the animated glTF models with the pbr shading, only entities
+// with an Animator are drawn since the joint matrices come from it
+pub struct SkinnedModelPass {
+    shadow_maps: Rc<ShadowMaps>,
+    environment_map: Rc<EnvironmentMap>,
+    clustered_lights: Rc<ClusteredLights>,
+    joint_palette: Rc<JointPalette>,
+    render_node: RenderNode,
+}
+
+impl SkinnedModelPass {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &Device,
+        global_uniforms: Vec<Arc<UniformBindGroup>>,
+        shadow_maps: Rc<ShadowMaps>,
+        environment_map: Rc<EnvironmentMap>,
+        clustered_lights: Rc<ClusteredLights>,
+        joint_palette: Rc<JointPalette>,
+        color_format: TextureFormat,
+        sample_count: u32,
+    ) -> Result<Self> {
+        let render_node = RenderNode::builder()
+            .add_vertex_buffer::<SkinnedVertex>()
+            .add_vertex_buffer::<SkinnedInstanceData>()
+            .set_vertex_shader(VertexShader::new(
+                device,
+                "src/shader_files/vs_skinned.shader",
+            )?)
+            .set_fragment_shader(FragmentShader::new(
+                device,
+                &expand_includes("src/shader_files/fs_pbr.shader")?,
+            )?)
+            // material maps and factors
+            .add_texture::<PbrTextures>()
+            // point, directional and spot light shadow maps
+            .add_texture::<ShadowTexture>()
+            // irradiance, prefiltered specular and brdf lut
+            .add_texture::<EnvironmentTextures>()
+            // light uniforms, point lights and their clusters
+            .add_texture::<ClusterTextures>()
+            // screen space ambient occlusion
+            .add_texture::<HdrTexture>()
+            // joint matrices and morph weights of every instance
+            .add_texture::<SkinningTextures>()
+            // morph target deltas of the mesh
+            .add_texture::<MorphTextures>()
+            .add_default_color_state_desc(color_format)
+            .set_default_depth_stencil_state()
+            .set_default_rasterization_state()
+            .set_sample_count(sample_count)
+            // camera
+            .add_shared_uniform_bind_group(global_uniforms[0].clone())
+            .build(&device)?;
+
+        Ok(Self {
+            shadow_maps,
+            environment_map,
+            clustered_lights,
+            joint_palette,
+            render_node,
+        })
+    }
+
+    // Writes the instance buffers and the joint palette shared with the
+    // geometry and shadow passes, so it must run before any of them.
+    // Instances without MorphWeights use the default weights of their model
+    pub fn update_uniform_data(
+        &self,
+        world: &World,
+        resources: &Resources,
+        device: &Device,
+        encoder: &mut CommandEncoder,
+    ) {
+        let asset_storage = resources
+            .get::<Assets<SkinnedModel>>()
+            .expect("Asset not registerd");
+        let mut joint_matrices = Vec::new();
+        let mut morph_weights = Vec::new();
+        let mut instances: HashMap<Handle<SkinnedModel>, Vec<SkinnedInstanceData>> = HashMap::new();
+        let query = <(
+            Read<GlobalTransform>,
+            Read<Animator>,
+            Tagged<Handle<SkinnedModel>>,
+        )>::query();
+        for chunk in query.par_iter_chunks(world) {
+            let model = chunk.tag::<Handle<SkinnedModel>>().unwrap();
+            let transforms = chunk.components::<GlobalTransform>().unwrap();
+            let animators = chunk.components::<Animator>().unwrap();
+            let instance_weights = chunk.components::<MorphWeights>();
+            let default_weights = asset_storage
+                .get(model)
+                .map_or(&[][..], |model| &model.morph_weights[..]);
+            let instance_data = instances.entry(model.clone()).or_default();
+            for (i, (transform, animator)) in transforms.iter().zip(animators.iter()).enumerate() {
+                instance_data.push(SkinnedInstanceData::new(
+                    transform.get_model_matrix(),
+                    joint_matrices.len() as u32,
+                    morph_weights.len() as u32,
+                ));
+                joint_matrices.extend_from_slice(animator.joint_matrices());
+                let weights = instance_weights
+                    .as_ref()
+                    .map_or(&[][..], |weights| &weights[i].weights[..]);
+                morph_weights.extend(
+                    default_weights
+                        .iter()
+                        .enumerate()
+                        .map(|(j, default)| *weights.get(j).unwrap_or(default)),
+                );
+            }
+        }
+        for (model, instance_data) in instances {
+            if let Some(model) = asset_storage.get(&model) {
+                model
+                    .instance_buffer
+                    .update(device, encoder, &instance_data);
+            }
+        }
+        self.joint_palette
+            .update(device, encoder, &joint_matrices, &morph_weights);
+    }
+
+    pub fn render<'encoder>(
+        &'encoder self,
+        resources: &'encoder Resources,
+        world: &World,
+        ambient_occlusion: &'encoder TextureData<HdrTexture>,
+        encoder: &mut CommandEncoder,
+        render_pass_descriptor: RenderPassDescriptor,
+    ) {
+        let asset_storage = resources
+            .get::<Assets<SkinnedModel>>()
+            .expect("Asset not registerd");
+        let mut runner = self.render_node.runner(encoder, render_pass_descriptor);
+        runner.set_texture_data(1, &self.shadow_maps.textures);
+        runner.set_texture_data(2, &self.environment_map.textures);
+        runner.set_texture_data(3, &self.clustered_lights.textures);
+        runner.set_texture_data(4, ambient_occlusion);
+        runner.set_texture_data(5, &self.joint_palette.textures);
+        let mut offset_map = HashMap::new();
+        let query = <(
+            Read<GlobalTransform>,
+            Read<Animator>,
+            Tagged<Handle<SkinnedModel>>,
+        )>::query();
+        for chunk in query.par_iter_chunks(world) {
+            // This is guaranteed to be the same for each chunk
+            let model = chunk.tag::<Handle<SkinnedModel>>().unwrap();
+            let offset = *offset_map.get(model).unwrap_or(&0);
+            let transforms = chunk.components::<GlobalTransform>().unwrap();
+            offset_map.insert(model.clone(), offset + transforms.len());
+            if let Some(model) = asset_storage.get(model) {
+                runner.draw_skinned_model_instanced(
+                    model,
+                    offset as u32..(offset + transforms.len()) as u32,
+                    6,
+                );
+            }
+        }
+    }
+}
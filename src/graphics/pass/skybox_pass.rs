@@ -19,6 +19,7 @@ impl SkyboxPass {
         device: &Device,
         global_unifroms: Vec<Arc<UniformBindGroup>>,
         color_format: TextureFormat,
+        sample_count: u32,
         skybox_texture: TextureData<SkyboxTexture>,
     ) -> Result<Self> {
         let render_node = RenderNode::builder()
@@ -34,12 +35,17 @@ impl SkyboxPass {
             .add_texture::<SkyboxTexture>()
             .add_default_color_state_desc(color_format)
             .set_default_rasterization_state() // THIS WAS PREVIOUSLY CW NOT CCW
+            .set_sample_count(sample_count)
             .build(device)?;
         Ok(Self {
             render_node,
             skybox_texture,
         })
     }
+
+    pub fn set_skybox_texture(&mut self, skybox_texture: TextureData<SkyboxTexture>) {
+        self.skybox_texture = skybox_texture;
+    }
 }
 
 impl Pass for SkyboxPass {
@@ -0,0 +1,387 @@
+use std::{collections::HashMap, rc::Rc, sync::Arc};
+
+use anyhow::Result;
+use legion::prelude::*;
+use nalgebra::Matrix4;
+use smol_renderer::{
+    FragmentShader, GpuData, RenderNode, SimpleTexture, UniformBindGroup, VertexShader,
+};
+use wgpu::{
+    CommandEncoder, Device, LoadOp, Operations, RenderPassColorAttachmentDescriptor,
+    RenderPassDepthStencilAttachmentDescriptor, RenderPassDescriptor, ShaderStage, TextureView,
+};
+
+use crate::{
+    animation::Animator,
+    assets::{Assets, Handle},
+    camera::Camera,
+    components::GlobalTransform,
+    graphics::{
+        g_buffer::NORMAL_FORMAT,
+        hdr_texture::{HdrTexture, SampledTarget, SceneTargets},
+        model::{DrawModel, InstanceData, MeshVertex, Model},
+        morph_targets::MorphTextures,
+        phong_material::PhongTextures,
+        skinned_model::{DrawSkinnedModel, SkinnedInstanceData, SkinnedModel, SkinnedVertex},
+        skinning::{JointPalette, SkinningTextures},
+        ssao_texture::{DepthNormalTextures, AMBIENT_OCCLUSION_FORMAT},
+    },
+};
+
+pub const MAX_SSAO_SAMPLES: u32 = 64;
+
+// Resource controlling the screen space ambient occlusion
+pub struct Ssao {
+    pub enabled: bool,
+    // world space radius of the hemisphere sampled around each pixel
+    pub radius: f32,
+    // samples per pixel, clamped to MAX_SSAO_SAMPLES
+    pub sample_count: u32,
+    // exponent applied to the ambient occlusion, 0 disables it
+    pub intensity: f32,
+    // depth offset that keeps flat surfaces from occluding themselves
+    pub bias: f32,
+}
+
+impl Default for Ssao {
+    fn default() -> Self {
+        Ssao {
+            enabled: true,
+            radius: 0.5,
+            sample_count: 32,
+            intensity: 1.0,
+            bias: 0.025,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(GpuData)]
+struct SsaoUniforms {
+    projection: Matrix4<f32>,
+    inverse_projection: Matrix4<f32>,
+    view: Matrix4<f32>,
+    radius: f32,
+    intensity: f32,
+    bias: f32,
+    sample_count: i32,
+    kernel: [[f32; 4]; MAX_SSAO_SAMPLES as usize],
+}
+
+fn radical_inverse(mut index: u32, base: u32) -> f32 {
+    let mut result = 0.0;
+    let mut fraction = 1.0 / base as f32;
+    while index > 0 {
+        result += (index % base) as f32 * fraction;
+        index /= base;
+        fraction /= base as f32;
+    }
+    result
+}
+
+// Low discrepancy samples in the tangent space hemisphere around +z, the
+// first samples are kept close to the center where occlusion matters the most
+fn hemisphere_kernel() -> [[f32; 4]; MAX_SSAO_SAMPLES as usize] {
+    let mut kernel = [[0.0; 4]; MAX_SSAO_SAMPLES as usize];
+    for (i, sample) in kernel.iter_mut().enumerate() {
+        let phi = 2.0 * std::f32::consts::PI * radical_inverse(i as u32 + 1, 2);
+        let cos_theta = radical_inverse(i as u32 + 1, 3).max(0.05);
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let t = i as f32 / MAX_SSAO_SAMPLES as f32;
+        let scale = 0.1 + 0.9 * t * t;
+        *sample = [
+            sin_theta * phi.cos() * scale,
+            sin_theta * phi.sin() * scale,
+            cos_theta * scale,
+            0.0,
+        ];
+    }
+    kernel
+}
+
+fn color_attachment(
+    view: &TextureView,
+    load: LoadOp<wgpu::Color>,
+) -> RenderPassColorAttachmentDescriptor {
+    RenderPassColorAttachmentDescriptor {
+        attachment: view,
+        resolve_target: None,
+        ops: Operations { load, store: true },
+    }
+}
+
+// Depth normal prepass, hemisphere sampled occlusion and a 4x4 blur that
+// removes the pattern of the repeating sample rotations
+pub struct SsaoPass {
+    prepass_node: RenderNode,
+    skinned_prepass_node: RenderNode,
+    joint_palette: Rc<JointPalette>,
+    ssao_node: RenderNode,
+    blur_node: RenderNode,
+    kernel: [[f32; 4]; MAX_SSAO_SAMPLES as usize],
+}
+
+impl SsaoPass {
+    pub fn new(
+        device: &Device,
+        global_uniforms: Vec<Arc<UniformBindGroup>>,
+        joint_palette: Rc<JointPalette>,
+    ) -> Result<Self> {
+        let prepass_node = RenderNode::builder()
+            .add_vertex_buffer::<MeshVertex>()
+            .add_vertex_buffer::<InstanceData>()
+            .set_vertex_shader(VertexShader::new(
+                device,
+                "src/shader_files/vs_depth_normal.shader",
+            )?)
+            .set_fragment_shader(FragmentShader::new(
+                device,
+                "src/shader_files/fs_depth_normal.shader",
+            )?)
+            // diffuse and phong factors for the alpha test
+            .add_texture::<SimpleTexture>()
+            .add_texture::<PhongTextures>()
+            .add_default_color_state_desc(NORMAL_FORMAT)
+            .set_default_depth_stencil_state()
+            .set_default_rasterization_state()
+            // camera
+            .add_shared_uniform_bind_group(global_uniforms[0].clone())
+            .build(device)?;
+        let skinned_prepass_node = RenderNode::builder()
+            .add_vertex_buffer::<SkinnedVertex>()
+            .add_vertex_buffer::<SkinnedInstanceData>()
+            .set_vertex_shader(VertexShader::new(
+                device,
+                "src/shader_files/vs_skinned_depth_normal.shader",
+            )?)
+            .set_fragment_shader(FragmentShader::new(
+                device,
+                "src/shader_files/fs_skinned_depth_normal.shader",
+            )?)
+            // joint matrices and morph weights of every instance
+            .add_texture::<SkinningTextures>()
+            // morph target deltas of the mesh
+            .add_texture::<MorphTextures>()
+            .add_default_color_state_desc(NORMAL_FORMAT)
+            .set_default_depth_stencil_state()
+            .set_default_rasterization_state()
+            // camera
+            .add_shared_uniform_bind_group(global_uniforms[0].clone())
+            .build(device)?;
+        let ssao_node = RenderNode::builder()
+            .set_vertex_shader(VertexShader::new(
+                device,
+                "src/shader_files/vs_fullscreen.shader",
+            )?)
+            .set_fragment_shader(FragmentShader::new(
+                device,
+                "src/shader_files/fs_ssao.shader",
+            )?)
+            .add_texture::<DepthNormalTextures>()
+            .add_default_color_state_desc(AMBIENT_OCCLUSION_FORMAT)
+            .set_default_rasterization_state()
+            .add_local_uniform_bind_group(
+                UniformBindGroup::with_name("Ssao uniform")
+                    .add_binding::<SsaoUniforms>(ShaderStage::FRAGMENT)?
+                    .build(device),
+            )
+            .build(device)?;
+        let blur_node = RenderNode::builder()
+            .set_vertex_shader(VertexShader::new(
+                device,
+                "src/shader_files/vs_fullscreen.shader",
+            )?)
+            .set_fragment_shader(FragmentShader::new(
+                device,
+                "src/shader_files/fs_ssao_blur.shader",
+            )?)
+            .add_texture::<HdrTexture>()
+            .add_default_color_state_desc(AMBIENT_OCCLUSION_FORMAT)
+            .set_default_rasterization_state()
+            .build(device)?;
+        Ok(SsaoPass {
+            prepass_node,
+            skinned_prepass_node,
+            joint_palette,
+            ssao_node,
+            blur_node,
+            kernel: hemisphere_kernel(),
+        })
+    }
+
+    // Recorded in the encoder like the camera uniforms so each camera gets its own
+    pub fn update_uniforms(
+        &self,
+        device: &Device,
+        resources: &Resources,
+        camera: &Camera,
+        encoder: &mut CommandEncoder,
+    ) {
+        let ssao = resources.get::<Ssao>().expect("Ssao not registered");
+        let projection = *camera.get_projection_matrix();
+        self.ssao_node
+            .update(
+                device,
+                encoder,
+                0,
+                &SsaoUniforms {
+                    projection,
+                    inverse_projection: projection.try_inverse().unwrap_or_else(Matrix4::identity),
+                    view: *camera.get_view_matrix(),
+                    radius: ssao.radius,
+                    intensity: ssao.intensity,
+                    bias: ssao.bias,
+                    sample_count: ssao.sample_count.max(1).min(MAX_SSAO_SAMPLES) as i32,
+                    kernel: self.kernel,
+                },
+            )
+            .unwrap();
+    }
+
+    // Fills the depth normal targets on the forward path, the deferred
+    // path reads them from its g-buffer instead
+    pub fn render_depth_normals(
+        &self,
+        resources: &Resources,
+        world: &World,
+        encoder: &mut CommandEncoder,
+        targets: &SceneTargets,
+    ) {
+        let ssao = resources.get::<Ssao>().expect("Ssao not registered");
+        let (normal_view, depth_view) = match targets.depth_normals().prepass_views() {
+            Some(views) if ssao.enabled => views,
+            _ => return,
+        };
+        let asset_storage = resources
+            .get::<Assets<Model>>()
+            .expect("Asset not registerd");
+        let mut runner = self.prepass_node.runner(
+            encoder,
+            RenderPassDescriptor {
+                color_attachments: &[color_attachment(
+                    normal_view,
+                    LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                )],
+                depth_stencil_attachment: Some(RenderPassDepthStencilAttachmentDescriptor {
+                    attachment: depth_view,
+                    depth_ops: Some(Operations {
+                        load: LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            },
+        );
+        let mut offset_map = HashMap::new();
+        let query = <(Read<GlobalTransform>, Tagged<Handle<Model>>)>::query();
+        for chunk in query.par_iter_chunks(world) {
+            // This is guaranteed to be the same for each chunk
+            let model = chunk.tag::<Handle<Model>>().unwrap();
+            let offset = *offset_map.get(model).unwrap_or(&0);
+            let transforms = chunk.components::<GlobalTransform>().unwrap();
+            offset_map.insert(model.clone(), offset + transforms.len());
+            if let Some(model) = asset_storage.get(model) {
+                runner.draw_untextured(model, offset as u32..(offset + transforms.len()) as u32);
+            }
+        }
+        drop(runner);
+        self.render_skinned_depth_normals(resources, world, encoder, normal_view, depth_view);
+    }
+
+    // Draws the animated models on top of the targets filled by render_depth_normals
+    fn render_skinned_depth_normals(
+        &self,
+        resources: &Resources,
+        world: &World,
+        encoder: &mut CommandEncoder,
+        normal_view: &TextureView,
+        depth_view: &TextureView,
+    ) {
+        let asset_storage = resources
+            .get::<Assets<SkinnedModel>>()
+            .expect("Asset not registerd");
+        let mut runner = self.skinned_prepass_node.runner(
+            encoder,
+            RenderPassDescriptor {
+                color_attachments: &[color_attachment(normal_view, LoadOp::Load)],
+                depth_stencil_attachment: Some(RenderPassDepthStencilAttachmentDescriptor {
+                    attachment: depth_view,
+                    depth_ops: Some(Operations {
+                        load: LoadOp::Load,
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            },
+        );
+        runner.set_texture_data(0, &self.joint_palette.textures);
+        let mut offset_map = HashMap::new();
+        let query = <(
+            Read<GlobalTransform>,
+            Read<Animator>,
+            Tagged<Handle<SkinnedModel>>,
+        )>::query();
+        for chunk in query.par_iter_chunks(world) {
+            // This is guaranteed to be the same for each chunk
+            let model = chunk.tag::<Handle<SkinnedModel>>().unwrap();
+            let offset = *offset_map.get(model).unwrap_or(&0);
+            let transforms = chunk.components::<GlobalTransform>().unwrap();
+            offset_map.insert(model.clone(), offset + transforms.len());
+            if let Some(model) = asset_storage.get(model) {
+                runner.draw_skinned_untextured(
+                    model,
+                    offset as u32..(offset + transforms.len()) as u32,
+                    1,
+                );
+            }
+        }
+    }
+
+    // Returns the blurred ambient occlusion, it's cleared to white if ssao is disabled
+    pub fn render<'a>(
+        &self,
+        resources: &Resources,
+        encoder: &mut CommandEncoder,
+        targets: &'a SceneTargets,
+    ) -> &'a SampledTarget {
+        let ssao = resources.get::<Ssao>().expect("Ssao not registered");
+        if !ssao.enabled {
+            encoder.begin_render_pass(&RenderPassDescriptor {
+                color_attachments: &[color_attachment(
+                    targets.ambient_occlusion(1).view(),
+                    LoadOp::Clear(wgpu::Color::WHITE),
+                )],
+                depth_stencil_attachment: None,
+            });
+            return targets.ambient_occlusion(1);
+        }
+        {
+            let mut runner = self.ssao_node.runner(
+                encoder,
+                RenderPassDescriptor {
+                    color_attachments: &[color_attachment(
+                        targets.ambient_occlusion(0).view(),
+                        LoadOp::Clear(wgpu::Color::WHITE),
+                    )],
+                    depth_stencil_attachment: None,
+                },
+            );
+            runner.set_texture_data(0, &targets.depth_normals().textures);
+            // the fullscreen triangle is hardcoded into the vertex shader
+            runner.draw(0..3, 0..1);
+        }
+        let mut runner = self.blur_node.runner(
+            encoder,
+            RenderPassDescriptor {
+                color_attachments: &[color_attachment(
+                    targets.ambient_occlusion(1).view(),
+                    LoadOp::Clear(wgpu::Color::WHITE),
+                )],
+                depth_stencil_attachment: None,
+            },
+        );
+        runner.set_texture_data(0, targets.ambient_occlusion(0).texture());
+        runner.draw(0..3, 0..1);
+        targets.ambient_occlusion(1)
+    }
+}
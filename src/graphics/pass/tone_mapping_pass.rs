@@ -0,0 +1,121 @@
+use anyhow::Result;
+use legion::prelude::*;
+use smol_renderer::{
+    FragmentShader, GpuData, RenderNode, TextureData, UniformBindGroup, VertexShader,
+};
+use wgpu::{CommandEncoder, Device, RenderPassDescriptor, ShaderStage, TextureFormat};
+
+use crate::graphics::hdr_texture::HdrTexture;
+
+use super::post_process_pass::Bloom;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToneMappingOperator {
+    // Only clamps, equivalent to rendering straight to the swap chain
+    Clamp,
+    Reinhard,
+    Aces,
+}
+
+// Resource controlling how the hdr scene is mapped to the display
+pub struct ToneMapping {
+    pub operator: ToneMappingOperator,
+    pub exposure: f32,
+    // Display gamma, 2.2 matches the srgb encoding of the swap chain
+    pub gamma: f32,
+}
+
+impl Default for ToneMapping {
+    fn default() -> Self {
+        ToneMapping {
+            operator: ToneMappingOperator::Aces,
+            exposure: 1.0,
+            gamma: 2.2,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, GpuData)]
+pub struct ToneMappingUniforms {
+    exposure: f32,
+    gamma: f32,
+    operator: i32,
+    bloom_intensity: f32,
+}
+
+impl From<(&ToneMapping, &Bloom)> for ToneMappingUniforms {
+    fn from((tone_mapping, bloom): (&ToneMapping, &Bloom)) -> Self {
+        ToneMappingUniforms {
+            exposure: tone_mapping.exposure,
+            gamma: tone_mapping.gamma,
+            operator: match tone_mapping.operator {
+                ToneMappingOperator::Clamp => 0,
+                ToneMappingOperator::Reinhard => 1,
+                ToneMappingOperator::Aces => 2,
+            },
+            bloom_intensity: if bloom.enabled { bloom.intensity } else { 0.0 },
+        }
+    }
+}
+
+pub struct ToneMappingPass {
+    render_node: RenderNode,
+}
+
+impl ToneMappingPass {
+    pub fn new(device: &Device, color_format: TextureFormat) -> Result<Self> {
+        let render_node = RenderNode::builder()
+            .set_vertex_shader(VertexShader::new(
+                device,
+                "src/shader_files/vs_fullscreen.shader",
+            )?)
+            .set_fragment_shader(FragmentShader::new(
+                device,
+                "src/shader_files/fs_tone_mapping.shader",
+            )?)
+            // scene
+            .add_texture::<HdrTexture>()
+            // bloom
+            .add_texture::<HdrTexture>()
+            .add_default_color_state_desc(color_format)
+            .set_default_rasterization_state()
+            .add_local_uniform_bind_group(
+                UniformBindGroup::with_name("Tone mapping uniform")
+                    .add_binding::<ToneMappingUniforms>(ShaderStage::FRAGMENT)?
+                    .build(device),
+            )
+            .build(device)?;
+        Ok(ToneMappingPass { render_node })
+    }
+
+    pub fn update_uniforms(
+        &self,
+        device: &Device,
+        resources: &Resources,
+        encoder: &mut CommandEncoder,
+    ) {
+        let tone_mapping = resources
+            .get::<ToneMapping>()
+            .expect("Tone mapping not registered");
+        let bloom = resources.get::<Bloom>().expect("Bloom not registered");
+        let uniforms: ToneMappingUniforms = (&*tone_mapping, &*bloom).into();
+        self.render_node
+            .update(device, encoder, 0, &uniforms)
+            .unwrap();
+    }
+
+    pub fn render<'encoder>(
+        &'encoder self,
+        hdr_texture: &'encoder TextureData<HdrTexture>,
+        bloom_texture: &'encoder TextureData<HdrTexture>,
+        encoder: &mut CommandEncoder,
+        render_pass_descriptor: RenderPassDescriptor,
+    ) {
+        let mut runner = self.render_node.runner(encoder, render_pass_descriptor);
+        runner.set_texture_data(0, hdr_texture);
+        runner.set_texture_data(1, bloom_texture);
+        // the fullscreen triangle is hardcoded into the vertex shader
+        runner.draw(0..3, 0..1);
+    }
+}
@@ -0,0 +1,268 @@
+use std::{collections::HashMap, rc::Rc, sync::Arc};
+
+use anyhow::Result;
+use legion::prelude::*;
+use smol_renderer::{
+    FragmentShader, RenderNode, SimpleTexture, TextureData, UniformBindGroup, VertexShader,
+};
+use wgpu::{
+    CommandEncoder, Device, LoadOp, Operations, RenderPassColorAttachmentDescriptor,
+    RenderPassDepthStencilAttachmentDescriptor, RenderPassDescriptor, TextureFormat,
+};
+
+use crate::{
+    assets::{Assets, Handle},
+    camera::Camera,
+    components::GlobalTransform,
+    graphics::{
+        clustered_lights::{ClusterTextures, ClusteredLights},
+        environment_map::{EnvironmentMap, EnvironmentTextures},
+        hdr_texture::{HdrTexture, SceneTargets},
+        model::{DrawModel, InstanceData, Material, Mesh, MeshVertex, Model},
+        pbr_material::PbrTextures,
+        phong_material::PhongTextures,
+        shader_include::expand_includes,
+        shadow_texture::{ShadowMaps, ShadowTexture},
+        wgpu_renderer::DEPTH_FORMAT,
+    },
+};
+
+fn alpha_blend_state(format: TextureFormat) -> wgpu::ColorStateDescriptor {
+    wgpu::ColorStateDescriptor {
+        format,
+        color_blend: wgpu::BlendDescriptor {
+            src_factor: wgpu::BlendFactor::SrcAlpha,
+            dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+            operation: wgpu::BlendOperation::Add,
+        },
+        alpha_blend: wgpu::BlendDescriptor {
+            src_factor: wgpu::BlendFactor::One,
+            dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+            operation: wgpu::BlendOperation::Add,
+        },
+        write_mask: wgpu::ColorWrite::ALL,
+    }
+}
+
+// Blended surfaces are tested against the opaque depth but don't write to it,
+// otherwise they would hide the blended surfaces behind them
+fn read_only_depth_state() -> wgpu::DepthStencilStateDescriptor {
+    wgpu::DepthStencilStateDescriptor {
+        format: DEPTH_FORMAT,
+        depth_write_enabled: false,
+        depth_compare: wgpu::CompareFunction::Less,
+        stencil_front: wgpu::StencilStateFaceDescriptor::IGNORE,
+        stencil_back: wgpu::StencilStateFaceDescriptor::IGNORE,
+        stencil_read_mask: 0,
+        stencil_write_mask: 0,
+    }
+}
+
+struct TransparentDraw<'a> {
+    distance: f32,
+    model: &'a Model,
+    mesh: &'a Mesh,
+    material: &'a Material,
+    instance: u32,
+}
+
+// Draws the meshes with blended materials on top of the opaque geometry. Every instance
+// is drawn on its own, sorted back to front by its distance to the camera, and the
+// forward shaders are reused so both render paths share this pass
+pub struct TransparentPass {
+    shadow_maps: Rc<ShadowMaps>,
+    environment_map: Rc<EnvironmentMap>,
+    clustered_lights: Rc<ClusteredLights>,
+    phong_node: RenderNode,
+    pbr_node: RenderNode,
+}
+
+impl TransparentPass {
+    pub fn new(
+        device: &Device,
+        global_uniforms: Vec<Arc<UniformBindGroup>>,
+        shadow_maps: Rc<ShadowMaps>,
+        environment_map: Rc<EnvironmentMap>,
+        clustered_lights: Rc<ClusteredLights>,
+        color_format: TextureFormat,
+        sample_count: u32,
+    ) -> Result<Self> {
+        let phong_node = RenderNode::builder()
+            .add_vertex_buffer::<MeshVertex>()
+            .add_vertex_buffer::<InstanceData>()
+            .set_vertex_shader(VertexShader::new(
+                device,
+                "src/shader_files/vs_model.shader",
+            )?)
+            .set_fragment_shader(FragmentShader::new(
+                device,
+                &expand_includes("src/shader_files/fs_model.shader")?,
+            )?)
+            // diffuse
+            .add_texture::<SimpleTexture>()
+            // specular
+            .add_texture::<SimpleTexture>()
+            // emissive map and factors
+            .add_texture::<PhongTextures>()
+            .add_texture::<ShadowTexture>()
+            .add_texture::<EnvironmentTextures>()
+            .add_texture::<ClusterTextures>()
+            .add_texture::<HdrTexture>()
+            .add_color_state_desc(alpha_blend_state(color_format))
+            .set_depth_stencil_state(read_only_depth_state())
+            .set_default_rasterization_state()
+            .set_sample_count(sample_count)
+            // camera
+            .add_shared_uniform_bind_group(global_uniforms[0].clone())
+            .build(&device)?;
+        let pbr_node = RenderNode::builder()
+            .add_vertex_buffer::<MeshVertex>()
+            .add_vertex_buffer::<InstanceData>()
+            .set_vertex_shader(VertexShader::new(device, "src/shader_files/vs_pbr.shader")?)
+            .set_fragment_shader(FragmentShader::new(
+                device,
+                &expand_includes("src/shader_files/fs_pbr.shader")?,
+            )?)
+            // material maps and factors
+            .add_texture::<PbrTextures>()
+            .add_texture::<ShadowTexture>()
+            .add_texture::<EnvironmentTextures>()
+            .add_texture::<ClusterTextures>()
+            .add_texture::<HdrTexture>()
+            .add_color_state_desc(alpha_blend_state(color_format))
+            .set_depth_stencil_state(read_only_depth_state())
+            .set_default_rasterization_state()
+            .set_sample_count(sample_count)
+            // camera
+            .add_shared_uniform_bind_group(global_uniforms[0].clone())
+            .build(&device)?;
+
+        Ok(Self {
+            shadow_maps,
+            environment_map,
+            clustered_lights,
+            phong_node,
+            pbr_node,
+        })
+    }
+
+    // The instance indices match the instance buffers written by the ModelPass
+    fn sorted_draws<'a>(
+        asset_storage: &'a Assets<Model>,
+        world: &World,
+        camera: &Camera,
+    ) -> Vec<TransparentDraw<'a>> {
+        let camera_position = camera.get_vec_position();
+        let mut draws = Vec::new();
+        let mut offset_map = HashMap::new();
+        let query = <(Read<GlobalTransform>, Tagged<Handle<Model>>)>::query();
+        for chunk in query.par_iter_chunks(world) {
+            // This is guaranteed to be the same for each chunk
+            let handle = chunk.tag::<Handle<Model>>().unwrap();
+            let offset = *offset_map.get(handle).unwrap_or(&0);
+            let transforms = chunk.components::<GlobalTransform>().unwrap();
+            offset_map.insert(handle.clone(), offset + transforms.len());
+            // the offsets still count models that aren't loaded, like the ModelPass does
+            let model = match asset_storage.get(handle) {
+                Some(model) => model,
+                None => continue,
+            };
+            for mesh in &model.meshes {
+                let material = &model.materials[mesh.material];
+                if !material.blend_mode.is_blended() {
+                    continue;
+                }
+                for (i, transform) in transforms.iter().enumerate() {
+                    draws.push(TransparentDraw {
+                        distance: (transform.translation() - camera_position).norm(),
+                        model,
+                        mesh,
+                        material,
+                        instance: (offset + i) as u32,
+                    });
+                }
+            }
+        }
+        // back to front
+        draws.sort_by(|a, b| {
+            b.distance
+                .partial_cmp(&a.distance)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        draws
+    }
+
+    // A render pass is started each time the shading model changes
+    // so the back to front order holds across phong and pbr materials
+    pub fn render<'encoder>(
+        &'encoder self,
+        resources: &'encoder Resources,
+        world: &World,
+        camera: &Camera,
+        ambient_occlusion: &'encoder TextureData<HdrTexture>,
+        encoder: &mut CommandEncoder,
+        targets: &SceneTargets,
+    ) {
+        let asset_storage = resources
+            .get::<Assets<Model>>()
+            .expect("Asset not registerd");
+        let draws = Self::sorted_draws(&asset_storage, world, camera);
+        let mut start = 0;
+        while start < draws.len() {
+            let pbr = draws[start].material.pbr.is_some();
+            let end = draws[start..]
+                .iter()
+                .position(|draw| draw.material.pbr.is_some() != pbr)
+                .map_or(draws.len(), |len| start + len);
+            let batch = &draws[start..end];
+            start = end;
+            let (render_node, first_shared_texture) = if pbr {
+                (&self.pbr_node, 1)
+            } else {
+                (&self.phong_node, 3)
+            };
+            let mut runner = render_node.runner(
+                encoder,
+                RenderPassDescriptor {
+                    color_attachments: &[RenderPassColorAttachmentDescriptor {
+                        attachment: targets.color_attachment(),
+                        resolve_target: targets.resolve_target(),
+                        ops: Operations {
+                            load: LoadOp::Load,
+                            store: true,
+                        },
+                    }],
+                    depth_stencil_attachment: Some(RenderPassDepthStencilAttachmentDescriptor {
+                        attachment: targets.scene_depth_view(),
+                        depth_ops: Some(Operations {
+                            load: LoadOp::Load,
+                            store: true,
+                        }),
+                        stencil_ops: None,
+                    }),
+                },
+            );
+            runner.set_texture_data(first_shared_texture, &self.shadow_maps.textures);
+            runner.set_texture_data(first_shared_texture + 1, &self.environment_map.textures);
+            runner.set_texture_data(first_shared_texture + 2, &self.clustered_lights.textures);
+            runner.set_texture_data(first_shared_texture + 3, ambient_occlusion);
+            for draw in batch {
+                let instances = draw.instance..draw.instance + 1;
+                match &draw.material.pbr {
+                    Some(pbr) => runner.draw_pbr_mesh_instanced(
+                        draw.mesh,
+                        pbr,
+                        &draw.model.instance_buffer,
+                        instances,
+                    ),
+                    None => runner.draw_mesh_instanced(
+                        draw.mesh,
+                        draw.material,
+                        &draw.model.instance_buffer,
+                        instances,
+                    ),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use anyhow::Result;
+use nalgebra::{Vector3, Vector4};
+use once_cell::sync::OnceCell;
+use smol_renderer::{TextureData, TextureShaderLayout};
+use std::path::Path;
+use wgpu::{
+    AddressMode, BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, Binding,
+    BindingResource, BindingType, BufferUsage, Device, Extent3d, FilterMode, Origin3d, Queue,
+    ShaderStage, TextureComponentType, TextureCopyView, TextureDataLayout, TextureDimension,
+    TextureFormat, TextureUsage, TextureViewDimension,
+};
+
+use super::model::{is_unlit, BlendMode};
+
+const PBR_TEXTURE_COUNT: u32 = 5;
+
+// Per material constants, multiplied with the texture values in the shader
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct PbrFactors {
+    pub albedo: Vector4<f32>,
+    pub emissive: Vector3<f32>,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub ambient_occlusion: f32,
+    // fragments with a lower albedo alpha are discarded, 0 keeps every fragment
+    pub alpha_cutoff: f32,
+    // skips the lighting, only the albedo and emissive colors are shown
+    pub unlit: i32,
+}
+
+impl Default for PbrFactors {
+    fn default() -> Self {
+        PbrFactors {
+            albedo: Vector4::new(1.0, 1.0, 1.0, 1.0),
+            emissive: Vector3::new(0.0, 0.0, 0.0),
+            metallic: 0.0,
+            roughness: 0.5,
+            ambient_occlusion: 1.0,
+            alpha_cutoff: 0.0,
+            unlit: 0,
+        }
+    }
+}
+
+// Albedo, metallic, roughness, ambient occlusion and emissive maps together
+// with the material factors in a single bind group
+pub struct PbrTextures;
+
+impl TextureShaderLayout for PbrTextures {
+    fn get_layout(device: &Device) -> &'static BindGroupLayout {
+        static LAYOUT: OnceCell<BindGroupLayout> = OnceCell::new();
+        LAYOUT.get_or_init(|| {
+            let mut bindings = (0..PBR_TEXTURE_COUNT)
+                .map(|binding| {
+                    BindGroupLayoutEntry::new(
+                        binding,
+                        ShaderStage::FRAGMENT,
+                        BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: TextureViewDimension::D2,
+                            component_type: TextureComponentType::Float,
+                        },
+                    )
+                })
+                .collect::<Vec<_>>();
+            bindings.push(BindGroupLayoutEntry::new(
+                PBR_TEXTURE_COUNT,
+                ShaderStage::FRAGMENT,
+                BindingType::Sampler { comparison: false },
+            ));
+            bindings.push(BindGroupLayoutEntry::new(
+                PBR_TEXTURE_COUNT + 1,
+                ShaderStage::FRAGMENT,
+                BindingType::UniformBuffer {
+                    dynamic: false,
+                    min_binding_size: None,
+                },
+            ));
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                bindings: &bindings,
+                label: Some("Pbr Textures layout"),
+            })
+        })
+    }
+}
+
+pub(crate) fn upload_texture(
+    device: &Device,
+    queue: &Queue,
+    width: u32,
+    height: u32,
+    format: TextureFormat,
+    pixels: &[u8],
+) -> wgpu::Texture {
+    let size = Extent3d {
+        width,
+        height,
+        depth: 1,
+    };
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Pbr map"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format,
+        usage: TextureUsage::SAMPLED | TextureUsage::COPY_DST,
+    });
+    queue.write_texture(
+        TextureCopyView {
+            texture: &texture,
+            mip_level: 0,
+            origin: Origin3d::ZERO,
+        },
+        pixels,
+        TextureDataLayout {
+            offset: 0,
+            bytes_per_row: 4 * width,
+            rows_per_image: 0,
+        },
+        size,
+    );
+    texture
+}
+
+// Missing maps are replaced by a white pixel so only the factor is used
+pub(crate) fn load_map(
+    device: &Device,
+    queue: &Queue,
+    path: Option<&Path>,
+    format: TextureFormat,
+) -> Result<wgpu::Texture> {
+    match path {
+        Some(path) => {
+            let image = image::open(path)?.to_rgba();
+            let (width, height) = image.dimensions();
+            Ok(upload_texture(
+                device,
+                queue,
+                width,
+                height,
+                format,
+                &image.into_raw(),
+            ))
+        }
+        None => Ok(upload_texture(
+            device,
+            queue,
+            1,
+            1,
+            format,
+            &[255, 255, 255, 255],
+        )),
+    }
+}
+
+pub(crate) fn parse_vec3(value: &str) -> Option<Vector3<f32>> {
+    let components = value
+        .split_whitespace()
+        .map(str::parse::<f32>)
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    match components.as_slice() {
+        [r, g, b] => Some(Vector3::new(*r, *g, *b)),
+        [value] => Some(Vector3::new(*value, *value, *value)),
+        _ => None,
+    }
+}
+
+pub struct PbrMaterial {
+    pub factors: PbrFactors,
+    pub textures: TextureData<PbrTextures>,
+    // the bind group only keeps views, the textures themselves must outlive it
+    _maps: Vec<wgpu::Texture>,
+    _factor_buffer: wgpu::Buffer,
+}
+
+impl PbrMaterial {
+    // Materials using the pbr extension of the mtl format (Pr/Pm/Ke and their maps)
+    // are rendered with the pbr pass, all others fall back to phong shading
+    pub fn is_pbr(material: &tobj::Material) -> bool {
+        ["Pr", "Pm", "map_Pr", "map_Pm"]
+            .iter()
+            .any(|key| material.unknown_param.contains_key(*key))
+    }
+
+    pub fn from_obj_material(
+        device: &Device,
+        queue: &Queue,
+        material: &tobj::Material,
+        folder: &Path,
+    ) -> Result<Self> {
+        let params = &material.unknown_param;
+        let float_param = |key: &str, default: f32| {
+            params
+                .get(key)
+                .and_then(|value| value.trim().parse::<f32>().ok())
+                .unwrap_or(default)
+        };
+        let map_path = |key: &str| params.get(key).map(|path| folder.join(path.trim()));
+        let albedo_path = if material.diffuse_texture.is_empty() {
+            None
+        } else {
+            Some(folder.join(&material.diffuse_texture))
+        };
+
+        let factors = PbrFactors {
+            albedo: Vector4::new(
+                material.diffuse[0],
+                material.diffuse[1],
+                material.diffuse[2],
+                material.dissolve,
+            ),
+            emissive: params
+                .get("Ke")
+                .and_then(|value| parse_vec3(value))
+                .unwrap_or_else(Vector3::zeros),
+            metallic: float_param("Pm", 0.0),
+            roughness: float_param("Pr", 0.5),
+            ambient_occlusion: 1.0,
+            alpha_cutoff: BlendMode::from_obj_material(material).alpha_cutoff(),
+            unlit: is_unlit(material) as i32,
+            ..PbrFactors::default()
+        };
+        let maps = vec![
+            load_map(
+                device,
+                queue,
+                albedo_path.as_deref(),
+                TextureFormat::Rgba8UnormSrgb,
+            )?,
+            load_map(
+                device,
+                queue,
+                map_path("map_Pm").as_deref(),
+                TextureFormat::Rgba8Unorm,
+            )?,
+            load_map(
+                device,
+                queue,
+                map_path("map_Pr").as_deref(),
+                TextureFormat::Rgba8Unorm,
+            )?,
+            load_map(
+                device,
+                queue,
+                map_path("map_ao").as_deref(),
+                TextureFormat::Rgba8Unorm,
+            )?,
+            load_map(
+                device,
+                queue,
+                map_path("map_Ke").as_deref(),
+                TextureFormat::Rgba8UnormSrgb,
+            )?,
+        ];
+        Ok(Self::new(device, factors, maps))
+    }
+
+    // maps must be ordered albedo, metallic, roughness, ambient occlusion, emissive
+    pub fn new(device: &Device, factors: PbrFactors, mut maps: Vec<wgpu::Texture>) -> Self {
+        assert!(
+            maps.len() == PBR_TEXTURE_COUNT as usize,
+            "Pbr materials need exactly 5 maps"
+        );
+        let factor_bytes = unsafe {
+            std::slice::from_raw_parts(
+                &factors as *const PbrFactors as *const u8,
+                std::mem::size_of::<PbrFactors>(),
+            )
+        };
+        let factor_buffer = device.create_buffer_with_data(factor_bytes, BufferUsage::UNIFORM);
+        let views = maps
+            .iter()
+            .map(|map| map.create_default_view())
+            .collect::<Vec<_>>();
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Pbr sampler"),
+            address_mode_u: AddressMode::Repeat,
+            address_mode_v: AddressMode::Repeat,
+            address_mode_w: AddressMode::Repeat,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Nearest,
+            lod_min_clamp: 0.0,
+            lod_max_clamp: 100.0,
+            compare: None,
+            ..Default::default()
+        });
+        let mut bindings = views
+            .iter()
+            .enumerate()
+            .map(|(i, view)| Binding {
+                binding: i as u32,
+                resource: BindingResource::TextureView(view),
+            })
+            .collect::<Vec<_>>();
+        bindings.push(Binding {
+            binding: PBR_TEXTURE_COUNT,
+            resource: BindingResource::Sampler(&sampler),
+        });
+        bindings.push(Binding {
+            binding: PBR_TEXTURE_COUNT + 1,
+            resource: BindingResource::Buffer(factor_buffer.slice(..)),
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: PbrTextures::get_layout(device),
+            bindings: &bindings,
+            label: Some("Pbr textures bindgroup"),
+        });
+        let albedo = maps.remove(0);
+        PbrMaterial {
+            factors,
+            textures: TextureData::new(bind_group, albedo, views, sampler),
+            _maps: maps,
+            _factor_buffer: factor_buffer,
+        }
+    }
+}
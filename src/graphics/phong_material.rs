@@ -0,0 +1,162 @@
+use anyhow::Result;
+use nalgebra::Vector3;
+use once_cell::sync::OnceCell;
+use smol_renderer::{TextureData, TextureShaderLayout};
+use std::path::Path;
+use wgpu::{
+    AddressMode, BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, Binding,
+    BindingResource, BindingType, BufferUsage, Device, FilterMode, Queue, ShaderStage,
+    TextureComponentType, TextureFormat, TextureViewDimension,
+};
+
+use super::{
+    model::{is_unlit, BlendMode},
+    pbr_material::{load_map, parse_vec3},
+};
+
+// Per material constants of the phong materials, the diffuse and specular
+// textures are bound separately so they can be swapped for render targets
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct PhongFactors {
+    // multiplied with the emissive map
+    pub emissive: Vector3<f32>,
+    // multiplied with the alpha of the diffuse texture
+    pub dissolve: f32,
+    // fragments with a lower alpha are discarded, 0 keeps every fragment
+    pub alpha_cutoff: f32,
+    // skips the lighting, only the diffuse and emissive colors are shown
+    pub unlit: i32,
+    _pad: [f32; 2],
+}
+
+impl Default for PhongFactors {
+    fn default() -> Self {
+        PhongFactors {
+            emissive: Vector3::zeros(),
+            dissolve: 1.0,
+            alpha_cutoff: 0.0,
+            unlit: 0,
+            _pad: [0.0; 2],
+        }
+    }
+}
+
+// Emissive map and the material factors in a single bind group
+pub struct PhongTextures;
+
+impl TextureShaderLayout for PhongTextures {
+    fn get_layout(device: &Device) -> &'static BindGroupLayout {
+        static LAYOUT: OnceCell<BindGroupLayout> = OnceCell::new();
+        LAYOUT.get_or_init(|| {
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                bindings: &[
+                    BindGroupLayoutEntry::new(
+                        0,
+                        ShaderStage::FRAGMENT,
+                        BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: TextureViewDimension::D2,
+                            component_type: TextureComponentType::Float,
+                        },
+                    ),
+                    BindGroupLayoutEntry::new(
+                        1,
+                        ShaderStage::FRAGMENT,
+                        BindingType::Sampler { comparison: false },
+                    ),
+                    BindGroupLayoutEntry::new(
+                        2,
+                        ShaderStage::FRAGMENT,
+                        BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                        },
+                    ),
+                ],
+                label: Some("Phong textures layout"),
+            })
+        })
+    }
+}
+
+pub struct PhongMaterial {
+    pub factors: PhongFactors,
+    pub textures: TextureData<PhongTextures>,
+    _factor_buffer: wgpu::Buffer,
+}
+
+impl PhongMaterial {
+    // Emission is read from Ke and map_Ke like the pbr materials
+    pub fn from_obj_material(
+        device: &Device,
+        queue: &Queue,
+        material: &tobj::Material,
+        folder: &Path,
+        blend_mode: BlendMode,
+    ) -> Result<Self> {
+        let params = &material.unknown_param;
+        let emissive_map = params.get("map_Ke").map(|path| folder.join(path.trim()));
+        let factors = PhongFactors {
+            emissive: params
+                .get("Ke")
+                .and_then(|value| parse_vec3(value))
+                .unwrap_or_else(Vector3::zeros),
+            dissolve: material.dissolve,
+            alpha_cutoff: blend_mode.alpha_cutoff(),
+            unlit: is_unlit(material) as i32,
+            ..PhongFactors::default()
+        };
+        let emissive_map = load_map(
+            device,
+            queue,
+            emissive_map.as_deref(),
+            TextureFormat::Rgba8UnormSrgb,
+        )?;
+        Ok(Self::new(device, factors, emissive_map))
+    }
+
+    pub fn new(device: &Device, factors: PhongFactors, emissive_map: wgpu::Texture) -> Self {
+        let factor_bytes = unsafe {
+            std::slice::from_raw_parts(
+                &factors as *const PhongFactors as *const u8,
+                std::mem::size_of::<PhongFactors>(),
+            )
+        };
+        let factor_buffer = device.create_buffer_with_data(factor_bytes, BufferUsage::UNIFORM);
+        let view = emissive_map.create_default_view();
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Phong sampler"),
+            address_mode_u: AddressMode::Repeat,
+            address_mode_v: AddressMode::Repeat,
+            address_mode_w: AddressMode::Repeat,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Nearest,
+            ..Default::default()
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: PhongTextures::get_layout(device),
+            bindings: &[
+                Binding {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&view),
+                },
+                Binding {
+                    binding: 1,
+                    resource: BindingResource::Sampler(&sampler),
+                },
+                Binding {
+                    binding: 2,
+                    resource: BindingResource::Buffer(factor_buffer.slice(..)),
+                },
+            ],
+            label: Some("Phong textures bindgroup"),
+        });
+        PhongMaterial {
+            factors,
+            textures: TextureData::new(bind_group, emissive_map, vec![view], sampler),
+            _factor_buffer: factor_buffer,
+        }
+    }
+}
@@ -1,67 +1,105 @@
-use nalgebra::{geometry::Orthographic3, Matrix4, Point3, Vector3};
-use once_cell::sync::Lazy;
+use nalgebra::{geometry::Perspective3, Matrix4, Point3, Vector3};
+use serde::{Deserialize, Serialize};
 use smol_renderer::GpuData;
+use std::f32::consts::FRAC_PI_2;
 
-static DIRECTIONAL_PROJECTION: Lazy<Orthographic3<f32>> =
-    Lazy::new(|| Orthographic3::new(-10.0, 10.0, -10.0, 10.0, 1.0, 100.0));
+pub const SHADOW_NEAR_PLANE: f32 = 0.1;
 
+// Look direction and up vector for each face, in the layer order of a cubemap
+const CUBE_FACE_ORIENTATIONS: [([f32; 3], [f32; 3]); 6] = [
+    ([1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+    ([-1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+    ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, -1.0, 0.0], [0.0, 0.0, -1.0]),
+    ([0.0, 0.0, 1.0], [0.0, -1.0, 0.0]),
+    ([0.0, 0.0, -1.0], [0.0, -1.0, 0.0]),
+];
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct PointLight {
-    pub ambient: Vector3<f32>,
+    // phong lighting terms, ambient light comes from the environment map
     pub specular: Vector3<f32>,
     pub diffuse: Vector3<f32>,
     pub constant: f32,
     pub linear: f32,
     pub quadratic: f32,
-    pub target_view: Option<wgpu::TextureView>,
+    // physically based lighting, the pbr pass uses inverse square falloff
+    pub color: Vector3<f32>,
+    // luminous intensity in candela
+    pub intensity: f32,
+    // the light fades out smoothly and has no effect past this distance
+    pub range: f32,
+    // lights that don't cast shadows don't take up a slot in the shadow atlas
+    pub cast_shadows: bool,
+    // nothing further away than this casts shadows from the light
+    pub shadow_far_plane: f32,
+    // lights asking for more texels than the regular cube maps have get a high
+    // resolution one while there are any left
+    pub shadow_resolution: u32,
+    // world space offset against shadow acne, scaled up on steep surfaces
+    pub shadow_bias: f32,
+    // radius of the light source, controls the penumbra size of pcss shadows
+    pub light_size: f32,
+}
+
+impl PointLight {
+    // View projection matrices for rendering the six faces of the shadow cubemap
+    pub fn shadow_view_projections(&self, position: Vector3<f32>) -> Vec<Matrix4<f32>> {
+        let projection =
+            Perspective3::new(1.0, FRAC_PI_2, SHADOW_NEAR_PLANE, self.shadow_far_plane)
+                .to_homogeneous();
+        // Cubemaps follow the opengl convention where the first row is at the bottom
+        // of the framebuffer, wgpu renders it at the top so y needs to be flipped
+        let flip_y = Matrix4::new_nonuniform_scaling(&Vector3::new(1.0, -1.0, 1.0));
+        let eye = Point3::from(position);
+        CUBE_FACE_ORIENTATIONS
+            .iter()
+            .map(|(direction, up)| {
+                let target = eye + Vector3::from(*direction);
+                flip_y * projection * Matrix4::look_at_rh(&eye, &target, &Vector3::from(*up))
+            })
+            .collect()
+    }
 }
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Default, GpuData)]
 pub struct PointLightRaw {
     position: [f32; 3],
-    _pad: f32,
-    ambient: [f32; 3],
-    _pad1: f32,
+    shadow_far_plane: f32,
     specular: [f32; 3],
-    _pad2: f32,
+    shadow_bias: f32,
     diffuse: [f32; 3],
     constant: f32,
     linear: f32,
     quadratic: f32,
-    _pad3: f32,
-    _pad4: f32,
-    pub light_space_matrix: [[f32; 4]; 4], //todo are these really necessary if you don't use as bytes anyways?
+    light_size: f32,
+    range: f32,
+    color: [f32; 3],
+    intensity: f32,
+    // slot of the shadow cube map or -1 when the light has no shadow
+    shadow_index: i32,
+    _pad: [f32; 3],
 }
 
-impl From<(&PointLight, Vector3<f32>)> for PointLightRaw {
-    fn from((light, position): (&PointLight, Vector3<f32>)) -> Self {
-        let view = Matrix4::look_at_rh(
-            &Point3::new(position.x, position.y, position.z),
-            &Point3::new(0.0, 0.0, 0.0),
-            &Vector3::y(),
-        );
-        // TODO: This shouldn't be Orthographic projection
-        let light_space_matrix = DIRECTIONAL_PROJECTION.to_homogeneous() * view;
-        let projection = light_space_matrix
-            .as_slice()
-            .chunks(4)
-            .map(|chunk| [chunk[0], chunk[1], chunk[2], chunk[3]])
-            .collect::<Vec<[f32; 4]>>();
-
+impl From<(&PointLight, Vector3<f32>, Option<u32>)> for PointLightRaw {
+    fn from((light, position, shadow_index): (&PointLight, Vector3<f32>, Option<u32>)) -> Self {
         PointLightRaw {
             position: [position.x, position.y, position.z],
-            ambient: [light.ambient.x, light.ambient.y, light.ambient.z],
+            shadow_far_plane: light.shadow_far_plane,
             specular: [light.specular.x, light.specular.y, light.specular.z],
             diffuse: [light.diffuse.x, light.diffuse.y, light.diffuse.z],
             constant: light.constant,
             linear: light.linear,
             quadratic: light.quadratic,
-            light_space_matrix: [projection[0], projection[1], projection[2], projection[3]],
-            _pad: 0.0,
-            _pad1: 0.0,
-            _pad2: 0.0,
-            _pad3: 0.0,
-            _pad4: 0.0,
+            color: [light.color.x, light.color.y, light.color.z],
+            intensity: light.intensity,
+            shadow_bias: light.shadow_bias,
+            light_size: light.light_size,
+            range: light.range,
+            shadow_index: shadow_index.map(|index| index as i32).unwrap_or(-1),
+            _pad: [0.0; 3],
         }
     }
 }
@@ -73,13 +111,19 @@ impl Default for PointLight {
         let quadratic = 0.032;
 
         PointLight {
-            ambient: Vector3::new(0.01, 0.01, 0.01),
             specular: Vector3::new(1.0, 1.0, 1.0),
             diffuse: Vector3::new(1.0, 1.0, 1.0),
             constant,
             linear,
             quadratic,
-            target_view: None,
+            color: Vector3::new(1.0, 1.0, 1.0),
+            intensity: 15.0,
+            range: 20.0,
+            cast_shadows: true,
+            shadow_far_plane: 50.0,
+            shadow_resolution: 512,
+            shadow_bias: 0.05,
+            light_size: 0.1,
         }
     }
 }
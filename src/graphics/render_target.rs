@@ -0,0 +1,184 @@
+use crate::camera::Camera;
+use smol_renderer::{SimpleTexture, TextureData, TextureShaderLayout};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use wgpu::{
+    AddressMode, Binding, BindingResource, Device, Extent3d, FilterMode, TextureDimension,
+    TextureFormat, TextureUsage, TextureView, TextureViewDescriptor, TextureViewDimension,
+};
+
+use super::{hdr_texture::SceneTargets, wgpu_renderer::RenderPath};
+
+pub(crate) fn create_color_texture(
+    device: &Device,
+    width: u32,
+    height: u32,
+    format: TextureFormat,
+    usage: TextureUsage,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Render target color texture"),
+        size: Extent3d {
+            width,
+            height,
+            depth: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format,
+        usage,
+    })
+}
+
+// An offscreen target the scene can be rendered into.
+// The color texture is shared so it can be used as a material texture
+// (mirrors, minimaps, security cameras etc)
+pub struct RenderTarget {
+    pub width: u32,
+    pub height: u32,
+    texture: Arc<TextureData<SimpleTexture>>,
+    color_view: TextureView,
+    scene_targets: SceneTargets,
+}
+
+impl RenderTarget {
+    pub fn new(
+        device: &Device,
+        width: u32,
+        height: u32,
+        format: TextureFormat,
+        sample_count: u32,
+        render_path: RenderPath,
+    ) -> Self {
+        let texture = create_color_texture(
+            device,
+            width,
+            height,
+            format,
+            TextureUsage::OUTPUT_ATTACHMENT | TextureUsage::SAMPLED,
+        );
+        let sampled_view = texture.create_default_view();
+        let color_view = texture.create_view(&TextureViewDescriptor {
+            format,
+            dimension: TextureViewDimension::D2,
+            aspect: wgpu::TextureAspect::default(),
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            array_layer_count: 1,
+            label: Some("Render target color view"),
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Render target sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            address_mode_w: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Nearest,
+            lod_min_clamp: 0.0,
+            lod_max_clamp: 100.0,
+            compare: None,
+            ..Default::default()
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: SimpleTexture::get_layout(device),
+            bindings: &[
+                Binding {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&sampled_view),
+                },
+                Binding {
+                    binding: 1,
+                    resource: BindingResource::Sampler(&sampler),
+                },
+            ],
+            label: Some("Render target bindgroup"),
+        });
+        RenderTarget {
+            width,
+            height,
+            texture: Arc::new(TextureData::new(
+                bind_group,
+                texture,
+                vec![sampled_view],
+                sampler,
+            )),
+            color_view,
+            scene_targets: SceneTargets::new(
+                device,
+                width,
+                height,
+                format,
+                sample_count,
+                render_path,
+            ),
+        }
+    }
+
+    // The rendered result, usable as a diffuse or specular texture in a Material
+    pub fn texture(&self) -> Arc<TextureData<SimpleTexture>> {
+        Arc::clone(&self.texture)
+    }
+
+    #[inline]
+    pub fn color_view(&self) -> &TextureView {
+        &self.color_view
+    }
+
+    #[inline]
+    pub fn scene_targets(&self) -> &SceneTargets {
+        &self.scene_targets
+    }
+}
+
+// Named render targets, creation is deferred to the renderer since
+// the device isn't available from gameplay code
+pub struct RenderTargets {
+    targets: HashMap<String, RenderTarget>,
+    create_queue: VecDeque<(String, u32, u32)>,
+}
+
+impl RenderTargets {
+    pub fn new() -> Self {
+        RenderTargets {
+            targets: HashMap::default(),
+            create_queue: VecDeque::default(),
+        }
+    }
+
+    pub fn create(&mut self, name: impl Into<String>, width: u32, height: u32) {
+        self.create_queue.push_back((name.into(), width, height));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&RenderTarget> {
+        self.targets.get(name)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<RenderTarget> {
+        self.targets.remove(name)
+    }
+
+    pub(crate) fn clear_create_queue(
+        &mut self,
+        device: &Device,
+        format: TextureFormat,
+        sample_count: u32,
+        render_path: RenderPath,
+    ) {
+        while let Some((name, width, height)) = self.create_queue.pop_front() {
+            self.targets.insert(
+                name,
+                RenderTarget::new(device, width, height, format, sample_count, render_path),
+            );
+        }
+    }
+}
+
+// Renders the scene from the given camera into the named render target each frame.
+// Note: a model using the target as a texture shouldn't be visible from the camera itself
+pub struct OffscreenCamera {
+    pub camera: Camera,
+    pub target: String,
+}
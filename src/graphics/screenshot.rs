@@ -0,0 +1,134 @@
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use wgpu::{
+    BufferCopyView, BufferDescriptor, BufferUsage, CommandEncoder, Device, Extent3d, Origin3d,
+    TextureCopyView, TextureDataLayout, TextureFormat, TextureUsage, TextureView,
+};
+
+use super::render_target::create_color_texture;
+
+// Screenshot requests, the renderer captures one per frame
+// and writes it to the given path as a png
+#[derive(Default)]
+pub struct ScreenshotQueue {
+    pending: VecDeque<PathBuf>,
+}
+
+impl ScreenshotQueue {
+    pub fn new() -> Self {
+        ScreenshotQueue::default()
+    }
+
+    pub fn request(&mut self, path: impl AsRef<Path>) {
+        self.pending.push_back(PathBuf::from(path.as_ref()));
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<PathBuf> {
+        self.pending.pop_front()
+    }
+}
+
+// The swap chain can't be copied from so the frame is resolved
+// a second time into this target which is then read back to the cpu
+pub struct ScreenshotTarget {
+    width: u32,
+    height: u32,
+    format: TextureFormat,
+    texture: wgpu::Texture,
+    color_view: TextureView,
+    padded_bytes_per_row: u32,
+    buffer: wgpu::Buffer,
+}
+
+const BYTES_PER_PIXEL: u32 = 4;
+
+impl ScreenshotTarget {
+    pub fn new(device: &Device, width: u32, height: u32, format: TextureFormat) -> Self {
+        let texture = create_color_texture(
+            device,
+            width,
+            height,
+            format,
+            TextureUsage::OUTPUT_ATTACHMENT | TextureUsage::COPY_SRC,
+        );
+        let color_view = texture.create_default_view();
+        // rows in buffer copies must be aligned
+        let unpadded_bytes_per_row = width * BYTES_PER_PIXEL;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = (unpadded_bytes_per_row + align - 1) / align * align;
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Screenshot buffer"),
+            size: (padded_bytes_per_row * height) as wgpu::BufferAddress,
+            usage: BufferUsage::MAP_READ | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        ScreenshotTarget {
+            width,
+            height,
+            format,
+            texture,
+            color_view,
+            padded_bytes_per_row,
+            buffer,
+        }
+    }
+
+    #[inline]
+    pub fn color_view(&self) -> &TextureView {
+        &self.color_view
+    }
+
+    pub fn copy_to_buffer(&self, encoder: &mut CommandEncoder) {
+        encoder.copy_texture_to_buffer(
+            TextureCopyView {
+                texture: &self.texture,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+            },
+            BufferCopyView {
+                buffer: &self.buffer,
+                layout: TextureDataLayout {
+                    offset: 0,
+                    bytes_per_row: self.padded_bytes_per_row,
+                    rows_per_image: 0,
+                },
+            },
+            Extent3d {
+                width: self.width,
+                height: self.height,
+                depth: 1,
+            },
+        );
+    }
+
+    // Must be called after the encoder containing the copy has been submitted
+    pub fn save_png(&self, device: &Device, path: impl AsRef<Path>) -> Result<()> {
+        let buffer_slice = self.buffer.slice(..);
+        let mapping = buffer_slice.map_async(wgpu::MapMode::Read);
+        device.poll(wgpu::Maintain::Wait);
+        futures::executor::block_on(mapping)?;
+
+        let unpadded_bytes_per_row = (self.width * BYTES_PER_PIXEL) as usize;
+        let mut pixels = Vec::with_capacity(unpadded_bytes_per_row * self.height as usize);
+        {
+            let padded_data = buffer_slice.get_mapped_range();
+            for row in padded_data.chunks(self.padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..unpadded_bytes_per_row]);
+            }
+        }
+        self.buffer.unmap();
+
+        if let TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb = self.format {
+            pixels.chunks_mut(4).for_each(|pixel| pixel.swap(0, 2));
+        }
+        image::save_buffer(
+            path.as_ref(),
+            &pixels,
+            self.width,
+            self.height,
+            image::ColorType::RGBA(8),
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::HashSet,
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// Replaces lines like #include "lighting.glsl" with the file they name, relative to the
+// shader including it. Shaders are compiled from a path, so the expanded source is written
+// to a directory of this process in the temp directory until the returned copy is dropped
+pub fn expand_includes(path: &str) -> Result<ExpandedShader> {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    let path = Path::new(path);
+    let source = read_expanded(path)?;
+    let directory =
+        std::env::temp_dir().join(format!("smol-engine-shaders-{}", std::process::id()));
+    fs::create_dir_all(&directory)
+        .with_context(|| format!("Can't create the shader directory {:?}", directory))?;
+    let name = path
+        .file_name()
+        .with_context(|| format!("{:?} isn't a shader file", path))?;
+    // shaders expanded at the same time, like the same one for different passes, can't collide
+    let expanded = directory.join(format!(
+        "{}-{}",
+        NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        name.to_string_lossy()
+    ));
+    fs::write(&expanded, source)
+        .with_context(|| format!("Can't write the expanded shader {:?}", expanded))?;
+    Ok(ExpandedShader {
+        path: expanded.to_string_lossy().into_owned(),
+    })
+}
+
+// Path of an expanded shader, it derefs to the path so it can be passed to the shaders
+pub struct ExpandedShader {
+    path: String,
+}
+
+impl Deref for ExpandedShader {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for ExpandedShader {
+    fn drop(&mut self) {
+        let path = Path::new(&self.path);
+        let _ = fs::remove_file(path);
+        // only succeeds once the last shader of the process is gone
+        if let Some(directory) = path.parent() {
+            let _ = fs::remove_dir(directory);
+        }
+    }
+}
+
+fn read_expanded(path: &Path) -> Result<String> {
+    let mut included = HashSet::new();
+    included.insert(path.to_path_buf());
+    expand(path, &mut included)
+}
+
+// Every file is only pasted in once, so includes can't recurse forever
+fn expand(path: &Path, included: &mut HashSet<PathBuf>) -> Result<String> {
+    let text = fs::read_to_string(path).with_context(|| format!("Can't open shader {:?}", path))?;
+    let mut source = String::with_capacity(text.len());
+    for line in text.lines() {
+        match include_path(line) {
+            Some(include) => {
+                let include = path.parent().unwrap_or_else(|| Path::new("")).join(include);
+                if included.insert(include.clone()) {
+                    source.push_str(
+                        &expand(&include, included)
+                            .with_context(|| format!("Included from {:?}", path))?,
+                    );
+                }
+            }
+            None => {
+                source.push_str(line);
+                source.push('\n');
+            }
+        }
+    }
+    Ok(source)
+}
+
+fn include_path(line: &str) -> Option<&str> {
+    line.trim()
+        .strip_prefix("#include")?
+        .trim()
+        .strip_prefix('"')?
+        .strip_suffix('"')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn include_lines_name_the_file() {
+        assert_eq!(
+            include_path("#include \"lighting.glsl\""),
+            Some("lighting.glsl")
+        );
+        assert_eq!(include_path("  #include   \"a/b.glsl\" "), Some("a/b.glsl"));
+        assert_eq!(include_path("#include <lighting.glsl>"), None);
+        assert_eq!(include_path("// #include \"lighting.glsl\""), None);
+    }
+
+    // A directory of the test in this process, removed again when the test is done
+    struct TestDirectory(PathBuf);
+
+    impl TestDirectory {
+        fn new(test: &str) -> Self {
+            let directory =
+                std::env::temp_dir().join(format!("smol-engine-{}-{}", test, std::process::id()));
+            fs::create_dir_all(&directory).unwrap();
+            TestDirectory(directory)
+        }
+    }
+
+    impl Drop for TestDirectory {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn includes_are_pasted_once() {
+        let directory = TestDirectory::new("includes-are-pasted-once");
+        fs::write(
+            directory.0.join("common.glsl"),
+            "#include \"main.shader\"\nshared\n",
+        )
+        .unwrap();
+        fs::write(
+            directory.0.join("main.shader"),
+            "first\n#include \"common.glsl\"\n#include \"common.glsl\"\nlast\n",
+        )
+        .unwrap();
+        let source = read_expanded(&directory.0.join("main.shader")).unwrap();
+        assert_eq!(source, "first\nshared\nlast\n");
+    }
+
+    #[test]
+    fn expanded_copies_are_removed_when_dropped() {
+        let directory = TestDirectory::new("expanded-copies-are-removed");
+        let shader = directory.0.join("main.shader");
+        fs::write(&shader, "main\n").unwrap();
+        let shader = shader.to_string_lossy().into_owned();
+        let first = expand_includes(&shader).unwrap();
+        let second = expand_includes(&shader).unwrap();
+        assert_ne!(&*first, &*second);
+        assert_eq!(fs::read_to_string(&*first).unwrap(), "main\n");
+        let copy = PathBuf::from(&*first);
+        drop(first);
+        assert!(!copy.exists());
+        assert!(Path::new(&*second).exists());
+    }
+}
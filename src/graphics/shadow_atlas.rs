@@ -0,0 +1,172 @@
+use legion::prelude::*;
+use std::collections::HashSet;
+
+use super::{
+    clustered_lights::MAX_SPOT_LIGHTS,
+    shadow_texture::{HIGH_RES_POINT_SHADOWS, MAX_POINT_LIGHT_SHADOWS, SHADOW_SIZE},
+    PointLight, SpotLight,
+};
+
+// Fixed number of shadow map slots, each held by at most one light entity
+struct ShadowSlots {
+    slots: Vec<Option<Entity>>,
+}
+
+impl ShadowSlots {
+    fn new(capacity: u32) -> Self {
+        ShadowSlots {
+            slots: vec![None; capacity as usize],
+        }
+    }
+
+    fn get(&self, entity: Entity) -> Option<u32> {
+        self.slots
+            .iter()
+            .position(|slot| *slot == Some(entity))
+            .map(|slot| slot as u32)
+    }
+
+    // Frees the slots of lights that are gone or stopped casting shadows
+    // and hands out free slots to the new casters, the rest go without.
+    // New casters get the first free slot from their preferred one on,
+    // wrapping around to the start
+    fn update(&mut self, casters: &[(Entity, u32)]) {
+        let caster_set = casters
+            .iter()
+            .map(|(entity, _)| *entity)
+            .collect::<HashSet<_>>();
+        for slot in self.slots.iter_mut() {
+            if matches!(slot, Some(entity) if !caster_set.contains(entity)) {
+                *slot = None;
+            }
+        }
+        for &(caster, preferred) in casters {
+            if self.get(caster).is_some() {
+                continue;
+            }
+            let capacity = self.slots.len();
+            let free = (0..capacity)
+                .map(|offset| (preferred as usize + offset) % capacity)
+                .find(|&slot| self.slots[slot].is_none());
+            if let Some(slot) = free {
+                self.slots[slot] = Some(caster);
+            }
+        }
+    }
+}
+
+// Resource assigning layers of the shadow textures to light entities. Slots
+// stay with a light for as long as it lives so removing other lights
+// doesn't move its shadow map
+pub struct ShadowAtlas {
+    point_lights: ShadowSlots,
+    spot_lights: ShadowSlots,
+}
+
+impl Default for ShadowAtlas {
+    fn default() -> Self {
+        ShadowAtlas {
+            point_lights: ShadowSlots::new(MAX_POINT_LIGHT_SHADOWS),
+            spot_lights: ShadowSlots::new(MAX_SPOT_LIGHTS),
+        }
+    }
+}
+
+impl ShadowAtlas {
+    pub fn update(&mut self, world: &World) {
+        // only lights that need more than the regular layers start at the high resolution slots
+        let point_casters = <Read<PointLight>>::query()
+            .iter_entities(world)
+            .filter(|(_, light)| light.cast_shadows)
+            .map(|(entity, light)| {
+                if light.shadow_resolution > SHADOW_SIZE.width {
+                    (entity, 0)
+                } else {
+                    (entity, HIGH_RES_POINT_SHADOWS)
+                }
+            })
+            .collect::<Vec<_>>();
+        self.point_lights.update(&point_casters);
+        let spot_casters = <Read<SpotLight>>::query()
+            .iter_entities(world)
+            .filter(|(_, light)| light.cast_shadows)
+            .map(|(entity, _)| (entity, 0))
+            .collect::<Vec<_>>();
+        self.spot_lights.update(&spot_casters);
+    }
+
+    // Index of the cube map, the first slots are in the high resolution point light shadow texture
+    pub fn point_light_slot(&self, entity: Entity) -> Option<u32> {
+        self.point_lights.get(entity)
+    }
+
+    // Layer in the spot light shadow texture
+    pub fn spot_light_slot(&self, entity: Entity) -> Option<u32> {
+        self.spot_lights.get(entity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entities(count: usize) -> Vec<Entity> {
+        let mut world = Universe::new().create_world();
+        world
+            .insert((), (0..count).map(|i| (i,)).collect::<Vec<_>>())
+            .to_vec()
+    }
+
+    fn casters(entities: &[Entity], preferred: u32) -> Vec<(Entity, u32)> {
+        entities.iter().map(|&entity| (entity, preferred)).collect()
+    }
+
+    #[test]
+    fn slots_stay_with_their_lights() {
+        let lights = entities(3);
+        let mut slots = ShadowSlots::new(4);
+        slots.update(&casters(&lights, 0));
+        let assigned = lights
+            .iter()
+            .map(|&light| slots.get(light))
+            .collect::<Vec<_>>();
+        assert_eq!(assigned, vec![Some(0), Some(1), Some(2)]);
+
+        slots.update(&casters(&[lights[0], lights[2]], 0));
+        assert_eq!(slots.get(lights[0]), Some(0));
+        assert_eq!(slots.get(lights[1]), None);
+        assert_eq!(slots.get(lights[2]), Some(2));
+    }
+
+    #[test]
+    fn new_lights_take_freed_slots() {
+        let lights = entities(3);
+        let mut slots = ShadowSlots::new(2);
+        slots.update(&casters(&lights[..2], 0));
+        slots.update(&casters(&[lights[1], lights[2]], 0));
+        assert_eq!(slots.get(lights[1]), Some(1));
+        assert_eq!(slots.get(lights[2]), Some(0));
+    }
+
+    #[test]
+    fn lights_start_at_their_preferred_slot_and_wrap_around() {
+        let lights = entities(3);
+        let mut slots = ShadowSlots::new(3);
+        slots.update(&casters(&lights, 1));
+        assert_eq!(slots.get(lights[0]), Some(1));
+        assert_eq!(slots.get(lights[1]), Some(2));
+        assert_eq!(slots.get(lights[2]), Some(0));
+    }
+
+    #[test]
+    fn lights_beyond_the_capacity_wait_for_a_slot() {
+        let lights = entities(3);
+        let mut slots = ShadowSlots::new(2);
+        slots.update(&casters(&lights, 0));
+        assert_eq!(slots.get(lights[2]), None);
+
+        slots.update(&casters(&lights[1..], 0));
+        assert_eq!(slots.get(lights[1]), Some(1));
+        assert_eq!(slots.get(lights[2]), Some(0));
+    }
+}
@@ -1,14 +1,95 @@
-use super::pass::model_pass::MAX_POINT_LIGHTS;
+use super::{clustered_lights::MAX_SPOT_LIGHTS, directional_light::SHADOW_CASCADES};
+use nalgebra::Matrix4;
 use once_cell::sync::OnceCell;
 use smol_renderer::textures::*;
 
 pub const SHADOW_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+pub const SHADOW_CUBE_FACES: u32 = 6;
+// Only this many point lights cast shadows, the rest are lit without them
+pub const MAX_POINT_LIGHT_SHADOWS: u32 = 16;
+// The first slots of the point lights are cube maps in a separate high resolution
+// texture, they go to lights asking for more than the regular layer size while
+// there are any left. Full size layers for every light would take up ~400 MB
+pub const HIGH_RES_POINT_SHADOWS: u32 = 2;
+pub const HIGH_RES_SHADOW_SIZE: wgpu::Extent3d = wgpu::Extent3d {
+    width: 1024,
+    height: 1024,
+    depth: HIGH_RES_POINT_SHADOWS * SHADOW_CUBE_FACES,
+};
+// Layer sizes are the highest resolution a light can ask for
 pub const SHADOW_SIZE: wgpu::Extent3d = wgpu::Extent3d {
     width: 512,
     height: 512,
-    depth: MAX_POINT_LIGHTS,
+    depth: (MAX_POINT_LIGHT_SHADOWS - HIGH_RES_POINT_SHADOWS) * SHADOW_CUBE_FACES,
+};
+pub const CASCADE_SHADOW_SIZE: wgpu::Extent3d = wgpu::Extent3d {
+    width: 2048,
+    height: 2048,
+    depth: SHADOW_CASCADES as u32,
+};
+pub const SPOT_SHADOW_SIZE: wgpu::Extent3d = wgpu::Extent3d {
+    width: 1024,
+    height: 1024,
+    depth: MAX_SPOT_LIGHTS,
 };
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShadowFilter {
+    // Single comparison, hard and blocky edges
+    Hard,
+    // Percentage closer filtering over a fixed size kernel
+    Pcf,
+    // Percentage closer soft shadows, the penumbra widens with the distance
+    // between the occluder and the receiver
+    Pcss,
+}
+
+// Resource controlling how every shadow map is sampled
+pub struct ShadowFiltering {
+    pub filter: ShadowFilter,
+    // Samples on each side of the center, 1 is a 3x3 kernel and 2 a 5x5 kernel
+    pub kernel_radius: u32,
+}
+
+impl Default for ShadowFiltering {
+    fn default() -> Self {
+        ShadowFiltering {
+            filter: ShadowFilter::Pcf,
+            kernel_radius: 2,
+        }
+    }
+}
+
+// Fraction of a layer used by a light with the given shadow resolution
+pub fn shadow_map_scale(resolution: u32, layer_size: u32) -> f32 {
+    resolution.max(1).min(layer_size) as f32 / layer_size as f32
+}
+
+// Moves the clip space of a shadow projection into the top left corner of the
+// layer so lower resolution lights only render to and sample from part of it
+pub fn shadow_map_crop(scale: f32) -> Matrix4<f32> {
+    Matrix4::new(
+        scale,
+        0.0,
+        0.0,
+        scale - 1.0,
+        0.0,
+        scale,
+        0.0,
+        1.0 - scale,
+        0.0,
+        0.0,
+        1.0,
+        0.0,
+        0.0,
+        0.0,
+        0.0,
+        1.0,
+    )
+}
+
+// Point light cube maps, directional light cascades and spot light
+// shadow maps sharing a single comparison sampler in one bind group
 pub struct ShadowTexture;
 
 impl TextureShaderLayout for ShadowTexture {
@@ -22,7 +103,7 @@ impl TextureShaderLayout for ShadowTexture {
                         wgpu::ShaderStage::FRAGMENT,
                         wgpu::BindingType::SampledTexture {
                             multisampled: false,
-                            dimension: wgpu::TextureViewDimension::D2Array,
+                            dimension: wgpu::TextureViewDimension::CubeArray,
                             component_type: wgpu::TextureComponentType::Float,
                         },
                     ),
@@ -31,6 +112,39 @@ impl TextureShaderLayout for ShadowTexture {
                         wgpu::ShaderStage::FRAGMENT,
                         wgpu::BindingType::Sampler { comparison: true },
                     ),
+                    wgpu::BindGroupLayoutEntry::new(
+                        2,
+                        wgpu::ShaderStage::FRAGMENT,
+                        wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::D2Array,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    ),
+                    wgpu::BindGroupLayoutEntry::new(
+                        3,
+                        wgpu::ShaderStage::FRAGMENT,
+                        wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::D2Array,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    ),
+                    // plain depth reads for the pcss blocker search
+                    wgpu::BindGroupLayoutEntry::new(
+                        4,
+                        wgpu::ShaderStage::FRAGMENT,
+                        wgpu::BindingType::Sampler { comparison: false },
+                    ),
+                    wgpu::BindGroupLayoutEntry::new(
+                        5,
+                        wgpu::ShaderStage::FRAGMENT,
+                        wgpu::BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: wgpu::TextureViewDimension::CubeArray,
+                            component_type: wgpu::TextureComponentType::Float,
+                        },
+                    ),
                 ],
                 label: Some("Shadow Texture layout"),
             })
@@ -38,47 +152,187 @@ impl TextureShaderLayout for ShadowTexture {
     }
 }
 
-impl Texture for ShadowTexture {
-    fn allocate_texture(device: &wgpu::Device) -> TextureData<ShadowTexture> {
-        // This is a texture array where each light gets its own layer
-        let texture = device.create_texture(&wgpu::TextureDescriptor {
-            label: Some("Shadow map texture"),
-            size: SHADOW_SIZE,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2, // create cube texture for omnidirectional shadows
-            format: SHADOW_FORMAT,
-            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
-        });
-        let view = texture.create_default_view();
+fn create_shadow_texture(
+    device: &wgpu::Device,
+    label: &str,
+    size: wgpu::Extent3d,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: SHADOW_FORMAT,
+        usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+    })
+}
+
+fn create_view(
+    texture: &wgpu::Texture,
+    dimension: wgpu::TextureViewDimension,
+    base_array_layer: u32,
+    array_layer_count: u32,
+) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        format: SHADOW_FORMAT,
+        dimension,
+        aspect: wgpu::TextureAspect::DepthOnly,
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer,
+        array_layer_count,
+        label: Some("Shadow view"),
+    })
+}
+
+pub struct ShadowMaps {
+    pub textures: TextureData<ShadowTexture>,
+    // the regular point light cube maps are owned by the texture data
+    high_res_point_texture: wgpu::Texture,
+    cascade_texture: wgpu::Texture,
+    spot_texture: wgpu::Texture,
+}
+
+impl ShadowMaps {
+    pub fn new(device: &wgpu::Device) -> Self {
+        // Each point light gets six consecutive layers in cube map order, they are
+        // rendered one face at a time and sampled by direction as a cube array
+        let point_texture = create_shadow_texture(device, "Shadow map texture", SHADOW_SIZE);
+        let high_res_point_texture = create_shadow_texture(
+            device,
+            "High resolution shadow map texture",
+            HIGH_RES_SHADOW_SIZE,
+        );
+        let cascade_texture =
+            create_shadow_texture(device, "Cascade shadow map texture", CASCADE_SHADOW_SIZE);
+        let spot_texture =
+            create_shadow_texture(device, "Spot shadow map texture", SPOT_SHADOW_SIZE);
+        let views = vec![
+            create_view(
+                &point_texture,
+                wgpu::TextureViewDimension::CubeArray,
+                0,
+                SHADOW_SIZE.depth,
+            ),
+            create_view(
+                &cascade_texture,
+                wgpu::TextureViewDimension::D2Array,
+                0,
+                CASCADE_SHADOW_SIZE.depth,
+            ),
+            create_view(
+                &spot_texture,
+                wgpu::TextureViewDimension::D2Array,
+                0,
+                SPOT_SHADOW_SIZE.depth,
+            ),
+            create_view(
+                &high_res_point_texture,
+                wgpu::TextureViewDimension::CubeArray,
+                0,
+                HIGH_RES_SHADOW_SIZE.depth,
+            ),
+        ];
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             label: Some("Shadow sampler"),
             address_mode_u: wgpu::AddressMode::ClampToEdge,
             address_mode_v: wgpu::AddressMode::ClampToEdge,
             address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Nearest,
-            min_filter: wgpu::FilterMode::Nearest,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
             mipmap_filter: wgpu::FilterMode::Nearest,
             lod_min_clamp: -100.0,
             lod_max_clamp: 100.0,
             compare: Some(wgpu::CompareFunction::LessEqual),
             ..Default::default()
         });
+        let depth_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Shadow depth sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
 
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: Self::get_layout(device),
+            layout: ShadowTexture::get_layout(device),
             bindings: &[
                 wgpu::Binding {
                     binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&view),
+                    resource: wgpu::BindingResource::TextureView(&views[0]),
                 },
                 wgpu::Binding {
                     binding: 1,
                     resource: wgpu::BindingResource::Sampler(&sampler),
                 },
+                wgpu::Binding {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&views[1]),
+                },
+                wgpu::Binding {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&views[2]),
+                },
+                wgpu::Binding {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Sampler(&depth_sampler),
+                },
+                wgpu::Binding {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(&views[3]),
+                },
             ],
             label: Some("Shadow texture bindgroup"),
         });
-        TextureData::new(bind_group, texture, vec![view], sampler)
+        ShadowMaps {
+            textures: TextureData::new(bind_group, point_texture, views, sampler),
+            high_res_point_texture,
+            cascade_texture,
+            spot_texture,
+        }
+    }
+
+    // Render target for a single face of the cube map of a point light slot
+    pub fn point_face_view(&self, slot: u32, face: u32) -> wgpu::TextureView {
+        if slot < HIGH_RES_POINT_SHADOWS {
+            return create_view(
+                &self.high_res_point_texture,
+                wgpu::TextureViewDimension::D2,
+                slot * SHADOW_CUBE_FACES + face,
+                1,
+            );
+        }
+        self.textures.create_new_view(&wgpu::TextureViewDescriptor {
+            format: SHADOW_FORMAT,
+            dimension: wgpu::TextureViewDimension::D2,
+            aspect: wgpu::TextureAspect::DepthOnly,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: (slot - HIGH_RES_POINT_SHADOWS) * SHADOW_CUBE_FACES + face,
+            array_layer_count: 1,
+            label: Some("Light target view"),
+        })
+    }
+
+    pub fn cascade_view(&self, cascade: u32) -> wgpu::TextureView {
+        create_view(
+            &self.cascade_texture,
+            wgpu::TextureViewDimension::D2,
+            cascade,
+            1,
+        )
+    }
+
+    pub fn spot_view(&self, light_index: u32) -> wgpu::TextureView {
+        create_view(
+            &self.spot_texture,
+            wgpu::TextureViewDimension::D2,
+            light_index,
+            1,
+        )
     }
 }
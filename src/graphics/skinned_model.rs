@@ -0,0 +1,631 @@
+use anyhow::{anyhow, bail, Result};
+use gltf::animation::util::ReadOutputs;
+use nalgebra::{Matrix4, Quaternion, UnitQuaternion, Vector3, Vector4};
+use smol_renderer::{
+    GpuData, ImmutableVertexData, MutableVertexData, RenderNodeRunner, VertexBuffer,
+};
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+};
+use wgpu::{
+    Buffer, BufferAddress, BufferUsage, Device, Queue, TextureFormat, VertexAttributeDescriptor,
+    VertexFormat,
+};
+
+use crate::{
+    animation::{
+        clip::{Channel, JointTrack},
+        skeleton::Joint,
+        track::{Interpolation, Track},
+        AnimationClip, JointPose, Skeleton,
+    },
+    assets::AssetLoader,
+};
+
+use super::{
+    morph_targets::{MorphDelta, MorphTargets},
+    pbr_material::{upload_texture, PbrFactors, PbrMaterial},
+};
+
+const MAX_INSTANCES: usize = 128;
+
+#[repr(C)]
+#[derive(GpuData, Debug)]
+pub struct SkinnedVertex {
+    position: [f32; 3],
+    normal: [f32; 3],
+    tex_coords: [f32; 2],
+    // the four joints influencing the vertex and how much they do so
+    joints: [u32; 4],
+    weights: [f32; 4],
+}
+
+impl VertexBuffer for SkinnedVertex {
+    const STEP_MODE: wgpu::InputStepMode = wgpu::InputStepMode::Vertex;
+
+    fn get_attributes<'a>() -> &'a [wgpu::VertexAttributeDescriptor] {
+        &[
+            VertexAttributeDescriptor {
+                offset: 0,
+                format: VertexFormat::Float3,
+                shader_location: 0,
+            },
+            VertexAttributeDescriptor {
+                offset: 12,
+                format: VertexFormat::Float3,
+                shader_location: 1,
+            },
+            VertexAttributeDescriptor {
+                offset: 24,
+                format: VertexFormat::Float2,
+                shader_location: 2,
+            },
+            VertexAttributeDescriptor {
+                offset: 32,
+                format: VertexFormat::Uint4,
+                shader_location: 3,
+            },
+            VertexAttributeDescriptor {
+                offset: 48,
+                format: VertexFormat::Float4,
+                shader_location: 4,
+            },
+        ]
+    }
+}
+
+#[repr(C)]
+#[derive(GpuData, Debug, Clone)]
+pub struct SkinnedInstanceData {
+    model_matrix: Matrix4<f32>,
+    // index of the first joint matrix of the instance in the joint palette
+    joint_offset: u32,
+    // index of the first morph target weight of the instance in the joint palette
+    morph_offset: u32,
+}
+
+impl SkinnedInstanceData {
+    pub fn new(model_matrix: Matrix4<f32>, joint_offset: u32, morph_offset: u32) -> Self {
+        SkinnedInstanceData {
+            model_matrix,
+            joint_offset,
+            morph_offset,
+        }
+    }
+}
+
+impl Default for SkinnedInstanceData {
+    fn default() -> Self {
+        SkinnedInstanceData {
+            model_matrix: Matrix4::identity(),
+            joint_offset: 0,
+            morph_offset: 0,
+        }
+    }
+}
+
+const ROW_SIZE: BufferAddress = (std::mem::size_of::<f32>() * 4) as BufferAddress;
+
+impl VertexBuffer for SkinnedInstanceData {
+    const STEP_MODE: wgpu::InputStepMode = wgpu::InputStepMode::Instance;
+
+    fn get_attributes<'a>() -> &'a [wgpu::VertexAttributeDescriptor] {
+        &[
+            VertexAttributeDescriptor {
+                offset: 0,
+                format: VertexFormat::Float4,
+                shader_location: 5,
+            },
+            VertexAttributeDescriptor {
+                offset: ROW_SIZE,
+                format: VertexFormat::Float4,
+                shader_location: 6,
+            },
+            VertexAttributeDescriptor {
+                offset: ROW_SIZE * 2,
+                format: VertexFormat::Float4,
+                shader_location: 7,
+            },
+            VertexAttributeDescriptor {
+                offset: ROW_SIZE * 3,
+                format: VertexFormat::Float4,
+                shader_location: 8,
+            },
+            VertexAttributeDescriptor {
+                offset: ROW_SIZE * 4,
+                format: VertexFormat::Uint,
+                shader_location: 9,
+            },
+            VertexAttributeDescriptor {
+                offset: ROW_SIZE * 4 + 4,
+                format: VertexFormat::Uint,
+                shader_location: 10,
+            },
+        ]
+    }
+}
+
+pub struct SkinnedMesh {
+    pub vertex_buffer: ImmutableVertexData<SkinnedVertex>,
+    pub index_buffer: Buffer,
+    pub material: usize,
+    pub num_indexes: u32,
+    // meshes without targets get an empty set
+    pub morph_targets: MorphTargets,
+}
+
+// A glTF model deformed by the first skin and the morph targets of the file, every
+// animation of the file that targets its joints is loaded as a clip. Files without
+// a skin get a single joint that every vertex follows
+pub struct SkinnedModel {
+    pub instance_buffer: MutableVertexData<SkinnedInstanceData>,
+    pub meshes: Vec<SkinnedMesh>,
+    // the last material is used by primitives without one
+    pub materials: Vec<PbrMaterial>,
+    pub skeleton: Skeleton,
+    pub clips: Vec<AnimationClip>,
+    // default weights of the morph targets of every glTF mesh one after the other
+    pub morph_weights: Vec<f32>,
+}
+
+fn to_matrix(columns: [[f32; 4]; 4]) -> Matrix4<f32> {
+    Matrix4::from_fn(|row, column| columns[column][row])
+}
+
+fn joint_pose(node: &gltf::Node) -> JointPose {
+    let (translation, [x, y, z, w], scale) = node.transform().decomposed();
+    JointPose {
+        translation: Vector3::from(translation),
+        rotation: UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z)),
+        scale: Vector3::from(scale),
+    }
+}
+
+fn rgba_pixels(image: &gltf::image::Data) -> Result<Vec<u8>> {
+    use gltf::image::Format;
+    let (channels, to_rgba): (usize, fn(&[u8]) -> [u8; 4]) = match image.format {
+        Format::R8 => (1, |p| [p[0], p[0], p[0], 255]),
+        Format::R8G8 => (2, |p| [p[0], p[1], 0, 255]),
+        Format::R8G8B8 => (3, |p| [p[0], p[1], p[2], 255]),
+        Format::R8G8B8A8 => (4, |p| [p[0], p[1], p[2], p[3]]),
+        Format::B8G8R8 => (3, |p| [p[2], p[1], p[0], 255]),
+        Format::B8G8R8A8 => (4, |p| [p[2], p[1], p[0], p[3]]),
+        format => bail!("Unsupported glTF image format {:?}", format),
+    };
+    let mut pixels = Vec::with_capacity(image.pixels.len() / channels * 4);
+    for pixel in image.pixels.chunks(channels) {
+        pixels.extend_from_slice(&to_rgba(pixel));
+    }
+    Ok(pixels)
+}
+
+// Missing maps are replaced by a white pixel like the mtl maps, a channel is
+// copied into the color channels when several maps are packed into one image
+fn gltf_map(
+    device: &Device,
+    queue: &Queue,
+    images: &[gltf::image::Data],
+    texture: Option<gltf::Texture>,
+    format: TextureFormat,
+    channel: Option<usize>,
+) -> Result<wgpu::Texture> {
+    match texture {
+        Some(texture) => {
+            let image = &images[texture.source().index()];
+            let mut pixels = rgba_pixels(image)?;
+            if let Some(channel) = channel {
+                for pixel in pixels.chunks_mut(4) {
+                    let value = pixel[channel];
+                    pixel[..3].copy_from_slice(&[value; 3]);
+                }
+            }
+            Ok(upload_texture(
+                device,
+                queue,
+                image.width,
+                image.height,
+                format,
+                &pixels,
+            ))
+        }
+        None => Ok(upload_texture(
+            device,
+            queue,
+            1,
+            1,
+            format,
+            &[255, 255, 255, 255],
+        )),
+    }
+}
+
+// Blended materials are drawn as opaque, the transparent pass only handles static models
+fn load_material(
+    device: &Device,
+    queue: &Queue,
+    images: &[gltf::image::Data],
+    material: &gltf::Material,
+) -> Result<PbrMaterial> {
+    let pbr = material.pbr_metallic_roughness();
+    let factors = PbrFactors {
+        albedo: Vector4::from(pbr.base_color_factor()),
+        emissive: Vector3::from(material.emissive_factor()),
+        metallic: pbr.metallic_factor(),
+        roughness: pbr.roughness_factor(),
+        ambient_occlusion: 1.0,
+        alpha_cutoff: match material.alpha_mode() {
+            gltf::material::AlphaMode::Mask => material.alpha_cutoff(),
+            _ => 0.0,
+        },
+        unlit: 0,
+    };
+    // metalness is stored in the blue and roughness in the green channel
+    let metallic_roughness = pbr.metallic_roughness_texture().map(|info| info.texture());
+    let maps = vec![
+        gltf_map(
+            device,
+            queue,
+            images,
+            pbr.base_color_texture().map(|info| info.texture()),
+            TextureFormat::Rgba8UnormSrgb,
+            None,
+        )?,
+        gltf_map(
+            device,
+            queue,
+            images,
+            metallic_roughness.clone(),
+            TextureFormat::Rgba8Unorm,
+            Some(2),
+        )?,
+        gltf_map(
+            device,
+            queue,
+            images,
+            metallic_roughness,
+            TextureFormat::Rgba8Unorm,
+            Some(1),
+        )?,
+        gltf_map(
+            device,
+            queue,
+            images,
+            material.occlusion_texture().map(|info| info.texture()),
+            TextureFormat::Rgba8Unorm,
+            Some(0),
+        )?,
+        gltf_map(
+            device,
+            queue,
+            images,
+            material.emissive_texture().map(|info| info.texture()),
+            TextureFormat::Rgba8UnormSrgb,
+            None,
+        )?,
+    ];
+    Ok(PbrMaterial::new(device, factors, maps))
+}
+
+// Only the values of cubic splines are kept, their tangents are replaced by a Catmull-Rom spline
+fn keyframe_values<T>(values: impl Iterator<Item = T>, cubic_spline: bool) -> Vec<T> {
+    if cubic_spline {
+        values.skip(1).step_by(3).collect()
+    } else {
+        values.collect()
+    }
+}
+
+impl SkinnedModel {
+    pub fn load(device: &Device, queue: &Queue, path: impl AsRef<Path>) -> Result<Self> {
+        let (document, buffers, images) = gltf::import(path.as_ref())?;
+        let skin = document.skins().next();
+
+        let nodes = document.nodes().collect::<Vec<_>>();
+        let mut parents = vec![None; nodes.len()];
+        for node in &nodes {
+            for child in node.children() {
+                parents[child.index()] = Some(node.index());
+            }
+        }
+        let joint_nodes = skin
+            .as_ref()
+            .map(|skin| skin.joints().map(|node| node.index()).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let joint_index = |node: usize| joint_nodes.iter().position(|&joint| joint == node);
+        let inverse_bind_matrices = skin
+            .as_ref()
+            .and_then(|skin| {
+                skin.reader(|buffer| Some(&buffers[buffer.index()]))
+                    .read_inverse_bind_matrices()
+            })
+            .map(|matrices| matrices.map(to_matrix).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let mut joints = Vec::with_capacity(joint_nodes.len());
+        for (i, &node) in joint_nodes.iter().enumerate() {
+            // the closest ancestor that is part of the skin
+            let mut ancestor = parents[node];
+            while let Some(node) = ancestor {
+                if joint_index(node).is_some() {
+                    break;
+                }
+                ancestor = parents[node];
+            }
+            joints.push(Joint {
+                name: nodes[node].name().unwrap_or_default().to_string(),
+                parent: ancestor.and_then(joint_index),
+                inverse_bind_matrix: inverse_bind_matrices
+                    .get(i)
+                    .copied()
+                    .unwrap_or_else(Matrix4::identity),
+                bind_pose: joint_pose(&nodes[node]),
+            });
+        }
+        if joints.is_empty() {
+            joints.push(Joint {
+                name: "root".to_string(),
+                parent: None,
+                inverse_bind_matrix: Matrix4::identity(),
+                bind_pose: JointPose::default(),
+            });
+        }
+        // the nodes above the first root joint
+        let mut root_transform = Matrix4::identity();
+        if let Some(&root) = joint_nodes
+            .iter()
+            .zip(&joints)
+            .find(|(_, joint)| joint.parent.is_none())
+            .map(|(node, _)| node)
+        {
+            let mut ancestor = parents[root];
+            while let Some(node) = ancestor {
+                root_transform = to_matrix(nodes[node].transform().matrix()) * root_transform;
+                ancestor = parents[node];
+            }
+        }
+        let skeleton = Skeleton::new(joints, root_transform);
+
+        let mut clips = Vec::new();
+        for (i, animation) in document.animations().enumerate() {
+            let mut channels = Vec::new();
+            for channel in animation.channels() {
+                let joint = match joint_index(channel.target().node().index()) {
+                    Some(joint) => joint,
+                    None => continue,
+                };
+                let reader = channel.reader(|buffer| Some(&buffers[buffer.index()]));
+                let times = match reader.read_inputs() {
+                    Some(times) => times.collect::<Vec<_>>(),
+                    None => continue,
+                };
+                let (interpolation, cubic_spline) = match channel.sampler().interpolation() {
+                    gltf::animation::Interpolation::Step => (Interpolation::Step, false),
+                    gltf::animation::Interpolation::Linear => (Interpolation::Linear, false),
+                    gltf::animation::Interpolation::CubicSpline => (Interpolation::Cubic, true),
+                };
+                let track = match reader.read_outputs() {
+                    Some(ReadOutputs::Translations(values)) => {
+                        JointTrack::Translation(Track::from_keyframes(
+                            interpolation,
+                            times,
+                            keyframe_values(values.map(Vector3::from), cubic_spline),
+                        ))
+                    }
+                    Some(ReadOutputs::Rotations(values)) => {
+                        JointTrack::Rotation(Track::from_keyframes(
+                            interpolation,
+                            times,
+                            keyframe_values(
+                                values.into_f32().map(|[x, y, z, w]| {
+                                    UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z))
+                                }),
+                                cubic_spline,
+                            ),
+                        ))
+                    }
+                    Some(ReadOutputs::Scales(values)) => JointTrack::Scale(Track::from_keyframes(
+                        interpolation,
+                        times,
+                        keyframe_values(values.map(Vector3::from), cubic_spline),
+                    )),
+                    // morph target weights are set through the MorphWeights component
+                    _ => continue,
+                };
+                channels.push(Channel { joint, track });
+            }
+            let name = animation
+                .name()
+                .map_or_else(|| format!("animation {}", i), str::to_string);
+            clips.push(AnimationClip::new(name, channels));
+        }
+
+        let mut materials = document
+            .materials()
+            .map(|material| load_material(device, queue, &images, &material))
+            .collect::<Result<Vec<_>>>()?;
+        let default_material = materials.len();
+        materials.push(PbrMaterial::new(
+            device,
+            PbrFactors::default(),
+            (0..5)
+                .map(|_| {
+                    gltf_map(
+                        device,
+                        queue,
+                        &images,
+                        None,
+                        TextureFormat::Rgba8Unorm,
+                        None,
+                    )
+                })
+                .collect::<Result<Vec<_>>>()?,
+        ));
+
+        // skinned meshes ignore the transforms of their nodes
+        let mut meshes = Vec::new();
+        let mut morph_weights = Vec::new();
+        for mesh in document.meshes() {
+            let weight_offset = morph_weights.len() as u32;
+            let mut target_count = mesh.weights().map_or(0, |weights| weights.len());
+            for primitive in mesh.primitives() {
+                target_count = target_count.max(primitive.morph_targets().count());
+            }
+            morph_weights.extend((0..target_count).map(|i| {
+                mesh.weights()
+                    .and_then(|weights| weights.get(i).copied())
+                    .unwrap_or(0.0)
+            }));
+            for primitive in mesh.primitives() {
+                let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+                let positions = reader
+                    .read_positions()
+                    .ok_or_else(|| anyhow!("Mesh without positions in {:?}", path.as_ref()))?
+                    .collect::<Vec<_>>();
+                let count = positions.len();
+                let normals = reader
+                    .read_normals()
+                    .map(|normals| normals.collect::<Vec<_>>())
+                    .unwrap_or_else(|| vec![[0.0, 1.0, 0.0]; count]);
+                let tex_coords = reader
+                    .read_tex_coords(0)
+                    .map(|tex_coords| tex_coords.into_f32().collect::<Vec<_>>())
+                    .unwrap_or_else(|| vec![[0.0, 0.0]; count]);
+                // vertices without weights follow the first joint
+                let vertex_joints = reader
+                    .read_joints(0)
+                    .map(|joints| {
+                        joints
+                            .into_u16()
+                            .map(|[a, b, c, d]| [a as u32, b as u32, c as u32, d as u32])
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_else(|| vec![[0; 4]; count]);
+                let weights = reader
+                    .read_weights(0)
+                    .map(|weights| weights.into_f32().collect::<Vec<_>>())
+                    .unwrap_or_else(|| vec![[1.0, 0.0, 0.0, 0.0]; count]);
+                let vertices = (0..count)
+                    .map(|i| SkinnedVertex {
+                        position: positions[i],
+                        normal: normals[i],
+                        tex_coords: tex_coords[i],
+                        joints: vertex_joints[i],
+                        weights: weights[i],
+                    })
+                    .collect::<Vec<_>>();
+                let vertex_buffer = VertexBuffer::allocate_immutable_buffer(device, &vertices);
+
+                // missing displacements of a target are left at zero
+                let mut deltas = Vec::new();
+                for (positions, normals, _) in reader.read_morph_targets() {
+                    let start = deltas.len();
+                    deltas.resize(start + count, MorphDelta::default());
+                    for (delta, [x, y, z]) in deltas[start..]
+                        .iter_mut()
+                        .zip(positions.into_iter().flatten())
+                    {
+                        delta.position = [x, y, z, 0.0];
+                    }
+                    for (delta, [x, y, z]) in deltas[start..]
+                        .iter_mut()
+                        .zip(normals.into_iter().flatten())
+                    {
+                        delta.normal = [x, y, z, 0.0];
+                    }
+                }
+                let morph_targets = MorphTargets::new(device, count as u32, weight_offset, &deltas);
+
+                let indices = reader
+                    .read_indices()
+                    .map(|indices| indices.into_u32().collect::<Vec<_>>())
+                    .unwrap_or_else(|| (0..count as u32).collect());
+                let index_bytes = unsafe {
+                    std::slice::from_raw_parts(indices.as_ptr() as *const u8, indices.len() * 4)
+                };
+                let index_buffer = device.create_buffer_with_data(index_bytes, BufferUsage::INDEX);
+
+                meshes.push(SkinnedMesh {
+                    vertex_buffer,
+                    index_buffer,
+                    material: primitive.material().index().unwrap_or(default_material),
+                    num_indexes: indices.len() as u32,
+                    morph_targets,
+                });
+            }
+        }
+        let buffer_data = vec![SkinnedInstanceData::default(); MAX_INSTANCES];
+        let instance_buffer = VertexBuffer::allocate_mutable_buffer(device, &buffer_data);
+        Ok(SkinnedModel {
+            instance_buffer,
+            meshes,
+            materials,
+            skeleton,
+            clips,
+            morph_weights,
+        })
+    }
+
+    pub fn clip_index(&self, name: &str) -> Option<usize> {
+        self.clips.iter().position(|clip| clip.name == name)
+    }
+}
+
+// The morph group is the set the morph targets of each mesh are bound to
+pub trait DrawSkinnedModel<'b> {
+    fn draw_skinned_model_instanced(
+        &mut self,
+        model: &'b SkinnedModel,
+        instances: Range<u32>,
+        morph_group: u32,
+    );
+
+    fn draw_skinned_untextured(
+        &mut self,
+        model: &'b SkinnedModel,
+        instances: Range<u32>,
+        morph_group: u32,
+    );
+}
+
+impl<'a, 'b> DrawSkinnedModel<'b> for RenderNodeRunner<'a, 'b> {
+    fn draw_skinned_model_instanced(
+        &mut self,
+        model: &'b SkinnedModel,
+        instances: Range<u32>,
+        morph_group: u32,
+    ) {
+        for mesh in &model.meshes {
+            self.set_vertex_buffer_data(0, &mesh.vertex_buffer);
+            self.set_vertex_buffer_data(1, &model.instance_buffer);
+            self.set_index_buffer(mesh.index_buffer.slice(..));
+            self.set_texture_data(0, &model.materials[mesh.material].textures);
+            self.set_texture_data(morph_group, &mesh.morph_targets.textures);
+            self.draw_indexed(0..mesh.num_indexes, 0, instances.clone());
+        }
+    }
+
+    fn draw_skinned_untextured(
+        &mut self,
+        model: &'b SkinnedModel,
+        instances: Range<u32>,
+        morph_group: u32,
+    ) {
+        for mesh in &model.meshes {
+            self.set_vertex_buffer_data(0, &mesh.vertex_buffer);
+            self.set_vertex_buffer_data(1, &model.instance_buffer);
+            self.set_index_buffer(mesh.index_buffer.slice(..));
+            self.set_texture_data(morph_group, &mesh.morph_targets.textures);
+            self.draw_indexed(0..mesh.num_indexes, 0, instances.clone());
+        }
+    }
+}
+
+impl AssetLoader for SkinnedModel {
+    fn load(path: &PathBuf, device: &Device, queue: &Queue) -> Result<SkinnedModel> {
+        SkinnedModel::load(device, queue, path)
+    }
+
+    fn extension() -> &'static str {
+        "gltf"
+    }
+}
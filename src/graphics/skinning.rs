@@ -0,0 +1,130 @@
+use nalgebra::Matrix4;
+use once_cell::sync::OnceCell;
+use smol_renderer::{TextureData, TextureShaderLayout};
+use wgpu::{
+    BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, Binding, BindingResource,
+    BindingType, Buffer, BufferUsage, CommandEncoder, Device, Extent3d, ShaderStage,
+    TextureDimension, TextureFormat, TextureUsage,
+};
+
+use super::clustered_lights::{as_bytes, create_buffer, upload};
+
+// Joint matrices of every animated instance in the scene, further joints are dropped
+pub const MAX_JOINT_MATRICES: u32 = 4096;
+// Morph target weights of every instance in the scene
+pub const MAX_MORPH_WEIGHTS: u32 = 16384;
+
+// The joint matrices and morph weights of all skinned instances, each instance
+// finds its own through the joint and morph offsets in its instance data
+pub struct SkinningTextures;
+
+impl TextureShaderLayout for SkinningTextures {
+    fn get_layout(device: &Device) -> &'static BindGroupLayout {
+        static LAYOUT: OnceCell<BindGroupLayout> = OnceCell::new();
+        LAYOUT.get_or_init(|| {
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                bindings: &[
+                    BindGroupLayoutEntry::new(
+                        0,
+                        ShaderStage::VERTEX,
+                        BindingType::StorageBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                            readonly: true,
+                        },
+                    ),
+                    BindGroupLayoutEntry::new(
+                        1,
+                        ShaderStage::VERTEX,
+                        BindingType::StorageBuffer {
+                            dynamic: false,
+                            min_binding_size: None,
+                            readonly: true,
+                        },
+                    ),
+                ],
+                label: Some("Skinning textures layout"),
+            })
+        })
+    }
+}
+
+// Shared by every pass drawing skinned meshes
+pub struct JointPalette {
+    pub textures: TextureData<SkinningTextures>,
+    joint_buffer: Buffer,
+    morph_weight_buffer: Buffer,
+}
+
+impl JointPalette {
+    pub fn new(device: &Device) -> Self {
+        let joint_buffer = create_buffer(
+            device,
+            "Joint matrices",
+            std::mem::size_of::<Matrix4<f32>>() * MAX_JOINT_MATRICES as usize,
+            BufferUsage::STORAGE,
+        );
+        let morph_weight_buffer = create_buffer(
+            device,
+            "Morph weights",
+            std::mem::size_of::<f32>() * MAX_MORPH_WEIGHTS as usize,
+            BufferUsage::STORAGE,
+        );
+        // The texture data wrapper needs a texture even though everything
+        // in this bind group is a buffer, it is never bound
+        let placeholder = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Skinning placeholder"),
+            size: Extent3d {
+                width: 1,
+                height: 1,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R8Unorm,
+            usage: TextureUsage::SAMPLED,
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Skinning placeholder sampler"),
+            ..Default::default()
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: SkinningTextures::get_layout(device),
+            bindings: &[
+                Binding {
+                    binding: 0,
+                    resource: BindingResource::Buffer(joint_buffer.slice(..)),
+                },
+                Binding {
+                    binding: 1,
+                    resource: BindingResource::Buffer(morph_weight_buffer.slice(..)),
+                },
+            ],
+            label: Some("Skinning textures bindgroup"),
+        });
+        JointPalette {
+            textures: TextureData::new(bind_group, placeholder, Vec::new(), sampler),
+            joint_buffer,
+            morph_weight_buffer,
+        }
+    }
+
+    pub fn update(
+        &self,
+        device: &Device,
+        encoder: &mut CommandEncoder,
+        joints: &[Matrix4<f32>],
+        morph_weights: &[f32],
+    ) {
+        let joints = &joints[..joints.len().min(MAX_JOINT_MATRICES as usize)];
+        upload(device, encoder, &self.joint_buffer, as_bytes(joints));
+        let morph_weights = &morph_weights[..morph_weights.len().min(MAX_MORPH_WEIGHTS as usize)];
+        upload(
+            device,
+            encoder,
+            &self.morph_weight_buffer,
+            as_bytes(morph_weights),
+        );
+    }
+}
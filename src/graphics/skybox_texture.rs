@@ -1,7 +1,14 @@
-use image::{DynamicImage, GenericImage};
+use half::f16;
+use image::hdr::HDRDecoder;
+use nalgebra::Vector3;
 use once_cell::sync::OnceCell;
 use smol_renderer::{LoadableTexture, RenderError, TextureData, TextureShaderLayout};
-use std::path::Path;
+use std::{
+    f32::consts::PI,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
 use wgpu::{
     AddressMode, BindGroup, BindGroupDescriptor, BindGroupLayout, BindGroupLayoutDescriptor,
     BindGroupLayoutEntry, Binding, BindingResource, BindingType, Device, Extent3d, FilterMode,
@@ -10,7 +17,33 @@ use wgpu::{
     TextureViewDescriptor, TextureViewDimension,
 };
 
-const REQUIRED_SKYBOX_TEXTURES: usize = 6;
+const CUBE_FACES: usize = 6;
+const SKYBOX_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+// Accepted file names (without extension) for every face, in the layer order of the cubemap
+const FACE_NAMES: [&[&str]; CUBE_FACES] = [
+    &["px", "right", "posx"],
+    &["nx", "left", "negx"],
+    &["py", "top", "up", "posy"],
+    &["ny", "bottom", "down", "negy"],
+    &["pz", "front", "posz"],
+    &["nz", "back", "negz"],
+];
+
+// Resource with the skybox the scene is drawn and lit with, a directory with one
+// image per face or a panorama. The renderer reloads it when the path changes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Skybox {
+    pub path: PathBuf,
+}
+
+impl Default for Skybox {
+    fn default() -> Self {
+        Skybox {
+            path: PathBuf::from("skybox"),
+        }
+    }
+}
 
 pub struct SkyboxTexture {
     pub texture: wgpu::Texture,
@@ -47,75 +80,56 @@ impl TextureShaderLayout for SkyboxTexture {
 }
 
 impl LoadableTexture for SkyboxTexture {
+    // Loads either a directory with one image per named face or a single
+    // equirectangular .hdr/.exr panorama which is converted to a cubemap
     fn load_texture(
         device: &Device,
         queue: &Queue,
-        dir_path: impl AsRef<Path>,
+        path: impl AsRef<Path>,
     ) -> Result<TextureData<Self>, RenderError> {
-        let directory_iterator = std::fs::read_dir(dir_path.as_ref())?;
-
-        let mut paths = directory_iterator
-            .filter_map(Result::ok)
-            .map(|entry| entry.path())
-            .filter(|path| !path.is_dir())
-            .collect::<Vec<_>>();
-
-        assert!(
-            paths.len() == REQUIRED_SKYBOX_TEXTURES,
-            format!(
-                "Skybox texture directory {:?} doesn't contain exacty 6 images",
-                dir_path.as_ref()
-            )
-        );
-        // sort the paths in order (skybox textures are ordered)
-        paths.sort();
-        let images = paths
-            .iter()
-            .map(image::open)
-            .collect::<Result<Vec<DynamicImage>, _>>()?;
-
-        let (width, height) = images.first().unwrap().dimensions();
+        let path = path.as_ref();
+        let (size, faces) = if path.is_dir() {
+            load_face_directory(path)?
+        } else {
+            let (width, height, pixels) = load_panorama(path)?;
+            equirectangular_to_cube(width, height, &pixels)
+        };
 
         let texture_size = Extent3d {
-            width,
-            height,
-            depth: REQUIRED_SKYBOX_TEXTURES as u32,
+            width: size,
+            height: size,
+            depth: CUBE_FACES as u32,
         };
 
         let (texture, texture_view, sampler) = Self::create_texture_data(device, texture_size);
 
-        images
-            .iter()
-            .map(|img| img.to_rgba())
-            .map(|img_buffer| img_buffer.to_vec())
-            .enumerate()
-            .for_each(|(i, buffer)| {
-                let texture_copy_view = TextureCopyView {
-                    texture: &texture,
-                    mip_level: 0,
-                    origin: Origin3d {
-                        x: 0,
-                        y: 0,
-                        z: i as u32,
-                    },
-                };
-
-                let texture_data_layout = TextureDataLayout {
-                    offset: 0,
-                    bytes_per_row: 4 * width,
-                    rows_per_image: 0,
-                };
-                queue.write_texture(
-                    texture_copy_view,
-                    &buffer,
-                    texture_data_layout,
-                    Extent3d {
-                        width,
-                        height,
-                        depth: 1,
-                    },
-                );
-            });
+        faces.iter().enumerate().for_each(|(i, face)| {
+            let texture_copy_view = TextureCopyView {
+                texture: &texture,
+                mip_level: 0,
+                origin: Origin3d {
+                    x: 0,
+                    y: 0,
+                    z: i as u32,
+                },
+            };
+
+            let texture_data_layout = TextureDataLayout {
+                offset: 0,
+                bytes_per_row: 8 * size,
+                rows_per_image: 0,
+            };
+            queue.write_texture(
+                texture_copy_view,
+                &to_half_floats(face),
+                texture_data_layout,
+                Extent3d {
+                    width: size,
+                    height: size,
+                    depth: 1,
+                },
+            );
+        });
         let bind_group = Self::create_bind_group(device, &texture_view, &sampler);
 
         Ok(TextureData::new(
@@ -127,6 +141,192 @@ impl LoadableTexture for SkyboxTexture {
     }
 }
 
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn to_half_floats(pixels: &[[f32; 4]]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(pixels.len() * 8);
+    for pixel in pixels {
+        for channel in pixel {
+            bytes.extend_from_slice(&f16::from_f32(*channel).to_bits().to_le_bytes());
+        }
+    }
+    bytes
+}
+
+fn face_index(path: &Path) -> Option<usize> {
+    let stem = path.file_stem()?.to_str()?.to_lowercase();
+    FACE_NAMES
+        .iter()
+        .position(|names| names.contains(&stem.as_str()))
+}
+
+// Ldr faces are stored as is in the float texture, i.e treated as linear values
+fn load_face_directory(dir_path: &Path) -> Result<(u32, Vec<Vec<[f32; 4]>>), RenderError> {
+    let mut face_paths: [Option<PathBuf>; CUBE_FACES] = Default::default();
+    for entry in std::fs::read_dir(dir_path)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            continue;
+        }
+        if let Some(index) = face_index(&path) {
+            if let Some(existing) = &face_paths[index] {
+                return Err(invalid_data(format!(
+                    "Skybox directory {:?} contains both {:?} and {:?} for the same face",
+                    dir_path, existing, path
+                ))
+                .into());
+            }
+            face_paths[index] = Some(path);
+        }
+    }
+
+    let mut size = None;
+    let mut faces = Vec::with_capacity(CUBE_FACES);
+    for (names, face_path) in FACE_NAMES.iter().zip(face_paths.iter()) {
+        let face_path = face_path.as_ref().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "Skybox directory {:?} is missing the face named one of {:?}",
+                    dir_path, names
+                ),
+            )
+        })?;
+        let image = image::open(face_path)?.to_rgba();
+        let (width, height) = image.dimensions();
+        if width != height || size.map_or(false, |size| size != width) {
+            return Err(invalid_data(format!(
+                "Skybox face {:?} must be square and the same size as the other faces, found {}x{}",
+                face_path, width, height
+            ))
+            .into());
+        }
+        size = Some(width);
+        faces.push(
+            image
+                .pixels()
+                .map(|pixel| {
+                    let [r, g, b, a] = pixel.data;
+                    [
+                        r as f32 / 255.0,
+                        g as f32 / 255.0,
+                        b as f32 / 255.0,
+                        a as f32 / 255.0,
+                    ]
+                })
+                .collect(),
+        );
+    }
+    Ok((size.unwrap(), faces))
+}
+
+fn load_panorama(path: &Path) -> Result<(u32, u32, Vec<[f32; 3]>), RenderError> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_lowercase);
+    match extension.as_deref() {
+        Some("hdr") => {
+            let decoder = HDRDecoder::new(BufReader::new(File::open(path)?))?;
+            let metadata = decoder.metadata();
+            let pixels = decoder
+                .read_image_hdr()?
+                .into_iter()
+                .map(|pixel| pixel.data)
+                .collect();
+            Ok((metadata.width, metadata.height, pixels))
+        }
+        Some("exr") => {
+            let image = exr::prelude::read_first_rgba_layer_from_file(
+                path,
+                |resolution, _| {
+                    (
+                        resolution.width(),
+                        vec![[0.0; 3]; resolution.width() * resolution.height()],
+                    )
+                },
+                |(width, pixels): &mut (usize, Vec<[f32; 3]>),
+                 position: exr::prelude::Vec2<usize>,
+                 (r, g, b, _): (f32, f32, f32, f32)| {
+                    pixels[position.y() * *width + position.x()] = [r, g, b];
+                },
+            )
+            .map_err(|err| invalid_data(format!("Failed to read {:?}: {}", path, err)))?;
+            let size = image.layer_data.size;
+            let (_, pixels) = image.layer_data.channel_data.pixels;
+            Ok((size.width() as u32, size.height() as u32, pixels))
+        }
+        _ => Err(invalid_data(format!(
+            "Skybox {:?} must be a directory of faces or an .hdr/.exr panorama",
+            path
+        ))
+        .into()),
+    }
+}
+
+// Direction through the texel at st (in [-1, 1], y pointing down) of the given face
+fn face_direction(face: usize, s: f32, t: f32) -> Vector3<f32> {
+    let direction = match face {
+        0 => Vector3::new(1.0, -t, -s),
+        1 => Vector3::new(-1.0, -t, s),
+        2 => Vector3::new(s, 1.0, t),
+        3 => Vector3::new(s, -1.0, -t),
+        4 => Vector3::new(s, -t, 1.0),
+        _ => Vector3::new(-s, -t, -1.0),
+    };
+    direction.normalize()
+}
+
+fn equirectangular_to_cube(
+    width: u32,
+    height: u32,
+    pixels: &[[f32; 3]],
+) -> (u32, Vec<Vec<[f32; 4]>>) {
+    let size = (width / 4).max(1);
+    let sample = |x: i64, y: i64| {
+        let x = x.rem_euclid(width as i64) as usize;
+        let y = y.max(0).min(height as i64 - 1) as usize;
+        pixels[y * width as usize + x]
+    };
+    let faces = (0..CUBE_FACES)
+        .map(|face| {
+            let mut face_pixels = Vec::with_capacity((size * size) as usize);
+            for y in 0..size {
+                for x in 0..size {
+                    let s = (x as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+                    let t = (y as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+                    let direction = face_direction(face, s, t);
+                    let u = 0.5 + direction.z.atan2(direction.x) / (2.0 * PI);
+                    let v = direction.y.max(-1.0).min(1.0).acos() / PI;
+                    // bilinear filtering, wrapping horizontally
+                    let px = u * width as f32 - 0.5;
+                    let py = v * height as f32 - 0.5;
+                    let (x0, y0) = (px.floor(), py.floor());
+                    let (fx, fy) = (px - x0, py - y0);
+                    let (x0, y0) = (x0 as i64, y0 as i64);
+                    let mut color = [0.0, 0.0, 0.0, 1.0];
+                    for (dx, dy, weight) in &[
+                        (0, 0, (1.0 - fx) * (1.0 - fy)),
+                        (1, 0, fx * (1.0 - fy)),
+                        (0, 1, (1.0 - fx) * fy),
+                        (1, 1, fx * fy),
+                    ] {
+                        let texel = sample(x0 + dx, y0 + dy);
+                        for channel in 0..3 {
+                            color[channel] += texel[channel] * weight;
+                        }
+                    }
+                    face_pixels.push(color);
+                }
+            }
+            face_pixels
+        })
+        .collect();
+    (size, faces)
+}
+
 impl SkyboxTexture {
     fn create_texture_data(
         device: &Device,
@@ -138,18 +338,18 @@ impl SkyboxTexture {
             mip_level_count: 1,
             sample_count: 1,
             dimension: TextureDimension::D2,
-            format: TextureFormat::Rgba8Unorm,
+            format: SKYBOX_FORMAT,
             usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
         });
 
         let texture_view = texture.create_view(&TextureViewDescriptor {
-            format: TextureFormat::Rgba8Unorm,
+            format: SKYBOX_FORMAT,
             dimension: TextureViewDimension::Cube,
             aspect: TextureAspect::default(),
             base_mip_level: 0,
             level_count: 1,
             base_array_layer: 0,
-            array_layer_count: REQUIRED_SKYBOX_TEXTURES as u32,
+            array_layer_count: CUBE_FACES as u32,
             label: Some("Skybox texture view"),
         });
 
@@ -0,0 +1,148 @@
+use nalgebra::{geometry::Perspective3, Matrix4, Point3, Vector3};
+use serde::{Deserialize, Serialize};
+use smol_renderer::GpuData;
+
+use crate::components::GlobalTransform;
+
+use super::{
+    point_light::SHADOW_NEAR_PLANE,
+    shadow_texture::{shadow_map_crop, shadow_map_scale, SPOT_SHADOW_SIZE},
+};
+
+// Cone shaped light, e.g a flashlight. The position comes from the Transform
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpotLight {
+    // rotated by the global transform so the light turns with its parents
+    pub direction: Vector3<f32>,
+    // angles in radians from the cone axis, the light fades out between the two
+    pub inner_cutoff: f32,
+    pub outer_cutoff: f32,
+    // nothing further away than this is lit or shadowed
+    pub range: f32,
+    // phong lighting terms
+    pub specular: Vector3<f32>,
+    pub diffuse: Vector3<f32>,
+    pub constant: f32,
+    pub linear: f32,
+    pub quadratic: f32,
+    // physically based lighting, the pbr pass uses inverse square falloff
+    pub color: Vector3<f32>,
+    // luminous intensity in candela
+    pub intensity: f32,
+    // lights that don't cast shadows don't take up a slot in the shadow atlas
+    pub cast_shadows: bool,
+    // size of the shadow map in texels
+    pub shadow_resolution: u32,
+    // world space offset against shadow acne, scaled up on steep surfaces
+    pub shadow_bias: f32,
+    // radius of the light source, controls the penumbra size of pcss shadows
+    pub light_size: f32,
+}
+
+impl Default for SpotLight {
+    fn default() -> Self {
+        SpotLight {
+            direction: Vector3::new(0.0, -1.0, 0.0),
+            inner_cutoff: 12.5_f32.to_radians(),
+            outer_cutoff: 17.5_f32.to_radians(),
+            range: 50.0,
+            specular: Vector3::new(1.0, 1.0, 1.0),
+            diffuse: Vector3::new(1.0, 1.0, 1.0),
+            constant: 1.0,
+            linear: 0.09,
+            quadratic: 0.032,
+            color: Vector3::new(1.0, 1.0, 1.0),
+            intensity: 15.0,
+            cast_shadows: true,
+            shadow_resolution: 1024,
+            shadow_bias: 0.05,
+            light_size: 0.1,
+        }
+    }
+}
+
+impl SpotLight {
+    pub fn world_direction(&self, transform: &GlobalTransform) -> Vector3<f32> {
+        (transform.rotation() * self.direction).normalize()
+    }
+
+    // Perspective projection covering the outer cone, used for the shadow map
+    pub fn view_projection(&self, transform: &GlobalTransform) -> Matrix4<f32> {
+        let position = transform.translation();
+        let direction = self.world_direction(transform);
+        let up = if direction.y.abs() > 0.99 {
+            Vector3::z()
+        } else {
+            Vector3::y()
+        };
+        let eye = Point3::from(position);
+        let view = Matrix4::look_at_rh(&eye, &(eye + direction), &up);
+        let projection =
+            Perspective3::new(1.0, 2.0 * self.outer_cutoff, SHADOW_NEAR_PLANE, self.range);
+        shadow_map_crop(self.shadow_scale()) * projection.as_matrix() * view
+    }
+
+    pub fn shadow_scale(&self) -> f32 {
+        shadow_map_scale(self.shadow_resolution, SPOT_SHADOW_SIZE.width)
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, GpuData)]
+pub struct SpotLightRaw {
+    position: [f32; 3],
+    range: f32,
+    direction: [f32; 3],
+    cos_inner_cutoff: f32,
+    specular: [f32; 3],
+    cos_outer_cutoff: f32,
+    diffuse: [f32; 3],
+    constant: f32,
+    linear: f32,
+    quadratic: f32,
+    shadow_bias: f32,
+    shadow_scale: f32,
+    color: [f32; 3],
+    intensity: f32,
+    light_size: f32,
+    // layer in the spot shadow texture or -1 when the light has no shadow
+    shadow_index: i32,
+    _pad: [f32; 2],
+    light_space_matrix: [[f32; 4]; 4],
+}
+
+impl From<(&SpotLight, &GlobalTransform, Option<u32>)> for SpotLightRaw {
+    fn from((light, transform, shadow_index): (&SpotLight, &GlobalTransform, Option<u32>)) -> Self {
+        let position = transform.translation();
+        let direction = light.world_direction(transform);
+        let view_projection = light.view_projection(transform);
+        let mut light_space_matrix = [[0.0; 4]; 4];
+        for (column, chunk) in light_space_matrix
+            .iter_mut()
+            .zip(view_projection.as_slice().chunks(4))
+        {
+            column.copy_from_slice(chunk);
+        }
+        SpotLightRaw {
+            position: [position.x, position.y, position.z],
+            range: light.range,
+            direction: [direction.x, direction.y, direction.z],
+            cos_inner_cutoff: light.inner_cutoff.cos(),
+            specular: [light.specular.x, light.specular.y, light.specular.z],
+            cos_outer_cutoff: light.outer_cutoff.cos(),
+            diffuse: [light.diffuse.x, light.diffuse.y, light.diffuse.z],
+            constant: light.constant,
+            linear: light.linear,
+            quadratic: light.quadratic,
+            color: [light.color.x, light.color.y, light.color.z],
+            intensity: light.intensity,
+            shadow_bias: light.shadow_bias,
+            shadow_scale: light.shadow_scale(),
+            light_size: light.light_size,
+            shadow_index: shadow_index.map(|index| index as i32).unwrap_or(-1),
+            _pad: [0.0; 2],
+            light_space_matrix,
+        }
+    }
+}
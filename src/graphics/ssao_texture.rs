@@ -0,0 +1,167 @@
+use once_cell::sync::OnceCell;
+use smol_renderer::{TextureData, TextureShaderLayout};
+use wgpu::{
+    AddressMode, BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, Binding,
+    BindingResource, BindingType, Device, Extent3d, FilterMode, ShaderStage, TextureComponentType,
+    TextureDimension, TextureFormat, TextureUsage, TextureView, TextureViewDimension,
+};
+
+use super::{
+    g_buffer::{GBuffer, NORMAL_FORMAT},
+    wgpu_renderer::DEPTH_FORMAT,
+};
+
+pub const AMBIENT_OCCLUSION_FORMAT: TextureFormat = TextureFormat::R8Unorm;
+
+// World space normals and depth of the visible surfaces, the input of the ssao pass
+pub struct DepthNormalTextures;
+
+impl TextureShaderLayout for DepthNormalTextures {
+    fn get_layout(device: &Device) -> &'static BindGroupLayout {
+        static LAYOUT: OnceCell<BindGroupLayout> = OnceCell::new();
+        LAYOUT.get_or_init(|| {
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                bindings: &[
+                    BindGroupLayoutEntry::new(
+                        0,
+                        ShaderStage::FRAGMENT,
+                        BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: TextureViewDimension::D2,
+                            component_type: TextureComponentType::Float,
+                        },
+                    ),
+                    BindGroupLayoutEntry::new(
+                        1,
+                        ShaderStage::FRAGMENT,
+                        BindingType::SampledTexture {
+                            multisampled: false,
+                            dimension: TextureViewDimension::D2,
+                            component_type: TextureComponentType::Float,
+                        },
+                    ),
+                    BindGroupLayoutEntry::new(
+                        2,
+                        ShaderStage::FRAGMENT,
+                        BindingType::Sampler { comparison: false },
+                    ),
+                ],
+                label: Some("Depth normal layout"),
+            })
+        })
+    }
+}
+
+fn create_target(
+    device: &Device,
+    label: &str,
+    width: u32,
+    height: u32,
+    format: TextureFormat,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: Extent3d {
+            width,
+            height,
+            depth: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format,
+        usage: TextureUsage::OUTPUT_ATTACHMENT | TextureUsage::SAMPLED,
+    })
+}
+
+fn create_texture_data(
+    device: &Device,
+    texture: wgpu::Texture,
+    normal_view: TextureView,
+    depth_view: TextureView,
+) -> TextureData<DepthNormalTextures> {
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+        label: Some("Depth normal sampler"),
+        address_mode_u: AddressMode::ClampToEdge,
+        address_mode_v: AddressMode::ClampToEdge,
+        address_mode_w: AddressMode::ClampToEdge,
+        mag_filter: FilterMode::Nearest,
+        min_filter: FilterMode::Nearest,
+        mipmap_filter: FilterMode::Nearest,
+        ..Default::default()
+    });
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: DepthNormalTextures::get_layout(device),
+        bindings: &[
+            Binding {
+                binding: 0,
+                resource: BindingResource::TextureView(&normal_view),
+            },
+            Binding {
+                binding: 1,
+                resource: BindingResource::TextureView(&depth_view),
+            },
+            Binding {
+                binding: 2,
+                resource: BindingResource::Sampler(&sampler),
+            },
+        ],
+        label: Some("Depth normal bindgroup"),
+    });
+    TextureData::new(bind_group, texture, vec![normal_view, depth_view], sampler)
+}
+
+// The views the depth normal prepass renders into
+struct PrepassTargets {
+    _depth_texture: wgpu::Texture,
+    normal_view: TextureView,
+    depth_view: TextureView,
+}
+
+pub struct DepthNormalTargets {
+    pub textures: TextureData<DepthNormalTextures>,
+    // None when the g-buffer is used instead of a prepass
+    prepass: Option<PrepassTargets>,
+}
+
+impl DepthNormalTargets {
+    // Targets for the forward path, filled by the depth normal prepass
+    pub fn new(device: &Device, width: u32, height: u32) -> Self {
+        let normal_texture = create_target(device, "Prepass normal", width, height, NORMAL_FORMAT);
+        let depth_texture = create_target(device, "Prepass depth", width, height, DEPTH_FORMAT);
+        let sampled_normal_view = normal_texture.create_default_view();
+        let sampled_depth_view = depth_texture.create_default_view();
+        let prepass = PrepassTargets {
+            normal_view: normal_texture.create_default_view(),
+            depth_view: depth_texture.create_default_view(),
+            _depth_texture: depth_texture,
+        };
+        DepthNormalTargets {
+            textures: create_texture_data(
+                device,
+                normal_texture,
+                sampled_normal_view,
+                sampled_depth_view,
+            ),
+            prepass: Some(prepass),
+        }
+    }
+
+    // The deferred path already has the normals and depth in its g-buffer
+    pub fn from_g_buffer(device: &Device, g_buffer: &GBuffer) -> Self {
+        // the texture data needs a texture of its own, the g-buffer keeps the real ones
+        let placeholder = create_target(device, "Depth normal placeholder", 1, 1, NORMAL_FORMAT);
+        let (normal_view, depth_view) = g_buffer.normal_depth_views();
+        DepthNormalTargets {
+            textures: create_texture_data(device, placeholder, normal_view, depth_view),
+            prepass: None,
+        }
+    }
+
+    // Color and depth attachment of the prepass, None if the g-buffer is used
+    pub fn prepass_views(&self) -> Option<(&TextureView, &TextureView)> {
+        self.prepass
+            .as_ref()
+            .map(|prepass| (&prepass.normal_view, &prepass.depth_view))
+    }
+}
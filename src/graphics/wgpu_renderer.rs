@@ -1,46 +1,79 @@
+use anyhow::{bail, Result};
 use glfw::Window;
 use legion::prelude::*;
+use nalgebra::Matrix4;
 use wgpu::{
-    BackendBit, CommandEncoder, CommandEncoderDescriptor, Device, DeviceDescriptor, Extent3d,
-    Instance, Limits, LoadOp, Operations, PowerPreference, PresentMode, Queue,
+    Backend, BackendBit, CommandEncoder, CommandEncoderDescriptor, Device, DeviceDescriptor,
+    Extent3d, Instance, Limits, LoadOp, Operations, PowerPreference, PresentMode, Queue,
     RenderPassColorAttachmentDescriptor, RenderPassDepthStencilAttachmentDescriptor,
     RenderPassDescriptor, RequestAdapterOptions, ShaderStage, Surface, SwapChain,
     SwapChainDescriptor, TextureDimension, TextureFormat, TextureUsage, TextureView,
 };
 
 use super::{
+    directional_light::{DirectionalLight, DirectionalLightRaw},
+    environment_map::{EnvironmentMap, ImageBasedLighting},
+    g_buffer::GBuffer,
+    hdr_texture::{SampledTarget, SceneTargets, HDR_FORMAT},
     model::Model,
-    pass::{shadow_pass::ShadowPass, skybox_pass::SkyboxPass},
+    pass::{
+        deferred_lighting_pass::DeferredLightingPass,
+        geometry_pass::GeometryPass,
+        projected_shadow_pass::ProjectedShadowPass,
+        shadow_pass::{ShadowFaceUniforms, ShadowPass},
+        skinned_model_pass::SkinnedModelPass,
+        skybox_pass::SkyboxPass,
+        ssao_pass::SsaoPass,
+        transparent_pass::TransparentPass,
+    },
     point_light::PointLightRaw,
-    skybox_texture::SkyboxTexture,
-    PointLight,
+    render_target::{OffscreenCamera, RenderTargets},
+    screenshot::{ScreenshotQueue, ScreenshotTarget},
+    skinned_model::SkinnedModel,
+    skinning::JointPalette,
+    skybox_texture::{Skybox, SkyboxTexture},
+    spot_light::SpotLightRaw,
+    PointLight, SpotLight,
+};
+use crate::graphics::clustered_lights::{
+    warn_once, ClusteredLights, LightBounds, LightUniforms, MAX_POINT_LIGHTS, MAX_SPOT_LIGHTS,
 };
-use crate::graphics::pass::light_object_pass::LightObjectPass;
 use crate::graphics::pass::model_pass::ModelPass;
-use crate::graphics::shadow_texture::ShadowTexture;
+use crate::graphics::pass::pbr_model_pass::PbrModelPass;
+use crate::graphics::pass::post_process_pass::PostProcessPass;
+use crate::graphics::pass::tone_mapping_pass::ToneMappingPass;
+use crate::graphics::shadow_atlas::ShadowAtlas;
+use crate::graphics::shadow_texture::{ShadowFiltering, ShadowMaps};
 use crate::{
     assets::Assets,
     camera::{Camera, CameraUniform},
 };
-use crate::{components::Transform, graphics::Pass};
+use crate::{components::GlobalTransform, graphics::Pass};
 use smol_renderer::{LoadableTexture, Texture, UniformBindGroup};
+use std::path::PathBuf;
 use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::{atomic::AtomicBool, Arc};
 
 pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
-fn create_depth_texture(
+// The model passes bind their uniforms, textures, shadows, lights and ambient
+// occlusion as separate groups, twice as many as wgpu allows by default
+const REQUIRED_BIND_GROUPS: u32 = 8;
+
+pub(crate) fn create_depth_texture(
     device: &wgpu::Device,
-    sc_desc: &wgpu::SwapChainDescriptor,
+    width: u32,
+    height: u32,
+    sample_count: u32,
 ) -> wgpu::Texture {
     let desc = wgpu::TextureDescriptor {
         label: None,
         size: Extent3d {
-            width: sc_desc.width,
-            height: sc_desc.height,
+            width,
+            height,
             depth: 1,
         },
         mip_level_count: 1,
-        sample_count: 1,
+        sample_count,
         dimension: TextureDimension::D2,
         format: DEPTH_FORMAT,
         usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
@@ -48,6 +81,40 @@ fn create_depth_texture(
     device.create_texture(&desc)
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RenderPath {
+    // Every mesh is shaded against its lights while it's drawn
+    Forward,
+    // Meshes are drawn into a g-buffer first and lit afterwards, point lights
+    // only shade the pixels covered by their light volumes
+    Deferred,
+}
+
+pub struct RendererSettings {
+    // multisample anti-aliasing sample count for the scene passes, 1 disables msaa
+    pub sample_count: u32,
+    pub render_path: RenderPath,
+}
+
+impl Default for RendererSettings {
+    fn default() -> Self {
+        RendererSettings {
+            sample_count: 4,
+            render_path: RenderPath::Forward,
+        }
+    }
+}
+
+// The sample counts wgpu accepts for the scene targets on each backend, the adapter
+// can't be asked for them so this is the only place they are checked. Only 1 and 4
+// are guaranteed, the native backends also handle 2 and 8
+fn supported_sample_counts(backend: Backend) -> &'static [u32] {
+    match backend {
+        Backend::Vulkan | Backend::Metal | Backend::Dx12 | Backend::Dx11 => &[1, 2, 4, 8],
+        _ => &[1, 4],
+    }
+}
+
 pub struct WgpuRenderer {
     surface: Surface,
     pub device: Device,
@@ -56,17 +123,30 @@ pub struct WgpuRenderer {
     swap_chain: SwapChain,
     width: u32,
     height: u32,
+    sample_count: u32,
+    render_path: RenderPath,
     global_camera_uniforms: Arc<UniformBindGroup>,
-    depth_texture: wgpu::Texture,
-    depth_texture_view: TextureView,
+    clustered_lights: Rc<ClusteredLights>,
+    scene_targets: SceneTargets,
     model_pass: ModelPass,
-    light_pass: LightObjectPass,
+    pbr_model_pass: PbrModelPass,
+    skinned_model_pass: SkinnedModelPass,
+    geometry_pass: GeometryPass,
+    deferred_lighting_pass: DeferredLightingPass,
+    ssao_pass: SsaoPass,
+    transparent_pass: TransparentPass,
     skybox_pass: SkyboxPass,
     shadow_pass: ShadowPass,
+    projected_shadow_pass: ProjectedShadowPass,
+    tone_mapping_pass: ToneMappingPass,
+    post_process_pass: PostProcessPass,
+    environment_map: Rc<EnvironmentMap>,
+    loaded_skybox: PathBuf,
+    screenshot_target: Option<ScreenshotTarget>,
 }
 
 impl WgpuRenderer {
-    pub async fn new(window: &Window) -> Self {
+    pub async fn new(window: &Window, settings: RendererSettings) -> Result<Self> {
         let (width, height) = window.get_size();
 
         let instance = Instance::new(BackendBit::PRIMARY);
@@ -83,7 +163,16 @@ impl WgpuRenderer {
 
         let features = adapter.features();
         let mut limits = Limits::default();
-        limits.max_bind_groups = 6;
+        limits.max_bind_groups = REQUIRED_BIND_GROUPS;
+        let supported_bind_groups = adapter.limits().max_bind_groups;
+        if supported_bind_groups < REQUIRED_BIND_GROUPS {
+            bail!(
+                "The renderer needs {} bind groups but the adapter {:?} only supports {}",
+                REQUIRED_BIND_GROUPS,
+                adapter.get_info().name,
+                supported_bind_groups
+            );
+        }
 
         let (device, queue) = adapter
             .request_device(
@@ -117,37 +206,125 @@ impl WgpuRenderer {
                 .build(&device),
         );
 
-        let depth_texture = create_depth_texture(&device, &swap_chain_desc);
-        let depth_texture_view = depth_texture.create_default_view();
+        let render_path = settings.render_path;
+        // the g-buffer isn't multisampled
+        let sample_count = match render_path {
+            RenderPath::Forward => settings.sample_count,
+            RenderPath::Deferred => 1,
+        };
+        if sample_count != settings.sample_count {
+            eprintln!(
+                "The deferred render path doesn't support msaa, using 1 sample instead of {}",
+                settings.sample_count
+            );
+        }
+        let backend = adapter.get_info().backend;
+        let supported_sample_counts = supported_sample_counts(backend);
+        if !supported_sample_counts.contains(&sample_count) {
+            bail!(
+                "The {:?} backend doesn't support {} msaa samples, expected one of {:?}",
+                backend,
+                sample_count,
+                supported_sample_counts
+            );
+        }
+        let scene_targets = SceneTargets::new(
+            &device,
+            swap_chain_desc.width,
+            swap_chain_desc.height,
+            swap_chain_desc.format,
+            sample_count,
+            render_path,
+        );
 
-        let shadow_texture = Rc::new(ShadowTexture::allocate_texture(&device));
+        let shadow_maps = Rc::new(ShadowMaps::new(&device));
+        // TODO: should be handled as an asset instead
+        let loaded_skybox = Skybox::default().path;
+        let skybox_texture = SkyboxTexture::load_texture(&device, &queue, &loaded_skybox).unwrap();
+        let environment_map =
+            Rc::new(EnvironmentMap::precompute(&device, &queue, &skybox_texture).unwrap());
+
+        let clustered_lights = Rc::new(ClusteredLights::new(&device));
+        let joint_palette = Rc::new(JointPalette::new(&device));
 
-        let shadow_pass = ShadowPass::new(&device, shadow_texture.clone()).unwrap();
+        let shadow_pass = ShadowPass::new(&device, &shadow_maps, joint_palette.clone()).unwrap();
+        let projected_shadow_pass =
+            ProjectedShadowPass::new(&device, &shadow_maps, joint_palette.clone()).unwrap();
 
         let model_pass = ModelPass::new(
             &device,
             vec![Arc::clone(&global_camera_uniforms)],
-            shadow_texture,
-            swap_chain_desc.format,
+            shadow_maps.clone(),
+            environment_map.clone(),
+            clustered_lights.clone(),
+            HDR_FORMAT,
+            sample_count,
         )
         .unwrap();
-        let light_pass = LightObjectPass::new(
+        let pbr_model_pass = PbrModelPass::new(
             &device,
             vec![Arc::clone(&global_camera_uniforms)],
-            swap_chain_desc.format,
+            shadow_maps.clone(),
+            environment_map.clone(),
+            clustered_lights.clone(),
+            HDR_FORMAT,
+            sample_count,
+        )
+        .unwrap();
+        let skinned_model_pass = SkinnedModelPass::new(
+            &device,
+            vec![Arc::clone(&global_camera_uniforms)],
+            shadow_maps.clone(),
+            environment_map.clone(),
+            clustered_lights.clone(),
+            joint_palette.clone(),
+            HDR_FORMAT,
+            sample_count,
+        )
+        .unwrap();
+        let geometry_pass = GeometryPass::new(
+            &device,
+            vec![Arc::clone(&global_camera_uniforms)],
+            joint_palette.clone(),
+        )
+        .unwrap();
+        let deferred_lighting_pass = DeferredLightingPass::new(
+            &device,
+            shadow_maps.clone(),
+            environment_map.clone(),
+            clustered_lights.clone(),
+            HDR_FORMAT,
+        )
+        .unwrap();
+        let transparent_pass = TransparentPass::new(
+            &device,
+            vec![Arc::clone(&global_camera_uniforms)],
+            shadow_maps,
+            environment_map.clone(),
+            clustered_lights.clone(),
+            HDR_FORMAT,
+            sample_count,
+        )
+        .unwrap();
+        let ssao_pass = SsaoPass::new(
+            &device,
+            vec![Arc::clone(&global_camera_uniforms)],
+            joint_palette,
         )
         .unwrap();
-        // TODO: should be handled as an asset instead
-        let skybox_texture = SkyboxTexture::load_texture(&device, &queue, "skybox").unwrap();
         let skybox_pass = SkyboxPass::new(
             &device,
             vec![Arc::clone(&global_camera_uniforms)],
-            swap_chain_desc.format,
+            HDR_FORMAT,
+            sample_count,
             skybox_texture,
         )
         .unwrap();
+        let tone_mapping_pass = ToneMappingPass::new(&device, swap_chain_desc.format).unwrap();
+        let post_process_pass =
+            PostProcessPass::new(&device, &queue, swap_chain_desc.format).unwrap();
 
-        WgpuRenderer {
+        Ok(WgpuRenderer {
             surface,
             device,
             queue,
@@ -155,14 +332,27 @@ impl WgpuRenderer {
             swap_chain,
             width: width as u32,
             height: height as u32,
-            depth_texture,
-            depth_texture_view,
+            sample_count,
+            render_path,
+            scene_targets,
             model_pass,
-            light_pass,
+            pbr_model_pass,
+            skinned_model_pass,
+            geometry_pass,
+            deferred_lighting_pass,
+            ssao_pass,
+            transparent_pass,
             skybox_pass,
             global_camera_uniforms,
+            clustered_lights,
             shadow_pass,
-        }
+            projected_shadow_pass,
+            tone_mapping_pass,
+            post_process_pass,
+            environment_map,
+            loaded_skybox,
+            screenshot_target: None,
+        })
     }
 
     pub fn resize(&mut self, width: u32, height: u32) {
@@ -172,15 +362,29 @@ impl WgpuRenderer {
         self.swap_chain_desc.width = width;
         self.swap_chain_desc.height = height;
 
-        self.depth_texture = create_depth_texture(&self.device, &self.swap_chain_desc);
-        self.depth_texture_view = self.depth_texture.create_default_view();
+        self.scene_targets = SceneTargets::new(
+            &self.device,
+            width,
+            height,
+            self.swap_chain_desc.format,
+            self.sample_count,
+            self.render_path,
+        );
+        // recreated with the new size on the next screenshot
+        self.screenshot_target = None;
 
         self.swap_chain = self
             .device
             .create_swap_chain(&self.surface, &self.swap_chain_desc);
     }
 
-    fn update_camera_uniforms(&mut self, camera: &Camera, encoder: &mut CommandEncoder) {
+    fn update_camera_uniforms(
+        &self,
+        resources: &Resources,
+        camera: &Camera,
+        light_bounds: &[LightBounds],
+        encoder: &mut CommandEncoder,
+    ) {
         self.global_camera_uniforms
             .update_buffer_data(
                 &self.device,
@@ -192,60 +396,189 @@ impl WgpuRenderer {
                 },
             )
             .unwrap();
+        self.clustered_lights
+            .update_clusters(&self.device, encoder, camera, light_bounds);
+        if self.render_path == RenderPath::Deferred {
+            self.deferred_lighting_pass
+                .update_uniforms(&self.device, camera, encoder);
+        }
+        self.ssao_pass
+            .update_uniforms(&self.device, resources, camera, encoder);
     }
 
-    // THIS SHOULD NOT REQUIRE MUTABLE REF TO RESOURCES!
-    pub fn render_frame(&mut self, world: &mut World, resources: &mut Resources) {
-        let frame = self.swap_chain.get_next_frame().unwrap().output;
-        let camera = resources.get::<Camera>().unwrap();
-        let mut encoder = self
-            .device
-            .create_command_encoder(&CommandEncoderDescriptor {
-                label: Some("Main CommandEncoder"),
+    // Returns the bounds of the point lights for binning them into clusters,
+    // the cascades of the directional light are fitted to the given camera
+    fn update_light_uniforms(
+        &self,
+        world: &World,
+        resources: &Resources,
+        camera: &Camera,
+        encoder: &mut CommandEncoder,
+    ) -> Vec<LightBounds> {
+        // only a single directional light is supported
+        let directional_light = <Read<DirectionalLight>>::query()
+            .iter(world)
+            .next()
+            .map(|light| DirectionalLightRaw::new(&light, camera))
+            .unwrap_or_else(DirectionalLightRaw::disabled);
+        let shadow_atlas = resources
+            .get::<ShadowAtlas>()
+            .expect("Shadow atlas not registered");
+        let query = <(Read<PointLight>, Read<GlobalTransform>)>::query();
+        let point_light_count = query.iter(world).count();
+        if point_light_count > MAX_POINT_LIGHTS as usize {
+            static WARNED: AtomicBool = AtomicBool::new(false);
+            warn_once(&WARNED, || {
+                format!(
+                    "{} point lights, only the first {} are drawn",
+                    point_light_count, MAX_POINT_LIGHTS
+                )
             });
-        self.update_camera_uniforms(&camera, &mut encoder);
-        let mut asset_storage = resources.get_mut::<Assets<Model>>().unwrap();
-        // TODO: This should be in an update method instead
-        asset_storage
-            .clear_load_queue(&self.device, &self.queue)
-            .unwrap();
-        drop(asset_storage);
-        self.model_pass
-            .update_uniform_data(&world, &resources, &self.device, &mut encoder);
+        }
+        let (raw_lights, light_bounds): (Vec<_>, Vec<_>) = query
+            .iter_entities(world)
+            .take(MAX_POINT_LIGHTS as usize)
+            .map(|(entity, (light, transform))| {
+                let position = transform.translation();
+                let shadow_slot = shadow_atlas.point_light_slot(entity);
+                (
+                    PointLightRaw::from((&*light, position, shadow_slot)),
+                    LightBounds {
+                        position,
+                        range: light.range,
+                    },
+                )
+            })
+            .unzip();
+        let spot_query = <(Read<SpotLight>, Read<GlobalTransform>)>::query();
+        let shadow_filtering = resources
+            .get::<ShadowFiltering>()
+            .expect("Shadow filtering not registered");
+        let spot_light_count = spot_query.iter(world).count();
+        if spot_light_count > MAX_SPOT_LIGHTS as usize {
+            static WARNED: AtomicBool = AtomicBool::new(false);
+            warn_once(&WARNED, || {
+                format!(
+                    "{} spot lights, only the first {} are drawn",
+                    spot_light_count, MAX_SPOT_LIGHTS
+                )
+            });
+        }
+        let raw_spot_lights = spot_query
+            .iter_entities(world)
+            .take(MAX_SPOT_LIGHTS as usize)
+            .map(|(entity, (light, transform))| {
+                SpotLightRaw::from((&*light, &*transform, shadow_atlas.spot_light_slot(entity)))
+            })
+            .collect::<Vec<_>>();
+        self.clustered_lights.update_lights(
+            &self.device,
+            encoder,
+            &LightUniforms::new(directional_light, &raw_spot_lights, &shadow_filtering),
+            &raw_lights,
+        );
+        light_bounds
+    }
 
-        // move somewhere else this isn't as nice
-        self.shadow_pass.update_lights_with_texture_view(world);
-        let query = <(Read<PointLight>, Read<Transform>)>::query();
-        for (light, transform) in query.iter(world) {
-            let raw_light = PointLightRaw::from((&*light, transform.translation()));
-            self.shadow_pass
-                .update_uniforms(&self.device, &raw_light, &mut encoder);
-            self.shadow_pass.render(
-                &resources,
-                world,
-                &mut encoder,
-                RenderPassDescriptor {
-                    color_attachments: &[],
-                    depth_stencil_attachment: Some(RenderPassDepthStencilAttachmentDescriptor {
-                        attachment: light.target_view.as_ref().unwrap(),
-                        depth_ops: Some(Operations {
-                            load: LoadOp::Clear(1.0),
-                            store: true,
-                        }),
-                        stencil_ops: None,
-                    }),
-                },
-            );
+    fn render_projected_shadow(
+        &self,
+        world: &World,
+        resources: &Resources,
+        encoder: &mut CommandEncoder,
+        view_projection: Matrix4<f32>,
+        target: &TextureView,
+    ) {
+        self.projected_shadow_pass
+            .update_uniforms(&self.device, view_projection, encoder);
+        self.projected_shadow_pass
+            .render(resources, world, encoder, target);
+        self.projected_shadow_pass
+            .render_skinned(resources, world, encoder, target);
+    }
+
+    // The cascades depend on the camera so they are rendered again for every view
+    fn render_cascade_shadows(
+        &self,
+        world: &World,
+        resources: &Resources,
+        camera: &Camera,
+        encoder: &mut CommandEncoder,
+    ) {
+        let light_query = <Read<DirectionalLight>>::query();
+        if let Some(light) = light_query
+            .iter(world)
+            .next()
+            .filter(|light| light.cast_shadows)
+        {
+            let view_projections = light.cascade_view_projections(camera);
+            for (cascade, view_projection) in view_projections.iter().enumerate() {
+                self.render_projected_shadow(
+                    world,
+                    resources,
+                    encoder,
+                    *view_projection,
+                    self.projected_shadow_pass.cascade_view(cascade),
+                );
+            }
+        }
+    }
+
+    // Spot lights only need a single projection each
+    fn render_spot_shadows(
+        &self,
+        world: &World,
+        resources: &Resources,
+        encoder: &mut CommandEncoder,
+    ) {
+        let shadow_atlas = resources
+            .get::<ShadowAtlas>()
+            .expect("Shadow atlas not registered");
+        let spot_query = <(Read<SpotLight>, Read<GlobalTransform>)>::query();
+        for (entity, (light, transform)) in spot_query.iter_entities(world) {
+            if let Some(slot) = shadow_atlas.spot_light_slot(entity) {
+                self.render_projected_shadow(
+                    world,
+                    resources,
+                    encoder,
+                    light.view_projection(&transform),
+                    self.projected_shadow_pass.spot_view(slot as usize),
+                );
+            }
         }
+    }
+
+    // Records everything that depends on the camera before its scene is rendered,
+    // returns the number of point lights
+    fn update_view(
+        &self,
+        world: &World,
+        resources: &Resources,
+        camera: &Camera,
+        encoder: &mut CommandEncoder,
+    ) -> u32 {
+        let light_bounds = self.update_light_uniforms(world, resources, camera, encoder);
+        self.render_cascade_shadows(world, resources, camera, encoder);
+        self.update_camera_uniforms(resources, camera, &light_bounds, encoder);
+        light_bounds.len() as u32
+    }
 
+    fn render_scene(
+        &self,
+        world: &World,
+        resources: &Resources,
+        encoder: &mut CommandEncoder,
+        camera: &Camera,
+        targets: &SceneTargets,
+        point_light_count: u32,
+    ) {
         self.skybox_pass.render(
             &resources,
             world,
-            &mut encoder,
+            encoder,
             RenderPassDescriptor {
                 color_attachments: &[RenderPassColorAttachmentDescriptor {
-                    attachment: &frame.view,
-                    resolve_target: None,
+                    attachment: targets.color_attachment(),
+                    resolve_target: targets.resolve_target(),
                     ops: Operations {
                         load: LoadOp::Clear(wgpu::Color {
                             r: 0.1,
@@ -260,52 +593,377 @@ impl WgpuRenderer {
             },
         );
 
-        self.model_pass.render(
-            &resources,
+        let ambient_occlusion = match targets.g_buffer() {
+            Some(g_buffer) => self.render_deferred(
+                world,
+                resources,
+                encoder,
+                targets,
+                g_buffer,
+                point_light_count,
+            ),
+            None => {
+                self.ssao_pass
+                    .render_depth_normals(resources, world, encoder, targets);
+                let ambient_occlusion = self.ssao_pass.render(resources, encoder, targets);
+                self.model_pass.render(
+                    &resources,
+                    world,
+                    ambient_occlusion.texture(),
+                    encoder,
+                    RenderPassDescriptor {
+                        color_attachments: &[RenderPassColorAttachmentDescriptor {
+                            attachment: targets.color_attachment(),
+                            resolve_target: targets.resolve_target(),
+                            ops: Operations {
+                                load: LoadOp::Load,
+                                store: true,
+                            },
+                        }],
+                        depth_stencil_attachment: Some(
+                            RenderPassDepthStencilAttachmentDescriptor {
+                                attachment: targets.depth_view(),
+                                depth_ops: Some(Operations {
+                                    load: LoadOp::Clear(1.0),
+                                    store: true,
+                                }),
+                                stencil_ops: None,
+                            },
+                        ),
+                    },
+                );
+                self.pbr_model_pass.render(
+                    &resources,
+                    world,
+                    ambient_occlusion.texture(),
+                    encoder,
+                    RenderPassDescriptor {
+                        color_attachments: &[RenderPassColorAttachmentDescriptor {
+                            attachment: targets.color_attachment(),
+                            resolve_target: targets.resolve_target(),
+                            ops: Operations {
+                                load: LoadOp::Load,
+                                store: true,
+                            },
+                        }],
+                        depth_stencil_attachment: Some(
+                            RenderPassDepthStencilAttachmentDescriptor {
+                                attachment: targets.depth_view(),
+                                depth_ops: Some(Operations {
+                                    load: LoadOp::Load,
+                                    store: true,
+                                }),
+                                stencil_ops: None,
+                            },
+                        ),
+                    },
+                );
+                self.skinned_model_pass.render(
+                    &resources,
+                    world,
+                    ambient_occlusion.texture(),
+                    encoder,
+                    RenderPassDescriptor {
+                        color_attachments: &[RenderPassColorAttachmentDescriptor {
+                            attachment: targets.color_attachment(),
+                            resolve_target: targets.resolve_target(),
+                            ops: Operations {
+                                load: LoadOp::Load,
+                                store: true,
+                            },
+                        }],
+                        depth_stencil_attachment: Some(
+                            RenderPassDepthStencilAttachmentDescriptor {
+                                attachment: targets.depth_view(),
+                                depth_ops: Some(Operations {
+                                    load: LoadOp::Load,
+                                    store: true,
+                                }),
+                                stencil_ops: None,
+                            },
+                        ),
+                    },
+                );
+                ambient_occlusion
+            }
+        };
+        self.transparent_pass.render(
+            resources,
             world,
-            &mut encoder,
+            camera,
+            ambient_occlusion.texture(),
+            encoder,
+            targets,
+        );
+    }
+
+    // Fills the g-buffer and lights it on top of the skybox,
+    // returns the ambient occlusion for the transparent pass
+    fn render_deferred<'a>(
+        &self,
+        world: &World,
+        resources: &Resources,
+        encoder: &mut CommandEncoder,
+        targets: &'a SceneTargets,
+        g_buffer: &GBuffer,
+        point_light_count: u32,
+    ) -> &'a SampledTarget {
+        self.geometry_pass
+            .render(resources, world, encoder, g_buffer);
+        // the occlusion is computed from the normals and depth in the g-buffer
+        let ambient_occlusion = self.ssao_pass.render(resources, encoder, targets);
+        self.deferred_lighting_pass.render_lighting(
+            &g_buffer.textures,
+            ambient_occlusion.texture(),
+            encoder,
             RenderPassDescriptor {
                 color_attachments: &[RenderPassColorAttachmentDescriptor {
-                    attachment: &frame.view,
-                    resolve_target: None,
+                    attachment: targets.color_attachment(),
+                    resolve_target: targets.resolve_target(),
                     ops: Operations {
                         load: LoadOp::Load,
                         store: true,
                     },
                 }],
-                depth_stencil_attachment: Some(RenderPassDepthStencilAttachmentDescriptor {
-                    attachment: &self.depth_texture_view,
-                    depth_ops: Some(Operations {
-                        load: LoadOp::Clear(1.0),
-                        store: true,
-                    }),
-                    stencil_ops: None,
-                }),
+                depth_stencil_attachment: None,
             },
         );
-        self.light_pass.render(
-            &resources,
-            world,
-            &mut encoder,
+        self.deferred_lighting_pass.render_light_volumes(
+            &g_buffer.textures,
+            point_light_count,
+            encoder,
             RenderPassDescriptor {
                 color_attachments: &[RenderPassColorAttachmentDescriptor {
-                    attachment: &frame.view,
-                    resolve_target: None,
+                    attachment: targets.color_attachment(),
+                    resolve_target: targets.resolve_target(),
                     ops: Operations {
                         load: LoadOp::Load,
                         store: true,
                     },
                 }],
-                depth_stencil_attachment: Some(RenderPassDepthStencilAttachmentDescriptor {
-                    attachment: &self.depth_texture_view,
-                    depth_ops: Some(Operations {
-                        load: LoadOp::Load,
+                depth_stencil_attachment: None,
+            },
+        );
+        ambient_occlusion
+    }
+
+    // Bloom -> tone mapping -> ldr post effects -> output
+    fn resolve_scene(
+        &self,
+        resources: &Resources,
+        encoder: &mut CommandEncoder,
+        targets: &SceneTargets,
+        output_view: &TextureView,
+    ) {
+        let bloom = self
+            .post_process_pass
+            .render_bloom(resources, encoder, targets);
+        let effects = self.post_process_pass.enabled_effects(resources);
+        let tone_mapped_view = if effects.is_empty() {
+            output_view
+        } else {
+            targets.ldr(0).view()
+        };
+        self.tone_mapping_pass.render(
+            targets.hdr().texture(),
+            bloom.texture(),
+            encoder,
+            RenderPassDescriptor {
+                color_attachments: &[RenderPassColorAttachmentDescriptor {
+                    attachment: tone_mapped_view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(wgpu::Color::BLACK),
                         store: true,
-                    }),
-                    stencil_ops: None,
-                }),
+                    },
+                }],
+                depth_stencil_attachment: None,
             },
         );
+        self.post_process_pass
+            .render_effects(&effects, encoder, targets, output_view);
+    }
+
+    // THIS SHOULD NOT REQUIRE MUTABLE REF TO RESOURCES!
+    // A skybox that fails to load keeps the previous one
+    fn update_skybox(&mut self, resources: &Resources) {
+        let skybox = resources.get::<Skybox>().expect("Skybox not registered");
+        if skybox.path == self.loaded_skybox {
+            return;
+        }
+        match SkyboxTexture::load_texture(&self.device, &self.queue, &skybox.path) {
+            Ok(skybox_texture) => {
+                self.environment_map
+                    .convolve(&self.device, &self.queue, &skybox_texture);
+                self.skybox_pass.set_skybox_texture(skybox_texture);
+            }
+            Err(err) => eprintln!("Failed to load skybox {:?}: {}", skybox.path, err),
+        }
+        self.loaded_skybox = skybox.path.clone();
+    }
+
+    pub fn render_frame(&mut self, world: &mut World, resources: &mut Resources) {
+        let frame = self.swap_chain.get_next_frame().unwrap().output;
+        let mut encoder = self
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor {
+                label: Some("Main CommandEncoder"),
+            });
+        let mut asset_storage = resources.get_mut::<Assets<Model>>().unwrap();
+        // TODO: This should be in an update method instead
+        asset_storage
+            .clear_load_queue(&self.device, &self.queue)
+            .unwrap();
+        drop(asset_storage);
+        resources
+            .get_mut::<Assets<SkinnedModel>>()
+            .unwrap()
+            .clear_load_queue(&self.device, &self.queue)
+            .unwrap();
+        resources
+            .get_mut::<RenderTargets>()
+            .expect("Render targets not registered")
+            .clear_create_queue(
+                &self.device,
+                self.swap_chain_desc.format,
+                self.sample_count,
+                self.render_path,
+            );
+        resources
+            .get_mut::<ShadowAtlas>()
+            .expect("Shadow atlas not registered")
+            .update(world);
+        self.update_skybox(resources);
+        self.model_pass
+            .update_uniform_data(&world, &resources, &self.device, &mut encoder);
+        self.skinned_model_pass
+            .update_uniform_data(&world, &resources, &self.device, &mut encoder);
+        self.tone_mapping_pass
+            .update_uniforms(&self.device, &resources, &mut encoder);
+        self.post_process_pass
+            .update_uniforms(&self.device, &self.queue, &resources, &mut encoder);
+        self.environment_map.update_lighting(
+            &self.queue,
+            &resources
+                .get::<ImageBasedLighting>()
+                .expect("Image based lighting not registered"),
+        );
+
+        let shadow_atlas = resources
+            .get::<ShadowAtlas>()
+            .expect("Shadow atlas not registered");
+        let query = <(Read<PointLight>, Read<GlobalTransform>)>::query();
+        for (entity, (light, transform)) in query.iter_entities(world) {
+            let target_views = match shadow_atlas.point_light_slot(entity) {
+                Some(slot) => self.shadow_pass.face_views(slot),
+                None => continue,
+            };
+            let position = transform.translation();
+            let view_projections = light.shadow_view_projections(position);
+            for (view_projection, target_view) in view_projections.into_iter().zip(target_views) {
+                self.shadow_pass.update_uniforms(
+                    &self.device,
+                    &ShadowFaceUniforms {
+                        view_projection,
+                        light_position: position,
+                        far_plane: light.shadow_far_plane,
+                    },
+                    &mut encoder,
+                );
+                self.shadow_pass.render(
+                    &resources,
+                    world,
+                    &mut encoder,
+                    RenderPassDescriptor {
+                        color_attachments: &[],
+                        depth_stencil_attachment: Some(
+                            RenderPassDepthStencilAttachmentDescriptor {
+                                attachment: target_view,
+                                depth_ops: Some(Operations {
+                                    load: LoadOp::Clear(1.0),
+                                    store: true,
+                                }),
+                                stencil_ops: None,
+                            },
+                        ),
+                    },
+                );
+                self.shadow_pass
+                    .render_skinned(&resources, world, &mut encoder, target_view);
+            }
+        }
+        drop(shadow_atlas);
+
+        self.render_spot_shadows(world, resources, &mut encoder);
+
+        // The camera uniforms are updated through copies recorded in the encoder
+        // so each camera sees its own data in the passes recorded after it
+        let render_targets = resources
+            .get::<RenderTargets>()
+            .expect("Render targets not registered");
+        let offscreen_query = <Read<OffscreenCamera>>::query();
+        for offscreen_camera in offscreen_query.iter(world) {
+            if let Some(target) = render_targets.get(&offscreen_camera.target) {
+                let point_light_count =
+                    self.update_view(world, resources, &offscreen_camera.camera, &mut encoder);
+                self.render_scene(
+                    world,
+                    resources,
+                    &mut encoder,
+                    &offscreen_camera.camera,
+                    target.scene_targets(),
+                    point_light_count,
+                );
+                self.resolve_scene(
+                    resources,
+                    &mut encoder,
+                    target.scene_targets(),
+                    target.color_view(),
+                );
+            }
+        }
+        drop(render_targets);
+
+        let camera = resources.get::<Camera>().unwrap();
+        let point_light_count = self.update_view(world, resources, &camera, &mut encoder);
+        self.render_scene(
+            world,
+            resources,
+            &mut encoder,
+            &camera,
+            &self.scene_targets,
+            point_light_count,
+        );
+        self.resolve_scene(resources, &mut encoder, &self.scene_targets, &frame.view);
+        drop(camera);
+
+        let screenshot_path = resources
+            .get_mut::<ScreenshotQueue>()
+            .and_then(|mut screenshots| screenshots.pop());
+        if screenshot_path.is_some() && self.screenshot_target.is_none() {
+            self.screenshot_target = Some(ScreenshotTarget::new(
+                &self.device,
+                self.width,
+                self.height,
+                self.swap_chain_desc.format,
+            ));
+        }
+        if let (Some(_), Some(target)) = (&screenshot_path, &self.screenshot_target) {
+            // the hdr buffer still contains the main camera's view
+            self.resolve_scene(
+                resources,
+                &mut encoder,
+                &self.scene_targets,
+                target.color_view(),
+            );
+            target.copy_to_buffer(&mut encoder);
+        }
         self.queue.submit(vec![encoder.finish()]);
+
+        if let (Some(path), Some(target)) = (screenshot_path, &self.screenshot_target) {
+            if let Err(err) = target.save_png(&self.device, &path) {
+                eprintln!("Failed to save screenshot {:?}: {}", path, err);
+            }
+        }
     }
 }
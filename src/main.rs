@@ -1,9 +1,13 @@
+mod animation;
 mod assets;
 mod camera;
 mod components;
 mod engine;
 mod graphics;
 mod physics;
+mod prefab;
+mod save_game;
+mod scene;
 mod states;
 
 use crate::engine::Engine;
@@ -13,11 +17,11 @@ use crate::states::BasicState;
 #[global_allocator]
 static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
-fn main() {
-    let mut engine = Engine::new(
-        "Smol engine",
-        Box::new(BasicState::new()),
-        //   BasicRenderer::new(),
-    );
+fn main() -> anyhow::Result<()> {
+    let mut engine = Engine::builder()
+        .set_name("Smol engine")
+        .set_msaa_samples(4)
+        .build(Box::new(BasicState::new()))?;
     engine.run();
+    Ok(())
 }
@@ -1,7 +1,7 @@
-use crate::components::{PhysicsBody, Transform};
+use crate::components::{GlobalTransform, Parent, PhysicsBody, Transform};
 
 use legion::prelude::*;
-use nalgebra::Vector3;
+use nalgebra::{Isometry3, Matrix4, Vector3};
 use ncollide3d::shape::{Ball, Cuboid, ShapeHandle};
 use nphysics3d::force_generator::DefaultForceGeneratorSet;
 use nphysics3d::joint::DefaultJointConstraintSet;
@@ -9,6 +9,7 @@ use nphysics3d::object::{
     BodyPartHandle, BodyStatus, ColliderDesc, DefaultBodySet, DefaultColliderSet, RigidBodyDesc,
 };
 use nphysics3d::world::{DefaultGeometricalWorld, DefaultMechanicalWorld};
+use std::collections::HashMap;
 
 // absolute shite this is
 pub struct Physics {
@@ -37,12 +38,19 @@ impl Physics {
             .write_resource::<DefaultForceGeneratorSet<f32>>()
             .write_resource::<DefaultBodySet<f32>>()
             .write_resource::<DefaultColliderSet<f32>>()
-            .with_query(<(Read<PhysicsBody>, Write<Transform>)>::query())
+            .read_component::<GlobalTransform>()
+            .with_query(
+                <(Read<PhysicsBody>, Write<Transform>)>::query().filter(!component::<Parent>()),
+            )
+            .with_query(<(Read<PhysicsBody>, Read<Parent>)>::query())
+            .with_query(
+                <(Read<PhysicsBody>, Write<Transform>)>::query().filter(component::<Parent>()),
+            )
             .build(
                 |_,
                  world,
                  (mech_world, geo_world, joint_set, force_gen, body_set, collider_set),
-                 query| {
+                 (query, parent_query, child_query)| {
                     mech_world.step(
                         geo_world,
                         body_set as &mut DefaultBodySet<f32>,
@@ -55,11 +63,35 @@ impl Physics {
                             transform.isometry = *collider.position();
                         }
                     }
+                    // bodies live in world space, children store their pose relative to the
+                    // global transform of the parent as of the last frame
+                    let parent_inverses = parent_query
+                        .iter_entities(world)
+                        .filter_map(|(entity, (_, parent))| {
+                            world
+                                .get_component::<GlobalTransform>(parent.0)
+                                .and_then(|global| global.matrix.try_inverse())
+                                .map(|inverse| (entity, inverse))
+                        })
+                        .collect::<HashMap<_, _>>();
+                    for (entity, (physics_body, mut transform)) in
+                        child_query.iter_entities_mut(world)
+                    {
+                        if let Some(collider) = collider_set.get(physics_body.collider_handle) {
+                            let parent_inverse = parent_inverses
+                                .get(&entity)
+                                .copied()
+                                .unwrap_or_else(Matrix4::identity);
+                            let matrix = parent_inverse * collider.position().to_homogeneous();
+                            transform.isometry = Transform::from_matrix(&matrix).isometry;
+                        }
+                    }
                 },
             );
         Physics { system }
     }
 
+    // Bodies live in world space, the transform has to be the world pose of the entity
     pub fn create_cube(
         resources: &mut Resources,
         transform: &Transform,
@@ -131,4 +163,32 @@ impl Physics {
             collider_handle,
         }
     }
+
+    // Moves the body to the world space position, for when something else than the
+    // simulation moves the entity. The physics system would move it back otherwise
+    pub fn set_position(
+        resources: &mut Resources,
+        physics_body: &PhysicsBody,
+        position: Isometry3<f32>,
+    ) {
+        if let Some(rigid_body) = resources
+            .get_mut::<DefaultBodySet<f32>>()
+            .expect("Default body set not added as a resource")
+            .rigid_body_mut(physics_body.body_handle)
+        {
+            rigid_body.set_position(position);
+        }
+    }
+
+    // Removes the body and collider behind the handles, the component itself is left alone
+    pub fn remove_body(resources: &mut Resources, physics_body: &PhysicsBody) {
+        resources
+            .get_mut::<DefaultColliderSet<f32>>()
+            .expect("Collider set not added to resources yet")
+            .remove(physics_body.collider_handle);
+        resources
+            .get_mut::<DefaultBodySet<f32>>()
+            .expect("Default body set not added as a resource")
+            .remove(physics_body.body_handle);
+    }
 }
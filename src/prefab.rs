@@ -0,0 +1,557 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use legion::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    assets::{Assets, Handle},
+    components::{update_global_transform, PhysicsBody, Transform},
+    graphics::{model::Model, DirectionalLight, PointLight, SpotLight},
+    physics::Physics,
+    scene::{add_component, EntityDescription},
+};
+
+// A reusable entity, stored as RON like scenes. If the entity names a prefab
+// itself it extends that one. Children are parented to the instance, or to an
+// earlier child when their parent is set, and can be instances of other prefabs
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Prefab {
+    pub entity: EntityDescription,
+    pub children: Vec<EntityDescription>,
+}
+
+impl Prefab {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let file = File::open(path.as_ref())
+            .with_context(|| format!("Can't open prefab {:?}", path.as_ref()))?;
+        ron::de::from_reader(file).with_context(|| format!("Invalid prefab {:?}", path.as_ref()))
+    }
+}
+
+// Prefabs are read the first time they are instantiated and kept until reloaded
+#[derive(Default)]
+pub struct Prefabs {
+    loaded: HashMap<PathBuf, Prefab>,
+}
+
+impl Prefabs {
+    pub fn new() -> Self {
+        Prefabs::default()
+    }
+
+    pub fn get(&mut self, path: impl AsRef<Path>) -> Result<&Prefab> {
+        let path = path.as_ref();
+        if !self.loaded.contains_key(path) {
+            let prefab = Prefab::load(path)?;
+            self.loaded.insert(path.to_path_buf(), prefab);
+        }
+        Ok(&self.loaded[path])
+    }
+
+    // Reads every loaded prefab again and returns the ones that changed,
+    // prefabs that fail to load keep their old contents
+    pub fn reload(&mut self) -> HashSet<PathBuf> {
+        let mut changed = HashSet::new();
+        for (path, prefab) in self.loaded.iter_mut() {
+            match Prefab::load(path) {
+                Ok(reloaded) if reloaded != *prefab => {
+                    *prefab = reloaded;
+                    changed.insert(path.clone());
+                }
+                Ok(_) => {}
+                Err(err) => eprintln!("Failed to reload prefab {:?}: {:?}", path, err),
+            }
+        }
+        changed
+    }
+
+    // The prefab with the prefabs it extends merged in, every file
+    // that was used is added to the dependencies
+    fn resolve(
+        &mut self,
+        path: &Path,
+        extended: &mut Vec<PathBuf>,
+        dependencies: &mut HashSet<PathBuf>,
+    ) -> Result<Prefab> {
+        if extended.iter().any(|extended| extended == path) {
+            bail!("Prefab {:?} extends itself", path);
+        }
+        dependencies.insert(path.to_path_buf());
+        let prefab = self.get(path)?.clone();
+        let base_path = match &prefab.entity.prefab {
+            Some(base_path) => base_path,
+            None => return Ok(prefab),
+        };
+        extended.push(path.to_path_buf());
+        let base = self.resolve(base_path, extended, dependencies);
+        extended.pop();
+        let base = base?;
+        // the parents of the children are indices into the combined children
+        let offset = base.children.len();
+        let children = base
+            .children
+            .into_iter()
+            .chain(prefab.children.into_iter().map(|mut child| {
+                child.parent = child.parent.map(|parent| parent + offset);
+                child
+            }))
+            .collect();
+        Ok(Prefab {
+            entity: prefab.entity.merged(&base.entity)?,
+            children,
+        })
+    }
+}
+
+// Added to every entity spawned from a prefab so it can be rebuilt when the prefab changes
+pub struct PrefabInstance {
+    pub prefab: PathBuf,
+    // fields of the entity that replace the ones of the prefab
+    pub overrides: EntityDescription,
+    // the entity of the prefab when it was last applied, without the overrides
+    pub base: EntityDescription,
+    // entities spawned for the children of the prefab, they are
+    // deleted and spawned again when the prefab changes
+    pub children: Vec<Entity>,
+    // the prefab, the prefabs it extends and the prefabs of its children
+    pub(crate) dependencies: HashSet<PathBuf>,
+}
+
+// Spawns the prefab under parent with the fields of overrides replacing the ones of
+// the prefab, the transform of overrides places the prefab. Returns the root entity
+pub fn instantiate(
+    world: &mut World,
+    resources: &mut Resources,
+    path: impl AsRef<Path>,
+    overrides: EntityDescription,
+    parent: Option<Entity>,
+) -> Result<Entity> {
+    instantiate_impl(
+        world,
+        resources,
+        path.as_ref(),
+        &overrides,
+        parent,
+        &mut Vec::new(),
+    )
+    .map(|(entity, _)| entity)
+}
+
+fn instantiate_impl(
+    world: &mut World,
+    resources: &mut Resources,
+    path: &Path,
+    overrides: &EntityDescription,
+    parent: Option<Entity>,
+    instantiating: &mut Vec<PathBuf>,
+) -> Result<(Entity, HashSet<PathBuf>)> {
+    if instantiating
+        .iter()
+        .any(|instantiating| instantiating == path)
+    {
+        bail!("Prefab {:?} contains itself", path);
+    }
+    let mut dependencies = HashSet::new();
+    let prefab = resources
+        .get_mut::<Prefabs>()
+        .expect("Prefabs not registered")
+        .resolve(path, &mut Vec::new(), &mut dependencies)?;
+    // the parent index only means something to whoever spawned the instance
+    let overrides = EntityDescription {
+        prefab: None,
+        parent: None,
+        ..overrides.clone()
+    };
+    let entity = overrides
+        .merged(&prefab.entity)?
+        .spawn(world, resources, parent)?;
+    instantiating.push(path.to_path_buf());
+    let children = spawn_children(
+        world,
+        resources,
+        entity,
+        &prefab.children,
+        instantiating,
+        &mut dependencies,
+    );
+    instantiating.pop();
+    let instance = PrefabInstance {
+        prefab: path.to_path_buf(),
+        overrides,
+        base: prefab.entity,
+        children: children?,
+        dependencies: dependencies.clone(),
+    };
+    add_component(world, entity, instance)?;
+    Ok((entity, dependencies))
+}
+
+fn spawn_children(
+    world: &mut World,
+    resources: &mut Resources,
+    root: Entity,
+    descriptions: &[EntityDescription],
+    instantiating: &mut Vec<PathBuf>,
+    dependencies: &mut HashSet<PathBuf>,
+) -> Result<Vec<Entity>> {
+    let mut children = Vec::with_capacity(descriptions.len());
+    for description in descriptions {
+        let parent = match description.parent {
+            Some(parent) => *children
+                .get(parent)
+                .ok_or_else(|| anyhow!("There is no earlier child {} to parent to", parent))?,
+            None => root,
+        };
+        let child = match &description.prefab {
+            Some(path) => {
+                let (child, child_dependencies) = instantiate_impl(
+                    world,
+                    resources,
+                    path,
+                    description,
+                    Some(parent),
+                    instantiating,
+                )?;
+                dependencies.extend(child_dependencies);
+                child
+            }
+            None => description.spawn(world, resources, Some(parent))?,
+        };
+        children.push(child);
+    }
+    Ok(children)
+}
+
+// Deletes the entity along with the children it was instantiated with and its physics body
+fn despawn(world: &mut World, resources: &mut Resources, entity: Entity) {
+    let children = world
+        .get_component::<PrefabInstance>(entity)
+        .map(|instance| instance.children.clone())
+        .unwrap_or_default();
+    for child in children {
+        despawn(world, resources, child);
+    }
+    if let Some(physics_body) = world.get_component::<PhysicsBody>(entity).map(|body| *body) {
+        Physics::remove_body(resources, &physics_body);
+    }
+    world.delete(entity);
+}
+
+fn replace_component<T: Component>(
+    world: &mut World,
+    entity: Entity,
+    component: Option<T>,
+) -> Result<()> {
+    let exists = world.get_component::<T>(entity).is_some();
+    match component {
+        Some(component) if exists => {
+            if let Some(mut current) = world.get_component_mut::<T>(entity) {
+                *current = component;
+            }
+            Ok(())
+        }
+        Some(component) => add_component(world, entity, component),
+        None if exists => world
+            .remove_component::<T>(entity)
+            .map_err(|_| anyhow!("Can't remove a component from {:?}", entity)),
+        None => Ok(()),
+    }
+}
+
+// Only the fields that changed in the prefab are written, so the parts of
+// the instance that the prefab edit didn't touch keep their current state
+fn apply_changes(
+    world: &mut World,
+    resources: &mut Resources,
+    entity: Entity,
+    old: &EntityDescription,
+    new: &EntityDescription,
+) -> Result<()> {
+    if old.transform != new.transform {
+        // keeps where the instance has moved to and swaps the transform of the prefab under it
+        let old_matrix = old
+            .transform
+            .as_ref()
+            .map(Transform::from)
+            .unwrap_or_default()
+            .get_model_matrix();
+        let new_transform = new
+            .transform
+            .as_ref()
+            .map(Transform::from)
+            .unwrap_or_default();
+        if let Some(mut transform) = world.get_component_mut::<Transform>(entity) {
+            // a prefab transform with a zero scale can't be undone, the instance is reset
+            // instead. The product is only split into a Transform at the end
+            *transform = match old_matrix.try_inverse() {
+                Some(inverse) => Transform::try_from_matrix(
+                    &(transform.get_model_matrix() * inverse * new_transform.get_model_matrix()),
+                )
+                .ok_or_else(|| {
+                    anyhow!(
+                        "The new transform of the prefab would shear the instance {:?}",
+                        entity
+                    )
+                })?,
+                None => new_transform,
+            };
+        }
+        // a body that is replaced below is created at the new position anyway
+        let physics_body = world
+            .get_component::<PhysicsBody>(entity)
+            .map(|body| *body)
+            .filter(|_| old.physics == new.physics);
+        if let Some(physics_body) = physics_body {
+            let matrix = update_global_transform(world, entity)?;
+            Physics::set_position(
+                resources,
+                &physics_body,
+                Transform::from_matrix(&matrix).isometry,
+            );
+        }
+    }
+    if old.model != new.model {
+        if world.get_tag::<Handle<Model>>(entity).is_some() {
+            world
+                .remove_tag::<Handle<Model>>(entity)
+                .map_err(|_| anyhow!("Can't remove the model of {:?}", entity))?;
+        }
+        if let Some(path) = &new.model {
+            let model = resources
+                .get_mut::<Assets<Model>>()
+                .expect("Asset not registerd")
+                .load(path)?;
+            world
+                .add_tag(entity, model)
+                .map_err(|_| anyhow!("Can't add a model to {:?}", entity))?;
+        }
+    }
+    if old.point_light != new.point_light {
+        replace_component::<PointLight>(world, entity, new.point_light.clone())?;
+    }
+    if old.spot_light != new.spot_light {
+        replace_component::<SpotLight>(world, entity, new.spot_light.clone())?;
+    }
+    if old.directional_light != new.directional_light {
+        replace_component::<DirectionalLight>(world, entity, new.directional_light.clone())?;
+    }
+    if old.physics != new.physics {
+        if let Some(physics_body) = world.get_component::<PhysicsBody>(entity).map(|body| *body) {
+            Physics::remove_body(resources, &physics_body);
+        }
+        let has_transform = world.get_component::<Transform>(entity).is_some();
+        let physics_body = match &new.physics {
+            Some(physics) if has_transform => {
+                let matrix = update_global_transform(world, entity)?;
+                Some(physics.create(resources, &Transform::from_matrix(&matrix)))
+            }
+            Some(_) => bail!("Physics bodies need a transform"),
+            None => None,
+        };
+        replace_component(world, entity, physics_body)?;
+    }
+    Ok(())
+}
+
+fn rebuild(world: &mut World, resources: &mut Resources, entity: Entity) -> Result<()> {
+    let (path, overrides, base, children) = match world.get_component::<PrefabInstance>(entity) {
+        Some(instance) => (
+            instance.prefab.clone(),
+            instance.overrides.clone(),
+            instance.base.clone(),
+            instance.children.clone(),
+        ),
+        None => bail!("{:?} isn't a prefab instance", entity),
+    };
+    for child in children {
+        despawn(world, resources, child);
+    }
+    let mut dependencies = HashSet::new();
+    let prefab = resources
+        .get_mut::<Prefabs>()
+        .expect("Prefabs not registered")
+        .resolve(&path, &mut Vec::new(), &mut dependencies)?;
+    apply_changes(
+        world,
+        resources,
+        entity,
+        &overrides.merged(&base)?,
+        &overrides.merged(&prefab.entity)?,
+    )?;
+    let children = spawn_children(
+        world,
+        resources,
+        entity,
+        &prefab.children,
+        &mut vec![path],
+        &mut dependencies,
+    )?;
+    if let Some(mut instance) = world.get_component_mut::<PrefabInstance>(entity) {
+        instance.base = prefab.entity;
+        instance.children = children;
+        instance.dependencies = dependencies;
+    }
+    Ok(())
+}
+
+// Reads the prefabs from disk again and rebuilds the instances of the ones that changed
+pub fn reload_prefabs(world: &mut World, resources: &mut Resources) -> Result<()> {
+    let changed = resources
+        .get_mut::<Prefabs>()
+        .expect("Prefabs not registered")
+        .reload();
+    if changed.is_empty() {
+        return Ok(());
+    }
+    // instances nested in other prefabs are rebuilt along with the outermost one
+    let query = <Read<PrefabInstance>>::query();
+    let nested = query
+        .iter(world)
+        .flat_map(|instance| instance.children.clone())
+        .collect::<HashSet<_>>();
+    let stale = query
+        .iter_entities(world)
+        .filter(|(entity, instance)| {
+            !nested.contains(entity) && !instance.dependencies.is_disjoint(&changed)
+        })
+        .map(|(entity, _)| entity)
+        .collect::<Vec<_>>();
+    for entity in stale {
+        rebuild(world, resources, entity)
+            .with_context(|| format!("Can't rebuild prefab instance {:?}", entity))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::{BodyType, PhysicsDescription, ShapeDescription};
+
+    fn prefabs(loaded: Vec<(&str, Prefab)>) -> Prefabs {
+        Prefabs {
+            loaded: loaded
+                .into_iter()
+                .map(|(path, prefab)| (PathBuf::from(path), prefab))
+                .collect(),
+        }
+    }
+
+    fn model(path: &str) -> EntityDescription {
+        EntityDescription {
+            model: Some(PathBuf::from(path)),
+            ..Default::default()
+        }
+    }
+
+    fn resolve(prefabs: &mut Prefabs, path: &str) -> Result<(Prefab, HashSet<PathBuf>)> {
+        let mut dependencies = HashSet::new();
+        let prefab = prefabs.resolve(Path::new(path), &mut Vec::new(), &mut dependencies)?;
+        Ok((prefab, dependencies))
+    }
+
+    fn base() -> Prefab {
+        Prefab {
+            entity: EntityDescription {
+                physics: Some(PhysicsDescription {
+                    body_type: BodyType::Static,
+                    shape: ShapeDescription::Cube,
+                }),
+                ..model("base.obj")
+            },
+            children: vec![model("base_child.obj")],
+        }
+    }
+
+    fn derived() -> Prefab {
+        Prefab {
+            entity: EntityDescription {
+                prefab: Some(PathBuf::from("base.ron")),
+                ..model("derived.obj")
+            },
+            children: vec![
+                model("derived_child.obj"),
+                EntityDescription {
+                    parent: Some(0),
+                    ..model("grandchild.obj")
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn resolve_without_a_base_is_the_prefab() {
+        let mut prefabs = prefabs(vec![("base.ron", base())]);
+        let (prefab, dependencies) = resolve(&mut prefabs, "base.ron").unwrap();
+        assert_eq!(prefab, base());
+        assert_eq!(
+            dependencies,
+            vec![PathBuf::from("base.ron")].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn resolve_merges_the_extended_prefab() {
+        let mut prefabs = prefabs(vec![("base.ron", base()), ("derived.ron", derived())]);
+        let (prefab, dependencies) = resolve(&mut prefabs, "derived.ron").unwrap();
+        assert_eq!(prefab.entity.prefab, None);
+        assert_eq!(prefab.entity.model, Some(PathBuf::from("derived.obj")));
+        assert_eq!(prefab.entity.physics, base().entity.physics);
+        assert_eq!(
+            dependencies,
+            vec![PathBuf::from("base.ron"), PathBuf::from("derived.ron")]
+                .into_iter()
+                .collect()
+        );
+    }
+
+    #[test]
+    fn resolve_offsets_the_parents_of_extending_children() {
+        let mut prefabs = prefabs(vec![("base.ron", base()), ("derived.ron", derived())]);
+        let (prefab, _) = resolve(&mut prefabs, "derived.ron").unwrap();
+        let children = prefab
+            .children
+            .iter()
+            .map(|child| (child.model.clone().unwrap(), child.parent))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            children,
+            vec![
+                (PathBuf::from("base_child.obj"), None),
+                (PathBuf::from("derived_child.obj"), None),
+                (PathBuf::from("grandchild.obj"), Some(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn overrides_replace_the_resolved_prefab() {
+        let mut prefabs = prefabs(vec![("base.ron", base()), ("derived.ron", derived())]);
+        let (prefab, _) = resolve(&mut prefabs, "derived.ron").unwrap();
+        let instance = model("instance.obj").merged(&prefab.entity).unwrap();
+        assert_eq!(instance.model, Some(PathBuf::from("instance.obj")));
+        assert_eq!(instance.physics, base().entity.physics);
+    }
+
+    #[test]
+    fn resolve_refuses_prefabs_extending_themselves() {
+        let extending = |path: &str| Prefab {
+            entity: EntityDescription {
+                prefab: Some(PathBuf::from(path)),
+                ..Default::default()
+            },
+            children: Vec::new(),
+        };
+        let mut prefabs = prefabs(vec![
+            ("first.ron", extending("second.ron")),
+            ("second.ron", extending("first.ron")),
+        ]);
+        assert!(resolve(&mut prefabs, "first.ron").is_err());
+    }
+}
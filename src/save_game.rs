@@ -0,0 +1,575 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use legion::prelude::*;
+use nalgebra::{Isometry3, Point3, Vector3};
+use nphysics3d::{
+    math::Velocity,
+    object::{DefaultBodySet, DefaultColliderSet},
+};
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    animation::{Animator, KeyframeAnimation},
+    assets::{Assets, Handle},
+    camera::Camera,
+    components::{update_global_transform, MainCamera, Parent, PhysicsBody, Transform},
+    engine::Time,
+    graphics::{
+        model::Model, morph_targets::MorphWeights, render_target::OffscreenCamera,
+        skinned_model::SkinnedModel, skybox_texture::Skybox, DirectionalLight, PointLight,
+        SpotLight,
+    },
+    physics::Physics,
+    prefab::PrefabInstance,
+    scene::{add_component, CameraDescription, EntityDescription, PhysicsDescription},
+};
+
+// Bumped whenever the layout of SaveGame changes, saves from older versions
+// are upgraded by migrate when they are loaded
+pub const SAVE_VERSION: u32 = 1;
+
+// Just the version so it can be checked before parsing the rest
+#[derive(Deserialize)]
+#[serde(rename = "SaveGame")]
+struct SaveHeader {
+    version: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BodySnapshot {
+    pub description: PhysicsDescription,
+    pub position: Isometry3<f32>,
+    pub linear_velocity: Vector3<f32>,
+    pub angular_velocity: Vector3<f32>,
+}
+
+// Indices of the children are into the entities of the save
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrefabSnapshot {
+    pub prefab: PathBuf,
+    pub overrides: EntityDescription,
+    pub base: EntityDescription,
+    pub children: Vec<usize>,
+    pub dependencies: HashSet<PathBuf>,
+}
+
+// The camera is stored like the main one, its target has to exist once it's restored
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OffscreenCameraSnapshot {
+    pub camera: CameraDescription,
+    pub aspect_ratio: f32,
+    pub target: String,
+}
+
+impl From<&OffscreenCamera> for OffscreenCameraSnapshot {
+    fn from(offscreen_camera: &OffscreenCamera) -> Self {
+        OffscreenCameraSnapshot {
+            camera: CameraDescription::from(&offscreen_camera.camera),
+            aspect_ratio: offscreen_camera.camera.get_aspect_ratio(),
+            target: offscreen_camera.target.clone(),
+        }
+    }
+}
+
+impl OffscreenCameraSnapshot {
+    fn restore(&self) -> OffscreenCamera {
+        let mut camera = Camera::new(Point3::origin(), -Vector3::z(), 1, 1);
+        camera.set_aspect_ratio(self.aspect_ratio);
+        self.camera.apply(&mut camera);
+        OffscreenCamera {
+            camera,
+            target: self.target.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EntitySnapshot {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transform: Option<Transform>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skinned_model: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub morph_weights: Option<MorphWeights>,
+    // the clips are referred to by their index in the skinned model
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub animator: Option<Animator>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyframe_animation: Option<KeyframeAnimation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offscreen_camera: Option<OffscreenCameraSnapshot>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub point_light: Option<PointLight>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spot_light: Option<SpotLight>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub directional_light: Option<DirectionalLight>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<BodySnapshot>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefab: Option<PrefabSnapshot>,
+    pub main_camera: bool,
+}
+
+// The whole game state, unlike a Scene it keeps every value exactly including
+// the velocities of physics bodies and where the animations are in their playback
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SaveGame {
+    pub version: u32,
+    pub time: Time,
+    pub camera: CameraDescription,
+    pub skybox: PathBuf,
+    pub entities: Vec<EntitySnapshot>,
+}
+
+// Reads a save of the given version into the current layout. When the layout changes
+// the old types move to a module named after their version, they get an arm here
+// and a From impl for the next version so older saves are upgraded step by step
+fn migrate(version: u32, text: &str) -> Result<SaveGame> {
+    match version {
+        SAVE_VERSION => Ok(ron::de::from_str(text)?),
+        _ => bail!("There is no migration from version {}", version),
+    }
+}
+
+// Every entity with a transform, a directional light, a keyframe animation or an offscreen camera
+fn saved_entities(world: &World) -> Vec<Entity> {
+    let mut entities = <Read<Transform>>::query()
+        .iter_entities(world)
+        .map(|(entity, _)| entity)
+        .collect::<Vec<_>>();
+    entities.extend(
+        <Read<DirectionalLight>>::query()
+            .filter(!component::<Transform>())
+            .iter_entities(world)
+            .map(|(entity, _)| entity),
+    );
+    // the camera can be animated without either of them
+    entities.extend(
+        <Read<KeyframeAnimation>>::query()
+            .filter(!component::<Transform>() & !component::<DirectionalLight>())
+            .iter_entities(world)
+            .map(|(entity, _)| entity),
+    );
+    entities.extend(
+        <Read<OffscreenCamera>>::query()
+            .filter(
+                !component::<Transform>()
+                    & !component::<DirectionalLight>()
+                    & !component::<KeyframeAnimation>(),
+            )
+            .iter_entities(world)
+            .map(|(entity, _)| entity),
+    );
+    entities
+}
+
+fn body_snapshot(
+    physics_body: &PhysicsBody,
+    body_set: &DefaultBodySet<f32>,
+    collider_set: &DefaultColliderSet<f32>,
+) -> Option<BodySnapshot> {
+    let description = PhysicsDescription::from_body(physics_body, body_set, collider_set)?;
+    let body = body_set.rigid_body(physics_body.body_handle)?;
+    Some(BodySnapshot {
+        description,
+        position: *body.position(),
+        linear_velocity: body.velocity().linear,
+        angular_velocity: body.velocity().angular,
+    })
+}
+
+impl SaveGame {
+    // Saves the entities returned by saved_entities. Fails when one of them has
+    // something the save couldn't restore, like a model that wasn't loaded from a file
+    pub fn capture(world: &World, resources: &Resources) -> Result<Self> {
+        let entities = saved_entities(world);
+        let indices = entities
+            .iter()
+            .enumerate()
+            .map(|(i, &entity)| (entity, i))
+            .collect::<HashMap<_, _>>();
+        let models = resources
+            .get::<Assets<Model>>()
+            .expect("Asset not registerd");
+        let skinned_models = resources
+            .get::<Assets<SkinnedModel>>()
+            .expect("Asset not registerd");
+        let body_set = resources.get::<DefaultBodySet<f32>>();
+        let collider_set = resources.get::<DefaultColliderSet<f32>>();
+        let entities = entities
+            .iter()
+            .map(|&entity| {
+                Ok(EntitySnapshot {
+                    transform: world
+                        .get_component::<Transform>(entity)
+                        .map(|transform| *transform),
+                    parent: world
+                        .get_component::<Parent>(entity)
+                        .and_then(|parent| indices.get(&parent.0).copied()),
+                    model: world
+                        .get_tag::<Handle<Model>>(entity)
+                        .map(|handle| {
+                            models.path(handle).map(Path::to_path_buf).ok_or_else(|| {
+                                anyhow!("The model of {:?} wasn't loaded from a file", entity)
+                            })
+                        })
+                        .transpose()?,
+                    skinned_model: world
+                        .get_tag::<Handle<SkinnedModel>>(entity)
+                        .map(|handle| {
+                            skinned_models
+                                .path(handle)
+                                .map(Path::to_path_buf)
+                                .ok_or_else(|| {
+                                    anyhow!(
+                                        "The skinned model of {:?} wasn't loaded from a file",
+                                        entity
+                                    )
+                                })
+                        })
+                        .transpose()?,
+                    morph_weights: world
+                        .get_component::<MorphWeights>(entity)
+                        .map(|weights| weights.clone()),
+                    animator: world
+                        .get_component::<Animator>(entity)
+                        .map(|animator| animator.clone()),
+                    keyframe_animation: world
+                        .get_component::<KeyframeAnimation>(entity)
+                        .map(|animation| animation.clone()),
+                    offscreen_camera: world
+                        .get_component::<OffscreenCamera>(entity)
+                        .map(|offscreen_camera| OffscreenCameraSnapshot::from(&*offscreen_camera)),
+                    point_light: world
+                        .get_component::<PointLight>(entity)
+                        .map(|light| light.clone()),
+                    spot_light: world
+                        .get_component::<SpotLight>(entity)
+                        .map(|light| light.clone()),
+                    directional_light: world
+                        .get_component::<DirectionalLight>(entity)
+                        .map(|light| light.clone()),
+                    body: world
+                        .get_component::<PhysicsBody>(entity)
+                        .map(|physics_body| {
+                            body_set
+                                .as_ref()
+                                .zip(collider_set.as_ref())
+                                .and_then(|(body_set, collider_set)| {
+                                    body_snapshot(&physics_body, body_set, collider_set)
+                                })
+                                .ok_or_else(|| {
+                                    anyhow!("The physics body of {:?} can't be read", entity)
+                                })
+                        })
+                        .transpose()?,
+                    prefab: world
+                        .get_component::<PrefabInstance>(entity)
+                        .map(|instance| PrefabSnapshot {
+                            prefab: instance.prefab.clone(),
+                            overrides: instance.overrides.clone(),
+                            base: instance.base.clone(),
+                            children: instance
+                                .children
+                                .iter()
+                                .filter_map(|child| indices.get(child).copied())
+                                .collect(),
+                            dependencies: instance.dependencies.clone(),
+                        }),
+                    main_camera: world.get_component::<MainCamera>(entity).is_some(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(SaveGame {
+            version: SAVE_VERSION,
+            time: *resources.get::<Time>().expect("Time not registered"),
+            camera: CameraDescription::from(
+                &*resources.get::<Camera>().expect("Camera not registered"),
+            ),
+            skybox: resources
+                .get::<Skybox>()
+                .expect("Skybox not registered")
+                .path
+                .clone(),
+            entities,
+        })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)
+                .with_context(|| format!("Can't create the save directory {:?}", directory))?;
+        }
+        let text = ron::ser::to_string_pretty(self, PrettyConfig::default())?;
+        fs::write(path, text).with_context(|| format!("Can't write save {:?}", path))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text =
+            fs::read_to_string(path).with_context(|| format!("Can't open save {:?}", path))?;
+        SaveGame::parse(&text).with_context(|| format!("Invalid save {:?}", path))
+    }
+
+    // The version is checked before reading the rest so older saves can be migrated
+    fn parse(text: &str) -> Result<Self> {
+        let header: SaveHeader = ron::de::from_str(text).context("The save has no version")?;
+        if header.version > SAVE_VERSION {
+            bail!(
+                "The save has version {}, newer than the supported {}",
+                header.version,
+                SAVE_VERSION
+            );
+        }
+        let mut save = migrate(header.version, text)
+            .with_context(|| format!("Can't read a save of version {}", header.version))?;
+        save.version = SAVE_VERSION;
+        Ok(save)
+    }
+
+    // Replaces the entities capture would save and their physics bodies with the ones
+    // of the save. Physics::new must have been called before
+    pub fn restore(&self, world: &mut World, resources: &mut Resources) -> Result<Vec<Entity>> {
+        for entity in saved_entities(world) {
+            let physics_body = world
+                .get_component::<PhysicsBody>(entity)
+                .map(|physics_body| *physics_body);
+            if let Some(physics_body) = physics_body {
+                Physics::remove_body(resources, &physics_body);
+            }
+            world.delete(entity);
+        }
+
+        let mut entities = Vec::with_capacity(self.entities.len());
+        for (i, snapshot) in self.entities.iter().enumerate() {
+            let entity = snapshot
+                .restore(world, resources)
+                .with_context(|| format!("Can't restore entity {} of the save", i))?;
+            entities.push(entity);
+        }
+        let entity_at = |i: usize| {
+            entities
+                .get(i)
+                .copied()
+                .ok_or_else(|| anyhow!("There is no entity {} in the save", i))
+        };
+        for (snapshot, &entity) in self.entities.iter().zip(&entities) {
+            if let Some(parent) = snapshot.parent {
+                add_component(world, entity, Parent(entity_at(parent)?))?;
+            }
+            if let Some(prefab) = &snapshot.prefab {
+                let instance = PrefabInstance {
+                    prefab: prefab.prefab.clone(),
+                    overrides: prefab.overrides.clone(),
+                    base: prefab.base.clone(),
+                    children: prefab
+                        .children
+                        .iter()
+                        .map(|&child| entity_at(child))
+                        .collect::<Result<_>>()?,
+                    dependencies: prefab.dependencies.clone(),
+                };
+                add_component(world, entity, instance)?;
+            }
+        }
+        for (snapshot, &entity) in self.entities.iter().zip(&entities) {
+            if snapshot.transform.is_some() {
+                update_global_transform(world, entity)?;
+            }
+        }
+
+        *resources.get_mut::<Time>().expect("Time not registered") = self.time;
+        self.camera.apply(
+            &mut resources
+                .get_mut::<Camera>()
+                .expect("Camera not registered"),
+        );
+        resources
+            .get_mut::<Skybox>()
+            .expect("Skybox not registered")
+            .path = self.skybox.clone();
+        Ok(entities)
+    }
+}
+
+impl EntitySnapshot {
+    fn restore(&self, world: &mut World, resources: &mut Resources) -> Result<Entity> {
+        let entity = match (
+            self.transform,
+            &self.directional_light,
+            &self.keyframe_animation,
+            &self.offscreen_camera,
+        ) {
+            (Some(transform), ..) => world.insert((), vec![(transform,)])[0],
+            (None, Some(light), ..) => world.insert((), vec![(light.clone(),)])[0],
+            (None, None, Some(animation), _) => world.insert((), vec![(animation.clone(),)])[0],
+            (None, None, None, Some(offscreen_camera)) => {
+                world.insert((), vec![(offscreen_camera.restore(),)])[0]
+            }
+            (None, None, None, None) => bail!(
+                "Entities need a transform, a directional light, \
+                 a keyframe animation or an offscreen camera"
+            ),
+        };
+        if let Some(path) = &self.model {
+            let model = resources
+                .get_mut::<Assets<Model>>()
+                .expect("Asset not registerd")
+                .load(path)?;
+            world
+                .add_tag(entity, model)
+                .map_err(|_| anyhow!("Can't add a model to {:?}", entity))?;
+        }
+        if let Some(path) = &self.skinned_model {
+            let model = resources
+                .get_mut::<Assets<SkinnedModel>>()
+                .expect("Asset not registerd")
+                .load(path)?;
+            world
+                .add_tag(entity, model)
+                .map_err(|_| anyhow!("Can't add a skinned model to {:?}", entity))?;
+        }
+        if let Some(weights) = &self.morph_weights {
+            add_component(world, entity, weights.clone())?;
+        }
+        if let Some(animator) = &self.animator {
+            add_component(world, entity, animator.clone())?;
+        }
+        // the entity may have been created with one of these
+        if let Some(animation) = &self.keyframe_animation {
+            if world.get_component::<KeyframeAnimation>(entity).is_none() {
+                add_component(world, entity, animation.clone())?;
+            }
+        }
+        if let Some(offscreen_camera) = &self.offscreen_camera {
+            if world.get_component::<OffscreenCamera>(entity).is_none() {
+                add_component(world, entity, offscreen_camera.restore())?;
+            }
+        }
+        if let Some(light) = &self.point_light {
+            add_component(world, entity, light.clone())?;
+        }
+        if let Some(light) = &self.spot_light {
+            add_component(world, entity, light.clone())?;
+        }
+        if let (Some(light), Some(_)) = (&self.directional_light, &self.transform) {
+            add_component(world, entity, light.clone())?;
+        }
+        if let Some(body) = &self.body {
+            let scale = self
+                .transform
+                .ok_or_else(|| anyhow!("Physics bodies need a transform"))?
+                .scale;
+            let transform = Transform::new(body.position, scale);
+            let physics_body = body.description.create(resources, &transform);
+            if let Some(rigid_body) = resources
+                .get_mut::<DefaultBodySet<f32>>()
+                .expect("Default body set not added as a resource")
+                .rigid_body_mut(physics_body.body_handle)
+            {
+                rigid_body.set_position(body.position);
+                rigid_body.set_velocity(Velocity::new(body.linear_velocity, body.angular_velocity));
+            }
+            add_component(world, entity, physics_body)?;
+        }
+        if self.main_camera {
+            add_component(world, entity, MainCamera)?;
+        }
+        Ok(entity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::animation::PlaybackMode;
+
+    fn save_text(version: u32) -> String {
+        let save = SaveGame {
+            version,
+            time: Time {
+                current_time: 12.5,
+                delta_time: 0.0,
+            },
+            camera: CameraDescription::default(),
+            skybox: PathBuf::from("skybox.hdr"),
+            entities: vec![EntitySnapshot {
+                transform: Some(Transform::from_position(Vector3::new(1.0, 2.0, 3.0))),
+                main_camera: true,
+                ..Default::default()
+            }],
+        };
+        ron::ser::to_string(&save).unwrap()
+    }
+
+    #[test]
+    fn saves_of_the_current_version_are_read() {
+        let save = SaveGame::parse(&save_text(SAVE_VERSION)).unwrap();
+        assert_eq!(save.version, SAVE_VERSION);
+        assert_eq!(save.time.current_time, 12.5);
+        assert_eq!(save.skybox, PathBuf::from("skybox.hdr"));
+        assert_eq!(save.entities.len(), 1);
+        assert!(save.entities[0].main_camera);
+    }
+
+    #[test]
+    fn animation_playback_is_saved() {
+        let mut animator = Animator::new();
+        animator.speed = 0.5;
+        animator.play(2, true);
+        let mut animation = KeyframeAnimation::new("door", PlaybackMode::Loop);
+        animation.speed = 2.0;
+        animation.playing = false;
+        let snapshot = EntitySnapshot {
+            transform: Some(Transform::default()),
+            animator: Some(animator),
+            keyframe_animation: Some(animation),
+            ..Default::default()
+        };
+        let text = ron::ser::to_string(&snapshot).unwrap();
+        let snapshot: EntitySnapshot = ron::de::from_str(&text).unwrap();
+        let animator = snapshot.animator.unwrap();
+        assert_eq!(animator.speed, 0.5);
+        assert_eq!(animator.current_clip(), Some(2));
+        let animation = snapshot.keyframe_animation.unwrap();
+        assert_eq!(animation.name, "door");
+        assert_eq!(animation.mode, PlaybackMode::Loop);
+        assert_eq!(animation.speed, 2.0);
+        assert!(!animation.playing);
+    }
+
+    #[test]
+    fn saves_of_newer_versions_are_refused() {
+        assert!(SaveGame::parse(&save_text(SAVE_VERSION + 1)).is_err());
+    }
+
+    #[test]
+    fn saves_without_a_migration_are_refused() {
+        assert!(SaveGame::parse(&save_text(0)).is_err());
+    }
+
+    #[test]
+    fn saves_without_a_version_are_refused() {
+        let text = save_text(SAVE_VERSION).replacen("version:", "revision:", 1);
+        assert!(SaveGame::parse(&text).is_err());
+    }
+
+    #[test]
+    fn the_header_only_reads_the_version() {
+        let header: SaveHeader = ron::de::from_str(&save_text(SAVE_VERSION + 3)).unwrap();
+        assert_eq!(header.version, SAVE_VERSION + 3);
+    }
+}
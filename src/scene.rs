@@ -0,0 +1,783 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use legion::prelude::*;
+use nalgebra::{Matrix4, Vector3};
+use ncollide3d::shape::Ball;
+use nphysics3d::object::{Body, BodyStatus, DefaultBodySet, DefaultColliderSet};
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    assets::{Assets, Handle},
+    camera::Camera,
+    components::{
+        hierarchy::world_matrix, update_global_transform, Parent, PhysicsBody, Transform,
+    },
+    graphics::{model::Model, skybox_texture::Skybox, DirectionalLight, PointLight, SpotLight},
+    physics::Physics,
+    prefab::{self, PrefabInstance},
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransformDescription {
+    pub translation: [f32; 3],
+    // roll, pitch and yaw in degrees
+    pub rotation: [f32; 3],
+    pub scale: [f32; 3],
+}
+
+impl Default for TransformDescription {
+    fn default() -> Self {
+        TransformDescription {
+            translation: [0.0; 3],
+            rotation: [0.0; 3],
+            scale: [1.0; 3],
+        }
+    }
+}
+
+impl TransformDescription {
+    // Compared as a model matrix with some tolerance, because a difference
+    // computed from floats is rarely exactly the default
+    fn is_identity(&self) -> bool {
+        (Transform::from(self).get_model_matrix() - Matrix4::identity()).amax() <= IDENTITY_EPSILON
+    }
+}
+
+const IDENTITY_EPSILON: f32 = 1e-4;
+
+impl From<&TransformDescription> for Transform {
+    fn from(description: &TransformDescription) -> Self {
+        let [roll, pitch, yaw] = description.rotation;
+        Transform::builder()
+            .set_translation(Vector3::from(description.translation))
+            .set_euler_rotation(roll.to_radians(), pitch.to_radians(), yaw.to_radians())
+            .set_non_uniform_scale(Vector3::from(description.scale))
+            .build()
+    }
+}
+
+impl From<&Transform> for TransformDescription {
+    fn from(transform: &Transform) -> Self {
+        let (roll, pitch, yaw) = transform.rotation().euler_angles();
+        TransformDescription {
+            translation: transform.translation().into(),
+            rotation: [roll.to_degrees(), pitch.to_degrees(), yaw.to_degrees()],
+            scale: transform.scale.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BodyType {
+    Static,
+    Dynamic,
+    Kinematic,
+    Disabled,
+}
+
+impl From<BodyType> for BodyStatus {
+    fn from(body_type: BodyType) -> Self {
+        match body_type {
+            BodyType::Static => BodyStatus::Static,
+            BodyType::Dynamic => BodyStatus::Dynamic,
+            BodyType::Kinematic => BodyStatus::Kinematic,
+            BodyType::Disabled => BodyStatus::Disabled,
+        }
+    }
+}
+
+// The size of cubes comes from the scale of the transform like in Physics::create_cube
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ShapeDescription {
+    Cube,
+    Sphere { radius: f32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PhysicsDescription {
+    pub body_type: BodyType,
+    pub shape: ShapeDescription,
+}
+
+impl PhysicsDescription {
+    // The transform is the pose of the entity in the world, not the one relative to its parent
+    pub(crate) fn create(&self, resources: &mut Resources, transform: &Transform) -> PhysicsBody {
+        let status = BodyStatus::from(self.body_type);
+        match self.shape {
+            ShapeDescription::Cube => Physics::create_cube(resources, transform, status),
+            ShapeDescription::Sphere { radius } => {
+                Physics::create_sphere(resources, transform, status, radius)
+            }
+        }
+    }
+
+    pub(crate) fn from_body(
+        physics_body: &PhysicsBody,
+        body_set: &DefaultBodySet<f32>,
+        collider_set: &DefaultColliderSet<f32>,
+    ) -> Option<Self> {
+        let body_type = match body_set.rigid_body(physics_body.body_handle)?.status() {
+            BodyStatus::Static => BodyType::Static,
+            BodyStatus::Dynamic => BodyType::Dynamic,
+            BodyStatus::Kinematic => BodyType::Kinematic,
+            BodyStatus::Disabled => BodyType::Disabled,
+        };
+        let collider = collider_set.get(physics_body.collider_handle)?;
+        let shape = match collider.shape().as_shape::<Ball<f32>>() {
+            Some(ball) => ShapeDescription::Sphere {
+                radius: ball.radius(),
+            },
+            None => ShapeDescription::Cube,
+        };
+        Some(PhysicsDescription { body_type, shape })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraDescription {
+    pub position: [f32; 3],
+    // degrees like Camera::set_yaw and Camera::set_pitch
+    pub yaw: f32,
+    pub pitch: f32,
+    pub fov: f32,
+}
+
+impl Default for CameraDescription {
+    fn default() -> Self {
+        CameraDescription {
+            position: [0.0, 0.0, 3.0],
+            yaw: -90.0,
+            pitch: 0.0,
+            fov: 45.0,
+        }
+    }
+}
+
+impl CameraDescription {
+    pub(crate) fn apply(&self, camera: &mut Camera) {
+        camera.set_position(Vector3::from(self.position));
+        camera.set_yaw(self.yaw);
+        camera.set_pitch(self.pitch);
+        camera.set_fov(self.fov);
+    }
+}
+
+impl From<&Camera> for CameraDescription {
+    fn from(camera: &Camera) -> Self {
+        CameraDescription {
+            position: camera.get_vec_position().into(),
+            yaw: camera.get_yaw(),
+            pitch: camera.get_pitch(),
+            fov: camera.get_fov(),
+        }
+    }
+}
+
+// Every entity needs a transform except for directional lights. Physics
+// bodies are created from the transform in the file, so they belong on
+// entities without a parent
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EntityDescription {
+    // the entity is an instance of the prefab, the other fields override the ones in it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefab: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transform: Option<TransformDescription>,
+    // index of the parent in the entities of the scene
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub point_light: Option<PointLight>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spot_light: Option<SpotLight>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub directional_light: Option<DirectionalLight>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub physics: Option<PhysicsDescription>,
+}
+
+pub(crate) fn add_component<T: Component>(
+    world: &mut World,
+    entity: Entity,
+    component: T,
+) -> Result<()> {
+    world
+        .add_component(entity, component)
+        .map_err(|_| anyhow!("Can't add a component to {:?}", entity))
+}
+
+fn compose(
+    parent: &Option<TransformDescription>,
+    child: &Option<TransformDescription>,
+) -> Result<Option<TransformDescription>> {
+    Ok(match (parent, child) {
+        (Some(parent), Some(child)) => Some(TransformDescription::from(
+            &Transform::from(parent)
+                .compose(&Transform::from(child))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "The scale of {:?} would shear the rotated {:?}",
+                        parent,
+                        child
+                    )
+                })?,
+        )),
+        (Some(transform), None) | (None, Some(transform)) => Some(transform.clone()),
+        (None, None) => None,
+    })
+}
+
+impl EntityDescription {
+    // The parent index of the description is ignored, the entity is spawned under parent
+    pub fn spawn(
+        &self,
+        world: &mut World,
+        resources: &mut Resources,
+        parent: Option<Entity>,
+    ) -> Result<Entity> {
+        match &self.prefab {
+            Some(path) => prefab::instantiate(world, resources, path, self.clone(), parent),
+            None => self.spawn_components(world, resources, parent),
+        }
+    }
+
+    // The fields of self on top of the ones of base. Components are replaced as
+    // a whole except for the transform, it places the transform of base. Fails when
+    // that needs shear, a non uniform scale in self with a rotation in base
+    pub fn merged(&self, base: &EntityDescription) -> Result<EntityDescription> {
+        Ok(EntityDescription {
+            prefab: None,
+            transform: compose(&self.transform, &base.transform)?,
+            parent: self.parent,
+            model: self.model.clone().or_else(|| base.model.clone()),
+            point_light: self
+                .point_light
+                .clone()
+                .or_else(|| base.point_light.clone()),
+            spot_light: self.spot_light.clone().or_else(|| base.spot_light.clone()),
+            directional_light: self
+                .directional_light
+                .clone()
+                .or_else(|| base.directional_light.clone()),
+            physics: self.physics.clone().or_else(|| base.physics.clone()),
+        })
+    }
+
+    // The overrides that turn base into self, the reverse of merged. None when no
+    // override transform can do that, because base has a zero scale or it would need shear
+    pub fn difference(&self, base: &EntityDescription) -> Option<EntityDescription> {
+        fn changed<T: Clone + PartialEq>(value: &Option<T>, base: &Option<T>) -> Option<T> {
+            value.clone().filter(|_| value != base)
+        }
+        let transform = match (&self.transform, &base.transform) {
+            // as matrices so only the result has to be a Transform
+            (Some(transform), Some(base)) => {
+                Some(TransformDescription::from(&Transform::try_from_matrix(
+                    &(Transform::from(transform).get_model_matrix()
+                        * Transform::from(base).get_model_matrix().try_inverse()?),
+                )?))
+            }
+            (transform, _) => transform.clone(),
+        };
+        Some(EntityDescription {
+            prefab: None,
+            transform: transform.filter(|transform| !transform.is_identity()),
+            parent: self.parent,
+            model: changed(&self.model, &base.model),
+            point_light: changed(&self.point_light, &base.point_light),
+            spot_light: changed(&self.spot_light, &base.spot_light),
+            directional_light: changed(&self.directional_light, &base.directional_light),
+            physics: changed(&self.physics, &base.physics),
+        })
+    }
+
+    fn spawn_components(
+        &self,
+        world: &mut World,
+        resources: &mut Resources,
+        parent: Option<Entity>,
+    ) -> Result<Entity> {
+        let transform = self.transform.as_ref().map(Transform::from);
+        let model = match &self.model {
+            Some(path) => Some(
+                resources
+                    .get_mut::<Assets<Model>>()
+                    .expect("Asset not registerd")
+                    .load(path)?,
+            ),
+            None => None,
+        };
+        let entity = match (transform, model, &self.directional_light) {
+            (Some(transform), Some(model), _) => world.insert((model, ()), vec![(transform,)])[0],
+            (Some(transform), None, _) => world.insert((), vec![(transform,)])[0],
+            (None, None, Some(light)) => world.insert((), vec![(light.clone(),)])[0],
+            (None, Some(_), _) => bail!("Models need a transform"),
+            (None, None, None) => bail!("Entities need a transform or a directional light"),
+        };
+        if let Some(parent) = parent {
+            add_component(world, entity, Parent(parent))?;
+        }
+        let global_matrix = match transform {
+            Some(_) => Some(update_global_transform(world, entity)?),
+            None => None,
+        };
+        if let Some(light) = &self.point_light {
+            add_component(world, entity, light.clone())?;
+        }
+        if let Some(light) = &self.spot_light {
+            add_component(world, entity, light.clone())?;
+        }
+        if let (Some(light), Some(_)) = (&self.directional_light, &transform) {
+            add_component(world, entity, light.clone())?;
+        }
+        if let Some(physics) = &self.physics {
+            let matrix = global_matrix.ok_or_else(|| anyhow!("Physics bodies need a transform"))?;
+            let physics_body = physics.create(resources, &Transform::from_matrix(&matrix));
+            add_component(world, entity, physics_body)?;
+        }
+        Ok(entity)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Scene {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera: Option<CameraDescription>,
+    // directory or panorama like the Skybox resource
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skybox: Option<PathBuf>,
+    pub entities: Vec<EntityDescription>,
+}
+
+// The index of the saved parent and the transform relative to it. Prefab children aren't
+// saved, so entities attached to one are attached to the instance that spawned it instead
+// and keep their pose in the world. When that would need shear they are saved without
+// a parent in their world pose
+fn saved_parent(
+    world: &World,
+    entity: Entity,
+    indices: &HashMap<Entity, usize>,
+    prefab_children: &HashMap<Entity, Entity>,
+) -> (Option<usize>, Option<Transform>) {
+    let transform = world
+        .get_component::<Transform>(entity)
+        .map(|transform| *transform);
+    let parent = match world.get_component::<Parent>(entity) {
+        Some(parent) => parent.0,
+        None => return (None, transform),
+    };
+    let mut ancestor = parent;
+    while !indices.contains_key(&ancestor) {
+        match prefab_children.get(&ancestor) {
+            Some(&instance) => ancestor = instance,
+            // the parent isn't saved, so neither is the link to it
+            None => return (None, transform),
+        }
+    }
+    if ancestor == parent || transform.is_none() {
+        return (indices.get(&ancestor).copied(), transform);
+    }
+    let entity_matrix = world_matrix(world, entity).ok();
+    let relative = world_matrix(world, ancestor)
+        .ok()
+        .and_then(|matrix| matrix.try_inverse())
+        .and_then(|inverse| Transform::try_from_matrix(&(inverse * entity_matrix?)));
+    match relative {
+        Some(relative) => (indices.get(&ancestor).copied(), Some(relative)),
+        None => {
+            eprintln!(
+                "{:?} can't keep its pose under the prefab instance {:?}, it is saved without a parent",
+                entity, ancestor
+            );
+            (
+                None,
+                entity_matrix
+                    .map(|matrix| Transform::from_matrix(&matrix))
+                    .or(transform),
+            )
+        }
+    }
+}
+
+impl Scene {
+    // Scenes are stored as RON
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let file = File::open(path.as_ref())
+            .with_context(|| format!("Can't open scene {:?}", path.as_ref()))?;
+        ron::de::from_reader(file).with_context(|| format!("Invalid scene {:?}", path.as_ref()))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let text = ron::ser::to_string_pretty(self, PrettyConfig::default())?;
+        std::fs::write(path.as_ref(), text)
+            .with_context(|| format!("Can't write scene {:?}", path.as_ref()))
+    }
+
+    // Spawns the entities and replaces the camera and skybox when the scene has
+    // them. Physics::new must have been called before. Returns the entities in file order
+    pub fn spawn(&self, world: &mut World, resources: &mut Resources) -> Result<Vec<Entity>> {
+        let mut entities = vec![None; self.entities.len()];
+        for i in 0..self.entities.len() {
+            self.spawn_entity(world, resources, i, &mut entities, &mut Vec::new())?;
+        }
+        if let Some(camera) = &self.camera {
+            camera.apply(
+                &mut resources
+                    .get_mut::<Camera>()
+                    .expect("Camera not registered"),
+            );
+        }
+        if let Some(path) = &self.skybox {
+            resources
+                .get_mut::<Skybox>()
+                .expect("Skybox not registered")
+                .path = path.clone();
+        }
+        Ok(entities.into_iter().flatten().collect())
+    }
+
+    // Parents are spawned before their children, transforms in the file are relative
+    // to the parent so the world pose of an entity is only known once its parent exists
+    fn spawn_entity(
+        &self,
+        world: &mut World,
+        resources: &mut Resources,
+        i: usize,
+        entities: &mut Vec<Option<Entity>>,
+        spawning: &mut Vec<usize>,
+    ) -> Result<Entity> {
+        if let Some(entity) = entities[i] {
+            return Ok(entity);
+        }
+        if spawning.contains(&i) {
+            bail!("The parents of entity {} of the scene form a cycle", i);
+        }
+        let description = &self.entities[i];
+        let parent = match description.parent {
+            Some(parent) if parent < self.entities.len() => {
+                spawning.push(i);
+                let parent = self.spawn_entity(world, resources, parent, entities, spawning);
+                spawning.pop();
+                Some(parent?)
+            }
+            Some(parent) => bail!("There is no entity {} to parent to", parent),
+            None => None,
+        };
+        let entity = description
+            .spawn(world, resources, parent)
+            .with_context(|| format!("Can't spawn entity {} of the scene", i))?;
+        entities[i] = Some(entity);
+        Ok(entity)
+    }
+
+    // Describes every entity with a transform or a directional light, components
+    // the scene format doesn't know about, like animations, aren't saved. Prefab
+    // instances are saved as their differences from the prefab, without the
+    // entities spawned for the children of the prefab
+    pub fn from_world(world: &World, resources: &Resources) -> Self {
+        // the instance each spawned prefab child belongs to
+        let prefab_children = <Read<PrefabInstance>>::query()
+            .iter_entities(world)
+            .flat_map(|(entity, instance)| {
+                instance
+                    .children
+                    .iter()
+                    .map(|&child| (child, entity))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<HashMap<_, _>>();
+        let mut entities = <Read<Transform>>::query()
+            .iter_entities(world)
+            .map(|(entity, _)| entity)
+            .collect::<Vec<_>>();
+        entities.extend(
+            <Read<DirectionalLight>>::query()
+                .filter(!component::<Transform>())
+                .iter_entities(world)
+                .map(|(entity, _)| entity),
+        );
+        entities.retain(|entity| !prefab_children.contains_key(entity));
+        let indices = entities
+            .iter()
+            .enumerate()
+            .map(|(i, &entity)| (entity, i))
+            .collect::<HashMap<_, _>>();
+        let models = resources
+            .get::<Assets<Model>>()
+            .expect("Asset not registerd");
+        let body_set = resources.get::<DefaultBodySet<f32>>();
+        let collider_set = resources.get::<DefaultColliderSet<f32>>();
+        let entities = entities
+            .iter()
+            .map(|&entity| {
+                let (parent, transform) = saved_parent(world, entity, &indices, &prefab_children);
+                EntityDescription {
+                    prefab: None,
+                    transform: transform.map(|transform| TransformDescription::from(&transform)),
+                    parent,
+                    model: world
+                        .get_tag::<Handle<Model>>(entity)
+                        .and_then(|handle| models.path(handle))
+                        .map(Path::to_path_buf),
+                    point_light: world
+                        .get_component::<PointLight>(entity)
+                        .map(|light| light.clone()),
+                    spot_light: world
+                        .get_component::<SpotLight>(entity)
+                        .map(|light| light.clone()),
+                    directional_light: world
+                        .get_component::<DirectionalLight>(entity)
+                        .map(|light| light.clone()),
+                    physics: match (
+                        world.get_component::<PhysicsBody>(entity),
+                        &body_set,
+                        &collider_set,
+                    ) {
+                        (Some(physics_body), Some(body_set), Some(collider_set)) => {
+                            PhysicsDescription::from_body(&physics_body, body_set, collider_set)
+                        }
+                        _ => None,
+                    },
+                }
+            })
+            .zip(&entities)
+            .map(|(description, &entity)| {
+                // instances the overrides can't describe are saved as plain entities
+                let overrides =
+                    world
+                        .get_component::<PrefabInstance>(entity)
+                        .and_then(|instance| {
+                            description.difference(&instance.base).map(|overrides| {
+                                EntityDescription {
+                                    prefab: Some(instance.prefab.clone()),
+                                    ..overrides
+                                }
+                            })
+                        });
+                overrides.unwrap_or(description)
+            })
+            .collect();
+        Scene {
+            camera: resources
+                .get::<Camera>()
+                .map(|camera| CameraDescription::from(&*camera)),
+            skybox: resources.get::<Skybox>().map(|skybox| skybox.path.clone()),
+            entities,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(
+        translation: [f32; 3],
+        rotation: [f32; 3],
+        scale: [f32; 3],
+    ) -> TransformDescription {
+        TransformDescription {
+            translation,
+            rotation,
+            scale,
+        }
+    }
+
+    fn assert_transform_eq(a: &TransformDescription, b: &TransformDescription) {
+        let close = |a: &[f32; 3], b: &[f32; 3]| a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-3);
+        assert!(
+            close(&a.translation, &b.translation)
+                && close(&a.rotation, &b.rotation)
+                && close(&a.scale, &b.scale),
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    fn cube(body_type: BodyType) -> Option<PhysicsDescription> {
+        Some(PhysicsDescription {
+            body_type,
+            shape: ShapeDescription::Cube,
+        })
+    }
+
+    fn base() -> EntityDescription {
+        EntityDescription {
+            transform: Some(transform([1.0, 2.0, 3.0], [0.0, 0.0, 0.0], [2.0, 2.0, 2.0])),
+            model: Some(PathBuf::from("base.obj")),
+            physics: cube(BodyType::Static),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merged_prefers_the_overrides() {
+        let overrides = EntityDescription {
+            model: Some(PathBuf::from("override.obj")),
+            parent: Some(3),
+            ..Default::default()
+        };
+        let merged = overrides.merged(&base()).unwrap();
+        assert_eq!(merged.model, Some(PathBuf::from("override.obj")));
+        assert_eq!(merged.physics, cube(BodyType::Static));
+        assert_eq!(merged.transform, base().transform);
+        assert_eq!(merged.parent, Some(3));
+        assert_eq!(merged.prefab, None);
+    }
+
+    #[test]
+    fn merged_places_the_base_transform() {
+        let overrides = EntityDescription {
+            transform: Some(transform(
+                [0.0, 0.0, -5.0],
+                [0.0, 0.0, 0.0],
+                [1.0, 1.0, 1.0],
+            )),
+            ..Default::default()
+        };
+        assert_transform_eq(
+            &overrides.merged(&base()).unwrap().transform.unwrap(),
+            &transform([1.0, 2.0, -2.0], [0.0, 0.0, 0.0], [2.0, 2.0, 2.0]),
+        );
+    }
+
+    #[test]
+    fn difference_of_an_unchanged_entity_is_empty() {
+        let difference = base().difference(&base()).unwrap();
+        assert_eq!(difference, EntityDescription::default());
+    }
+
+    #[test]
+    fn difference_keeps_only_the_changes() {
+        let mut description = base();
+        description.physics = cube(BodyType::Dynamic);
+        let difference = description.difference(&base()).unwrap();
+        assert_eq!(
+            difference,
+            EntityDescription {
+                physics: cube(BodyType::Dynamic),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn difference_is_the_reverse_of_merged() {
+        let mut description = base();
+        description.transform = Some(transform(
+            [-4.0, 0.5, 2.0],
+            [10.0, 30.0, -20.0],
+            [2.0, 2.0, 2.0],
+        ));
+        description.model = Some(PathBuf::from("changed.obj"));
+        let merged = description
+            .difference(&base())
+            .unwrap()
+            .merged(&base())
+            .unwrap();
+        assert_eq!(merged.model, description.model);
+        assert_eq!(merged.physics, description.physics);
+        assert_transform_eq(
+            &merged.transform.unwrap(),
+            description.transform.as_ref().unwrap(),
+        );
+    }
+
+    #[test]
+    fn difference_from_a_zero_scale_is_none() {
+        let mut zero_scale = base();
+        zero_scale.transform = Some(transform([0.0; 3], [0.0; 3], [0.0, 1.0, 1.0]));
+        assert!(base().difference(&zero_scale).is_none());
+    }
+
+    fn rotated_base() -> EntityDescription {
+        EntityDescription {
+            transform: Some(transform(
+                [1.0, 2.0, 3.0],
+                [20.0, 45.0, -70.0],
+                [2.0, 2.0, 2.0],
+            )),
+            ..base()
+        }
+    }
+
+    #[test]
+    fn rotated_and_scaled_overrides_survive_merged_and_difference() {
+        let overrides = EntityDescription {
+            transform: Some(transform(
+                [0.5, -1.0, 2.0],
+                [10.0, 30.0, -20.0],
+                [1.5, 1.5, 1.5],
+            )),
+            ..Default::default()
+        };
+        let difference = overrides
+            .merged(&rotated_base())
+            .unwrap()
+            .difference(&rotated_base())
+            .unwrap();
+        assert_transform_eq(
+            &difference.transform.unwrap(),
+            overrides.transform.as_ref().unwrap(),
+        );
+    }
+
+    #[test]
+    fn difference_ignores_rounding_errors_of_the_transform() {
+        let merged = EntityDescription::default()
+            .merged(&rotated_base())
+            .unwrap();
+        let mut almost = merged.clone();
+        almost.transform.as_mut().unwrap().rotation[1] += 1e-5;
+        almost.transform.as_mut().unwrap().scale[0] += 1e-6;
+        assert_eq!(merged.difference(&rotated_base()).unwrap().transform, None);
+        assert_eq!(almost.difference(&rotated_base()).unwrap().transform, None);
+    }
+
+    #[test]
+    fn entities_under_prefab_children_are_saved_under_the_instance() {
+        let mut world = Universe::new().create_world();
+        let mut spawn = |description: TransformDescription, parent: Option<Entity>| {
+            let entity = world.insert((), vec![(Transform::from(&description),)])[0];
+            if let Some(parent) = parent {
+                add_component(&mut world, entity, Parent(parent)).unwrap();
+            }
+            entity
+        };
+        let instance = spawn(transform([1.0, 0.0, 0.0], [0.0; 3], [2.0; 3]), None);
+        let prefab_child = spawn(
+            transform([0.0, 1.0, 0.0], [0.0; 3], [1.0; 3]),
+            Some(instance),
+        );
+        let attached = spawn(
+            transform([0.0, 0.0, 1.0], [0.0; 3], [1.0; 3]),
+            Some(prefab_child),
+        );
+        let indices = vec![(instance, 0), (attached, 1)].into_iter().collect();
+        let prefab_children = vec![(prefab_child, instance)].into_iter().collect();
+
+        let (parent, saved) = saved_parent(&world, attached, &indices, &prefab_children);
+        assert_eq!(parent, Some(0));
+        assert_transform_eq(
+            &TransformDescription::from(&saved.unwrap()),
+            &transform([0.0, 1.0, 1.0], [0.0; 3], [1.0; 3]),
+        );
+        assert_eq!(
+            saved_parent(&world, instance, &indices, &prefab_children).0,
+            None
+        );
+    }
+}
@@ -0,0 +1,25 @@
+#version 450
+
+#extension GL_EXT_samplerless_texture_functions : require
+
+layout (location = 0) in vec2 v_tex_coords;
+layout (location = 0) out vec4 f_color;
+
+layout (set=0, binding=0) uniform texture2D t_input;
+layout (set=0, binding=1) uniform sampler s_input;
+
+layout (set=1, binding=0) uniform Blur {
+    vec2 direction;
+};
+
+const float WEIGHTS[5] = float[](0.227027, 0.1945946, 0.1216216, 0.054054, 0.016216);
+
+void main() {
+    vec2 texel_offset = direction / vec2(textureSize(t_input, 0));
+    vec3 result = texture(sampler2D(t_input, s_input), v_tex_coords).rgb * WEIGHTS[0];
+    for (int i = 1; i < 5; ++i) {
+        result += texture(sampler2D(t_input, s_input), v_tex_coords + texel_offset * i).rgb * WEIGHTS[i];
+        result += texture(sampler2D(t_input, s_input), v_tex_coords - texel_offset * i).rgb * WEIGHTS[i];
+    }
+    f_color = vec4(result, 1.0);
+}
@@ -0,0 +1,21 @@
+#version 450
+layout (location = 0) in vec2 v_tex_coords;
+layout (location = 0) out vec4 f_color;
+
+layout (set=0, binding=0) uniform texture2D t_input;
+layout (set=0, binding=1) uniform sampler s_input;
+
+layout (set=1, binding=0) uniform BrightPass {
+    float threshold;
+    float knee;
+};
+
+void main() {
+    vec3 color = texture(sampler2D(t_input, s_input), v_tex_coords).rgb;
+    float brightness = max(color.r, max(color.g, color.b));
+    // quadratic curve around the threshold to avoid a hard cutoff
+    float soft = clamp(brightness - threshold + knee, 0.0, 2.0 * knee);
+    soft = (soft * soft) / (4.0 * knee + 0.00001);
+    float contribution = max(soft, brightness - threshold) / max(brightness, 0.00001);
+    f_color = vec4(color * contribution, 1.0);
+}
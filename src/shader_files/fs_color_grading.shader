@@ -0,0 +1,27 @@
+#version 450
+
+#extension GL_EXT_samplerless_texture_functions : require
+
+layout (location = 0) in vec2 v_tex_coords;
+layout (location = 0) out vec4 f_color;
+
+layout (set=0, binding=0) uniform texture2D t_input;
+layout (set=0, binding=1) uniform sampler s_input;
+
+layout (set=1, binding=0) uniform texture3D t_lut;
+layout (set=1, binding=1) uniform sampler s_lut;
+
+layout (set=2, binding=0) uniform ColorGrading {
+    float contribution;
+};
+
+void main() {
+    vec3 color = texture(sampler2D(t_input, s_input), v_tex_coords).rgb;
+    // luts are authored in gamma space but the srgb buffers are sampled as linear
+    vec3 gamma_color = pow(color, vec3(1.0 / 2.2));
+    float lut_size = float(textureSize(t_lut, 0).x);
+    // sample texel centers so the edges of the lut aren't blended with the clamp
+    vec3 lut_coords = gamma_color * ((lut_size - 1.0) / lut_size) + 0.5 / lut_size;
+    vec3 graded = texture(sampler3D(t_lut, s_lut), lut_coords).rgb;
+    f_color = vec4(mix(color, pow(graded, vec3(2.2)), contribution), 1.0);
+}
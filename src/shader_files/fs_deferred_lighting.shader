@@ -0,0 +1,148 @@
+#version 450
+
+#extension GL_EXT_samplerless_texture_functions : require
+
+layout(location=0) in vec2 v_tex_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform texture2D t_albedo;
+layout(set = 0, binding = 1) uniform texture2D t_normal;
+layout(set = 0, binding = 2) uniform texture2D t_material;
+layout(set = 0, binding = 3) uniform texture2D t_emissive;
+layout(set = 0, binding = 4) uniform texture2D t_depth;
+layout(set = 0, binding = 5) uniform sampler s_g_buffer;
+
+// World space position of the surface, reconstructed from depth in main
+vec3 fragment_position;
+
+#define SHADOW_SET 1
+#define LIGHTS_SET 3
+#include "lighting.glsl"
+
+layout(set = 2, binding = 0) uniform textureCube t_irradiance;
+layout(set = 2, binding = 1) uniform textureCube t_prefiltered;
+layout(set = 2, binding = 2) uniform texture2D t_brdf_lut;
+layout(set = 2, binding = 3) uniform sampler s_environment;
+layout(set = 2, binding = 4) uniform EnvironmentLighting {
+    float environment_intensity;
+    float max_reflection_lod;
+};
+
+// screen space ambient occlusion of the current target
+layout(set = 4, binding = 0) uniform texture2D t_ssao;
+layout(set = 4, binding = 1) uniform sampler s_ssao;
+
+float screen_space_occlusion() {
+    return texture(sampler2D(t_ssao, s_ssao), gl_FragCoord.xy / vec2(textureSize(t_ssao, 0))).r;
+}
+layout(set=5, binding=0) uniform DeferredUniforms {
+    mat4 view_projection;
+    mat4 inverse_view_projection;
+    vec3 view_pos;
+};
+
+const float SHADING_MODEL_PHONG = 0.0;
+const float SHADING_MODEL_PBR = 1.0;
+
+struct Surface {
+    vec3 albedo;
+    vec3 normal;
+    // phong: specular color, pbr: metallic, roughness and ambient occlusion
+    vec3 material;
+    vec3 emissive;
+    float shading_model;
+    bool unlit;
+};
+
+// Reads the g-buffer at the current pixel, returns false for the background
+bool read_surface(out Surface surface) {
+    ivec2 pixel = ivec2(gl_FragCoord.xy);
+    float depth = texelFetch(t_depth, pixel, 0).r;
+    if (depth >= 1.0) {
+        return false;
+    }
+    vec2 uv = gl_FragCoord.xy / vec2(textureSize(t_depth, 0));
+    // the depth buffer is in [0, 1] while the matrix expects opengl clip space
+    vec4 clip_position = vec4(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth * 2.0 - 1.0, 1.0);
+    vec4 world_position = inverse_view_projection * clip_position;
+    fragment_position = world_position.xyz / world_position.w;
+
+    vec4 albedo = texelFetch(t_albedo, pixel, 0);
+    surface.albedo = albedo.rgb;
+    surface.shading_model = albedo.a;
+    vec4 normal = texelFetch(t_normal, pixel, 0);
+    surface.normal = normalize(normal.xyz);
+    surface.unlit = normal.w > 0.5;
+    surface.material = texelFetch(t_material, pixel, 0).rgb;
+    surface.emissive = texelFetch(t_emissive, pixel, 0).rgb;
+    return true;
+}
+
+// The same lighting as the forward model shaders, picked by the shading model of the pixel
+vec3 shade(Surface surface, vec3 light_dir, vec3 pbr_radiance, vec3 phong_diffuse, vec3 phong_specular) {
+    vec3 view_dir = normalize(view_pos - fragment_position);
+    if (surface.shading_model < 0.5) {
+        vec3 halfway = normalize(light_dir + view_dir);
+        float spec = pow(max(dot(surface.normal, halfway), 0.0), 32.0);
+        float diff = max(dot(surface.normal, light_dir), 0.0);
+        return phong_specular * spec * surface.material + phong_diffuse * diff * surface.albedo;
+    }
+    float metallic = surface.material.r;
+    float roughness = surface.material.g;
+    vec3 f0 = mix(vec3(0.04), surface.albedo, metallic);
+    return cook_torrance(surface.normal, view_dir, light_dir, pbr_radiance, surface.albedo, metallic, roughness, f0);
+}
+
+vec3 ambient_light(Surface surface) {
+    vec3 irradiance = texture(samplerCube(t_irradiance, s_environment), surface.normal).rgb;
+    if (surface.shading_model < 0.5) {
+        return environment_intensity * irradiance * surface.albedo * screen_space_occlusion() + surface.emissive;
+    }
+    // split sum approximation of the image based lighting
+    float metallic = surface.material.r;
+    float roughness = surface.material.g;
+    float ambient_occlusion = surface.material.b;
+    vec3 view_dir = normalize(view_pos - fragment_position);
+    vec3 f0 = mix(vec3(0.04), surface.albedo, metallic);
+    float n_dot_v = max(dot(surface.normal, view_dir), 0.0);
+    vec3 fresnel = fresnel_schlick_roughness(n_dot_v, f0, roughness);
+    vec3 k_diffuse = (vec3(1.0) - fresnel) * (1.0 - metallic);
+    vec3 reflection = reflect(-view_dir, surface.normal);
+    vec3 prefiltered = textureLod(samplerCube(t_prefiltered, s_environment), reflection, roughness * max_reflection_lod).rgb;
+    vec2 brdf = texture(sampler2D(t_brdf_lut, s_environment), vec2(n_dot_v, roughness)).rg;
+    vec3 ambient = (k_diffuse * irradiance * surface.albedo + prefiltered * (fresnel * brdf.x + brdf.y)) * environment_intensity;
+    return ambient * ambient_occlusion * screen_space_occlusion() + surface.emissive;
+}
+
+// Ambient, directional and spot lighting, the point lights are added by their light volumes
+void main() {
+    Surface surface;
+    if (!read_surface(surface)) {
+        discard;
+    }
+    if (surface.unlit) {
+        f_color = vec4(surface.albedo + surface.emissive, 1.0);
+        return;
+    }
+    vec3 result = ambient_light(surface);
+
+    if (directional_light.enabled != 0) {
+        vec3 light_dir = normalize(-directional_light.direction);
+        vec3 radiance = directional_light.color * directional_light.intensity;
+        float shadow_value = calc_directional_shadow(surface.normal);
+        result += (1.0 - shadow_value) * shade(surface, light_dir, radiance, radiance, radiance);
+    }
+    for(int i = 0; i < spot_lights_used; i++) {
+        SpotLight light = spotLights[i];
+        vec3 light_dir = normalize(light.position - fragment_position);
+        float distance = length(light.position - fragment_position);
+        float cone = calc_spot_cone(light, light_dir);
+        vec3 radiance = light.color * light.intensity * cone / (distance * distance);
+        float attenuation = calculate_attenuation(light.position, light.constant, light.linear, light.quadratic) * cone;
+
+        float shadow_value = light.shadow_index >= 0 ? calc_spot_shadow(light, surface.normal) : 0.0;
+        result += (1.0 - shadow_value) * shade(surface, light_dir, radiance, attenuation * light.diffuse, attenuation * light.specular);
+    }
+    f_color = vec4(result, 1.0);
+}
@@ -0,0 +1,58 @@
+#version 450
+
+#extension GL_EXT_samplerless_texture_functions : require
+
+layout (location = 0) in vec2 v_tex_coords;
+layout (location = 0) out vec4 f_color;
+
+layout (set=0, binding=0) uniform texture2D t_input;
+layout (set=0, binding=1) uniform sampler s_input;
+
+layout (set=1, binding=0) uniform Fxaa {
+    float span_max;
+    float reduce_min;
+    float reduce_mul;
+};
+
+const vec3 LUMA = vec3(0.299, 0.587, 0.114);
+
+vec3 sample_input(vec2 coords) {
+    return texture(sampler2D(t_input, s_input), coords).rgb;
+}
+
+void main() {
+    vec2 texel_size = 1.0 / vec2(textureSize(t_input, 0));
+
+    float luma_nw = dot(sample_input(v_tex_coords + vec2(-1.0, -1.0) * texel_size), LUMA);
+    float luma_ne = dot(sample_input(v_tex_coords + vec2(1.0, -1.0) * texel_size), LUMA);
+    float luma_sw = dot(sample_input(v_tex_coords + vec2(-1.0, 1.0) * texel_size), LUMA);
+    float luma_se = dot(sample_input(v_tex_coords + vec2(1.0, 1.0) * texel_size), LUMA);
+    vec3 color_m = sample_input(v_tex_coords);
+    float luma_m = dot(color_m, LUMA);
+
+    float luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    float luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+    // blur direction is perpendicular to the local luma gradient
+    vec2 direction = vec2(
+        -((luma_nw + luma_ne) - (luma_sw + luma_se)),
+        ((luma_nw + luma_sw) - (luma_ne + luma_se))
+    );
+    float direction_reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * 0.25 * reduce_mul, reduce_min);
+    float inverse_direction_min = 1.0 / (min(abs(direction.x), abs(direction.y)) + direction_reduce);
+    direction = clamp(direction * inverse_direction_min, vec2(-span_max), vec2(span_max)) * texel_size;
+
+    vec3 color_a = 0.5 * (
+        sample_input(v_tex_coords + direction * (1.0 / 3.0 - 0.5)) +
+        sample_input(v_tex_coords + direction * (2.0 / 3.0 - 0.5)));
+    vec3 color_b = color_a * 0.5 + 0.25 * (
+        sample_input(v_tex_coords + direction * -0.5) +
+        sample_input(v_tex_coords + direction * 0.5));
+
+    float luma_b = dot(color_b, LUMA);
+    if (luma_b < luma_min || luma_b > luma_max) {
+        f_color = vec4(color_a, 1.0);
+    } else {
+        f_color = vec4(color_b, 1.0);
+    }
+}
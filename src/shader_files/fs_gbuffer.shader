@@ -0,0 +1,35 @@
+#version 450
+
+layout(location=0) in vec2 v_tex_coords;
+layout(location=1) in vec3 normal;
+
+layout(location=0) out vec4 g_albedo;
+layout(location=1) out vec4 g_normal;
+layout(location=2) out vec4 g_material;
+layout(location=3) out vec4 g_emissive;
+
+layout(set = 0, binding = 0) uniform texture2D t_diffuse;
+layout(set = 0, binding = 1) uniform sampler s_diffuse;
+layout(set = 1, binding = 0) uniform texture2D t_specular;
+layout(set = 1, binding = 1) uniform sampler s_specular;
+layout(set = 2, binding = 0) uniform texture2D t_emissive;
+layout(set = 2, binding = 1) uniform sampler s_emissive;
+layout(set = 2, binding = 2) uniform MaterialFactors {
+    vec3 emissive_factor;
+    float dissolve;
+    float alpha_cutoff;
+    int unlit;
+};
+
+const float SHADING_MODEL_PHONG = 0.0;
+
+void main() {
+    vec4 diffuse = texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords);
+    if (diffuse.a * dissolve < alpha_cutoff) {
+        discard;
+    }
+    g_albedo = vec4(diffuse.rgb, SHADING_MODEL_PHONG);
+    g_normal = vec4(normalize(normal), float(unlit));
+    g_material = vec4(texture(sampler2D(t_specular, s_specular), v_tex_coords).rgb, 0.0);
+    g_emissive = vec4(texture(sampler2D(t_emissive, s_emissive), v_tex_coords).rgb * emissive_factor, 0.0);
+}
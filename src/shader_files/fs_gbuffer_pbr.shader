@@ -0,0 +1,44 @@
+#version 450
+
+layout(location=0) in vec2 v_tex_coords;
+layout(location=1) in vec3 normal;
+
+layout(location=0) out vec4 g_albedo;
+layout(location=1) out vec4 g_normal;
+layout(location=2) out vec4 g_material;
+layout(location=3) out vec4 g_emissive;
+
+layout(set = 0, binding = 0) uniform texture2D t_albedo;
+layout(set = 0, binding = 1) uniform texture2D t_metallic;
+layout(set = 0, binding = 2) uniform texture2D t_roughness;
+layout(set = 0, binding = 3) uniform texture2D t_ambient_occlusion;
+layout(set = 0, binding = 4) uniform texture2D t_emissive;
+layout(set = 0, binding = 5) uniform sampler s_material;
+layout(set = 0, binding = 6) uniform MaterialFactors {
+    vec4 albedo_factor;
+    vec3 emissive_factor;
+    float metallic_factor;
+    float roughness_factor;
+    float ambient_occlusion_factor;
+    float alpha_cutoff;
+    int unlit;
+};
+
+const float SHADING_MODEL_PBR = 1.0;
+
+void main() {
+    vec4 albedo_sample = texture(sampler2D(t_albedo, s_material), v_tex_coords) * albedo_factor;
+    if (albedo_sample.a < alpha_cutoff) {
+        discard;
+    }
+    vec3 albedo = albedo_sample.rgb;
+    float metallic = texture(sampler2D(t_metallic, s_material), v_tex_coords).r * metallic_factor;
+    float roughness = texture(sampler2D(t_roughness, s_material), v_tex_coords).r * roughness_factor;
+    float ambient_occlusion = texture(sampler2D(t_ambient_occlusion, s_material), v_tex_coords).r * ambient_occlusion_factor;
+    vec3 emissive = texture(sampler2D(t_emissive, s_material), v_tex_coords).rgb * emissive_factor;
+
+    g_albedo = vec4(albedo, SHADING_MODEL_PBR);
+    g_normal = vec4(normalize(normal), float(unlit));
+    g_material = vec4(metallic, roughness, ambient_occlusion, 0.0);
+    g_emissive = vec4(emissive, 0.0);
+}
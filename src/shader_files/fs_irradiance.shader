@@ -0,0 +1,47 @@
+#version 450
+layout (location = 0) in vec2 v_tex_coords;
+layout (location = 0) out vec4 f_color;
+
+layout (set=0, binding=0) uniform textureCube t_cubemap;
+layout (set=0, binding=1) uniform sampler s_cubemap;
+
+layout (set=1, binding=0) uniform CubeFace {
+    int face;
+    float roughness;
+};
+
+const float PI = 3.14159265359;
+const float SAMPLE_DELTA = 0.025;
+
+// direction through the given texel of the current cube face
+vec3 face_direction(vec2 uv) {
+    vec2 st = uv * 2.0 - 1.0;
+    switch(face) {
+        case 0: return normalize(vec3(1.0, -st.y, -st.x));
+        case 1: return normalize(vec3(-1.0, -st.y, st.x));
+        case 2: return normalize(vec3(st.x, 1.0, st.y));
+        case 3: return normalize(vec3(st.x, -1.0, -st.y));
+        case 4: return normalize(vec3(st.x, -st.y, 1.0));
+        default: return normalize(vec3(-st.x, -st.y, -1.0));
+    }
+}
+
+void main() {
+    vec3 normal = face_direction(v_tex_coords);
+    vec3 up = abs(normal.y) < 0.999 ? vec3(0.0, 1.0, 0.0) : vec3(0.0, 0.0, 1.0);
+    vec3 right = normalize(cross(up, normal));
+    up = cross(normal, right);
+
+    // cosine weighted convolution over the hemisphere
+    vec3 irradiance = vec3(0.0);
+    float samples = 0.0;
+    for (float phi = 0.0; phi < 2.0 * PI; phi += SAMPLE_DELTA) {
+        for (float theta = 0.0; theta < 0.5 * PI; theta += SAMPLE_DELTA) {
+            vec3 tangent_sample = vec3(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            vec3 direction = tangent_sample.x * right + tangent_sample.y * up + tangent_sample.z * normal;
+            irradiance += texture(samplerCube(t_cubemap, s_cubemap), direction).rgb * cos(theta) * sin(theta);
+            samples += 1.0;
+        }
+    }
+    f_color = vec4(PI * irradiance / samples, 1.0);
+}
@@ -0,0 +1,108 @@
+#version 450
+
+#extension GL_EXT_samplerless_texture_functions : require
+
+layout(location=0) flat in int light_index;
+
+layout(location=0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform texture2D t_albedo;
+layout(set = 0, binding = 1) uniform texture2D t_normal;
+layout(set = 0, binding = 2) uniform texture2D t_material;
+layout(set = 0, binding = 3) uniform texture2D t_emissive;
+layout(set = 0, binding = 4) uniform texture2D t_depth;
+layout(set = 0, binding = 5) uniform sampler s_g_buffer;
+
+// World space position of the surface, reconstructed from depth in main
+vec3 fragment_position;
+
+#define SHADOW_SET 1
+#define LIGHTS_SET 3
+#include "lighting.glsl"
+
+layout(set = 2, binding = 0) uniform textureCube t_irradiance;
+layout(set = 2, binding = 1) uniform textureCube t_prefiltered;
+layout(set = 2, binding = 2) uniform texture2D t_brdf_lut;
+layout(set = 2, binding = 3) uniform sampler s_environment;
+layout(set = 2, binding = 4) uniform EnvironmentLighting {
+    float environment_intensity;
+    float max_reflection_lod;
+};
+
+layout(set=4, binding=0) uniform DeferredUniforms {
+    mat4 view_projection;
+    mat4 inverse_view_projection;
+    vec3 view_pos;
+};
+
+const float SHADING_MODEL_PHONG = 0.0;
+const float SHADING_MODEL_PBR = 1.0;
+
+struct Surface {
+    vec3 albedo;
+    vec3 normal;
+    // phong: specular color, pbr: metallic, roughness and ambient occlusion
+    vec3 material;
+    vec3 emissive;
+    float shading_model;
+    bool unlit;
+};
+
+// Reads the g-buffer at the current pixel, returns false for the background
+bool read_surface(out Surface surface) {
+    ivec2 pixel = ivec2(gl_FragCoord.xy);
+    float depth = texelFetch(t_depth, pixel, 0).r;
+    if (depth >= 1.0) {
+        return false;
+    }
+    vec2 uv = gl_FragCoord.xy / vec2(textureSize(t_depth, 0));
+    // the depth buffer is in [0, 1] while the matrix expects opengl clip space
+    vec4 clip_position = vec4(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth * 2.0 - 1.0, 1.0);
+    vec4 world_position = inverse_view_projection * clip_position;
+    fragment_position = world_position.xyz / world_position.w;
+
+    vec4 albedo = texelFetch(t_albedo, pixel, 0);
+    surface.albedo = albedo.rgb;
+    surface.shading_model = albedo.a;
+    vec4 normal = texelFetch(t_normal, pixel, 0);
+    surface.normal = normalize(normal.xyz);
+    surface.unlit = normal.w > 0.5;
+    surface.material = texelFetch(t_material, pixel, 0).rgb;
+    surface.emissive = texelFetch(t_emissive, pixel, 0).rgb;
+    return true;
+}
+
+// The same lighting as the forward model shaders, picked by the shading model of the pixel
+vec3 shade(Surface surface, vec3 light_dir, vec3 pbr_radiance, vec3 phong_diffuse, vec3 phong_specular) {
+    vec3 view_dir = normalize(view_pos - fragment_position);
+    if (surface.shading_model < 0.5) {
+        vec3 halfway = normalize(light_dir + view_dir);
+        float spec = pow(max(dot(surface.normal, halfway), 0.0), 32.0);
+        float diff = max(dot(surface.normal, light_dir), 0.0);
+        return phong_specular * spec * surface.material + phong_diffuse * diff * surface.albedo;
+    }
+    float metallic = surface.material.r;
+    float roughness = surface.material.g;
+    vec3 f0 = mix(vec3(0.04), surface.albedo, metallic);
+    return cook_torrance(surface.normal, view_dir, light_dir, pbr_radiance, surface.albedo, metallic, roughness, f0);
+}
+
+// Adds a single point light to the pixels covered by its light volume
+void main() {
+    Surface surface;
+    if (!read_surface(surface) || surface.unlit) {
+        discard;
+    }
+    PointLight light = pointLights[light_index];
+    float distance = length(light.position - fragment_position);
+    if (distance > light.range) {
+        discard;
+    }
+    vec3 light_dir = normalize(light.position - fragment_position);
+    float falloff = range_falloff(distance, light.range);
+    vec3 radiance = light.color * light.intensity * falloff / (distance * distance);
+    float attenuation = calculate_attenuation(light.position, light.constant, light.linear, light.quadratic) * falloff;
+
+    float shadow_value = light.shadow_index >= 0 ? calc_shadow(light, surface.normal) : 0.0;
+    f_color = vec4((1.0 - shadow_value) * shade(surface, light_dir, radiance, attenuation * light.diffuse, attenuation * light.specular), 1.0);
+}
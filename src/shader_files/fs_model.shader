@@ -9,59 +9,41 @@ layout(location=3) in vec3 view_pos;
 
 layout(location=0) out vec4 f_color;
 
-struct PointLight {
-    vec3 position;
-    vec3 ambient;
-    vec3 specular;
-    vec3 diffuse;
-    float constant;
-    float linear;
-    float quadratic;
-    mat4 light_space_matrix; // used for shadowmapping
-};
 // handle multiple textures?
 layout(set = 0, binding = 0) uniform texture2D t_diffuse;
 layout(set = 0, binding = 1) uniform sampler s_diffuse;
 layout(set = 1, binding = 0) uniform texture2D t_specular;
 layout(set = 1, binding = 1) uniform sampler s_specular;
-
-
-layout(set = 2, binding = 0) uniform texture2DArray t_shadow;
-layout(set = 2, binding = 1) uniform samplerShadow s_shadow;
-
-const int MAX_POINT_LIGHTS = 16;
-layout(set=4, binding=0) uniform PointLights {
-    int lights_used;
-    PointLight pointLights[MAX_POINT_LIGHTS];
+layout(set = 2, binding = 0) uniform texture2D t_emissive;
+layout(set = 2, binding = 1) uniform sampler s_emissive;
+layout(set = 2, binding = 2) uniform MaterialFactors {
+    vec3 emissive_factor;
+    float dissolve;
+    float alpha_cutoff;
+    int unlit;
 };
 
+#define SHADOW_SET 3
+#define LIGHTS_SET 5
+#include "lighting.glsl"
+
+layout(set = 4, binding = 0) uniform textureCube t_irradiance;
+layout(set = 4, binding = 1) uniform textureCube t_prefiltered;
+layout(set = 4, binding = 2) uniform texture2D t_brdf_lut;
+layout(set = 4, binding = 3) uniform sampler s_environment;
+layout(set = 4, binding = 4) uniform EnvironmentLighting {
+    float environment_intensity;
+    float max_reflection_lod;
+};
 
-float calc_shadow(int light_id, vec4 homo_coords) {
-    vec3 projCoords = homo_coords.xyz / homo_coords.w;
-    if (projCoords.z > 1.0) {
-        return 0.0;
-    } 
-    const vec2 flip_correction = vec2(0.5, -0.5);
-    // PCF for softer shadows:
-    float shadow = 0.0;
-    const vec3 texelSize = 1.0 / textureSize(t_shadow, 0);
-    float currentDepth = projCoords.z;
-    for (int x = -1; x <= 1; ++x) {
-        for(int y = -1; y <= 1; ++y) {
-            float pcfDepth = texture(sampler2DArrayShadow(t_shadow, s_shadow), vec4(projCoords.xy * flip_correction + 0.5 + vec2(x, y) * texelSize.xy, light_id, projCoords.z));
-            shadow += currentDepth > pcfDepth ? 1.0 : 0.0;
-        }
-    }
-    return shadow / 9.0;
-}
-
+// screen space ambient occlusion of the current target
+layout(set = 6, binding = 0) uniform texture2D t_ssao;
+layout(set = 6, binding = 1) uniform sampler s_ssao;
 
-float calculate_attenuation(vec3 light_position, float constant, float linear, float quadratic) {
-    float distance = length(light_position - fragment_position);
-    return 1.0 / (constant + (linear * distance) + quadratic * (distance * distance));
+float screen_space_occlusion() {
+    return texture(sampler2D(t_ssao, s_ssao), gl_FragCoord.xy / vec2(textureSize(t_ssao, 0))).r;
 }
 
-
 vec3 calculate_point_light(PointLight light, vec3 normal, float shadow_value) {
 
     vec3 direction_to_light = normalize(light.position - fragment_position);
@@ -74,35 +56,73 @@ vec3 calculate_point_light(PointLight light, vec3 normal, float shadow_value) {
     float spec = pow(max(dot(normal, halfwayDir), 0.0), 32.0);
     float diff = max(dot(normal, direction_to_light), 0.0);
     float attenuation = calculate_attenuation(light.position, light.constant, light.linear, light.quadratic);
-
+    attenuation *= range_falloff(length(light.position - fragment_position), light.range);
 
     result += (1.0 - shadow_value) * light.specular * attenuation * spec * texture(sampler2D(t_specular, s_specular), v_tex_coords).rgb;
 
-
     result += (1.0 - shadow_value) * light.diffuse * attenuation * diff * texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords).rgb;
 
+    return result;
+}
 
-    result += light.ambient * attenuation * texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords).rgb;
+vec3 calculate_spot_light(SpotLight light, vec3 normal, float shadow_value) {
+    vec3 direction_to_light = normalize(light.position - fragment_position);
+    vec3 viewDir = normalize(view_pos - fragment_position);
+    vec3 halfwayDir = normalize(direction_to_light + viewDir);
 
-    return result;
+    float spec = pow(max(dot(normal, halfwayDir), 0.0), 32.0);
+    float diff = max(dot(normal, direction_to_light), 0.0);
+    float attenuation = calculate_attenuation(light.position, light.constant, light.linear, light.quadratic);
+    attenuation *= calc_spot_cone(light, direction_to_light);
+
+    vec3 result = light.specular * spec * texture(sampler2D(t_specular, s_specular), v_tex_coords).rgb;
+    result += light.diffuse * diff * texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords).rgb;
+    return (1.0 - shadow_value) * attenuation * result;
 }
 
-const mat4 CONVERSION = mat4(
-1.0, 0.0, 0.0, 0.0,
-0.0, 1.0, 0.0, 0.0,
-0.0, 0.0, 0.5, 0.0,
-0.0, 0.0, 0.5, 1.0);
+vec3 calculate_directional_light(vec3 normal, float shadow_value) {
+    vec3 direction_to_light = normalize(-directional_light.direction);
+    vec3 viewDir = normalize(view_pos - fragment_position);
+    vec3 halfwayDir = normalize(direction_to_light + viewDir);
+
+    float spec = pow(max(dot(normal, halfwayDir), 0.0), 32.0);
+    float diff = max(dot(normal, direction_to_light), 0.0);
+    vec3 radiance = directional_light.color * directional_light.intensity;
 
+    vec3 result = radiance * spec * texture(sampler2D(t_specular, s_specular), v_tex_coords).rgb;
+    result += radiance * diff * texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords).rgb;
+    return (1.0 - shadow_value) * result;
+}
 
 void main() {
+    vec4 diffuse_sample = texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords);
+    float alpha = diffuse_sample.a * dissolve;
+    if (alpha < alpha_cutoff) {
+        discard;
+    }
+    vec3 emissive = texture(sampler2D(t_emissive, s_emissive), v_tex_coords).rgb * emissive_factor;
+    if (unlit != 0) {
+        f_color = vec4(diffuse_sample.rgb + emissive, alpha);
+        return;
+    }
 
     vec3 norm = normalize(normal);
-    vec3 result = vec3(0.0);
-   
-    for(int i = 0; i < lights_used; i++) {
-        vec4 light_space_pos = CONVERSION * pointLights[i].light_space_matrix * vec4(fragment_position, 1.0);
-        float shadow_value = calc_shadow(i, light_space_pos);
-        result += calculate_point_light(pointLights[i], norm, shadow_value);
+    // diffuse image based lighting replaces the per light ambient term
+    vec3 irradiance = texture(samplerCube(t_irradiance, s_environment), norm).rgb;
+    vec3 result = environment_intensity * irradiance * diffuse_sample.rgb * screen_space_occlusion();
+
+    if (directional_light.enabled != 0) {
+        result += calculate_directional_light(norm, calc_directional_shadow(norm));
+    }
+    uvec2 cluster = cluster_lights();
+    for(uint i = 0; i < cluster.y; i++) {
+        PointLight light = pointLights[light_indices[cluster.x + i]];
+        float shadow_value = light.shadow_index >= 0 ? calc_shadow(light, norm) : 0.0;
+        result += calculate_point_light(light, norm, shadow_value);
+    }
+    for(int i = 0; i < spot_lights_used; i++) {
+        float shadow_value = spotLights[i].shadow_index >= 0 ? calc_spot_shadow(spotLights[i], norm) : 0.0;
+        result += calculate_spot_light(spotLights[i], norm, shadow_value);
     }
-    f_color = vec4(result ,1.0);
+    f_color = vec4(result + emissive, alpha);
 }
\ No newline at end of file
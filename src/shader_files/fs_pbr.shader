@@ -0,0 +1,108 @@
+#version 450
+
+#extension GL_EXT_samplerless_texture_functions : require
+
+layout(location=0) in vec2 v_tex_coords;
+layout(location=1) in vec3 normal;
+layout(location=2) in vec3 fragment_position;
+layout(location=3) in vec3 view_pos;
+
+layout(location=0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform texture2D t_albedo;
+layout(set = 0, binding = 1) uniform texture2D t_metallic;
+layout(set = 0, binding = 2) uniform texture2D t_roughness;
+layout(set = 0, binding = 3) uniform texture2D t_ambient_occlusion;
+layout(set = 0, binding = 4) uniform texture2D t_emissive;
+layout(set = 0, binding = 5) uniform sampler s_material;
+layout(set = 0, binding = 6) uniform MaterialFactors {
+    vec4 albedo_factor;
+    vec3 emissive_factor;
+    float metallic_factor;
+    float roughness_factor;
+    float ambient_occlusion_factor;
+    float alpha_cutoff;
+    int unlit;
+};
+
+#define SHADOW_SET 1
+#define LIGHTS_SET 3
+#include "lighting.glsl"
+
+layout(set = 2, binding = 0) uniform textureCube t_irradiance;
+layout(set = 2, binding = 1) uniform textureCube t_prefiltered;
+layout(set = 2, binding = 2) uniform texture2D t_brdf_lut;
+layout(set = 2, binding = 3) uniform sampler s_environment;
+layout(set = 2, binding = 4) uniform EnvironmentLighting {
+    float environment_intensity;
+    float max_reflection_lod;
+};
+
+// screen space ambient occlusion of the current target
+layout(set = 4, binding = 0) uniform texture2D t_ssao;
+layout(set = 4, binding = 1) uniform sampler s_ssao;
+
+float screen_space_occlusion() {
+    return texture(sampler2D(t_ssao, s_ssao), gl_FragCoord.xy / vec2(textureSize(t_ssao, 0))).r;
+}
+
+void main() {
+    vec4 albedo_sample = texture(sampler2D(t_albedo, s_material), v_tex_coords) * albedo_factor;
+    if (albedo_sample.a < alpha_cutoff) {
+        discard;
+    }
+    vec3 albedo = albedo_sample.rgb;
+    float metallic = texture(sampler2D(t_metallic, s_material), v_tex_coords).r * metallic_factor;
+    float roughness = texture(sampler2D(t_roughness, s_material), v_tex_coords).r * roughness_factor;
+    float ambient_occlusion = texture(sampler2D(t_ambient_occlusion, s_material), v_tex_coords).r * ambient_occlusion_factor;
+    vec3 emissive = texture(sampler2D(t_emissive, s_material), v_tex_coords).rgb * emissive_factor;
+    if (unlit != 0) {
+        f_color = vec4(albedo + emissive, albedo_sample.a);
+        return;
+    }
+
+    vec3 norm = normalize(normal);
+    vec3 view_dir = normalize(view_pos - fragment_position);
+    // dielectrics reflect roughly 4% at normal incidence
+    vec3 f0 = mix(vec3(0.04), albedo, metallic);
+
+    vec3 radiance_out = vec3(0.0);
+    if (directional_light.enabled != 0) {
+        vec3 light_dir = normalize(-directional_light.direction);
+        vec3 radiance = directional_light.color * directional_light.intensity;
+        float shadow_value = calc_directional_shadow(norm);
+        radiance_out += (1.0 - shadow_value) * cook_torrance(norm, view_dir, light_dir, radiance, albedo, metallic, roughness, f0);
+    }
+    uvec2 cluster = cluster_lights();
+    for(uint i = 0; i < cluster.y; i++) {
+        PointLight light = pointLights[light_indices[cluster.x + i]];
+        vec3 light_dir = normalize(light.position - fragment_position);
+        float distance = length(light.position - fragment_position);
+        vec3 radiance = light.color * light.intensity * range_falloff(distance, light.range) / (distance * distance);
+
+        float shadow_value = light.shadow_index >= 0 ? calc_shadow(light, norm) : 0.0;
+        radiance_out += (1.0 - shadow_value) * cook_torrance(norm, view_dir, light_dir, radiance, albedo, metallic, roughness, f0);
+    }
+    for(int i = 0; i < spot_lights_used; i++) {
+        SpotLight light = spotLights[i];
+        vec3 light_dir = normalize(light.position - fragment_position);
+        float distance = length(light.position - fragment_position);
+        vec3 radiance = light.color * light.intensity * calc_spot_cone(light, light_dir) / (distance * distance);
+
+        float shadow_value = light.shadow_index >= 0 ? calc_spot_shadow(light, norm) : 0.0;
+        radiance_out += (1.0 - shadow_value) * cook_torrance(norm, view_dir, light_dir, radiance, albedo, metallic, roughness, f0);
+    }
+
+    // split sum approximation of the image based lighting
+    float n_dot_v = max(dot(norm, view_dir), 0.0);
+    vec3 fresnel = fresnel_schlick_roughness(n_dot_v, f0, roughness);
+    vec3 k_diffuse = (vec3(1.0) - fresnel) * (1.0 - metallic);
+    vec3 irradiance = texture(samplerCube(t_irradiance, s_environment), norm).rgb;
+    vec3 reflection = reflect(-view_dir, norm);
+    vec3 prefiltered = textureLod(samplerCube(t_prefiltered, s_environment), reflection, roughness * max_reflection_lod).rgb;
+    vec2 brdf = texture(sampler2D(t_brdf_lut, s_environment), vec2(n_dot_v, roughness)).rg;
+    vec3 ambient = (k_diffuse * irradiance * albedo + prefiltered * (fresnel * brdf.x + brdf.y)) * environment_intensity;
+
+    vec3 color = ambient * ambient_occlusion * screen_space_occlusion() + radiance_out + emissive;
+    f_color = vec4(color, albedo_sample.a);
+}
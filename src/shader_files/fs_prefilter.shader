@@ -0,0 +1,73 @@
+#version 450
+layout (location = 0) in vec2 v_tex_coords;
+layout (location = 0) out vec4 f_color;
+
+layout (set=0, binding=0) uniform textureCube t_cubemap;
+layout (set=0, binding=1) uniform sampler s_cubemap;
+
+layout (set=1, binding=0) uniform CubeFace {
+    int face;
+    float roughness;
+};
+
+const float PI = 3.14159265359;
+const uint SAMPLE_COUNT = 512u;
+
+// direction through the given texel of the current cube face
+vec3 face_direction(vec2 uv) {
+    vec2 st = uv * 2.0 - 1.0;
+    switch(face) {
+        case 0: return normalize(vec3(1.0, -st.y, -st.x));
+        case 1: return normalize(vec3(-1.0, -st.y, st.x));
+        case 2: return normalize(vec3(st.x, 1.0, st.y));
+        case 3: return normalize(vec3(st.x, -1.0, -st.y));
+        case 4: return normalize(vec3(st.x, -st.y, 1.0));
+        default: return normalize(vec3(-st.x, -st.y, -1.0));
+    }
+}
+
+float radical_inverse(uint bits) {
+    bits = (bits << 16u) | (bits >> 16u);
+    bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
+    bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
+    bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
+    bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
+    return float(bits) * 2.3283064365386963e-10;
+}
+
+vec2 hammersley(uint i, uint count) {
+    return vec2(float(i) / float(count), radical_inverse(i));
+}
+
+// GGX distributed halfway vector around the normal
+vec3 importance_sample_ggx(vec2 xi, vec3 normal, float roughness) {
+    float a = roughness * roughness;
+    float phi = 2.0 * PI * xi.x;
+    float cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    float sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    vec3 halfway = vec3(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+
+    vec3 up = abs(normal.z) < 0.999 ? vec3(0.0, 0.0, 1.0) : vec3(1.0, 0.0, 0.0);
+    vec3 tangent = normalize(cross(up, normal));
+    vec3 bitangent = cross(normal, tangent);
+    return normalize(tangent * halfway.x + bitangent * halfway.y + normal * halfway.z);
+}
+
+void main() {
+    // assumes the view direction equals the normal, the usual split sum simplification
+    vec3 normal = face_direction(v_tex_coords);
+    vec3 view_dir = normal;
+
+    vec3 color = vec3(0.0);
+    float total_weight = 0.0;
+    for (uint i = 0u; i < SAMPLE_COUNT; ++i) {
+        vec3 halfway = importance_sample_ggx(hammersley(i, SAMPLE_COUNT), normal, roughness);
+        vec3 light_dir = normalize(2.0 * dot(view_dir, halfway) * halfway - view_dir);
+        float n_dot_l = max(dot(normal, light_dir), 0.0);
+        if (n_dot_l > 0.0) {
+            color += textureLod(samplerCube(t_cubemap, s_cubemap), light_dir, 0.0).rgb * n_dot_l;
+            total_weight += n_dot_l;
+        }
+    }
+    f_color = vec4(color / total_weight, 1.0);
+}
@@ -1,5 +1,29 @@
 #version 450
 
+layout(location=0) in vec3 fragment_position;
+layout(location=1) in vec2 v_tex_coords;
+
+layout(set=0, binding=0) uniform texture2D t_diffuse;
+layout(set=0, binding=1) uniform sampler s_diffuse;
+// only the dissolve and alpha cutoff of the phong factors are used
+layout(set=1, binding=2) uniform MaterialFactors {
+    vec3 emissive_factor;
+    float dissolve;
+    float alpha_cutoff;
+    int unlit;
+};
+
+layout(set=2, binding=0) uniform ShadowFace {
+    mat4 view_projection;
+    vec3 light_position;
+    float far_plane;
+};
+
 void main() {
-    // nothing needed here
-}
\ No newline at end of file
+    // opaque materials have a cutoff of 0 and skip the texture read
+    if (alpha_cutoff > 0.0 && texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords).a * dissolve < alpha_cutoff) {
+        discard;
+    }
+    // store the linear distance so the cube map can be sampled by direction
+    gl_FragDepth = length(fragment_position - light_position) / far_plane;
+}
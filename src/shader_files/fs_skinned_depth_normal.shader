@@ -0,0 +1,9 @@
+#version 450
+
+layout(location=0) in vec3 normal;
+
+layout(location=0) out vec4 f_normal;
+
+void main() {
+    f_normal = vec4(normalize(normal), 0.0);
+}
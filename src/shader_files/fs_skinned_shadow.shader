@@ -0,0 +1,15 @@
+#version 450
+
+layout(location=0) in vec3 fragment_position;
+
+// the joint matrices and morph targets come before the face uniforms for skinned meshes
+layout(set=2, binding=0) uniform ShadowFace {
+    mat4 view_projection;
+    vec3 light_position;
+    float far_plane;
+};
+
+void main() {
+    // store the linear distance so the cube map can be sampled by direction
+    gl_FragDepth = length(fragment_position - light_position) / far_plane;
+}
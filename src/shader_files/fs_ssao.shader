@@ -0,0 +1,84 @@
+#version 450
+
+#extension GL_EXT_samplerless_texture_functions : require
+
+layout (location = 0) in vec2 v_tex_coords;
+layout (location = 0) out vec4 f_color;
+
+layout (set=0, binding=0) uniform texture2D t_normal;
+layout (set=0, binding=1) uniform texture2D t_depth;
+layout (set=0, binding=2) uniform sampler s_depth_normal;
+
+const int MAX_SSAO_SAMPLES = 64;
+layout (set=1, binding=0) uniform Ssao {
+    mat4 projection;
+    mat4 inverse_projection;
+    mat4 view;
+    float radius;
+    float intensity;
+    float bias;
+    int sample_count;
+    vec4 kernel[MAX_SSAO_SAMPLES];
+};
+
+const mat4 CONVERSION = mat4(
+1.0, 0.0, 0.0, 0.0,
+0.0, 1.0, 0.0, 0.0,
+0.0, 0.0, 0.5, 0.0,
+0.0, 0.0, 0.5, 1.0);
+
+// 4x4 ordered dither pattern, each pixel in a block rotates the kernel differently
+const float ROTATIONS[16] = float[](
+    0.0, 8.0, 2.0, 10.0,
+    12.0, 4.0, 14.0, 6.0,
+    3.0, 11.0, 1.0, 9.0,
+    15.0, 7.0, 13.0, 5.0
+);
+
+const float PI = 3.14159265359;
+
+float read_depth(vec2 uv) {
+    return texture(sampler2D(t_depth, s_depth_normal), uv).r;
+}
+
+// The depth buffer is in [0, 1] while the matrix expects opengl clip space
+vec3 view_position(vec2 uv, float depth) {
+    vec4 clip_position = vec4(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth * 2.0 - 1.0, 1.0);
+    vec4 position = inverse_projection * clip_position;
+    return position.xyz / position.w;
+}
+
+void main() {
+    float depth = read_depth(v_tex_coords);
+    if (depth >= 1.0) {
+        f_color = vec4(1.0);
+        return;
+    }
+    vec3 position = view_position(v_tex_coords, depth);
+    vec3 normal = normalize(mat3(view) * texture(sampler2D(t_normal, s_depth_normal), v_tex_coords).xyz);
+
+    ivec2 pixel = ivec2(gl_FragCoord.xy) % 4;
+    float angle = 2.0 * PI * ROTATIONS[pixel.x + pixel.y * 4] / 16.0;
+    vec3 random_direction = vec3(cos(angle), sin(angle), 0.0);
+    // Gram-Schmidt, falls back to another axis if the normal faces the camera head on
+    vec3 tangent = random_direction - normal * dot(random_direction, normal);
+    if (dot(tangent, tangent) < 0.0001) {
+        tangent = vec3(random_direction.y, 0.0, -random_direction.x);
+        tangent -= normal * dot(tangent, normal);
+    }
+    tangent = normalize(tangent);
+    mat3 tbn = mat3(tangent, cross(normal, tangent), normal);
+
+    float occlusion = 0.0;
+    for (int i = 0; i < sample_count; ++i) {
+        vec3 sample_position = position + tbn * kernel[i].xyz * radius;
+        vec4 offset = CONVERSION * projection * vec4(sample_position, 1.0);
+        vec2 sample_uv = offset.xy / offset.w * vec2(0.5, -0.5) + 0.5;
+        float sample_depth = view_position(sample_uv, read_depth(sample_uv)).z;
+        // occluders far outside the radius are separate objects, not creases
+        float range_check = smoothstep(0.0, 1.0, radius / abs(position.z - sample_depth));
+        occlusion += (sample_depth >= sample_position.z + bias ? 1.0 : 0.0) * range_check;
+    }
+    float ambient_occlusion = 1.0 - occlusion / float(sample_count);
+    f_color = vec4(pow(ambient_occlusion, intensity));
+}
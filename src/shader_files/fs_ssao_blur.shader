@@ -0,0 +1,21 @@
+#version 450
+
+#extension GL_EXT_samplerless_texture_functions : require
+
+layout (location = 0) in vec2 v_tex_coords;
+layout (location = 0) out vec4 f_color;
+
+layout (set=0, binding=0) uniform texture2D t_input;
+layout (set=0, binding=1) uniform sampler s_input;
+
+// Averages a 4x4 block which matches the size of the sample rotation pattern
+void main() {
+    vec2 texel_size = 1.0 / vec2(textureSize(t_input, 0));
+    float result = 0.0;
+    for (int x = -2; x < 2; ++x) {
+        for (int y = -2; y < 2; ++y) {
+            result += texture(sampler2D(t_input, s_input), v_tex_coords + vec2(x, y) * texel_size).r;
+        }
+    }
+    f_color = vec4(result / 16.0);
+}
@@ -0,0 +1,50 @@
+#version 450
+layout (location = 0) in vec2 v_tex_coords;
+layout (location = 0) out vec4 f_color;
+
+layout (set=0, binding=0) uniform texture2D t_hdr;
+layout (set=0, binding=1) uniform sampler s_hdr;
+
+layout (set=1, binding=0) uniform texture2D t_bloom;
+layout (set=1, binding=1) uniform sampler s_bloom;
+
+layout (set=2, binding=0) uniform ToneMapping {
+    float exposure;
+    float gamma;
+    int operator;
+    float bloom_intensity;
+};
+
+const int CLAMP = 0;
+const int REINHARD = 1;
+const int ACES = 2;
+
+vec3 reinhard(vec3 color) {
+    return color / (color + vec3(1.0));
+}
+
+// Narkowicz 2015, "ACES Filmic Tone Mapping Curve"
+vec3 aces(vec3 color) {
+    const float a = 2.51;
+    const float b = 0.03;
+    const float c = 2.43;
+    const float d = 0.59;
+    const float e = 0.14;
+    return clamp((color * (a * color + b)) / (color * (c * color + d) + e), 0.0, 1.0);
+}
+
+void main() {
+    vec3 hdr_color = texture(sampler2D(t_hdr, s_hdr), v_tex_coords).rgb;
+    hdr_color += texture(sampler2D(t_bloom, s_bloom), v_tex_coords).rgb * bloom_intensity;
+    hdr_color *= exposure;
+    vec3 mapped;
+    switch(operator) {
+        case REINHARD: mapped = reinhard(hdr_color); break;
+        case ACES: mapped = aces(hdr_color); break;
+        default: mapped = clamp(hdr_color, 0.0, 1.0); break;
+    }
+    // the swap chain is srgb so it already applies a 2.2 gamma curve,
+    // only the difference to the requested gamma is applied here
+    mapped = pow(mapped, vec3(2.2 / gamma));
+    f_color = vec4(mapped, 1.0);
+}
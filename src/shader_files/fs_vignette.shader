@@ -0,0 +1,19 @@
+#version 450
+layout (location = 0) in vec2 v_tex_coords;
+layout (location = 0) out vec4 f_color;
+
+layout (set=0, binding=0) uniform texture2D t_input;
+layout (set=0, binding=1) uniform sampler s_input;
+
+layout (set=1, binding=0) uniform Vignette {
+    float intensity;
+    float radius;
+    float smoothness;
+};
+
+void main() {
+    vec3 color = texture(sampler2D(t_input, s_input), v_tex_coords).rgb;
+    float distance_to_center = length(v_tex_coords - vec2(0.5));
+    float vignette = smoothstep(radius, radius - smoothness, distance_to_center);
+    f_color = vec4(color * mix(1.0, vignette, intensity), 1.0);
+}
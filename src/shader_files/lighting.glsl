@@ -0,0 +1,450 @@
+// Lights, shadows and the lighting math shared by the forward and deferred lighting
+// shaders, pasted in place of #include "lighting.glsl" when a shader is loaded.
+// The including shader defines SHADOW_SET and LIGHTS_SET for its bind groups and
+// declares fragment_position, the world space position being lit
+
+struct PointLight {
+    vec3 position;
+    float shadow_far_plane;
+    vec3 specular;
+    float shadow_bias;
+    vec3 diffuse;
+    float constant;
+    float linear;
+    float quadratic;
+    float light_size;
+    float range;
+    vec3 color;
+    float intensity;
+    int shadow_index;
+};
+
+const int SHADOW_CASCADES = 4;
+struct DirectionalLight {
+    vec3 direction;
+    float intensity;
+    vec3 color;
+    int enabled;
+    float shadow_bias;
+    float shadow_scale;
+    float penumbra_slope;
+    int cast_shadows;
+    mat4 cascade_matrices[SHADOW_CASCADES];
+};
+
+struct SpotLight {
+    vec3 position;
+    float range;
+    vec3 direction;
+    float cos_inner_cutoff;
+    vec3 specular;
+    float cos_outer_cutoff;
+    vec3 diffuse;
+    float constant;
+    float linear;
+    float quadratic;
+    float shadow_bias;
+    float shadow_scale;
+    vec3 color;
+    float intensity;
+    float light_size;
+    int shadow_index;
+    mat4 light_space_matrix;
+};
+
+layout(set = SHADOW_SET, binding = 0) uniform textureCubeArray t_shadow;
+layout(set = SHADOW_SET, binding = 1) uniform samplerShadow s_shadow;
+layout(set = SHADOW_SET, binding = 2) uniform texture2DArray t_cascade_shadow;
+layout(set = SHADOW_SET, binding = 3) uniform texture2DArray t_spot_shadow;
+layout(set = SHADOW_SET, binding = 4) uniform sampler s_shadow_depth;
+layout(set = SHADOW_SET, binding = 5) uniform textureCubeArray t_high_res_shadow;
+
+const int MAX_SPOT_LIGHTS = 8;
+layout(set=LIGHTS_SET, binding=0) uniform Lights {
+    DirectionalLight directional_light;
+    int spot_lights_used;
+    int shadow_filter;
+    int shadow_kernel_radius;
+    SpotLight spotLights[MAX_SPOT_LIGHTS];
+};
+
+const int CLUSTER_TILES_X = 16;
+const int CLUSTER_TILES_Y = 9;
+const int CLUSTER_SLICES = 24;
+layout(set=LIGHTS_SET, binding=1) uniform Clusters {
+    mat4 cluster_view;
+    mat4 cluster_projection;
+    float cluster_near;
+    float cluster_far;
+    int lights_used;
+};
+layout(set=LIGHTS_SET, binding=2) readonly buffer PointLights {
+    PointLight pointLights[];
+};
+// offset into the light indices and light count of each cluster
+layout(set=LIGHTS_SET, binding=3) readonly buffer ClusterGrid {
+    uvec2 cluster_grid[];
+};
+layout(set=LIGHTS_SET, binding=4) readonly buffer ClusterLightIndices {
+    uint light_indices[];
+};
+
+const float PI = 3.14159265359;
+
+// Trowbridge-Reitz GGX normal distribution
+float distribution_ggx(vec3 normal, vec3 halfway, float roughness) {
+    float a = roughness * roughness;
+    float a2 = a * a;
+    float n_dot_h = max(dot(normal, halfway), 0.0);
+    float denominator = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * denominator * denominator);
+}
+
+float geometry_schlick_ggx(float n_dot_v, float roughness) {
+    float r = roughness + 1.0;
+    float k = (r * r) / 8.0;
+    return n_dot_v / (n_dot_v * (1.0 - k) + k);
+}
+
+// Smith's method combining view and light direction occlusion
+float geometry_smith(vec3 normal, vec3 view_dir, vec3 light_dir, float roughness) {
+    float n_dot_v = max(dot(normal, view_dir), 0.0);
+    float n_dot_l = max(dot(normal, light_dir), 0.0);
+    return geometry_schlick_ggx(n_dot_v, roughness) * geometry_schlick_ggx(n_dot_l, roughness);
+}
+
+vec3 fresnel_schlick(float cos_theta, vec3 f0) {
+    return f0 + (1.0 - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// rough surfaces reflect less of the environment at grazing angles
+vec3 fresnel_schlick_roughness(float cos_theta, vec3 f0, float roughness) {
+    return f0 + (max(vec3(1.0 - roughness), f0) - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// Outgoing radiance from a single light using the Cook-Torrance brdf
+vec3 cook_torrance(vec3 norm, vec3 view_dir, vec3 light_dir, vec3 radiance, vec3 albedo, float metallic, float roughness, vec3 f0) {
+    vec3 halfway = normalize(view_dir + light_dir);
+    float ndf = distribution_ggx(norm, halfway, roughness);
+    float geometry = geometry_smith(norm, view_dir, light_dir, roughness);
+    vec3 fresnel = fresnel_schlick(max(dot(halfway, view_dir), 0.0), f0);
+
+    vec3 specular = (ndf * geometry * fresnel) /
+        (4.0 * max(dot(norm, view_dir), 0.0) * max(dot(norm, light_dir), 0.0) + 0.0001);
+    // metals have no diffuse reflection
+    vec3 k_diffuse = (vec3(1.0) - fresnel) * (1.0 - metallic);
+    float n_dot_l = max(dot(norm, light_dir), 0.0);
+    return (k_diffuse * albedo / PI + specular) * radiance * n_dot_l;
+}
+
+const int SHADOW_FILTER_HARD = 0;
+const int SHADOW_FILTER_PCF = 1;
+const int SHADOW_FILTER_PCSS = 2;
+
+const int POINT_SHADOW_MAP = 0;
+const int CASCADE_SHADOW_MAP = 1;
+const int SPOT_SHADOW_MAP = 2;
+const int HIGH_RES_POINT_SHADOW_MAP = 3;
+// the first point light slots are in the high resolution texture
+const int HIGH_RES_POINT_SHADOWS = 2;
+
+// Samples on each side of the center during the pcss blocker search
+const int BLOCKER_SEARCH_RADIUS = 2;
+const float SHADOW_NEAR_PLANE = 0.1;
+
+float sample_shadow_map(int map, vec3 uv_layer, float depth) {
+    if (map == CASCADE_SHADOW_MAP) {
+        return texture(sampler2DArrayShadow(t_cascade_shadow, s_shadow), vec4(uv_layer, depth));
+    }
+    return texture(sampler2DArrayShadow(t_spot_shadow, s_shadow), vec4(uv_layer, depth));
+}
+
+float read_shadow_depth(int map, vec3 uv_layer) {
+    if (map == CASCADE_SHADOW_MAP) {
+        return texture(sampler2DArray(t_cascade_shadow, s_shadow_depth), uv_layer).r;
+    }
+    return texture(sampler2DArray(t_spot_shadow, s_shadow_depth), uv_layer).r;
+}
+
+vec2 shadow_texel_size(int map) {
+    if (map == CASCADE_SHADOW_MAP) {
+        return 1.0 / textureSize(t_cascade_shadow, 0).xy;
+    }
+    return 1.0 / textureSize(t_spot_shadow, 0).xy;
+}
+
+// Offset against shadow acne, steep surfaces need a larger one
+float slope_scaled_bias(float bias, vec3 normal, vec3 direction_to_light) {
+    float n_dot_l = clamp(dot(normal, direction_to_light), 0.05, 1.0);
+    float slope = sqrt(1.0 - n_dot_l * n_dot_l) / n_dot_l;
+    return bias * min(1.0 + slope, 10.0);
+}
+
+// Average depth of the occluders around uv or -1.0 if nothing is in front of the receiver.
+// uv_max is the part of the layer the light rendered to
+float find_blocker_depth(int map, float layer, vec2 uv, float depth, float uv_max, float search_uv) {
+    vec2 texel_size = shadow_texel_size(map);
+    vec2 step_size = max(vec2(search_uv / float(BLOCKER_SEARCH_RADIUS)), texel_size);
+    float blocker_sum = 0.0;
+    int blockers = 0;
+    for (int x = -BLOCKER_SEARCH_RADIUS; x <= BLOCKER_SEARCH_RADIUS; ++x) {
+        for (int y = -BLOCKER_SEARCH_RADIUS; y <= BLOCKER_SEARCH_RADIUS; ++y) {
+            vec2 sample_uv = clamp(uv + vec2(x, y) * step_size, 0.5 * texel_size, uv_max - 0.5 * texel_size);
+            float sample_depth = read_shadow_depth(map, vec3(sample_uv, layer));
+            if (sample_depth < depth) {
+                blocker_sum += sample_depth;
+                blockers++;
+            }
+        }
+    }
+    return blockers > 0 ? blocker_sum / float(blockers) : -1.0;
+}
+
+// Returns how much of the light is blocked using the selected filter, the kernel is
+// spread over penumbra_uv but the samples are never closer than a texel apart
+float filter_shadow(int map, float layer, vec2 uv, float depth, float uv_max, float penumbra_uv) {
+    vec2 texel_size = shadow_texel_size(map);
+    vec2 uv_min = 0.5 * texel_size;
+    vec2 uv_limit = uv_max - 0.5 * texel_size;
+    if (shadow_filter == SHADOW_FILTER_HARD) {
+        return 1.0 - sample_shadow_map(map, vec3(clamp(uv, uv_min, uv_limit), layer), depth);
+    }
+    int radius = max(shadow_kernel_radius, 1);
+    vec2 step_size = texel_size;
+    if (shadow_filter == SHADOW_FILTER_PCSS) {
+        step_size = max(vec2(penumbra_uv / float(radius)), texel_size);
+    }
+    float lit = 0.0;
+    for (int x = -radius; x <= radius; ++x) {
+        for (int y = -radius; y <= radius; ++y) {
+            vec2 sample_uv = clamp(uv + vec2(x, y) * step_size, uv_min, uv_limit);
+            lit += sample_shadow_map(map, vec3(sample_uv, layer), depth);
+        }
+    }
+    float kernel_size = float(2 * radius + 1);
+    return 1.0 - lit / (kernel_size * kernel_size);
+}
+
+// Point lights are sampled from cube arrays by direction so the hardware picks the face,
+// their filter kernels are spread over the directions around the one to the fragment
+float sample_point_shadow(int map, vec3 direction, float cube, float depth) {
+    if (map == HIGH_RES_POINT_SHADOW_MAP) {
+        return texture(samplerCubeArrayShadow(t_high_res_shadow, s_shadow), vec4(direction, cube), depth);
+    }
+    return texture(samplerCubeArrayShadow(t_shadow, s_shadow), vec4(direction, cube), depth);
+}
+
+float read_point_shadow_depth(int map, vec3 direction, float cube) {
+    if (map == HIGH_RES_POINT_SHADOW_MAP) {
+        return texture(samplerCubeArray(t_high_res_shadow, s_shadow_depth), vec4(direction, cube)).r;
+    }
+    return texture(samplerCubeArray(t_shadow, s_shadow_depth), vec4(direction, cube)).r;
+}
+
+// A face covers 2 units at unit distance from the light
+float point_shadow_texel_size(int map) {
+    if (map == HIGH_RES_POINT_SHADOW_MAP) {
+        return 2.0 / float(textureSize(t_high_res_shadow, 0).x);
+    }
+    return 2.0 / float(textureSize(t_shadow, 0).x);
+}
+
+// Axes perpendicular to a unit direction to offset the kernel samples along
+void direction_tangents(vec3 direction, out vec3 tangent, out vec3 bitangent) {
+    vec3 up = abs(direction.y) < 0.99 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0);
+    tangent = normalize(cross(up, direction));
+    bitangent = cross(direction, tangent);
+}
+
+// Same as find_blocker_depth with the radius in offsets of the unit direction
+float find_point_blocker_depth(int map, float cube, vec3 direction, float depth, float search_radius) {
+    float step_size = max(search_radius / float(BLOCKER_SEARCH_RADIUS), point_shadow_texel_size(map));
+    vec3 tangent;
+    vec3 bitangent;
+    direction_tangents(direction, tangent, bitangent);
+    float blocker_sum = 0.0;
+    int blockers = 0;
+    for (int x = -BLOCKER_SEARCH_RADIUS; x <= BLOCKER_SEARCH_RADIUS; ++x) {
+        for (int y = -BLOCKER_SEARCH_RADIUS; y <= BLOCKER_SEARCH_RADIUS; ++y) {
+            vec3 sample_direction = direction + (float(x) * tangent + float(y) * bitangent) * step_size;
+            float sample_depth = read_point_shadow_depth(map, sample_direction, cube);
+            if (sample_depth < depth) {
+                blocker_sum += sample_depth;
+                blockers++;
+            }
+        }
+    }
+    return blockers > 0 ? blocker_sum / float(blockers) : -1.0;
+}
+
+// Same as filter_shadow with the penumbra in offsets of the unit direction
+float filter_point_shadow(int map, float cube, vec3 direction, float depth, float penumbra) {
+    if (shadow_filter == SHADOW_FILTER_HARD) {
+        return 1.0 - sample_point_shadow(map, direction, cube, depth);
+    }
+    int radius = max(shadow_kernel_radius, 1);
+    float step_size = point_shadow_texel_size(map);
+    if (shadow_filter == SHADOW_FILTER_PCSS) {
+        step_size = max(penumbra / float(radius), step_size);
+    }
+    vec3 tangent;
+    vec3 bitangent;
+    direction_tangents(direction, tangent, bitangent);
+    float lit = 0.0;
+    for (int x = -radius; x <= radius; ++x) {
+        for (int y = -radius; y <= radius; ++y) {
+            vec3 sample_direction = direction + (float(x) * tangent + float(y) * bitangent) * step_size;
+            lit += sample_point_shadow(map, sample_direction, cube, depth);
+        }
+    }
+    float kernel_size = float(2 * radius + 1);
+    return 1.0 - lit / (kernel_size * kernel_size);
+}
+
+float calc_shadow(PointLight light, vec3 normal) {
+    vec3 light_to_fragment = fragment_position - light.position;
+    float distance_to_light = length(light_to_fragment);
+    if (distance_to_light > light.shadow_far_plane) {
+        return 0.0;
+    }
+    vec3 direction = light_to_fragment / distance_to_light;
+    float bias = slope_scaled_bias(light.shadow_bias, normal, -direction);
+    // the shadow map stores the linear distance divided by the far plane
+    float depth = (distance_to_light - bias) / light.shadow_far_plane;
+    bool high_res = light.shadow_index < HIGH_RES_POINT_SHADOWS;
+    int map = high_res ? HIGH_RES_POINT_SHADOW_MAP : POINT_SHADOW_MAP;
+    float cube = float(high_res ? light.shadow_index : light.shadow_index - HIGH_RES_POINT_SHADOWS);
+    // direction offset per world unit on the plane of the fragment
+    float world_to_direction = 1.0 / distance_to_light;
+
+    float penumbra = 0.0;
+    if (shadow_filter == SHADOW_FILTER_PCSS) {
+        float search_radius = light.light_size * world_to_direction;
+        float blocker_depth = find_point_blocker_depth(map, cube, direction, depth, search_radius);
+        if (blocker_depth < 0.0) {
+            return 0.0;
+        }
+        float blocker_distance = blocker_depth * light.shadow_far_plane;
+        penumbra = light.light_size * (distance_to_light - blocker_distance) / blocker_distance * world_to_direction;
+    }
+    return filter_point_shadow(map, cube, direction, depth, penumbra);
+}
+
+const mat4 CONVERSION = mat4(
+1.0, 0.0, 0.0, 0.0,
+0.0, 1.0, 0.0, 0.0,
+0.0, 0.0, 0.5, 0.0,
+0.0, 0.0, 0.5, 1.0);
+
+// Uses the first (highest resolution) cascade containing the fragment
+float calc_directional_shadow(vec3 normal) {
+    if (directional_light.cast_shadows == 0) {
+        return 0.0;
+    }
+    const vec2 flip_correction = vec2(0.5, -0.5);
+    float uv_max = directional_light.shadow_scale;
+    for (int cascade = 0; cascade < SHADOW_CASCADES; ++cascade) {
+        mat4 light_matrix = CONVERSION * directional_light.cascade_matrices[cascade];
+        vec4 light_space_pos = light_matrix * vec4(fragment_position, 1.0);
+        vec3 coords = light_space_pos.xyz / light_space_pos.w;
+        vec2 uv = coords.xy * flip_correction + 0.5;
+        if (any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(uv_max))) || coords.z > 1.0) {
+            continue;
+        }
+        // the projection is orthographic so world units scale linearly into uv and depth
+        float world_to_uv = 0.5 * length(vec3(light_matrix[0][0], light_matrix[1][0], light_matrix[2][0]));
+        float world_to_depth = length(vec3(light_matrix[0][2], light_matrix[1][2], light_matrix[2][2]));
+        float bias = slope_scaled_bias(directional_light.shadow_bias, normal, normalize(-directional_light.direction));
+        float depth = coords.z - bias * world_to_depth;
+
+        float penumbra_uv = 0.0;
+        if (shadow_filter == SHADOW_FILTER_PCSS) {
+            // widest possible penumbra, from an occluder right at the near plane
+            float search_uv = directional_light.penumbra_slope * depth / world_to_depth * world_to_uv;
+            float blocker_depth = find_blocker_depth(CASCADE_SHADOW_MAP, float(cascade), uv, depth, uv_max, search_uv);
+            if (blocker_depth < 0.0) {
+                return 0.0;
+            }
+            penumbra_uv = directional_light.penumbra_slope * (depth - blocker_depth) / world_to_depth * world_to_uv;
+        }
+        return filter_shadow(CASCADE_SHADOW_MAP, float(cascade), uv, depth, uv_max, penumbra_uv);
+    }
+    return 0.0;
+}
+
+// Perspective depth of the spot light shadow map to distance along the light direction
+float linearize_spot_depth(float depth, float far_plane) {
+    return SHADOW_NEAR_PLANE * far_plane / (far_plane - depth * (far_plane - SHADOW_NEAR_PLANE));
+}
+
+float spot_depth(float distance, float far_plane) {
+    return far_plane * (distance - SHADOW_NEAR_PLANE) / (distance * (far_plane - SHADOW_NEAR_PLANE));
+}
+
+float calc_spot_shadow(SpotLight light, vec3 normal) {
+    const vec2 flip_correction = vec2(0.5, -0.5);
+    vec4 light_space_pos = CONVERSION * light.light_space_matrix * vec4(fragment_position, 1.0);
+    if (light_space_pos.w <= 0.0) {
+        return 0.0;
+    }
+    vec3 coords = light_space_pos.xyz / light_space_pos.w;
+    vec2 uv = coords.xy * flip_correction + 0.5;
+    if (any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(light.shadow_scale))) || coords.z > 1.0) {
+        return 0.0;
+    }
+    // w is the distance along the light direction, the bias is applied to it
+    // before converting back so it stays in world units across the whole range
+    float distance = light_space_pos.w;
+    vec3 direction_to_light = normalize(light.position - fragment_position);
+    float bias = slope_scaled_bias(light.shadow_bias, normal, direction_to_light);
+    float depth = spot_depth(max(distance - bias, SHADOW_NEAR_PLANE), light.range);
+    mat4 light_matrix = light.light_space_matrix;
+    float world_to_uv = 0.5 * length(vec3(light_matrix[0][0], light_matrix[1][0], light_matrix[2][0])) / distance;
+
+    float penumbra_uv = 0.0;
+    if (shadow_filter == SHADOW_FILTER_PCSS) {
+        float search_uv = light.light_size * world_to_uv;
+        float blocker_depth = find_blocker_depth(SPOT_SHADOW_MAP, float(light.shadow_index), uv, depth, light.shadow_scale, search_uv);
+        if (blocker_depth < 0.0) {
+            return 0.0;
+        }
+        float blocker_distance = linearize_spot_depth(blocker_depth, light.range);
+        penumbra_uv = light.light_size * (distance - blocker_distance) / blocker_distance * world_to_uv;
+    }
+    return filter_shadow(SPOT_SHADOW_MAP, float(light.shadow_index), uv, depth, light.shadow_scale, penumbra_uv);
+}
+
+// Offset and count of the lights in the cluster containing the fragment
+uvec2 cluster_lights() {
+    vec4 view_position = cluster_view * vec4(fragment_position, 1.0);
+    vec4 clip_position = cluster_projection * view_position;
+    vec2 ndc = clip_position.xy / clip_position.w;
+    float depth = max(-view_position.z, cluster_near);
+    ivec3 cluster = ivec3(
+        int((ndc.x * 0.5 + 0.5) * CLUSTER_TILES_X),
+        int((ndc.y * 0.5 + 0.5) * CLUSTER_TILES_Y),
+        int(log(depth / cluster_near) / log(cluster_far / cluster_near) * CLUSTER_SLICES)
+    );
+    cluster = clamp(cluster, ivec3(0), ivec3(CLUSTER_TILES_X - 1, CLUSTER_TILES_Y - 1, CLUSTER_SLICES - 1));
+    return cluster_grid[cluster.x + cluster.y * CLUSTER_TILES_X + cluster.z * CLUSTER_TILES_X * CLUSTER_TILES_Y];
+}
+
+// Fades the light out smoothly so it has no effect past its range
+float range_falloff(float distance, float range) {
+    float ratio = distance / range;
+    float window = clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0);
+    return window * window;
+}
+
+// Fades the light out between the inner and outer cone and past the range
+float calc_spot_cone(SpotLight light, vec3 direction_to_light) {
+    float theta = dot(direction_to_light, normalize(-light.direction));
+    float cone = smoothstep(light.cos_outer_cutoff, light.cos_inner_cutoff, theta);
+    float distance = length(light.position - fragment_position);
+    return cone * step(distance, light.range);
+}
+
+float calculate_attenuation(vec3 light_position, float constant, float linear, float quadratic) {
+    float distance = length(light_position - fragment_position);
+    return 1.0 / (constant + (linear * distance) + quadratic * (distance * distance));
+}
@@ -0,0 +1,14 @@
+#version 450
+layout (location = 0) out vec2 v_tex_coords;
+
+void main() {
+    vec2 pos = vec2(0.0);
+    switch(gl_VertexIndex) {
+        case 0: pos = vec2(-1.0, -1.0); break;
+        case 1: pos = vec2( 3.0, -1.0); break;
+        case 2: pos = vec2(-1.0,  3.0); break;
+    }
+    // texture coordinates have y pointing down
+    v_tex_coords = vec2(pos.x + 1.0, 1.0 - pos.y) * 0.5;
+    gl_Position = vec4(pos, 0.0, 1.0);
+}
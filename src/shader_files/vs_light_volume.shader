@@ -0,0 +1,57 @@
+#version 450
+
+layout(location=0) flat out int light_index;
+
+struct PointLight {
+    vec3 position;
+    float shadow_far_plane;
+    vec3 specular;
+    float shadow_bias;
+    vec3 diffuse;
+    float constant;
+    float linear;
+    float quadratic;
+    float light_size;
+    float range;
+    vec3 color;
+    float intensity;
+    int shadow_index;
+};
+
+
+layout(set=3, binding=2) readonly buffer PointLights {
+    PointLight pointLights[];
+};
+
+layout(set=4, binding=0) uniform DeferredUniforms {
+    mat4 view_projection;
+    mat4 inverse_view_projection;
+    vec3 view_pos;
+};
+
+const mat4 CONVERSION = mat4(
+1.0, 0.0, 0.0, 0.0,
+0.0, 1.0, 0.0, 0.0,
+0.0, 0.0, 0.5, 0.0,
+0.0, 0.0, 0.5, 1.0);
+
+// Corner bits are x, y, z with the triangles wound counter clockwise from the outside
+const int CUBE_INDICES[36] = int[36](
+    0, 4, 6, 0, 6, 2,
+    1, 3, 7, 1, 7, 5,
+    0, 1, 5, 0, 5, 4,
+    2, 6, 7, 2, 7, 3,
+    0, 2, 3, 0, 3, 1,
+    4, 5, 7, 4, 7, 6
+);
+
+// A cube enclosing the range of the point light of this instance,
+// its back faces are drawn so it works from inside the volume as well
+void main() {
+    light_index = gl_InstanceIndex;
+    PointLight light = pointLights[gl_InstanceIndex];
+    int corner = CUBE_INDICES[gl_VertexIndex];
+    vec3 offset = vec3(corner & 1, (corner >> 1) & 1, (corner >> 2) & 1) * 2.0 - 1.0;
+    vec3 world_position = light.position + offset * light.range;
+    gl_Position = CONVERSION * view_projection * vec4(world_position, 1.0);
+}
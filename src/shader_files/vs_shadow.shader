@@ -6,8 +6,14 @@ layout(location=2) in vec2 tex_coords;
 
 layout(location=3) in mat4 model;
 
-layout(set=0, binding=0) uniform LightProjection {
-    mat4 light_projection;
+layout(location=0) out vec3 fragment_position;
+layout(location=1) out vec2 v_tex_coords;
+
+// the diffuse texture and phong factors of the alpha test come first
+layout(set=2, binding=0) uniform ShadowFace {
+    mat4 view_projection;
+    vec3 light_position;
+    float far_plane;
 };
 
 
@@ -18,5 +24,7 @@ const mat4 CONVERSION = mat4(
 0.0, 0.0, 0.5, 1.0);
 
 void main() {
-    gl_Position = CONVERSION * light_projection *  model * vec4(a_position, 1.0);
-}
\ No newline at end of file
+    v_tex_coords = tex_coords;
+    fragment_position = vec3(model * vec4(a_position, 1.0));
+    gl_Position = CONVERSION * view_projection * vec4(fragment_position, 1.0);
+}
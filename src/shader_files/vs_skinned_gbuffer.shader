@@ -0,0 +1,70 @@
+#version 450
+
+layout(location=0) in vec3 a_position;
+layout(location=1) in vec3 a_normal;
+layout(location=2) in vec2 tex_coords;
+layout(location=3) in uvec4 joints;
+layout(location=4) in vec4 weights;
+
+layout(location=5) in mat4 model;
+layout(location=9) in uint joint_offset;
+layout(location=10) in uint morph_offset;
+
+layout(location=0) out vec2 v_tex_coords;
+layout(location=1) out vec3 normal;
+
+layout(set=1, binding=0) readonly buffer JointMatrices {
+    mat4 joint_matrices[];
+};
+
+layout(set=1, binding=1) readonly buffer MorphWeights {
+    float morph_weights[];
+};
+
+struct MorphDelta {
+    vec4 position;
+    vec4 normal;
+};
+
+layout(set=2, binding=0) uniform MorphTargets {
+    uint vertex_count;
+    uint target_count;
+    uint weight_offset;
+};
+
+// the deltas of every vertex of the first target, then the second and so on
+layout(set=2, binding=1) readonly buffer MorphDeltas {
+    MorphDelta morph_deltas[];
+};
+
+layout(set=3, binding=0)
+uniform Uniforms {
+    mat4 view;
+    mat4 projection;
+    vec3 view_pos;
+};
+
+const mat4 CONVERSION = mat4(
+1.0, 0.0, 0.0, 0.0,
+0.0, 1.0, 0.0, 0.0,
+0.0, 0.0, 0.5, 0.0,
+0.0, 0.0, 0.5, 1.0);
+
+void main() {
+    vec3 position = a_position;
+    vec3 morphed_normal = a_normal;
+    for (uint i = 0; i < target_count; i++) {
+        float weight = morph_weights[morph_offset + weight_offset + i];
+        MorphDelta delta = morph_deltas[i * vertex_count + uint(gl_VertexIndex)];
+        position += weight * delta.position.xyz;
+        morphed_normal += weight * delta.normal.xyz;
+    }
+    mat4 skin = weights.x * joint_matrices[joint_offset + joints.x]
+        + weights.y * joint_matrices[joint_offset + joints.y]
+        + weights.z * joint_matrices[joint_offset + joints.z]
+        + weights.w * joint_matrices[joint_offset + joints.w];
+    mat4 skinned_model = model * skin;
+    v_tex_coords = tex_coords;
+    normal = mat3(transpose(inverse(mat3(skinned_model)))) * morphed_normal;
+    gl_Position = CONVERSION * projection * view * skinned_model * vec4(position, 1.0);
+}
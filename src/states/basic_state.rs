@@ -1,18 +1,23 @@
-use crate::{assets::Assets, camera::Camera, graphics::model::Model};
+use crate::camera::Camera;
 //use crate::components::Selected;
-use crate::components::Transform;
+use crate::components::transform_hierarchy_system;
 use crate::engine::{InputEvent, Time, WINDOW_HEIGHT, WINDOW_WIDTH};
 //use crate::physics::Physics;
 use glfw::{Action, Key};
 use legion::prelude::*;
-use nalgebra::{Isometry3, Vector3};
 
 //use nphysics3d::object::BodyStatus;
 use std::collections::HashMap;
 
 use super::State;
-use crate::{graphics::PointLight, physics::Physics};
-use nphysics3d::object::BodyStatus;
+use crate::{
+    animation::{animation_system, keyframe_animation_system},
+    graphics::{pass::tone_mapping_pass::ToneMapping, screenshot::ScreenshotQueue},
+    physics::Physics,
+    prefab::reload_prefabs,
+    save_game::SaveGame,
+    scene::Scene,
+};
 
 pub struct BasicState {
     schedule: Option<Schedule>,
@@ -34,96 +39,28 @@ impl BasicState {
     }
 }
 const CAMERA_SPEED: f32 = 4.5;
+const EXPOSURE_STEP: f32 = 1.1;
+const SCENE_PATH: &str = "scenes/basic.ron";
+const SAVED_SCENE_PATH: &str = "scenes/saved.ron";
+const QUICKSAVE_PATH: &str = "saves/quicksave.ron";
 
 impl State for BasicState {
     fn start(&mut self, world: &mut World, resources: &mut Resources) {
-        let mut model_storage = resources.get_mut::<Assets<Model>>().unwrap();
-        let suit_handle = model_storage.load("nanosuit/nanosuit.obj").unwrap();
-        let cube_handle = model_storage.load("box/cube.obj").unwrap();
-        let light_box_handle = model_storage.load("light/light_cube.obj").unwrap();
-        drop(model_storage);
         let physicis = Physics::new(resources);
-        let schedule = Schedule::builder().add_system(physicis.system).build();
+        let schedule = Schedule::builder()
+            .add_system(physicis.system)
+            .add_system(keyframe_animation_system())
+            .add_system(animation_system())
+            // after everything that moves entities
+            .add_system(transform_hierarchy_system())
+            .build();
 
         self.schedule = Some(schedule);
 
-        let light_positions = vec![
-            //    Vector3::new(0.0, 5.0, 3.0),
-            Vector3::new(2.0, 4.3, -3.0),
-            //  Vector3::new(1.0, 1.0, 0.0),
-            // Vector3::new(0.0, 0.0, -3.0),
-        ];
-        world.insert(
-            (light_box_handle, ()),
-            light_positions.iter().map(|&position| {
-                (
-                    Transform::new(
-                        Isometry3::translation(position.x, position.y, position.z),
-                        Vector3::new(0.5, 0.5, 0.5),
-                    ),
-                    PointLight {
-                        diffuse: Vector3::new(1.0, 1.0, 1.0),
-                        ..PointLight::default()
-                    },
-                )
-            }),
-        );
-        let mut components = Vec::new();
-
-        for x in 0..1 {
-            for y in 0..1 {
-                components.push((Transform::new(
-                    Isometry3::translation((x + 2) as f32, -1.75, y as f32),
-                    Vector3::new(0.2, 0.2, 0.2),
-                ),));
-            }
+        let scene = Scene::load(SCENE_PATH).and_then(|scene| scene.spawn(world, resources));
+        if let Err(err) = scene {
+            eprintln!("Failed to load scene {:?}: {:?}", SCENE_PATH, err);
         }
-
-        world.insert(
-            (suit_handle, ()), // selected
-            components,
-        );
-        let floor_transform = Transform::new(
-            Isometry3::new(
-                Vector3::new(0.0, -5.0, -2.0),
-                Vector3::z() * 90.0_f32.to_radians(),
-            ),
-            Vector3::new(0.1, 10.0, 10.0),
-        );
-        world.insert(
-            (cube_handle.clone(), ()),
-            vec![(
-                Physics::create_cube(resources, &floor_transform, BodyStatus::Static),
-                floor_transform,
-            )],
-        );
-
-        let cube_positions = vec![
-            Vector3::new(0.0, -3.0, 0.0),
-            Vector3::new(2.0, 5.0, -15.0),
-            Vector3::new(-1.5, -2.2, -2.5),
-            Vector3::new(-3.8, -2.0, -12.0),
-            Vector3::new(2.4, -0.4, -3.5),
-            Vector3::new(-1.7, 3.0, -7.5),
-            Vector3::new(1.3, -2.0, -2.5),
-            Vector3::new(1.5, 2.0, -2.5),
-            Vector3::new(1.5, 0.2, -1.5),
-            Vector3::new(-1.3, 1.0, -1.5),
-        ];
-
-        world.insert(
-            (cube_handle, ()),
-            cube_positions.iter().map(|&position| {
-                let transform = Transform::new(
-                    Isometry3::translation(position.x, position.y, position.z),
-                    Vector3::new(0.7, 0.7, 0.7),
-                );
-                (
-                    Physics::create_cube(resources, &transform, BodyStatus::Dynamic),
-                    transform,
-                )
-            }),
-        );
     }
 
     fn update(&mut self, world: &mut World, resources: &mut Resources) {
@@ -141,7 +78,7 @@ impl State for BasicState {
     fn handle_event(
         &mut self,
         event: InputEvent,
-        _world: &mut World,
+        world: &mut World,
         resources: &mut Resources,
     ) -> bool {
         match event {
@@ -149,6 +86,45 @@ impl State for BasicState {
                 if key == Key::Escape {
                     return true;
                 }
+                if key == Key::F12 && action == Action::Press {
+                    let current_time = resources.get::<Time>().unwrap().current_time;
+                    resources
+                        .get_mut::<ScreenshotQueue>()
+                        .unwrap()
+                        .request(format!("screenshot-{:.0}.png", current_time * 1000.0));
+                }
+                if key == Key::F5 && action == Action::Press {
+                    if let Err(err) = Scene::from_world(world, resources).save(SAVED_SCENE_PATH) {
+                        eprintln!("Failed to save scene {:?}: {:?}", SAVED_SCENE_PATH, err);
+                    }
+                }
+                if key == Key::F8 && action == Action::Press {
+                    if let Err(err) = SaveGame::capture(world, resources)
+                        .and_then(|save| save.save(QUICKSAVE_PATH))
+                    {
+                        eprintln!("Failed to save game {:?}: {:?}", QUICKSAVE_PATH, err);
+                    }
+                }
+                if key == Key::F9 && action == Action::Press {
+                    let save = SaveGame::load(QUICKSAVE_PATH)
+                        .and_then(|save| save.restore(world, resources));
+                    if let Err(err) = save {
+                        eprintln!("Failed to load game {:?}: {:?}", QUICKSAVE_PATH, err);
+                    }
+                }
+                if key == Key::F6 && action == Action::Press {
+                    if let Err(err) = reload_prefabs(world, resources) {
+                        eprintln!("Failed to reload prefabs: {:?}", err);
+                    }
+                }
+                if action == Action::Press && (key == Key::Up || key == Key::Down) {
+                    let mut tone_mapping = resources.get_mut::<ToneMapping>().unwrap();
+                    if key == Key::Up {
+                        tone_mapping.exposure *= EXPOSURE_STEP;
+                    } else {
+                        tone_mapping.exposure /= EXPOSURE_STEP;
+                    }
+                }
                 let time = resources.get::<Time>().unwrap();
                 let mut camera = resources.get_mut::<Camera>().unwrap();
                 if action == Action::Press {